@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
 // SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
 use std::fs::File;
@@ -9,8 +10,14 @@ use std::ffi::{OsStr, OsString};
 use std::process::Command;
 use std::path::{Path, PathBuf};
 
-use log::{LevelFilter, info, error};
-use diffy::Patch;
+use log::{LevelFilter, info, warn, error};
+
+#[path = "build/cache.rs"]
+mod cache;
+#[path = "build/patch.rs"]
+mod patch;
+#[path = "build/redist.rs"]
+mod redist;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum BuildType
@@ -66,6 +73,16 @@ fn getenv(v: &str) -> Option<String>
     env::var(v).ok()
 }
 
+/// The clang target triple for a Rust target triple. Rust's `windows-gnu` and `windows-gnullvm`
+/// targets are clang's `w64-mingw32` ones, and the rest are spelled the same.
+fn clang_target(rust_target: &str) -> String
+{
+    match rust_target.split_once("-pc-windows-") {
+        Some((arch, "gnu" | "gnullvm")) => format!("{}-w64-mingw32", arch),
+        _ => rust_target.to_string(),
+    }
+}
+
 /// Reimplementation of private function [cc::Build::get_var].
 fn get_cc_var(var_base: &str) -> Option<String>
 {
@@ -87,6 +104,18 @@ fn get_cc_var(var_base: &str) -> Option<String>
     }
 }
 
+/// Escapes a path for use in a C string literal define, e.g. `-DUSER_DIR="C:\\signed-driver"`.
+fn c_string_define(path: &str) -> String
+{
+    format!(r#""{}""#, path.replace('\\', "\\\\"))
+}
+
+/// Whether we're building for a `*-windows-gnu` target, i.e. with mingw-w64 instead of MSVC.
+fn is_gnu_target() -> bool
+{
+    env::var("CARGO_CFG_TARGET_ENV").map(|target_env| target_env == "gnu").unwrap_or(false)
+}
+
 /// Turns a [cc::Build] into a [Command] that can be used to create an executable,
 /// since cc-rs doesn't directly support compiling executables.
 trait CcOutputExecutable
@@ -116,6 +145,17 @@ struct LibwdiBuild
     /// The base directory we'll use for libwdi sources.
     /// This will be a subdirectory of out_dir.
     libwdi_src: PathBuf,
+
+    /// The directory holding a prebuilt libwdi static library, from `LIBWDI_LIB_DIR`.
+    /// If this is set, we don't build libwdi at all.
+    prebuilt_dir: Option<PathBuf>,
+
+    /// The directory to cache the embedder step's artifacts in, from `LIBWDI_CACHE_DIR`.
+    cache_dir: Option<PathBuf>,
+
+    /// Whether to skip building libwdi entirely and link Rust stubs instead, with the `stub`
+    /// feature or on docs.rs.
+    stub: bool,
 }
 
 impl LibwdiBuild
@@ -126,12 +166,21 @@ impl LibwdiBuild
         let out_dir = PathBuf::from(env::var("OUT_DIR").expect("Cargo always sets OUT_DIR"));
         let libwdi_repo = PathBuf::from(std::env::current_dir().unwrap()).join("libwdi");
         let libwdi_src = out_dir.join("libwdi");
+        let prebuilt_dir = env::var_os("LIBWDI_LIB_DIR").map(PathBuf::from);
+        println!("cargo:rerun-if-env-changed=LIBWDI_LIB_DIR");
+        let cache_dir = env::var_os("LIBWDI_CACHE_DIR").map(PathBuf::from);
+        println!("cargo:rerun-if-env-changed=LIBWDI_CACHE_DIR");
+        let stub = cfg!(feature = "stub") || env::var_os("DOCS_RS").is_some();
+        println!("cargo:rerun-if-env-changed=DOCS_RS");
 
         Self {
             cwd,
             out_dir,
             libwdi_repo,
             libwdi_src,
+            prebuilt_dir,
+            cache_dir,
+            stub,
         }
     }
 
@@ -148,6 +197,11 @@ impl LibwdiBuild
 
         let mut compile_args: Vec<String> = Vec::new();
 
+        // mingw-w64 toolchains ship their own headers and libraries for each architecture.
+        if is_gnu_target() {
+            return compile_args;
+        }
+
         let cflags = match get_cc_var("CFLAGS") {
             Some(v) => v,
             None => return compile_args,
@@ -185,6 +239,10 @@ impl LibwdiBuild
 
         let mut link_args: Vec<String> = Vec::with_capacity(3);
 
+        if is_gnu_target() {
+            return link_args;
+        }
+
         let cflags = match get_cc_var("CFLAGS") {
             Some(v) => v,
             None => return link_args,
@@ -238,12 +296,19 @@ impl LibwdiBuild
             .flag_if_supported("/MT") // Runtime library: multi-threaded.
             .flag_if_supported("/Zc:wchar_t") // Treat wchar_t as a built-in type.
             .flag_if_supported("/TC") // Compile as C code.
+            .flag_if_supported("/Brepro") // No timestamps in object files, for reproducibility.
         ;
 
         if build_type == BuildType::Host && cfg!(not(windows)) {
             // If we're cross compiling generally, but this cc::Build is for the host,
             // add the special host-include path which includes config.h but not the msvc headers.
             build.include(self.libwdi_src.join("host-include"));
+        } else if is_gnu_target() {
+            // mingw-w64 has its own inttypes.h and stdint.h, and msvc/config.h refuses to be
+            // used without MSVC, so use the generated config.h instead.
+            build
+                .include(self.libwdi_src.join("gnu-include"))
+                .flag_if_supported(&format!("-ffile-prefix-map={}=.", self.out_dir.display()));
         } else {
             // Otherwise, make sure the "msvc" directory is in the include path.
             build.include(self.libwdi_src.join("msvc"));
@@ -254,23 +319,18 @@ impl LibwdiBuild
     /// Copies and patches libwdi source files to a source hierarchy in OUT_DIR/libwdi.
     fn populate_source_tree(&self)
     {
-        // The source files that we'll copy, but need to be patched first, and their patch files.
-        // Source files relative from libwdi_repo; patch files relative from current directory.
-        let needs_patch = &[
-            // libwdi's embedder host program hardcodes the path to installer_x64.exe based on
-            // Visual Studio's default directory structure (e.g. `x64/Release/helper`).
-            // We're not using that, so let's patch that path.
-            (Path::new("libwdi/embedder.h"), Path::new("installer_path.patch")),
-
-            // libwdi doesn't let you simply not define driver file locations for libusb-win32
-            // or libusbK to disable them, so let's cut default paths with a patch.
-            // We still can enable them later by defining LIBUSB0_DIR or LIBUSBK_DIR
-            (Path::new("msvc/config.h"), Path::new("no_default_paths.patch")),
-
-            // libwdi's installer makes a mess of some types that makes ARM compilation
-            // angry, so fix the type mistakes with a patch.
-            (Path::new("libwdi/installer.c"), Path::new("installer_types_mismatches.patch")),
-        ];
+        // The source files that we'll copy, but need to be patched first, and their patch files,
+        // from the patch manifest. See patches/manifest.txt.
+        let patches_dir = self.cwd.join("patches");
+        let version = self.expected_version();
+        let manifest = patch::read_manifest(&patches_dir.join("manifest.txt"))
+            .unwrap_or_else(|e| panic!("{}", e));
+        let needs_patch = patch::select(&manifest, version.as_deref());
+        info!(
+            "Patching libwdi {} with {} patches",
+            version.as_deref().unwrap_or("(unknown version)"),
+            needs_patch.len(),
+        );
 
         // The source files that we'll copy as-is.
         // Again relative from libwdi_repo (after that .map() below, at least).
@@ -315,10 +375,12 @@ impl LibwdiBuild
         as_is.extend(msvc_headers);
 
         // Let Cargo know we depend on aaaaaall of these files.
-        let mut all_needed_files: Vec<PathBuf> = Vec::with_capacity(as_is.len() + needs_patch.len());
-        all_needed_files.extend(needs_patch.map(|item| self.libwdi_repo.join(item.0)));
-        all_needed_files.extend(needs_patch.map(|item| self.cwd.join(item.1)));
+        let mut all_needed_files: Vec<PathBuf> = Vec::with_capacity(as_is.len() + needs_patch.len() * 2 + 2);
+        all_needed_files.extend(needs_patch.iter().map(|entry| self.libwdi_repo.join(&entry.source)));
+        all_needed_files.extend(needs_patch.iter().map(|entry| patches_dir.join(&entry.patch)));
         all_needed_files.extend(as_is.iter().map(|path| self.libwdi_repo.join(path)));
+        all_needed_files.push(patches_dir.join("manifest.txt"));
+        all_needed_files.push(self.libwdi_repo.join("configure.ac"));
 
         for file in all_needed_files {
             println!("cargo:rerun-if-changed={}", file.display());
@@ -337,8 +399,8 @@ impl LibwdiBuild
         }
 
         // Almost done. Patch the sources we need to patch...
-        for (src_file, patch_file) in needs_patch {
-            self.apply_patch_file(src_file, patch_file);
+        for entry in &needs_patch {
+            self.apply_patch_file(entry, &patches_dir, version.as_deref());
         }
 
         // Disable target support if flag is not set
@@ -349,9 +411,17 @@ impl LibwdiBuild
             self.replace_in_file(Path::new("msvc/config.h"), "#define OPT_ARM", "//#define OPT_ARM");
         }
 
+        // For mingw-w64 builds, generate a config.h that doesn't insist on MSVC. The host build
+        // uses it too, so it doesn't need to pretend to be MSVC either.
+        if is_gnu_target() {
+            self.write_gnu_config("gnu-include");
+        }
+
         // Minor hack: when cross compiling, the host needs a config.h, but needs to NOT have the
         // msvc headers in the include path. Let's create a special directory for that.
-        if cfg!(not(windows)) {
+        if cfg!(not(windows)) && is_gnu_target() {
+            self.write_gnu_config("host-include");
+        } else if cfg!(not(windows)) {
 
             let host_include = self.libwdi_src.join("host-include");
             fs::create_dir_all(&host_include)
@@ -385,44 +455,76 @@ impl LibwdiBuild
         // And we're done! :tada:
     }
 
-    /// `src_file` should be a relative path from source tree base, e.g. `libwdi/libwdi.c`.
-    /// `patch_file` should be either absolute or relative from the current working directory.
-    fn apply_patch_file<SrcP, PatchP>(&self, src_file: SrcP, patch_file: PatchP)
-    where
-        SrcP: AsRef<Path>,
-        PatchP: AsRef<Path>
+    /// Applies the patch described by the manifest entry `entry` to its source file, writing the
+    /// result to the source tree in OUT_DIR. See [patch::apply] for how drift is tolerated.
+    fn apply_patch_file(&self, entry: &patch::ManifestEntry, patches_dir: &Path, version: Option<&str>)
     {
-        let src_file = src_file.as_ref();
-        let patch_file = patch_file.as_ref();
+        let base_path = self.libwdi_repo.join(&entry.source);
+        let target_path = self.libwdi_src.join(&entry.source);
+        let patch_path = patches_dir.join(&entry.patch);
 
-        let base_path = self.libwdi_repo.join(src_file);
-        let target_path = self.libwdi_src.join(src_file);
+        info!("Applying {} to {}", patch_path.display(), target_path.display());
 
-        info!("Applying {} to {}", patch_file.display(), target_path.display());
-
-        // First, create the Patch structure from the patch file.
-        let patch_text = fs::read_to_string(patch_file)
-            .expect(&format!("Error reading patch file {}", patch_file.display()));
-
-        // HACK: convert from crlf to lf, as diffy doesn't support Windows line endings.
-        let patch_text: String = patch_text.chars().into_iter().filter(|c| *c != '\r').collect();
-        let patch = Patch::from_str(&patch_text)
-            .expect(&format!("Patch file {} seems invalid", patch_file.display()));
-
-        // Now read the file we need to patch, and then patch its text.
+        // Patches are kept with LF line endings, so convert the source to match.
         let base_text = fs::read_to_string(&base_path)
-            .expect(&format!("Error reading source file {} for patching", src_file.display()));
+            .expect(&format!("Error reading source file {} for patching", entry.source.display()));
+        let base_text: String = base_text.chars().filter(|c| *c != '\r').collect();
+
+        let applied = match patch::apply_entry(entry, &patch_path, &base_text, version) {
+            Ok(applied) => applied,
+            Err(e) => {
+                error!("{}", e);
+                panic!("Error applying patch {} to source file {}", entry.patch.display(), entry.source.display());
+            },
+        };
 
-        // HACK: convert from crlf to lf, as diffy doesn't support Windows line endings.
-        let base_text: String = base_text.chars().into_iter().filter(|c| *c != '\r').collect();
-        let patched_text = diffy::apply(&base_text, &patch)
-            .expect(&format!("Error applying patch {} to source file {}", patch_file.display(), src_file.display()));
+        if applied.is_upstream() {
+            println!(
+                "cargo:warning={} is already applied upstream in libwdi {}; it can be removed from patches/manifest.txt",
+                entry.patch.display(),
+                version.unwrap_or("(unknown version)"),
+            );
+        } else if !applied.already_applied.is_empty() {
+            warn!("{}: hunks {:?} were already applied upstream", entry.patch.display(), applied.already_applied);
+        }
+        if !applied.fuzzed.is_empty() {
+            warn!("{}: hunks {:?} only applied with fuzz; consider refreshing the patch", entry.patch.display(), applied.fuzzed);
+        }
 
         // Finally, write the patched text to the target source tree (in OUT_DIR).
-        fs::write(&target_path, &patched_text)
+        fs::write(&target_path, &applied.text)
             .expect(&format!("Error writing patched source file {}", target_path.display()));
     }
 
+    /// Writes a config.h for non-MSVC compilers to `dir` (relative from the source tree base),
+    /// derived from the patched msvc/config.h so the two never disagree on driver paths or
+    /// enabled architectures. The `#error` guards that reject non-MSVC compilers and the
+    /// MSVC-only pragmas are stripped.
+    fn write_gnu_config(&self, dir: &str)
+    {
+        let config_dir = self.libwdi_src.join(dir);
+        fs::create_dir_all(&config_dir)
+            .expect(&format!("Error creating {} directory", config_dir.display()));
+
+        let msvc_config = fs::read_to_string(self.libwdi_src.join("msvc/config.h"))
+            .expect("Error reading patched msvc/config.h");
+        let gnu_config: String = msvc_config
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("#pragma warning"))
+            .map(|line| {
+                if line.trim_start().starts_with("#error") {
+                    format!("// {}\n", line)
+                } else {
+                    format!("{}\n", line)
+                }
+            })
+            .collect();
+
+        let config_path = config_dir.join("config.h");
+        fs::write(&config_path, gnu_config)
+            .expect(&format!("Error writing {}", config_path.display()));
+    }
+
     fn replace_in_file<TargetP>(&self, target_file: TargetP, target_str: &str, replacement: &str)
     where
         TargetP: AsRef<Path>
@@ -430,26 +532,30 @@ impl LibwdiBuild
         let target_path = self.libwdi_src.join(target_file);
         let content = fs::read_to_string(&target_path)
             .expect(&format!("Error reading source file for replace {}", target_path.display()));
+        // Fail loudly rather than silently building with the wrong configuration if upstream
+        // changed the text we're looking for.
+        if !content.contains(target_str) && !content.contains(replacement) {
+            error!("Neither {:?} nor {:?} found in {}", target_str, replacement, target_path.display());
+            panic!("Error replacing text in {}; has libwdi changed?", target_path.display());
+        }
         let new_content = content.replace(target_str, replacement);
         fs::write(&target_path, new_content)
             .expect(&format!("Error writing source file for replace {}", target_path.display()));
     }
 
-    /// Compiles the embedder host binary that is needed to compile libwdi.
+    /// Returns the command that compiles the embedder host binary that is needed to compile libwdi.
     ///
     /// libwdi's normal build process involves compiling an executable, which is then run
     /// during the build process to generate a C header file which contains the bytes of
-    /// the output of [make_installer_x86_64]. This embedder binary thus must be a host executable.
+    /// the output of [installer_x86_64_command]. This embedder binary thus must be a host executable.
     /// It also has to be built before the other build steps.
     /// [cc] does not support building executables, directly, so this function contains some hacks
     /// to try to get it to work.
     ///
     /// If something in this build script blows up, it's probably this, especially during cross
     /// compilation.
-    fn make_embedder(&self)
+    fn embedder_command(&self) -> Command
     {
-        info!("Building embedder host binary...");
-
         let mut embedder = cc::Build::new();
         embedder
             .static_crt(true)
@@ -505,10 +611,18 @@ impl LibwdiBuild
             println!("cargo:rerun-if-env-changed=LIBUSBK_DIR");
         }
 
+        // And the same for a user supplied driver.
+        if let Some((user_dir, _)) = self.user_driver() {
+            embedder.define("USER_DIR", Some(c_string_define(&user_dir).as_str()));
+        }
+
         // If we're cross compiling...
         if !cfg!(windows) {
-            // config.h errors if _MSC_VER isn't defined, so let's just define it.
-            embedder.define("_MSC_VER", "1929");
+            // msvc/config.h errors if _MSC_VER isn't defined, so let's just define it. GNU target
+            // builds use the generated config.h in host-include instead.
+            if !is_gnu_target() {
+                embedder.define("_MSC_VER", "1929");
+            }
 
             // Also let the user know that WDK_DIR is required when cross compiling.
             if let Err(_e) = env::var("WDK_DIR") {
@@ -536,24 +650,16 @@ impl LibwdiBuild
         // Add the C source file, because cc::Build::file() doesn't survive the conversion to a Command.
         cc_cmd.arg(self.libwdi_src.join("libwdi/embedder.c").as_os_str());
 
-        info!("{:?}", cc_cmd);
-
-        let success = cc_cmd
-            .current_dir(&self.out_dir)
-            .status()
-            .unwrap()
-            .success();
-        if !success {
-            panic!("Compiler returned non-zero exit code");
-        }
+        cc_cmd.current_dir(&self.out_dir);
+        cc_cmd
     }
 
-    /// Builds the `installer_x64.exe` binary which gets embedded into a C header for the
-    /// rest of the library.
+    /// Returns the command that builds the `installer_x64.exe` binary which gets embedded into a
+    /// C header for the rest of the library.
     ///
-    /// Like [make_embedder], this function also contains hacks to use [cc] to compile an
+    /// Like [embedder_command], this function also contains hacks to use [cc] to compile an
     /// executable instead of a library.
-    fn make_installer_x86_64(&self)
+    fn installer_x86_64_command(&self) -> Command
     {
         // The equivalent msbuild command to build this manually:
         // $MSBUILD libwdi/.msvc/installer_x64.vcxproj -p:PlatformToolset=142
@@ -564,8 +670,6 @@ impl LibwdiBuild
         // /D _WINDLL /D _UNICODE /Gm- /EHsc /MT /GS /fp:precise /Qspectre /Zc:wchar_t /Zc:forScope
         // /Zc:inline /external:W3 /Gd /TC /FC ..\installer.c
 
-        info!("Building installer_x64...");
-
         let output_path = self.out_dir.join("installer_x64.exe");
 
         // HACK: if we're running under cargo-xwin then we need to scrape out its xwin directory
@@ -576,45 +680,30 @@ impl LibwdiBuild
         let mut installer = cc::Build::new();
         self.apply_common_cc_options(&mut installer, BuildType::Target);
 
-        let mut cc_cmd = installer
-            .target("x86_64-pc-windows-msvc")
-            .static_crt(true)
-            .flag(&format!("/Fe{}", output_path.display()))
-            .output_executable();
+        let mut cc_cmd = if is_gnu_target() {
+            installer
+                .target("x86_64-pc-windows-gnu")
+                .output_executable()
+        } else {
+            installer
+                .target("x86_64-pc-windows-msvc")
+                .static_crt(true)
+                .flag(&format!("/Fe{}", output_path.display()))
+                .output_executable()
+        };
 
+        self.add_installer_args(&mut cc_cmd, &output_path, &compile_args, &linker_flags);
+
+        cc_cmd.current_dir(&self.out_dir);
         cc_cmd
-            .args(&compile_args)
-            // See [PathToArg] for why the .to_arg() is here.
-            .arg(self.libwdi_src.join("libwdi/installer.c").to_arg())
-            // Add the WinAPI libraries we need to link against.
-            .args(&[
-                "/link",
-                "newdev.lib",
-                "setupapi.lib",
-                "user32.lib",
-                "ole32.lib",
-                "advapi32.lib",
-            ])
-            .args(&linker_flags);
-
-        info!("{:?}", cc_cmd);
-
-        let success = cc_cmd
-            .current_dir(&self.out_dir)
-            .status()
-            .unwrap()
-            .success();
-        if !success {
-            panic!("Compiler returned non-zero exit code");
-        }
     }
 
-    /// Builds the `installer_arm64.exe` binary which gets embedded into a C header for the
-    /// rest of the library.
+    /// Returns the command that builds the `installer_arm64.exe` binary which gets embedded into a
+    /// C header for the rest of the library.
     ///
-    /// Like [make_embedder], this function also contains hacks to use [cc] to compile an
+    /// Like [embedder_command], this function also contains hacks to use [cc] to compile an
     /// executable instead of a library.
-    fn make_installer_arm64(&self)
+    fn installer_arm64_command(&self) -> Command
     {
         // The equivalent msbuild command to build this manually:
         // $MSBUILD libwdi/.msvc/installer_arm64.vcxproj -p:PlatformToolset=143
@@ -625,8 +714,6 @@ impl LibwdiBuild
         // /D _WINDLL /D _UNICODE /Gm- /EHsc /MT /GS /fp:precise /Qspectre /Zc:wchar_t /Zc:forScope
         // /Zc:inline /external:W3 /Gd /TC /FC ..\installer.c
 
-        info!("Building installer_arm64...");
-
         let output_path = self.out_dir.join("installer_arm64.exe");
 
         // HACK: if we're running under cargo-xwin then we need to scrape out its xwin directory
@@ -637,40 +724,344 @@ impl LibwdiBuild
         let mut installer = cc::Build::new();
         self.apply_common_cc_options(&mut installer, BuildType::Target);
 
-        let mut cc_cmd = installer
-            .target("aarch64-pc-windows-msvc")
-            .static_crt(true)
-            .flag(&format!("/Fe{}", output_path.display()))
-            .output_executable();
+        let mut cc_cmd = if is_gnu_target() {
+            installer
+                .target("aarch64-pc-windows-gnullvm")
+                .output_executable()
+        } else {
+            installer
+                .target("aarch64-pc-windows-msvc")
+                .static_crt(true)
+                .flag(&format!("/Fe{}", output_path.display()))
+                .output_executable()
+        };
 
+        self.add_installer_args(&mut cc_cmd, &output_path, &compile_args, &linker_flags);
+
+        cc_cmd.current_dir(&self.out_dir);
         cc_cmd
-            .args(&compile_args)
-            // See [PathToArg] for why the .to_arg() is here.
-            .arg(self.libwdi_src.join("libwdi/installer.c").to_arg())
-            // Add the WinAPI libraries we need to link against.
-            .args(&[
-                "/link",
-                "newdev.lib",
-                "setupapi.lib",
-                "user32.lib",
-                "ole32.lib",
-                "advapi32.lib",
-            ])
-            .args(&linker_flags);
-
-        info!("{:?}", cc_cmd);
-
-        let success = cc_cmd
-            .current_dir(&self.out_dir)
-            .status()
-            .unwrap()
-            .success();
-        if !success {
-            panic!("Compiler returned non-zero exit code");
+    }
+
+    /// Adds the installer source, the output path and the WinAPI libraries the installers link
+    /// against to `cc_cmd`, in either MSVC or GNU style.
+    fn add_installer_args(&self, cc_cmd: &mut Command, output_path: &Path, compile_args: &[String], linker_flags: &[String])
+    {
+        cc_cmd.args(compile_args);
+
+        if is_gnu_target() {
+            cc_cmd
+                .arg("-o")
+                .arg(output_path)
+                .arg(self.libwdi_src.join("libwdi/installer.c"))
+                // Don't depend on the mingw runtime DLLs being next to the installer.
+                .arg("-static")
+                // Leave the PE timestamp and OUT_DIR out of the installer, so it's reproducible.
+                .arg("-Wl,--no-insert-timestamp")
+                .arg(format!("-ffile-prefix-map={}=.", self.out_dir.display()))
+                .args(&[
+                    "-lnewdev",
+                    "-lsetupapi",
+                    "-luser32",
+                    "-lole32",
+                    "-ladvapi32",
+                ]);
+        } else {
+            cc_cmd
+                // See [PathToArg] for why the .to_arg() is here.
+                .arg(self.libwdi_src.join("libwdi/installer.c").to_arg())
+                // Replace the PE and object file timestamps with a hash of the contents, so the
+                // installer is reproducible. Both cl.exe and clang-cl accept this, as do link.exe
+                // and lld-link.
+                .arg("/Brepro")
+                // Add the WinAPI libraries we need to link against.
+                .args(&[
+                    "/link",
+                    "/Brepro",
+                    "newdev.lib",
+                    "setupapi.lib",
+                    "user32.lib",
+                    "ole32.lib",
+                    "advapi32.lib",
+                ]);
+        }
+
+        cc_cmd.args(linker_flags);
+    }
+
+    /// The directory macros the embedder is given, by the environment variable of the same name.
+    fn embedded_dir_vars(&self) -> Vec<&'static str>
+    {
+        let mut vars = vec!["WDK_DIR"];
+        if cfg!(feature = "libusb0") {
+            vars.push("LIBUSB0_DIR");
+        }
+        if cfg!(feature = "libusbk") {
+            vars.push("LIBUSBK_DIR");
+        }
+        if cfg!(feature = "user-driver") {
+            vars.push("USER_DIR");
+        }
+
+        vars
+    }
+
+    /// The `#define`s in `msvc/config.h` under `libwdi_dir`, which is either the patched sources
+    /// or the submodule.
+    fn config_defines(&self, libwdi_dir: &Path) -> Result<BTreeMap<String, String>, String>
+    {
+        let path = libwdi_dir.join("msvc/config.h");
+        let config = fs::read_to_string(&path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+
+        Ok(redist::parse_defines(&config))
+    }
+
+    /// The directory the embedder takes the files for `var` from: the environment variable, if
+    /// the embedder is given it, or for `WDK_DIR`, `msvc/config.h`'s default.
+    fn redist_dir(&self, var: &str) -> Option<PathBuf>
+    {
+        if let Some(dir) = getenv(var).filter(|_| self.embedded_dir_vars().contains(&var)) {
+            return Some(dir.into());
+        }
+        if var != "WDK_DIR" {
+            return None;
+        }
+
+        self.config_defines(&self.libwdi_src)
+            .ok()?
+            .get("WDK_DIR")
+            .and_then(|value| redist::string_value(value))
+            .map(PathBuf::from)
+    }
+
+    /// The files the embedder embeds with the enabled features, from `embedder_files.h` and
+    /// `msvc/config.h` under `libwdi_dir`, which is either the patched sources or the submodule.
+    fn embedded_files(&self, libwdi_dir: &Path) -> Result<Vec<redist::EmbeddedFile>, String>
+    {
+        let path = libwdi_dir.join("libwdi/embedder_files.h");
+        let header = fs::read_to_string(&path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+
+        // Only whether the directories are defined matters here. The submodule's config.h
+        // doesn't know about the features, so they're applied as in populate_source_tree.
+        let dir_vars = self.embedded_dir_vars();
+        let mut defines = self.config_defines(libwdi_dir)?;
+        for var in ["LIBUSB0_DIR", "LIBUSBK_DIR", "USER_DIR"] {
+            if !dir_vars.contains(&var) {
+                defines.remove(var);
+            }
+        }
+        for var in dir_vars {
+            defines.entry(var.to_string()).or_insert_with(|| String::from("\"\""));
+        }
+        if cfg!(not(feature = "enable-x86")) {
+            defines.remove("OPT_M32");
+        }
+        if cfg!(not(feature = "enable-arm64")) {
+            defines.remove("OPT_ARM");
+        }
+
+        redist::parse_embedder_files(&header, &defines)
+    }
+
+    /// The versions of the redistributable files the embedder embeds, by directory variable;
+    /// see [redist::Redist::version].
+    fn redist_versions(&self) -> BTreeMap<&'static str, String>
+    {
+        let Ok(files) = self.embedded_files(&self.libwdi_src) else {
+            return BTreeMap::new();
+        };
+
+        redist::DIR_VARS
+            .iter()
+            .filter_map(|var| {
+                let files = files.iter().filter(|file| file.var == *var).cloned().collect();
+                let redist = redist::Redist { var, dir: self.redist_dir(var)?, files };
+                Some((*var, redist.version()?))
+            })
+            .collect()
+    }
+
+    /// Checks that `WDK_DIR`, and `LIBUSB0_DIR` and `LIBUSBK_DIR` with their features, contain
+    /// every file the embedder needs according to `embedder_files.h`, naming each missing file
+    /// and its architecture, rather than leaving the embedder to fail obscurely. With
+    /// `LIBWDI_REDIST_MANIFEST` set, those files are also checked against their pinned hashes.
+    ///
+    /// If `embedder_files.h` can't be understood, this only warns, leaving the embedder to check.
+    fn validate_redist(&self)
+    {
+        println!("cargo:rerun-if-env-changed=LIBWDI_REDIST_MANIFEST");
+
+        // If we can't tell what the embedder takes, it is left to complain itself.
+        let files = match self.embedded_files(&self.libwdi_src) {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Not checking the redistributable driver files: {}", e);
+                println!("cargo:warning=Not checking the redistributable driver files: {}", e);
+                return;
+            },
+        };
+
+        // Without WDK_DIR, msvc/config.h's default is used, which only exists on Windows and is
+        // only our business if the embedder can't find it.
+        let mut dirs = Vec::new();
+        for var in redist::DIR_VARS {
+            if let Some(dir) = getenv(var).filter(|_| self.embedded_dir_vars().contains(var)) {
+                let files = files.iter().filter(|file| file.var == *var).cloned().collect();
+                dirs.push(redist::Redist { var, dir: dir.into(), files });
+            }
+        }
+
+        let manifest = getenv("LIBWDI_REDIST_MANIFEST").map(|path| {
+            redist::HashManifest::read(Path::new(&path)).unwrap_or_else(|e| panic!("{}", e))
+        });
+
+        let mut problems = Vec::new();
+        for dir in &dirs {
+            problems.extend(dir.check_layout());
+            if let Some(manifest) = &manifest {
+                problems.extend(dir.check_hashes(manifest));
+            }
+        }
+
+        if !problems.is_empty() {
+            for problem in &problems {
+                error!("{}", problem);
+            }
+            panic!(
+                "{} problem(s) with the redistributable driver files:\n{}",
+                problems.len(),
+                problems
+                    .iter()
+                    .map(|problem| format!("  {}", problem))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            );
+        }
+    }
+
+    /// Builds the embedder and installers and runs the embedder to generate `embedded.h`.
+    ///
+    /// With `LIBWDI_CACHE_DIR` set, the inputs to all of that are hashed first (see
+    /// [artifact_key]), and if the cache has artifacts for the same key they're used instead,
+    /// so builds with identical inputs reuse the exact same `embedded.h`. Otherwise the artifacts
+    /// are stored there after building. Rebuilding isn't guaranteed to give the same bytes; only
+    /// cache hits are.
+    fn make_embedded_header(&self)
+    {
+        let mut commands = vec![("embedder", self.embedder_command())];
+        if cfg!(feature = "enable-x86") {
+            commands.push(("installer_x64", self.installer_x86_64_command()));
+        }
+        if cfg!(feature = "enable-arm64") {
+            commands.push(("installer_arm64", self.installer_arm64_command()));
+        }
+
+        let header_path = self.libwdi_src.join("libwdi/embedded.h");
+        let mut artifacts = vec![("embedded.h", header_path)];
+        for (name, _) in &commands[1..] {
+            artifacts.push((*name, self.out_dir.join(format!("{}.exe", name))));
+        }
+        let artifacts: Vec<(&str, &Path)> = artifacts
+            .iter()
+            .map(|(name, path)| (*name, path.as_path()))
+            .collect();
+
+        let cache = self.cache_dir.clone().map(|dir| {
+            let key = self.artifact_key(&commands);
+            (cache::ArtifactCache::new(dir), key)
+        });
+
+        if let Some((cache, key)) = &cache {
+            if cache.restore(key, &artifacts) {
+                info!("Using cached embedder artifacts {}", key);
+                return;
+            }
+            info!("No cached embedder artifacts for {}; building them", key);
+        }
+
+        for (name, mut cc_cmd) in commands {
+            info!("Building {}...", name);
+            info!("{:?}", cc_cmd);
+
+            let success = cc_cmd
+                .status()
+                .unwrap()
+                .success();
+            if !success {
+                panic!("Compiler returned non-zero exit code");
+            }
+        }
+        self.run_embedder();
+
+        if let Some((cache, key)) = &cache {
+            // A cache we can't write to only costs us the next build's time.
+            if let Err(e) = cache.store(key, &artifacts) {
+                warn!("Error storing embedder artifacts in the cache: {}", e);
+                println!("cargo:warning=Error storing embedder artifacts in LIBWDI_CACHE_DIR: {}", e);
+            }
+        }
+    }
+
+    /// Hashes everything that goes into `embedded.h`: the patched sources and the patches, the
+    /// compiler invocations, the enabled features, and the WDK and driver directories that get
+    /// embedded.
+    fn artifact_key(&self, commands: &[(&str, Command)]) -> String
+    {
+        let mut fingerprint = cache::Fingerprint::new(&self.out_dir);
+        fingerprint.str("crate", env!("CARGO_PKG_VERSION"));
+        fingerprint.str("target", &env::var("TARGET").expect("Cargo always sets TARGET"));
+        fingerprint.str("host", &env::var("HOST").expect("Cargo always sets HOST"));
+        for (feature, enabled) in [
+            ("enable-x86", cfg!(feature = "enable-x86")),
+            ("enable-arm64", cfg!(feature = "enable-arm64")),
+            ("libusb0", cfg!(feature = "libusb0")),
+            ("libusbk", cfg!(feature = "libusbk")),
+            ("user-driver", cfg!(feature = "user-driver")),
+        ] {
+            fingerprint.str(feature, if enabled { "on" } else { "off" });
+        }
+
+        for (name, cc_cmd) in commands {
+            fingerprint.command(name, cc_cmd);
+        }
+
+        // A stale embedded.h from a previous run in the same OUT_DIR isn't an input.
+        let mut dirs = vec![
+            ("sources", self.libwdi_src.clone()),
+            ("patches", self.cwd.join("patches")),
+        ];
+
+        // The embedder only takes the redistributable co-installers from the WDK, and hashing
+        // the whole kit would take a while. Without WDK_DIR, that's msvc/config.h's default, and
+        // if that doesn't exist the embedder will fail anyway.
+        if let Some(wdk_dir) = self.redist_dir("WDK_DIR") {
+            let redist = wdk_dir.join("redist");
+            if redist.is_dir() {
+                dirs.push(("WDK_DIR", redist));
+            } else if wdk_dir.is_dir() {
+                dirs.push(("WDK_DIR", wdk_dir));
+            } else {
+                fingerprint.str("WDK_DIR", &wdk_dir.to_string_lossy());
+            }
+        }
+        for var in ["LIBUSB0_DIR", "LIBUSBK_DIR"] {
+            if let Some(dir) = self.redist_dir(var) {
+                dirs.push((var, dir));
+            }
+        }
+        if let Some((user_dir, _)) = self.user_driver() {
+            dirs.push(("USER_DIR", user_dir.into()));
+        }
+
+        for (label, dir) in dirs {
+            fingerprint
+                .path(label, &dir, &["embedded.h"])
+                .expect(&format!("Error hashing {} for the artifact cache", dir.display()));
         }
+
+        fingerprint.finish()
     }
 
-    /// Runs the host embedder executable built in [make_embedder]. See that function for more
+    /// Runs the host embedder executable built by [embedder_command]. See that function for more
     /// details.
     fn run_embedder(&self)
     {
@@ -693,8 +1084,7 @@ impl LibwdiBuild
 
     /// Builds the actual libwdi static library (wdi.lib and libwdi.a).
     ///
-    /// With [make_embedder], [make_installer_x86_64], and [run_embedder] out of the way, this function
-    /// finally uses [cc] only for its intended purpose.
+    /// With [make_embedded_header] out of the way, this function finally uses [cc] only for its intended purpose.
     fn make_lib(&self)
     {
         info!("Building libwdi static library...");
@@ -728,7 +1118,7 @@ impl LibwdiBuild
                     .unwrap();
                 lib.define("LIBUSB0_DIR", Some(format!(r#""{}""#, path).as_str()));
             }
-            // Everything else is handled by make_embedder
+            // Everything else is handled by embedder_command
         }
         // Ditto for libusbk
         if cfg!(feature = "libusbk") {
@@ -742,7 +1132,11 @@ impl LibwdiBuild
                     .unwrap();
                 lib.define("LIBUSBK_DIR", Some(format!(r#""{}""#, path).as_str()));
             }
-            // Everything else is handled by make_embedder
+            // Everything else is handled by embedder_command
+        }
+
+        if let Some((user_dir, _)) = self.user_driver() {
+            lib.define("USER_DIR", Some(c_string_define(&user_dir).as_str()));
         }
 
         // Tell the builder about all the input files that are needed
@@ -752,17 +1146,401 @@ impl LibwdiBuild
         lib.compile("wdi");
 
         println!("cargo:include={}", self.libwdi_src.join("libwdi").to_str().unwrap());
+        self.link_system_libs();
+    }
+
+    /// Tells Cargo which Windows libraries libwdi itself needs.
+    fn link_system_libs(&self)
+    {
         println!("cargo:rustc-link-lib=shell32");
         println!("cargo:rustc-link-lib=ole32");
+
+        // MSVC picks these up from #pragma comment(lib, ...) in the sources, but GNU linkers
+        // ignore those pragmas.
+        if is_gnu_target() {
+            for lib in ["setupapi", "newdev", "advapi32", "user32", "crypt32"] {
+                println!("cargo:rustc-link-lib={}", lib);
+            }
+        }
     }
 
-    /// This function is only used when the feature "dynamic-bindgen" is enabled, which isn't
-    /// recommended.
-    fn run_bindgen(&self)
+    /// With the `user-driver` feature, validates `USER_DIR` and returns it along with the name
+    /// of the INF in it. Returns None without the feature, or in stub builds.
+    ///
+    /// libwdi embeds every file in `USER_DIR`, and the INF has to be passed by name to
+    /// `wdi_prepare_driver` and `wdi_install_driver`, so the directory must hold exactly one
+    /// INF, and a catalog for it since Windows refuses unsigned driver packages.
+    fn user_driver(&self) -> Option<(String, String)>
+    {
+        if cfg!(not(feature = "user-driver")) || self.stub {
+            return None;
+        }
+        println!("cargo:rerun-if-env-changed=USER_DIR");
+
+        let user_dir = env::var("USER_DIR").unwrap_or_else(|_| {
+            error!("USER_DIR environment variable required when compiling with user-driver");
+            panic!("USER_DIR environment variable required when compiling with user-driver");
+        });
+        let entries = fs::read_dir(&user_dir)
+            .expect(&format!("Error reading USER_DIR {}", user_dir));
+        println!("cargo:rerun-if-changed={}", user_dir);
+
+        let names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+        let with_extension = |extension: &str| -> Vec<&String> {
+            names
+                .iter()
+                .filter(|name| {
+                    Path::new(name)
+                        .extension()
+                        .map(|ext| ext.eq_ignore_ascii_case(extension))
+                        .unwrap_or(false)
+                })
+                .collect()
+        };
+
+        let infs = with_extension("inf");
+        let inf_name = match infs.as_slice() {
+            [inf] => inf.to_string(),
+            [] => {
+                error!("USER_DIR {} does not contain an INF file", user_dir);
+                panic!("USER_DIR does not contain an INF file");
+            },
+            _ => {
+                error!("USER_DIR {} contains more than one INF file: {:?}", user_dir, infs);
+                panic!("USER_DIR must contain exactly one INF file");
+            },
+        };
+        if with_extension("cat").is_empty() {
+            error!("USER_DIR {} does not contain a catalog (.cat) file; Windows will not install an unsigned driver", user_dir);
+            panic!("USER_DIR does not contain a catalog file");
+        }
+
+        Some((user_dir, inf_name))
+    }
+
+    /// Generates `user_driver.rs` in OUT_DIR, exposing the name of the embedded user driver's INF.
+    fn write_user_driver_consts(&self)
+    {
+        let inf_name = if self.stub && cfg!(feature = "user-driver") {
+            // Nothing is embedded in stub builds, but dependents still need the constant.
+            String::from("user.inf")
+        } else if let Some((_, inf_name)) = self.user_driver() {
+            inf_name
+        } else {
+            return;
+        };
+
+        let path = self.out_dir.join("user_driver.rs");
+        fs::write(
+            &path,
+            format!(
+                "/// The name of the INF of the user driver embedded from `USER_DIR`.\npub const WDI_USER_INF: &str = {:?};\n",
+                inf_name,
+            ),
+        )
+            .expect(&format!("Error writing {}", path.display()));
+    }
+
+    /// The version from the `DriverVer` line of the user driver's INF, e.g. `1.2.3.4`.
+    fn user_driver_version(&self, user_dir: &str, inf_name: &str) -> Option<String>
+    {
+        let contents = fs::read(Path::new(user_dir).join(inf_name)).ok()?;
+        let text = match contents.strip_prefix(&[0xff, 0xfe]) {
+            Some(utf16) => String::from_utf16_lossy(
+                &utf16.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect::<Vec<_>>(),
+            ),
+            None => String::from_utf8_lossy(&contents).into_owned(),
+        };
+
+        // DriverVer = 01/02/2023,1.2.3.4 ; comment
+        text.lines()
+            .map(|line| line.split(';').next().unwrap_or("").trim())
+            .find_map(|line| {
+                let (key, value) = line.split_once('=')?;
+                key.trim().eq_ignore_ascii_case("DriverVer").then_some(value)
+            })
+            .and_then(|value| value.split(',').nth(1))
+            .map(|version| version.trim().to_string())
+            .filter(|version| !version.is_empty())
+    }
+
+    /// The git directory of the libwdi submodule, which its `.git` file points to.
+    fn libwdi_git_dir(&self) -> Option<PathBuf>
+    {
+        let dot_git = self.libwdi_repo.join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+
+        // gitdir: ../../.git/modules/libwdi-sys/libwdi
+        let link = fs::read_to_string(&dot_git).ok()?;
+        let git_dir = link.trim().strip_prefix("gitdir:")?.trim();
+        Some(self.libwdi_repo.join(git_dir))
+    }
+
+    /// The commit of the libwdi submodule, if it is a git checkout and git is available.
+    fn libwdi_revision(&self) -> Option<String>
+    {
+        // Checking out another commit in the submodule changes its HEAD, or the branch it names.
+        if let Some(git_dir) = self.libwdi_git_dir() {
+            let head = git_dir.join("HEAD");
+            println!("cargo:rerun-if-changed={}", head.display());
+            let branch = fs::read_to_string(&head)
+                .ok()
+                .and_then(|head| head.trim().strip_prefix("ref:").map(|branch| git_dir.join(branch.trim())));
+            if let Some(branch) = branch.filter(|branch| branch.is_file()) {
+                println!("cargo:rerun-if-changed={}", branch.display());
+            }
+        }
+
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.libwdi_repo)
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        String::from_utf8(output.stdout).ok().map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
+    }
+
+    /// Describes what this build of libwdi embeds: as `wdi_has_*` cfg flags for this crate, as
+    /// `links` metadata (e.g. `DEP_WDI_HAS_LIBUSB0`) for dependents' build scripts, and as
+    /// `build_info.rs` in OUT_DIR, which src/lib.rs exposes as `build_info`.
+    fn write_build_info(&self)
+    {
+        let user_driver = self.user_driver();
+        let embeds = !self.stub;
+        let flags = [
+            ("wdi_has_libusb0", embeds && cfg!(feature = "libusb0")),
+            ("wdi_has_libusbk", embeds && cfg!(feature = "libusbk")),
+            ("wdi_has_user_driver", user_driver.is_some()),
+            ("wdi_has_x64_installer", embeds && cfg!(feature = "enable-x86")),
+            ("wdi_has_arm64_installer", embeds && cfg!(feature = "enable-arm64")),
+            ("wdi_prebuilt", embeds && self.prebuilt_dir.is_some()),
+            ("wdi_stub", self.stub),
+            ("wdi_dynamic_bindings", embeds && cfg!(feature = "dynamic-bindgen")),
+        ];
+        for (flag, enabled) in flags {
+            println!("cargo:rustc-check-cfg=cfg({})", flag);
+            if enabled {
+                println!("cargo:rustc-cfg={}", flag);
+                // Which bindings are used is this crate's business.
+                if flag != "wdi_dynamic_bindings" {
+                    println!("cargo:{}=1", flag.trim_start_matches("wdi_"));
+                }
+            }
+        }
+
+        // Prebuilt libraries weren't necessarily built from our submodule, and stubs aren't built
+        // from anything. Only versions are recorded, not paths on the build machine.
+        let from_source = self.prebuilt_dir.is_none() && !self.stub;
+        let (version, revision, mut redist_versions) = if from_source {
+            (self.expected_version(), self.libwdi_revision(), self.redist_versions())
+        } else {
+            (None, None, BTreeMap::new())
+        };
+        let user_driver_version = user_driver
+            .filter(|_| from_source)
+            .and_then(|(user_dir, inf_name)| self.user_driver_version(&user_dir, &inf_name));
+
+        // What libwdi extracts to a driver package. A prebuilt library is assumed to embed what
+        // the submodule would with the same features, if it's checked out.
+        let package_files: Vec<(String, String)> = if self.stub {
+            Vec::new()
+        } else {
+            let libwdi_dir = if from_source { &self.libwdi_src } else { &self.libwdi_repo };
+            match self.embedded_files(libwdi_dir) {
+                Ok(files) => files.into_iter().map(|file| (file.arch, file.name)).collect(),
+                Err(e) => {
+                    warn!("Not listing the embedded files in build_info: {}", e);
+                    Vec::new()
+                },
+            }
+        };
+
+        let contents = format!(
+            "pub const LIBWDI_VERSION: Option<&str> = {:?};\n\
+            pub const LIBWDI_REVISION: Option<&str> = {:?};\n\
+            pub const TARGET: &str = {:?};\n\
+            pub const WDK_VERSION: Option<&str> = {:?};\n\
+            pub const LIBUSB0_VERSION: Option<&str> = {:?};\n\
+            pub const LIBUSBK_VERSION: Option<&str> = {:?};\n\
+            pub const USER_DRIVER_VERSION: Option<&str> = {:?};\n\
+            /// The files libwdi extracts to a driver package, as (subdirectory, file name), from \
+            its embedder_files.h. The user driver's files aren't included.\n\
+            pub const PACKAGE_FILES: &[(&str, &str)] = &{:?};\n",
+            version,
+            revision,
+            env::var("TARGET").expect("Cargo always sets TARGET"),
+            redist_versions.remove("WDK_DIR"),
+            redist_versions.remove("LIBUSB0_DIR"),
+            redist_versions.remove("LIBUSBK_DIR"),
+            user_driver_version,
+            package_files,
+        );
+
+        let path = self.out_dir.join("build_info.rs");
+        fs::write(&path, contents)
+            .expect(&format!("Error writing {}", path.display()));
+    }
+
+    /// The libwdi version the committed bindings were generated from, as declared in the
+    /// submodule's `configure.ac`. Returns None if the submodule isn't checked out.
+    fn expected_version(&self) -> Option<String>
+    {
+        let configure = fs::read_to_string(self.libwdi_repo.join("configure.ac")).ok()?;
+        let ac_init = configure.lines().find(|line| line.trim_start().starts_with("AC_INIT"))?;
+
+        // AC_INIT([libwdi], [1.5.1], [https://github.com/pbatard/libwdi/issues], ...)
+        ac_init
+            .split(',')
+            .nth(1)
+            .map(|version| version.trim().trim_matches(|c| c == '[' || c == ']').to_string())
+    }
+
+    /// The names of the `wdi_*` functions declared in the committed bindings.
+    fn bound_functions(&self) -> Vec<String>
+    {
+        // The functions are the same for every architecture.
+        let bindings = fs::read_to_string(self.cwd.join("src/bindings/x86_64.rs"))
+            .expect("Error reading src/bindings/x86_64.rs");
+
+        bindings
+            .lines()
+            .filter_map(|line| line.trim_start().strip_prefix("pub fn "))
+            .filter_map(|decl| decl.split('(').next())
+            .filter(|name| name.starts_with("wdi_"))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Links against a prebuilt libwdi static library (`wdi.lib` or `libwdi.a`) in `lib_dir`
+    /// instead of building it, after checking that it matches the bindings and the enabled
+    /// features as far as we can tell from its contents.
+    fn link_prebuilt(&self, lib_dir: &Path)
+    {
+        let lib_path = ["wdi.lib", "libwdi.a"]
+            .into_iter()
+            .map(|name| lib_dir.join(name))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| {
+                error!("LIBWDI_LIB_DIR is set, but {} contains neither wdi.lib nor libwdi.a", lib_dir.display());
+                panic!("No libwdi static library found in LIBWDI_LIB_DIR");
+            });
+        info!("Using prebuilt libwdi {}", lib_path.display());
+        println!("cargo:rerun-if-changed={}", lib_path.display());
+
+        let contents = fs::read(&lib_path)
+            .expect(&format!("Error reading {}", lib_path.display()));
+        let contains = |needle: &str| contents.windows(needle.len()).any(|window| window == needle.as_bytes());
+
+        // Every function the bindings declare must be in the library's symbol table, or
+        // linking fails later with a far less helpful error.
+        let missing: Vec<String> = self.bound_functions()
+            .into_iter()
+            .filter(|name| !contains(name))
+            .collect();
+        if !missing.is_empty() {
+            error!("{} does not export these functions from the bindings: {}", lib_path.display(), missing.join(", "));
+            panic!("Prebuilt libwdi does not match the bindings");
+        }
+
+        // libwdi doesn't expose its version in the library, so the best we can do is look for
+        // the version string in its data.
+        match self.expected_version() {
+            Some(version) if contains(&version) => info!("Prebuilt libwdi appears to be version {}", version),
+            Some(version) => println!(
+                "cargo:warning=Could not confirm that {} is libwdi {}, which the bindings were generated from",
+                lib_path.display(),
+                version,
+            ),
+            None => println!("cargo:warning=libwdi submodule not checked out; not checking the prebuilt library's version"),
+        }
+
+        // The embedded driver files are stored under their file names, so we can tell which
+        // drivers the library was built with.
+        let drivers = [
+            ("libusb0", cfg!(feature = "libusb0"), "libusb0.sys"),
+            ("libusbk", cfg!(feature = "libusbk"), "libusbK.sys"),
+        ];
+        for (feature, enabled, file) in drivers {
+            match (enabled, contains(file)) {
+                (true, false) => {
+                    error!("The {} feature is enabled, but {} does not embed {}", feature, lib_path.display(), file);
+                    panic!("Prebuilt libwdi was built without {}", feature);
+                },
+                (false, true) => println!(
+                    "cargo:warning={} embeds {}, but the {} feature is disabled",
+                    lib_path.display(),
+                    file,
+                    feature,
+                ),
+                _ => (),
+            }
+        }
+        let installers = [
+            ("enable-x86", cfg!(feature = "enable-x86"), "installer_x64.exe"),
+            ("enable-arm64", cfg!(feature = "enable-arm64"), "installer_arm64.exe"),
+        ];
+        for (feature, enabled, file) in installers {
+            if enabled && !contains(file) {
+                println!("cargo:warning=The {} feature is enabled, but {} does not embed {}", feature, lib_path.display(), file);
+            }
+        }
+        if let Some((user_dir, inf_name)) = self.user_driver() {
+            if !contains(&inf_name) {
+                error!("The user-driver feature is enabled, but {} does not embed {} from {}", lib_path.display(), inf_name, user_dir);
+                panic!("Prebuilt libwdi was built without the user driver");
+            }
+        }
+        if !contains("winusbcoinstaller2.dll") {
+            println!("cargo:warning={} does not embed the WinUSB co-installer; it was probably built without WDK_DIR", lib_path.display());
+        }
+
+        println!("cargo:rustc-link-search=native={}", lib_dir.display());
+        println!("cargo:rustc-link-lib=static=wdi");
+        println!("cargo:include={}", self.header_dir().display());
+        self.link_system_libs();
+    }
+
+    /// The directory containing libwdi.h. For prebuilt libraries, this is `LIBWDI_INCLUDE_DIR`
+    /// if set, or the headers in the submodule otherwise.
+    fn header_dir(&self) -> PathBuf
+    {
+        if self.prebuilt_dir.is_none() {
+            return self.libwdi_src.join("libwdi");
+        }
+
+        println!("cargo:rerun-if-env-changed=LIBWDI_INCLUDE_DIR");
+        env::var_os("LIBWDI_INCLUDE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.libwdi_repo.join("libwdi"))
+    }
+
+    /// Points clang-sys at the libclang that ships with Visual Studio, if `LIBCLANG_PATH` isn't
+    /// already set and we're on Windows, where there's no system libclang to find. Everywhere
+    /// else, clang-sys finds the system libclang (or the one in `LIBCLANG_PATH`) by itself.
+    fn find_libclang(&self)
     {
-        // HACK: attempt to find libclang.dll from Visual Studio.
-        let msvc = cc::windows_registry::find_tool("x86_64-pc-windows-msvc", "vcruntime140.dll")
-            .expect("Failed to find MSVC");
+        println!("cargo:rerun-if-env-changed=LIBCLANG_PATH");
+        if env::var_os("LIBCLANG_PATH").is_some() || cfg!(not(windows)) {
+            return;
+        }
+
+        let msvc = match cc::windows_registry::find_tool("x86_64-pc-windows-msvc", "vcruntime140.dll") {
+            Some(msvc) => msvc,
+            None => {
+                warn!("Couldn't find MSVC to look for its libclang; set LIBCLANG_PATH if bindgen fails");
+                return;
+            },
+        };
         let msvc_path = msvc.path();
 
         let clang_dir = msvc_path // cl.exe
@@ -773,27 +1551,197 @@ impl LibwdiBuild
             .parent().unwrap() // MSVC
             .parent().unwrap() // Tools
             .join(&["Llvm", "x64", "bin"].into_iter().collect::<PathBuf>());
-        dbg!(clang_dir.join("libclang.dll"));
+        info!("Using libclang from {}", clang_dir.display());
         env::set_var("LIBCLANG_PATH", clang_dir.join("libclang.dll"));
+    }
+
+    /// Extra clang arguments for finding the Windows headers when generating bindings for
+    /// `clang_target` on a non-Windows host.
+    ///
+    /// `LIBWDI_BINDGEN_SYSROOT` takes priority: for MinGW targets it's passed as `--sysroot`,
+    /// and for MSVC targets it's taken to be an `xwin splat` directory, whose CRT and SDK
+    /// include directories are added. Failing that, the include directories cargo-xwin passes
+    /// in CFLAGS are used. With neither, clang is left to find the headers itself, which works
+    /// for MinGW targets with a `<triple>-gcc` in PATH, and `BINDGEN_EXTRA_CLANG_ARGS` can
+    /// always fill in the rest.
+    fn bindgen_sysroot_args(&self, clang_target: &str) -> Vec<String>
+    {
+        println!("cargo:rerun-if-env-changed=LIBWDI_BINDGEN_SYSROOT");
+        if cfg!(windows) {
+            return Vec::new();
+        }
+
+        if let Some(sysroot) = getenv("LIBWDI_BINDGEN_SYSROOT").map(PathBuf::from) {
+            if clang_target.ends_with("-mingw32") {
+                return vec![format!("--sysroot={}", sysroot.display())];
+            }
+            return ["crt/include", "sdk/include/ucrt", "sdk/include/um", "sdk/include/shared"]
+                .into_iter()
+                .map(|dir| format!("-isystem{}", sysroot.join(dir).display()))
+                .collect();
+        }
 
+        // cargo-xwin passes the headers with clang-cl's /imsvc, but bindgen drives clang, not
+        // clang-cl.
+        get_cc_var("CFLAGS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|flag| flag.strip_prefix("/imsvc"))
+            .map(|dir| format!("-isystem{}", dir))
+            .collect()
+    }
+
+    /// Runs bindgen on wrapper.h for `clang_target`.
+    fn generate_bindings(&self, clang_target: &str) -> bindgen::Bindings
+    {
         println!("cargo:rerun-if-changed=wrapper.h");
 
-        let bindings = bindgen::Builder::default()
+        bindgen::Builder::default()
             .header("wrapper.h")
             .parse_callbacks(Box::new(bindgen::CargoCallbacks))
-            .clang_arg(format!("-I{}", self.libwdi_src.join("libwdi").to_str().unwrap()))
+            .clang_arg(format!("--target={}", clang_target))
+            .clang_args(self.bindgen_sysroot_args(clang_target))
+            .clang_arg(format!("-I{}", self.header_dir().to_str().unwrap()))
             .allowlist_function("wdi_.*")
             .allowlist_var("wdi_.*")
             .allowlist_type("wdi_.*")
             .prepend_enum_name(false)
             .detect_include_paths(true)
             .generate()
-            .expect("Unable to generate bindings");
+            .expect("Unable to generate bindings")
+    }
+
+    /// This function is only used when the feature "dynamic-bindgen" is enabled, which isn't
+    /// recommended.
+    fn run_bindgen(&self)
+    {
+        self.find_libclang();
 
+        let target = env::var("TARGET").expect("Cargo always sets TARGET");
+        let bindings = self.generate_bindings(&clang_target(&target));
         bindings
             .write_to_file(self.out_dir.join("bindings.rs"))
             .expect("Couldn't write bindings");
     }
+
+    /// Picks the committed bindings for the target architecture, for src/lib.rs to include as
+    /// `WDI_COMMITTED_BINDINGS`. Struct layouts differ between architectures, so a target without
+    /// its own copy in src/bindings is an error rather than borrowing another's; generate it with
+    /// `LIBWDI_CHECK_BINDINGS=update`.
+    fn select_committed_bindings(&self)
+    {
+        println!("cargo:rerun-if-changed=src/bindings");
+
+        // Non-stub dynamic-bindgen builds use the bindings generated in OUT_DIR instead.
+        if cfg!(feature = "dynamic-bindgen") && !self.stub {
+            return;
+        }
+
+        let arch = match env::var("CARGO_CFG_TARGET_ARCH").expect("Cargo always sets CARGO_CFG_TARGET_ARCH").as_str() {
+            "x86" => String::from("i686"),
+            arch => arch.to_string(),
+        };
+        let mut path = self.cwd.join("src/bindings").join(format!("{}.rs", arch));
+        // LIBWDI_CHECK_BINDINGS=update creates it once libwdi is built.
+        let updating = env::var("LIBWDI_CHECK_BINDINGS").as_deref() == Ok("update");
+        if !path.exists() && !updating {
+            panic!(
+                "No committed bindings for {} in src/bindings; generate them with LIBWDI_CHECK_BINDINGS=update",
+                arch,
+            );
+        }
+
+        // The bindings declare DWORD as Windows' 32 bit unsigned long, which is 64 bits on other
+        // hosts. Stub builds for those get a fixed width DWORD, so the layouts (and their tests)
+        // still match Windows.
+        let windows = env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows");
+        if !windows && path.exists() {
+            let bindings = fs::read_to_string(&path)
+                .expect(&format!("Error reading {}", path.display()));
+            let fixed = bindings.replace(
+                "pub type DWORD = ::std::os::raw::c_ulong;",
+                "pub type DWORD = u32;",
+            );
+            path = self.out_dir.join("committed_bindings.rs");
+            fs::write(&path, fixed)
+                .expect(&format!("Error writing {}", path.display()));
+        }
+
+        println!("cargo:rustc-env=WDI_COMMITTED_BINDINGS={}", path.display());
+    }
+
+    /// Regenerates the committed bindings in src/bindings for every architecture and compares
+    /// them to the committed copies, if `LIBWDI_CHECK_BINDINGS` is set. With
+    /// `LIBWDI_CHECK_BINDINGS=update`, the committed copies are overwritten, or created for
+    /// architectures that don't have one yet, instead.
+    ///
+    /// When not running on Windows, the Windows headers are found as described in
+    /// [bindgen_sysroot_args].
+    fn check_committed_bindings(&self)
+    {
+        println!("cargo:rerun-if-env-changed=LIBWDI_CHECK_BINDINGS");
+        let Ok(mode) = env::var("LIBWDI_CHECK_BINDINGS") else {
+            return;
+        };
+        let update = mode == "update";
+
+        self.find_libclang();
+
+        let targets = [
+            ("x86_64", "x86_64-pc-windows-msvc"),
+            ("i686", "i686-pc-windows-msvc"),
+            ("aarch64", "aarch64-pc-windows-msvc"),
+        ];
+
+        // Keep the license header on the committed copies.
+        let bindings_dir = self.cwd.join("src/bindings");
+        let reference = fs::read_to_string(bindings_dir.join("x86_64.rs"))
+            .expect("Error reading src/bindings/x86_64.rs");
+        let header: String = reference
+            .lines()
+            .take_while(|line| line.starts_with("//"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+
+        let mut stale: Vec<String> = Vec::new();
+        for (arch, clang_target) in targets {
+            let committed_path = bindings_dir.join(format!("{}.rs", arch));
+            let generated = format!("{}{}", header, self.generate_bindings(clang_target));
+
+            if update {
+                fs::write(&committed_path, &generated)
+                    .expect(&format!("Error writing {}", committed_path.display()));
+                info!("Updated {}", committed_path.display());
+                continue;
+            }
+
+            let Ok(committed) = fs::read_to_string(&committed_path) else {
+                error!("{} is missing", committed_path.display());
+                stale.push(arch.to_string());
+                continue;
+            };
+
+            let committed_lines: Vec<&str> = committed.lines().collect();
+            let generated_lines: Vec<&str> = generated.lines().collect();
+            let difference = (0..committed_lines.len().max(generated_lines.len()))
+                .find(|line| committed_lines.get(*line) != generated_lines.get(*line));
+            if let Some(line) = difference {
+                error!(
+                    "{} differs from freshly generated bindings for {}, starting at line {}:\n  committed: {:?}\n  generated: {:?}",
+                    committed_path.display(),
+                    clang_target,
+                    line + 1,
+                    committed_lines.get(line).unwrap_or(&""),
+                    generated_lines.get(line).unwrap_or(&""),
+                );
+                stale.push(arch.to_string());
+            }
+        }
+
+        if !stale.is_empty() {
+            panic!("Committed bindings for {} are stale; rerun with LIBWDI_CHECK_BINDINGS=update", stale.join(", "));
+        }
+    }
 }
 
 fn main()
@@ -804,18 +1752,26 @@ fn main()
         .init();
 
     let build = LibwdiBuild::new();
-    build.populate_source_tree();
-    build.make_embedder();
-    if cfg!(feature = "enable-x86") {
-        build.make_installer_x86_64();
+    build.write_user_driver_consts();
+    build.select_committed_bindings();
+    if build.stub {
+        build.write_build_info();
+        info!("Stub build; not building libwdi");
+        return;
     }
-    if cfg!(feature = "enable-arm64") {
-        build.make_installer_arm64();
+    if let Some(lib_dir) = &build.prebuilt_dir {
+        build.write_build_info();
+        build.link_prebuilt(lib_dir);
+    } else {
+        build.populate_source_tree();
+        build.validate_redist();
+        build.write_build_info();
+        build.make_embedded_header();
+        build.make_lib();
     }
-    build.run_embedder();
-    build.make_lib();
 
     if cfg!(feature = "dynamic-bindgen") {
         build.run_bindgen();
     }
+    build.check_committed_bindings();
 }
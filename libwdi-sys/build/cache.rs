@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! A content-addressed cache for the artifacts of the embedder step, so identical inputs skip
+//! building the embedder and installers and reuse the exact same bytes.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use sha2::{Digest, Sha256};
+
+
+/// Bump this whenever what goes into a [Fingerprint] or how artifacts are laid out in the cache
+/// changes, so old entries are never mistaken for new ones.
+const CACHE_FORMAT: &str = "libwdi-sys artifact cache v1";
+
+/// Incrementally hashes everything that can affect the embedder's output.
+///
+/// Every input is labelled and length prefixed, so that moving bytes from one input to the next
+/// can't produce the same key.
+pub struct Fingerprint
+{
+    hasher: Sha256,
+
+    /// Replaced with a placeholder in every string hashed, so the key doesn't depend on where
+    /// Cargo put OUT_DIR.
+    out_dir: String,
+}
+
+impl Fingerprint
+{
+    pub fn new(out_dir: &Path) -> Self
+    {
+        let mut fingerprint = Self {
+            hasher: Sha256::new(),
+            out_dir: out_dir.display().to_string(),
+        };
+        fingerprint.str("format", CACHE_FORMAT);
+
+        fingerprint
+    }
+
+    fn bytes(&mut self, label: &str, bytes: &[u8])
+    {
+        for part in [label.as_bytes(), bytes] {
+            self.hasher.update((part.len() as u64).to_le_bytes());
+            self.hasher.update(part);
+        }
+    }
+
+    pub fn str(&mut self, label: &str, value: &str)
+    {
+        let value = value.replace(&self.out_dir, "$OUT_DIR");
+        self.bytes(label, value.as_bytes());
+    }
+
+    fn os_str(&mut self, label: &str, value: &OsStr)
+    {
+        self.str(label, &value.to_string_lossy());
+    }
+
+    /// Hashes a compiler invocation: the program, its arguments, the environment it's given, and
+    /// the compiler's own version banner.
+    pub fn command(&mut self, label: &str, command: &Command)
+    {
+        self.str("command", label);
+        self.os_str("program", command.get_program());
+        for arg in command.get_args() {
+            self.os_str("arg", arg);
+        }
+        for (key, value) in command.get_envs() {
+            self.os_str("env", key);
+            self.os_str("value", value.unwrap_or_default());
+        }
+
+        // cl.exe doesn't understand --version, but still prints its version banner before
+        // complaining about it, which is all we need.
+        if let Ok(output) = Command::new(command.get_program()).arg("--version").output() {
+            self.bytes("version", &output.stdout);
+            self.bytes("version", &output.stderr);
+        }
+    }
+
+    /// Hashes the contents of `path` if it's a file, or the relative paths and contents of every
+    /// file under it in a stable order if it's a directory. Files named in `skip` are ignored.
+    pub fn path(&mut self, label: &str, path: &Path, skip: &[&str]) -> io::Result<()>
+    {
+        self.str("path", label);
+        if path.is_file() {
+            let contents = fs::read(path)?;
+            self.bytes("contents", &contents);
+            return Ok(());
+        }
+
+        let mut files = Vec::new();
+        collect_files(path, path, skip, &mut files)?;
+        files.sort();
+
+        for relative in files {
+            let contents = fs::read(path.join(&relative))?;
+            self.str("file", &relative);
+            self.bytes("contents", &contents);
+        }
+
+        Ok(())
+    }
+
+    /// The key, as lower case hex.
+    pub fn finish(self) -> String
+    {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// Collects the paths of the files under `dir` relative to `base`, with `/` separators so the
+/// key is the same on every host.
+fn collect_files(base: &Path, dir: &Path, skip: &[&str], files: &mut Vec<String>) -> io::Result<()>
+{
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if skip.contains(&name.as_ref()) {
+            continue;
+        }
+
+        if path.is_dir() {
+            collect_files(base, &path, skip, files)?;
+        } else {
+            let relative = path.strip_prefix(base).unwrap_or(&path);
+            let components: Vec<_> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            files.push(components.join("/"));
+        }
+    }
+
+    Ok(())
+}
+
+/// A directory of artifacts keyed by [Fingerprint], from `LIBWDI_CACHE_DIR`. Each entry is a
+/// subdirectory named after its key, holding the artifacts by file name.
+pub struct ArtifactCache
+{
+    dir: PathBuf,
+}
+
+impl ArtifactCache
+{
+    pub fn new(dir: PathBuf) -> Self
+    {
+        Self { dir }
+    }
+
+    /// Copies every artifact in `files` out of the entry for `key` to its destination. Returns
+    /// false, having copied nothing, if the entry doesn't exist or is missing any of them.
+    pub fn restore(&self, key: &str, files: &[(&str, &Path)]) -> bool
+    {
+        let entry = self.dir.join(key);
+        if !files.iter().all(|(name, _)| entry.join(name).is_file()) {
+            return false;
+        }
+
+        files
+            .iter()
+            .all(|(name, dest)| fs::copy(entry.join(name), dest).is_ok())
+    }
+
+    /// Stores every artifact in `files` under `key`. The entry is assembled in a temporary
+    /// directory and renamed into place, so concurrent builds sharing a cache never see a
+    /// partial entry.
+    pub fn store(&self, key: &str, files: &[(&str, &Path)]) -> io::Result<()>
+    {
+        let entry = self.dir.join(key);
+        if entry.is_dir() {
+            return Ok(());
+        }
+
+        let staging = self.dir.join(format!("{}.tmp-{}", key, process::id()));
+        fs::create_dir_all(&staging)?;
+        for (name, src) in files {
+            fs::copy(src, staging.join(name))?;
+        }
+
+        // If another build stored the same key first, its entry is just as good as ours.
+        if fs::rename(&staging, &entry).is_err() {
+            fs::remove_dir_all(&staging)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,565 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! The patch manifest and a unified diff applier that tolerates upstream drift, for patching the
+//! libwdi submodule sources. See `patches/manifest.txt`.
+
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+
+/// How many lines of context at each end of a hunk may be ignored when it doesn't apply as is,
+/// like `patch --fuzz 2`.
+const MAX_FUZZ: usize = 2;
+
+/// How far from where a hunk expects to be, in lines, its replacement text may be found for the
+/// hunk to count as already applied.
+const MAX_APPLIED_DISTANCE: usize = 50;
+
+/// One line of `patches/manifest.txt`.
+#[derive(Debug, Clone)]
+pub struct ManifestEntry
+{
+    /// Relative from the patches directory.
+    pub patch: PathBuf,
+
+    /// Relative from the libwdi repository.
+    pub source: PathBuf,
+
+    versions: Vec<String>,
+}
+
+impl ManifestEntry
+{
+    /// Whether this entry applies to libwdi `version`. Entries for specific versions never apply
+    /// to an unknown version.
+    pub fn matches_version(&self, version: Option<&str>) -> bool
+    {
+        self.versions.iter().any(|spec| match (spec.strip_suffix('*'), version) {
+            (Some(""), _) => true,
+            (Some(prefix), Some(version)) => version.starts_with(prefix),
+            (None, Some(version)) => spec == version,
+            (_, None) => false,
+        })
+    }
+}
+
+/// Reads the manifest, returning an error message naming the offending line if it is malformed.
+pub fn read_manifest(path: &Path) -> Result<Vec<ManifestEntry>, String>
+{
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Error reading patch manifest {}: {}", path.display(), e))?;
+
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [patch, source, versions] = fields.as_slice() else {
+            return Err(format!(
+                "{}:{}: expected `<patch file> <source file> <libwdi versions>`, got {:?}",
+                path.display(),
+                index + 1,
+                line,
+            ));
+        };
+
+        entries.push(ManifestEntry {
+            patch: PathBuf::from(patch),
+            source: PathBuf::from(source),
+            versions: versions.split(',').map(String::from).collect(),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Picks the entries to apply for libwdi `version`: the first matching entry for each source
+/// file, in manifest order.
+pub fn select(entries: &[ManifestEntry], version: Option<&str>) -> Vec<ManifestEntry>
+{
+    let mut selected: Vec<ManifestEntry> = Vec::new();
+    for entry in entries {
+        if entry.matches_version(version) && !selected.iter().any(|e| e.source == entry.source) {
+            selected.push(entry.clone());
+        }
+    }
+
+    selected
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum HunkLine
+{
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+#[derive(Debug, Clone)]
+struct Hunk
+{
+    /// The `@@ -a,b +c,d @@` line, for error messages.
+    header: String,
+
+    /// Zero based line the hunk expects to start at in the original file.
+    old_start: usize,
+
+    lines: Vec<HunkLine>,
+}
+
+impl Hunk
+{
+    /// The lines this hunk expects to find, and the lines it replaces them with.
+    fn old_and_new(lines: &[HunkLine]) -> (Vec<&str>, Vec<&str>)
+    {
+        let old = lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect();
+        let new = lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Add(text) => Some(text.as_str()),
+                HunkLine::Remove(_) => None,
+            })
+            .collect();
+
+        (old, new)
+    }
+
+    /// The hunk's lines with `fuzz` lines of context dropped from each end, where there is
+    /// that much context, along with the number of lines dropped from the start.
+    fn fuzzed(&self, fuzz: usize) -> (usize, &[HunkLine])
+    {
+        let leading = self.lines.iter().take_while(|l| matches!(l, HunkLine::Context(_))).count().min(fuzz);
+        let trailing = self.lines.iter().rev().take_while(|l| matches!(l, HunkLine::Context(_))).count().min(fuzz);
+        let end = self.lines.len().saturating_sub(trailing).max(leading);
+
+        (leading, &self.lines[leading..end])
+    }
+}
+
+/// A parsed unified diff for a single file.
+#[derive(Debug, Clone)]
+pub struct Patch
+{
+    hunks: Vec<Hunk>,
+}
+
+impl Patch
+{
+    pub fn parse(text: &str) -> Result<Self, String>
+    {
+        let mut hunks: Vec<Hunk> = Vec::new();
+
+        for line in text.lines() {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+
+            if line.starts_with("@@") {
+                // @@ -50,13 +50,13 @@ optional section heading
+                let old_range = line
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|range| range.strip_prefix('-'))
+                    .ok_or_else(|| format!("malformed hunk header {:?}", line))?;
+                let old_start: usize = old_range
+                    .split(',')
+                    .next()
+                    .and_then(|start| start.parse().ok())
+                    .ok_or_else(|| format!("malformed hunk header {:?}", line))?;
+
+                hunks.push(Hunk {
+                    header: line.to_string(),
+                    old_start: old_start.saturating_sub(1),
+                    lines: Vec::new(),
+                });
+                continue;
+            }
+
+            // Anything before the first hunk is the diff/---/+++ preamble.
+            let Some(hunk) = hunks.last_mut() else {
+                continue;
+            };
+
+            if let Some(text) = line.strip_prefix(' ') {
+                hunk.lines.push(HunkLine::Context(text.to_string()));
+            } else if let Some(text) = line.strip_prefix('-') {
+                hunk.lines.push(HunkLine::Remove(text.to_string()));
+            } else if let Some(text) = line.strip_prefix('+') {
+                hunk.lines.push(HunkLine::Add(text.to_string()));
+            } else if line.is_empty() {
+                // Some editors strip the single space from empty context lines.
+                hunk.lines.push(HunkLine::Context(String::new()));
+            }
+            // Ignore `\ No newline at end of file` and trailing junk.
+        }
+
+        if hunks.is_empty() {
+            return Err(String::from("patch contains no hunks"));
+        }
+
+        Ok(Self { hunks })
+    }
+}
+
+
+/// The result of successfully applying a [Patch].
+#[derive(Debug, Clone)]
+pub struct Applied
+{
+    pub text: String,
+
+    /// 1-based numbers of hunks that only applied with fuzz.
+    pub fuzzed: Vec<usize>,
+
+    /// 1-based numbers of hunks whose changes were already present, and were skipped.
+    pub already_applied: Vec<usize>,
+
+    pub total_hunks: usize,
+}
+
+impl Applied
+{
+    /// Whether every hunk was already present, i.e. the patch has been merged upstream.
+    pub fn is_upstream(&self) -> bool
+    {
+        self.already_applied.len() == self.total_hunks
+    }
+}
+
+/// The error returned when a hunk neither applies nor is already applied.
+#[derive(Debug, Clone)]
+pub struct PatchError
+{
+    pub patch: PathBuf,
+    pub source: PathBuf,
+
+    /// The libwdi version, or None if it couldn't be determined.
+    pub version: Option<String>,
+
+    /// 1-based hunk number.
+    pub hunk: usize,
+    pub header: String,
+
+    /// The first line of the hunk's expected text, to grep upstream for.
+    pub first_line: String,
+}
+
+impl Display for PatchError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(
+            f,
+            "hunk {} ({}) of {} does not apply to {} from libwdi {}, and its changes are not already present.\n\
+            The hunk expects to find {:?} near there.\n\
+            If libwdi was updated, add an updated patch for this version to patches/manifest.txt.",
+            self.hunk,
+            self.header,
+            self.patch.display(),
+            self.source.display(),
+            self.version.as_deref().unwrap_or("(unknown version)"),
+            self.first_line,
+        )
+    }
+}
+
+/// Collapses runs of whitespace and trims, for whitespace-insensitive comparison.
+fn normalize(line: &str) -> String
+{
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Finds `needle` in `lines[from..]` at most `max_distance` lines from `expected`, preferring
+/// positions closest to `expected`.
+fn find(
+    lines: &[String],
+    needle: &[&str],
+    from: usize,
+    expected: usize,
+    max_distance: usize,
+    fuzzy_whitespace: bool,
+) -> Option<usize>
+{
+    if needle.is_empty() || needle.len() > lines.len() {
+        return None;
+    }
+
+    let matches_at = |pos: usize| -> bool {
+        lines[pos..pos + needle.len()].iter().zip(needle).all(|(line, wanted)| {
+            if fuzzy_whitespace {
+                normalize(line) == normalize(wanted)
+            } else {
+                line == wanted
+            }
+        })
+    };
+
+    let last = lines.len() - needle.len();
+    let mut candidates: Vec<usize> = (from..=last).filter(|pos| pos.abs_diff(expected) <= max_distance).collect();
+    candidates.sort_by_key(|pos| pos.abs_diff(expected));
+    candidates.into_iter().find(|pos| matches_at(*pos))
+}
+
+/// Whether any run of consecutive lines `hunk_lines` removes is present in `lines[start..end]`.
+fn removed_lines_present(lines: &[String], hunk_lines: &[HunkLine], start: usize, end: usize, fuzzy_whitespace: bool)
+    -> bool
+{
+    let region = &lines[start..end];
+    hunk_lines
+        .split(|line| !matches!(line, HunkLine::Remove(_)))
+        .filter(|run| !run.is_empty())
+        .any(|run| {
+            let removed: Vec<&str> = run
+                .iter()
+                .filter_map(|line| match line {
+                    HunkLine::Remove(text) => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect();
+            find(region, &removed, 0, 0, usize::MAX, fuzzy_whitespace).is_some()
+        })
+}
+
+/// Applies `patch` to `text`, which must use LF line endings. Each hunk is tried exactly first,
+/// then ignoring whitespace, then with up to [MAX_FUZZ] lines of context dropped from each end.
+///
+/// A hunk that doesn't apply counts as already applied if its full replacement text, without
+/// fuzz, is within [MAX_APPLIED_DISTANCE] lines of where the hunk expects to be, and none of the
+/// lines it removes are still around it.
+///
+/// On failure, returns the 1-based number of the hunk that failed.
+pub fn apply(patch: &Patch, text: &str) -> Result<Applied, usize>
+{
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    let trailing_newline = text.ends_with('\n');
+
+    let mut applied = Applied {
+        text: String::new(),
+        fuzzed: Vec::new(),
+        already_applied: Vec::new(),
+        total_hunks: patch.hunks.len(),
+    };
+
+    // Line offset introduced by previous hunks, and where the previous hunk ended.
+    let mut offset: isize = 0;
+    let mut from = 0;
+
+    'hunks: for (index, hunk) in patch.hunks.iter().enumerate() {
+        let number = index + 1;
+        let expected = (hunk.old_start as isize + offset).max(0) as usize;
+
+        for fuzz in 0..=MAX_FUZZ {
+            let (skipped_context, hunk_lines) = hunk.fuzzed(fuzz);
+            let (old, new) = Hunk::old_and_new(hunk_lines);
+
+            for fuzzy_whitespace in [false, true] {
+                let Some(pos) = find(&lines, &old, from, expected + skipped_context, usize::MAX, fuzzy_whitespace) else {
+                    continue;
+                };
+
+                // Keep upstream's context lines, in case they only matched fuzzily.
+                let mut replacement = Vec::with_capacity(new.len());
+                let mut cursor = pos;
+                for line in hunk_lines {
+                    match line {
+                        HunkLine::Context(_) => {
+                            replacement.push(lines[cursor].clone());
+                            cursor += 1;
+                        },
+                        HunkLine::Remove(_) => cursor += 1,
+                        HunkLine::Add(text) => replacement.push(text.clone()),
+                    }
+                }
+                lines.splice(pos..pos + old.len(), replacement);
+
+                if fuzz > 0 || fuzzy_whitespace {
+                    applied.fuzzed.push(number);
+                }
+                offset += new.len() as isize - old.len() as isize;
+                from = pos + new.len();
+                continue 'hunks;
+            }
+        }
+
+        let (old, new) = Hunk::old_and_new(&hunk.lines);
+        for fuzzy_whitespace in [false, true] {
+            let Some(pos) = find(&lines, &new, from, expected, MAX_APPLIED_DISTANCE, fuzzy_whitespace) else {
+                continue;
+            };
+
+            let start = pos.saturating_sub(old.len());
+            let end = (pos + new.len() + old.len()).min(lines.len());
+            if removed_lines_present(&lines, &hunk.lines, start, end, fuzzy_whitespace) {
+                continue;
+            }
+
+            applied.already_applied.push(number);
+            from = pos + new.len();
+            continue 'hunks;
+        }
+
+        return Err(number);
+    }
+
+    applied.text = lines.join("\n");
+    if trailing_newline {
+        applied.text.push('\n');
+    }
+
+    Ok(applied)
+}
+
+/// Applies the manifest entry `entry` to `source_text`, producing a [PatchError] that names the
+/// patch, file, hunk and libwdi version on failure.
+pub fn apply_entry(entry: &ManifestEntry, patch_path: &Path, source_text: &str, version: Option<&str>)
+    -> Result<Applied, Box<PatchError>>
+{
+    let patch_text = fs::read_to_string(patch_path)
+        .unwrap_or_else(|e| panic!("Error reading patch file {}: {}", patch_path.display(), e));
+    let patch = Patch::parse(&patch_text)
+        .unwrap_or_else(|e| panic!("Patch file {} seems invalid: {}", patch_path.display(), e));
+
+    apply(&patch, source_text).map_err(|number| {
+        let hunk = &patch.hunks[number - 1];
+        let (old, _) = Hunk::old_and_new(&hunk.lines);
+        Box::new(PatchError {
+            patch: entry.patch.clone(),
+            source: entry.source.clone(),
+            version: version.map(String::from),
+            hunk: number,
+            header: hunk.header.clone(),
+            first_line: old.first().map(|s| s.to_string()).unwrap_or_default(),
+        })
+    })
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const SOURCE: &str = "\
+#include <stdio.h>
+
+int main(void)
+{
+\tint count = 0;
+\tprintf(\"hello\\n\");
+\tcount++;
+\treturn count;
+}
+";
+
+    const PATCH: &str = "\
+--- a/main.c
++++ b/main.c
+@@ -5,4 +5,4 @@ int main(void)
+ \tint count = 0;
+-\tprintf(\"hello\\n\");
++\tprintf(\"goodbye\\n\");
+ \tcount++;
+ \treturn count;
+";
+
+    fn patch(text: &str) -> Patch
+    {
+        Patch::parse(text).unwrap()
+    }
+
+    #[test]
+    fn apply_exact()
+    {
+        let applied = apply(&patch(PATCH), SOURCE).unwrap();
+        assert_eq!(applied.text, SOURCE.replace("hello", "goodbye"));
+        assert!(applied.fuzzed.is_empty());
+        assert!(applied.already_applied.is_empty());
+        assert!(!applied.is_upstream());
+    }
+
+    #[test]
+    fn apply_moved()
+    {
+        // Upstream added lines before the hunk, so it's found further down than it expects.
+        let source = SOURCE.replace("#include <stdio.h>\n", "#include <stdio.h>\n#include <stdlib.h>\n\n");
+        let applied = apply(&patch(PATCH), &source).unwrap();
+        assert_eq!(applied.text, source.replace("hello", "goodbye"));
+        assert!(applied.fuzzed.is_empty());
+    }
+
+    #[test]
+    fn apply_fuzzed()
+    {
+        // Whitespace changes.
+        let source = SOURCE.replace("\tcount++;", "\tcount++;  ");
+        let applied = apply(&patch(PATCH), &source).unwrap();
+        assert_eq!(applied.text, source.replace("hello", "goodbye"));
+        assert_eq!(applied.fuzzed, [1]);
+
+        // A changed outer context line.
+        let source = SOURCE.replace("return count;", "return count > 0;");
+        let applied = apply(&patch(PATCH), &source).unwrap();
+        assert_eq!(applied.text, source.replace("hello", "goodbye"));
+        assert_eq!(applied.fuzzed, [1]);
+    }
+
+    #[test]
+    fn already_applied()
+    {
+        let source = SOURCE.replace("hello", "goodbye");
+        let applied = apply(&patch(PATCH), &source).unwrap();
+        assert_eq!(applied.text, source);
+        assert_eq!(applied.already_applied, [1]);
+        assert!(applied.is_upstream());
+    }
+
+    #[test]
+    fn failing_hunks()
+    {
+        // The removed line changed upstream.
+        let source = SOURCE.replace("hello", "hi");
+        assert_eq!(apply(&patch(PATCH), &source).unwrap_err(), 1);
+
+        // The replacement text is only present with fuzz, which isn't enough to be already applied.
+        let source = SOURCE.replace("hello", "goodbye").replace("return count;", "return count > 0;");
+        assert_eq!(apply(&patch(PATCH), &source).unwrap_err(), 1);
+
+        // The replacement text is present, but far from where the hunk expects it.
+        let padding = "/* padding */\n".repeat(MAX_APPLIED_DISTANCE + 10);
+        let source = format!("{}{}", padding, SOURCE.replace("hello", "goodbye"));
+        assert_eq!(apply(&patch(PATCH), &source).unwrap_err(), 1);
+    }
+
+    #[test]
+    fn pure_removal()
+    {
+        let removal = "\
+@@ -3,7 +3,6 @@
+ int main(void)
+ {
+ \tint count = 0;
+-\tprintf(\"hello\\n\");
+ \tcount++;
+ \treturn count;
+ }
+";
+        let without = SOURCE.replace("\tprintf(\"hello\\n\");\n", "");
+        let applied = apply(&patch(removal), &without).unwrap();
+        assert_eq!(applied.text, without);
+        assert_eq!(applied.already_applied, [1]);
+
+        // The context is present, but so is the removed line, right after it.
+        let moved = format!("{}\nvoid greet(void)\n{{\n\tprintf(\"hello\\n\");\n}}\n", without);
+        assert_eq!(apply(&patch(removal), &moved).unwrap_err(), 1);
+    }
+}
@@ -0,0 +1,679 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! The files libwdi's embedder takes from `WDK_DIR`, `LIBUSB0_DIR` and `LIBUSBK_DIR`, as listed
+//! in its `embedder_files.h`, and checks that they're all there (and optionally that they're the
+//! expected files) before the embedder trips over them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+
+/// The directory macros `embedder_files.h` takes files from, in the order they're documented.
+pub const DIR_VARS: &[&str] = &["WDK_DIR", "LIBUSB0_DIR", "LIBUSBK_DIR"];
+
+/// A file the embedder embeds, from an entry of libwdi's `embedder_files.h`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddedFile
+{
+    /// The macro naming the directory the file is taken from, e.g. `LIBUSB0_DIR` or
+    /// `INSTALLER_PATH_64`.
+    pub var: String,
+
+    /// Relative from that directory, with `/` separators.
+    pub path: String,
+
+    /// The subdirectory the file is extracted to, which is the architecture for driver files.
+    pub arch: String,
+
+    /// The name the file is extracted as, which is usually its own.
+    pub name: String,
+}
+
+/// The `#define`s in `config` that have a value, e.g. `WDF_VER` and `X64_DIR` from libwdi's
+/// `msvc/config.h`, and those without one, e.g. `OPT_M32`, with an empty value. Commented out
+/// defines are skipped, and where a name is defined more than once the first wins.
+pub fn parse_defines(config: &str) -> BTreeMap<String, String>
+{
+    let mut defines = BTreeMap::new();
+    for line in config.lines() {
+        let Some(define) = line.trim().strip_prefix("#define") else {
+            continue;
+        };
+        let mut parts = define.trim().splitn(2, char::is_whitespace);
+        let Some(name) = parts.next().filter(|name| !name.is_empty() && !name.contains('(')) else {
+            continue;
+        };
+        let value = parts.next().unwrap_or("").split("//").next().unwrap_or("").trim();
+        defines.entry(name.to_string()).or_insert_with(|| value.to_string());
+    }
+
+    defines
+}
+
+/// Evaluates a `#if` condition made of `defined(NAME)`, `defined NAME`, `!`, `&&`, `||` and
+/// parentheses, which is all `embedder_files.h` uses.
+fn evaluate(condition: &str, defines: &BTreeMap<String, String>) -> Result<bool, String>
+{
+    // Tokens: identifiers and the operators.
+    let mut tokens: Vec<String> = Vec::new();
+    let mut chars = condition.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '(' | ')' | '!' => tokens.push(c.to_string()),
+            '&' | '|' if chars.peek() == Some(&c) => {
+                chars.next();
+                tokens.push(format!("{}{}", c, c));
+            },
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(&next) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+                    ident.push(next);
+                    chars.next();
+                }
+                tokens.push(ident);
+            },
+            _ => return Err(format!("unsupported condition {:?}", condition)),
+        }
+    }
+
+    fn or(tokens: &[String], pos: &mut usize, defines: &BTreeMap<String, String>) -> Option<bool>
+    {
+        let mut value = and(tokens, pos, defines)?;
+        while tokens.get(*pos).map(String::as_str) == Some("||") {
+            *pos += 1;
+            value |= and(tokens, pos, defines)?;
+        }
+        Some(value)
+    }
+
+    fn and(tokens: &[String], pos: &mut usize, defines: &BTreeMap<String, String>) -> Option<bool>
+    {
+        let mut value = unary(tokens, pos, defines)?;
+        while tokens.get(*pos).map(String::as_str) == Some("&&") {
+            *pos += 1;
+            value &= unary(tokens, pos, defines)?;
+        }
+        Some(value)
+    }
+
+    fn unary(tokens: &[String], pos: &mut usize, defines: &BTreeMap<String, String>) -> Option<bool>
+    {
+        let token = tokens.get(*pos)?;
+        *pos += 1;
+        match token.as_str() {
+            "!" => unary(tokens, pos, defines).map(|value| !value),
+            "(" => {
+                let value = or(tokens, pos, defines)?;
+                (tokens.get(*pos).map(String::as_str) == Some(")")).then(|| *pos += 1)?;
+                Some(value)
+            },
+            "defined" => {
+                let parenthesized = tokens.get(*pos).map(String::as_str) == Some("(");
+                if parenthesized {
+                    *pos += 1;
+                }
+                let name = tokens.get(*pos).filter(|name| !["(", ")", "!", "&&", "||"].contains(&name.as_str()))?;
+                *pos += 1;
+                if parenthesized {
+                    (tokens.get(*pos).map(String::as_str) == Some(")")).then(|| *pos += 1)?;
+                }
+                Some(defines.contains_key(name))
+            },
+            _ => None,
+        }
+    }
+
+    let mut pos = 0;
+    or(&tokens, &mut pos, defines)
+        .filter(|_| pos == tokens.len())
+        .ok_or_else(|| format!("unsupported condition {:?}", condition))
+}
+
+/// The value of a `#define` that is a string literal, e.g. `"01011"`.
+pub fn string_value(value: &str) -> Option<String>
+{
+    value.starts_with('"').then(|| parse_string(value, &BTreeMap::new()).ok()).flatten().map(|(_, text)| text)
+}
+
+/// The file version from the `VS_FIXEDFILEINFO` in a PE file's version resource, e.g. `1.2.6.0`.
+pub fn file_version(contents: &[u8]) -> Option<String>
+{
+    const SIGNATURE: [u8; 4] = 0xfeef04bd_u32.to_le_bytes();
+
+    let start = contents.windows(SIGNATURE.len()).position(|window| window == SIGNATURE)?;
+    let dword = |offset: usize| -> Option<u32> {
+        let bytes = contents.get(start + offset..start + offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    };
+    // dwSignature, dwStrucVersion, dwFileVersionMS, dwFileVersionLS.
+    let (ms, ls) = (dword(8)?, dword(12)?);
+
+    Some(format!("{}.{}.{}.{}", ms >> 16, ms & 0xffff, ls >> 16, ls & 0xffff))
+}
+
+/// Splits the inside of a `{ ... }` initializer at the commas outside string literals.
+fn split_fields(initializer: &str) -> Vec<&str>
+{
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in initializer.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                fields.push(initializer[start..index].trim());
+                start = index + 1;
+            },
+            _ => (),
+        }
+    }
+    fields.push(initializer[start..].trim());
+
+    fields.into_iter().filter(|field| !field.is_empty()).collect()
+}
+
+/// Parses an expression of concatenated string literals and macros, e.g.
+/// `WDK_DIR "\\redist\\wdf\\" X64_DIR "\\WdfCoInstaller" WDF_VER ".dll"`. Returns the leading
+/// macro, which names a directory and is left alone, and the rest of the string with the other
+/// macros replaced by their values in `defines`.
+fn parse_string(expression: &str, defines: &BTreeMap<String, String>) -> Result<(Option<String>, String), String>
+{
+    let mut base = None;
+    let mut text = String::new();
+    let mut rest = expression.trim();
+    while !rest.is_empty() {
+        if let Some(literal) = rest.strip_prefix('"') {
+            let mut chars = literal.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((_, '\\')) => {
+                        let (_, escaped) = chars.next().ok_or_else(|| format!("unterminated string in {:?}", expression))?;
+                        text.push(escaped);
+                    },
+                    Some((index, '"')) => break index,
+                    Some((_, c)) => text.push(c),
+                    None => return Err(format!("unterminated string in {:?}", expression)),
+                }
+            };
+            rest = literal[end + 1..].trim_start();
+        } else {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!("unexpected {:?} in {:?}", rest, expression));
+            }
+            let name = &rest[..end];
+            if rest.len() == expression.trim().len() {
+                base = Some(name.to_string());
+            } else {
+                let value = defines.get(name).ok_or_else(|| format!("{} is not defined, in {:?}", name, expression))?;
+                if value.starts_with('"') {
+                    text.push_str(&parse_string(value, &BTreeMap::new())?.1);
+                } else {
+                    text.push_str(value);
+                }
+            }
+            rest = rest[end..].trim_start();
+        }
+    }
+
+    Ok((base, text))
+}
+
+/// Parses the entries of libwdi's `embedder_files.h` that are enabled by `defines`, which must
+/// include the values `config.h` gives macros like `WDF_VER`.
+///
+/// Each entry looks like `{ 0, LIBUSB0_DIR "\\bin\\x86\\libusb0.sys", "x86" }`, optionally with
+/// the name to extract the file as after the architecture. Entries that take a whole directory,
+/// like that for `USER_DIR`, are skipped.
+pub fn parse_embedder_files(header: &str, defines: &BTreeMap<String, String>) -> Result<Vec<EmbeddedFile>, String>
+{
+    // Whether each enclosing #if is active, and whether any of its branches has been.
+    let mut conditions: Vec<(bool, bool)> = Vec::new();
+    let active = |conditions: &[(bool, bool)]| conditions.iter().all(|(active, _)| *active);
+
+    let mut files = Vec::new();
+    for (index, line) in header.lines().enumerate() {
+        let line = line.split("//").next().unwrap_or("").trim();
+        let context = |e: String| format!("embedder_files.h:{}: {}", index + 1, e);
+
+        if let Some(directive) = line.strip_prefix('#') {
+            let directive = directive.trim_start();
+            let (keyword, argument) = directive.split_once(char::is_whitespace).unwrap_or((directive, ""));
+            let argument = argument.trim();
+            match keyword {
+                "if" => {
+                    let value = evaluate(argument, defines).map_err(context)?;
+                    conditions.push((value, value));
+                },
+                "ifdef" | "ifndef" => {
+                    let value = defines.contains_key(argument) == (keyword == "ifdef");
+                    conditions.push((value, value));
+                },
+                "elif" => {
+                    let value = evaluate(argument, defines).map_err(context)?;
+                    let (current, taken) = conditions.last_mut().ok_or_else(|| context(String::from("#elif without #if")))?;
+                    *current = !*taken && value;
+                    *taken |= value;
+                },
+                "else" => {
+                    let (current, taken) = conditions.last_mut().ok_or_else(|| context(String::from("#else without #if")))?;
+                    *current = !*taken;
+                    *taken = true;
+                },
+                "endif" => {
+                    conditions.pop().ok_or_else(|| context(String::from("#endif without #if")))?;
+                },
+                _ => (),
+            }
+            continue;
+        }
+
+        if !active(&conditions) {
+            continue;
+        }
+
+        let Some(initializer) = line.strip_prefix('{').and_then(|line| line.split_once('}')).map(|(inside, _)| inside) else {
+            continue;
+        };
+        let fields = split_fields(initializer);
+        if fields.len() < 3 || fields[0].parse::<u32>().is_err() {
+            continue;
+        }
+
+        let (var, path) = parse_string(fields[1], defines).map_err(context)?;
+        let Some(var) = var else {
+            return Err(context(format!("{:?} doesn't start with a directory macro", fields[1])));
+        };
+        let path = path.trim_start_matches(['\\', '/']).replace('\\', "/");
+        if path.is_empty() {
+            continue;
+        }
+        let (_, arch) = parse_string(fields[2], defines).map_err(context)?;
+        let name = match fields.get(3) {
+            Some(name) => parse_string(name, defines).map_err(context)?.1,
+            None => path.rsplit('/').next().unwrap_or(&path).to_string(),
+        };
+
+        files.push(EmbeddedFile { var, path, arch, name });
+    }
+
+    if !conditions.is_empty() {
+        return Err(String::from("embedder_files.h: unterminated #if"));
+    }
+
+    Ok(files)
+}
+
+/// A redistributable directory and what the embedder needs from it.
+pub struct Redist
+{
+    /// The environment variable naming the directory, e.g. `WDK_DIR`.
+    pub var: &'static str,
+
+    pub dir: PathBuf,
+
+    /// The files the embedder takes from the directory.
+    pub files: Vec<EmbeddedFile>,
+}
+
+/// Something wrong with a redistributable directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem
+{
+    /// A file the embedder needs doesn't exist.
+    Missing
+    {
+        var: &'static str,
+        arch: String,
+        path: PathBuf,
+    },
+
+    /// A file's SHA-256 doesn't match the manifest.
+    HashMismatch
+    {
+        var: &'static str,
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+
+    /// A file in the manifest couldn't be read.
+    Unreadable
+    {
+        var: &'static str,
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl Display for Problem
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Problem::Missing { var, arch, path } => {
+                write!(f, "{} is missing the {} file {}", var, arch, path.display())
+            },
+            Problem::HashMismatch { var, path, expected, actual } => {
+                write!(f, "{} file {} has SHA-256 {}, but the manifest expects {}", var, path.display(), actual, expected)
+            },
+            Problem::Unreadable { var, path, reason } => {
+                write!(f, "Error reading {} file {}: {}", var, path.display(), reason)
+            },
+        }
+    }
+}
+
+impl Redist
+{
+    /// Checks that every file the embedder needs is there.
+    pub fn check_layout(&self) -> Vec<Problem>
+    {
+        self.files
+            .iter()
+            .map(|file| (file, self.dir.join(&file.path)))
+            .filter(|(_, path)| !path.is_file())
+            .map(|(file, path)| Problem::Missing { var: self.var, arch: file.arch.clone(), path })
+            .collect()
+    }
+
+    /// The file version of the first file the embedder takes from this directory that has one,
+    /// preferring drivers, e.g. that of `libusb0.sys` for `LIBUSB0_DIR`.
+    pub fn version(&self) -> Option<String>
+    {
+        let (drivers, others): (Vec<&EmbeddedFile>, Vec<&EmbeddedFile>) = self
+            .files
+            .iter()
+            .partition(|file| file.path.to_ascii_lowercase().ends_with(".sys"));
+
+        drivers
+            .into_iter()
+            .chain(others)
+            .find_map(|file| file_version(&fs::read(self.dir.join(&file.path)).ok()?))
+    }
+
+    /// Checks every file the embedder takes from this directory that is in `manifest` against
+    /// its hash. Manifest entries for files the embedder doesn't take, e.g. for architectures
+    /// that aren't enabled, are ignored, and files missing from the directory are left to
+    /// [Redist::check_layout].
+    pub fn check_hashes(&self, manifest: &HashManifest) -> Vec<Problem>
+    {
+        let mut problems = Vec::new();
+        for (relative, expected) in manifest.for_var(self.var) {
+            if !self.files.iter().any(|file| file.path == relative) {
+                continue;
+            }
+
+            let path = self.dir.join(relative);
+            if !path.is_file() {
+                continue;
+            }
+
+            match fs::read(&path) {
+                Ok(contents) => {
+                    let actual = sha256_hex(&contents);
+                    if !actual.eq_ignore_ascii_case(expected) {
+                        problems.push(Problem::HashMismatch {
+                            var: self.var,
+                            path,
+                            expected: expected.to_string(),
+                            actual,
+                        });
+                    }
+                },
+                Err(e) => problems.push(Problem::Unreadable { var: self.var, path, reason: e.to_string() }),
+            }
+        }
+
+        problems
+    }
+}
+
+/// Pinned SHA-256 hashes of redistributable files, from `LIBWDI_REDIST_MANIFEST`.
+///
+/// The format is that of `sha256sum`, with each path starting with the environment variable
+/// naming its directory:
+///
+/// ```text
+/// # Comments and blank lines are ignored.
+/// 0123...cdef  WDK_DIR/redist/wdf/x86/WdfCoInstaller01011.dll
+/// ```
+pub struct HashManifest
+{
+    /// By path, including the variable name.
+    entries: BTreeMap<String, String>,
+}
+
+impl HashManifest
+{
+    /// Reads the manifest, returning an error message naming the offending line if it is
+    /// malformed.
+    pub fn read(path: &Path) -> Result<Self, String>
+    {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading redistributable manifest {}: {}", path.display(), e))?;
+
+        let mut entries = BTreeMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // sha256sum marks binary mode files with a `*` before the path.
+            let parsed = line
+                .split_once(char::is_whitespace)
+                .map(|(hash, path)| (hash, path.trim_start().trim_start_matches('*')))
+                .filter(|(hash, path)| {
+                    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) && path.contains('/')
+                });
+            let Some((hash, path)) = parsed else {
+                return Err(format!(
+                    "{}:{}: expected `<sha256> <VAR>/<path>`, got {:?}",
+                    path.display(),
+                    index + 1,
+                    line,
+                ));
+            };
+
+            entries.insert(path.replace('\\', "/"), hash.to_string());
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// The entries under `var`, by path relative to its directory.
+    fn for_var<'s>(&'s self, var: &'s str) -> impl Iterator<Item = (&'s str, &'s str)>
+    {
+        self.entries.iter().filter_map(move |(path, hash)| {
+            let relative = path.strip_prefix(var)?.strip_prefix('/')?;
+            Some((relative, hash.as_str()))
+        })
+    }
+}
+
+fn sha256_hex(contents: &[u8]) -> String
+{
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf
+    {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    /// The defines the build script passes for `vars` on top of the fixture config.h, with
+    /// `OPT_M32` enabled if `x86`.
+    fn defines(vars: &[&str], x86: bool) -> BTreeMap<String, String>
+    {
+        let mut defines: BTreeMap<String, String> = vars
+            .iter()
+            .map(|var| (var.to_string(), format!("\"C:\\\\{}\"", var)))
+            .collect();
+        if x86 {
+            defines.insert(String::from("OPT_M32"), String::new());
+        }
+        let config = fs::read_to_string(fixture("config.h")).unwrap();
+        for (name, value) in parse_defines(&config) {
+            defines.entry(name).or_insert(value);
+        }
+
+        defines
+    }
+
+    fn embedded(vars: &[&str], x86: bool) -> Vec<EmbeddedFile>
+    {
+        let header = fs::read_to_string(fixture("embedder_files.h")).unwrap();
+        parse_embedder_files(&header, &defines(vars, x86)).unwrap()
+    }
+
+    fn paths<'f>(files: &'f [EmbeddedFile], var: &str) -> Vec<(&'f str, &'f str, &'f str)>
+    {
+        files
+            .iter()
+            .filter(|file| file.var == var)
+            .map(|file| (file.arch.as_str(), file.path.as_str(), file.name.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn config_defines()
+    {
+        let config = fs::read_to_string(fixture("config.h")).unwrap();
+        let defines = parse_defines(&config);
+        assert_eq!(defines["WDF_VER"], "\"01011\"");
+        assert_eq!(defines["OPT_M64"], "");
+        assert!(!defines.contains_key("OPT_M32"));
+    }
+
+    #[test]
+    fn embedder_files()
+    {
+        let files = embedded(&["WDK_DIR", "LIBUSB0_DIR", "LIBUSBK_DIR"], true);
+        assert_eq!(paths(&files, "WDK_DIR"), [
+            ("x86", "redist/wdf/x86/WdfCoInstaller01011.dll", "WdfCoInstaller01011.dll"),
+            ("x86", "redist/winusb/x86/winusbcoinstaller2.dll", "winusbcoinstaller2.dll"),
+            ("amd64", "redist/wdf/x64/WdfCoInstaller01011.dll", "WdfCoInstaller01011.dll"),
+            ("amd64", "redist/winusb/x64/winusbcoinstaller2.dll", "winusbcoinstaller2.dll"),
+        ]);
+        assert_eq!(paths(&files, "LIBUSB0_DIR"), [
+            ("x86", "bin/x86/libusb0_x86.dll", "libusb0_x86.dll"),
+            ("x86", "bin/x86/libusb0.sys", "libusb0.sys"),
+            ("amd64", "bin/amd64/libusb0.dll", "libusb0.dll"),
+            ("amd64", "bin/amd64/libusb0.sys", "libusb0.sys"),
+        ]);
+        assert_eq!(paths(&files, "LIBUSBK_DIR"), [
+            ("x86", "sys/x86/libusbK.sys", "libusbK.sys"),
+            ("x86", "dll/x86/libusbK.dll", "libusbK.dll"),
+            ("amd64", "sys/amd64/libusbK.sys", "libusbK.sys"),
+            ("amd64", "dll/amd64/libusbK.dll", "libusbK.dll"),
+            ("amd64", "dll/x86/libusbK.dll", "libusbK_x86.dll"),
+        ]);
+        assert_eq!(paths(&files, "INSTALLER_PATH_64"), [(".", "installer_x64.exe", "installer_x64.exe")]);
+
+        // Without OPT_M32 and the driver directories.
+        let files = embedded(&["WDK_DIR"], false);
+        assert!(files.iter().all(|file| file.arch != "x86" && file.var != "LIBUSB0_DIR" && file.var != "LIBUSBK_DIR"));
+        assert_eq!(paths(&files, "WDK_DIR").len(), 2);
+        assert!(paths(&files, "INSTALLER_PATH_32").is_empty());
+
+        // The user driver directory is embedded as a whole.
+        assert!(embedded(&["USER_DIR"], true).iter().all(|file| file.var != "USER_DIR"));
+    }
+
+    #[test]
+    fn conditions()
+    {
+        let defines: BTreeMap<String, String> = [("A", ""), ("B", "")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(evaluate("defined(A) && defined B", &defines), Ok(true));
+        assert_eq!(evaluate("defined(A) && !defined(C)", &defines), Ok(true));
+        assert_eq!(evaluate("(defined(C) || defined(D)) && defined(A)", &defines), Ok(false));
+        assert!(evaluate("_MSC_VER >= 1900", &defines).is_err());
+
+        let header = "#if defined(C)\n{ 0, A \"\\\\x\", \"x86\" },\n#elif defined(B)\n{ 0, A \"\\\\y\", \"x86\" },\n#else\n{ 0, A \"\\\\z\", \"x86\" },\n#endif\n";
+        let files = parse_embedder_files(header, &defines).unwrap();
+        assert_eq!(files.iter().map(|file| file.path.as_str()).collect::<Vec<_>>(), ["y"]);
+
+        assert!(parse_embedder_files("#if defined(A)\n", &defines).is_err());
+        assert!(parse_embedder_files("{ 0, A \"\\\\x\" UNDEFINED, \"x86\" },\n", &defines).is_err());
+    }
+
+    #[test]
+    fn file_versions()
+    {
+        let mut resource = b"MZ padding VS_VERSION_INFO\0".to_vec();
+        for dword in [0xfeef04bd_u32, 0x10000, (1 << 16) | 2, 6 << 16] {
+            resource.extend(dword.to_le_bytes());
+        }
+        assert_eq!(file_version(&resource).as_deref(), Some("1.2.6.0"));
+        assert_eq!(file_version(&resource[..resource.len() - 1]), None);
+        assert_eq!(file_version(b"MZ no version"), None);
+
+        assert_eq!(string_value("\"01011\"").as_deref(), Some("01011"));
+        assert_eq!(string_value("1"), None);
+    }
+
+    #[test]
+    fn check_fixture_layout()
+    {
+        let files = embedded(&["LIBUSBK_DIR"], true);
+        let dir = std::env::temp_dir().join(format!("libwdi-sys-redist-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in files.iter().filter(|file| file.var == "LIBUSBK_DIR") {
+            let path = dir.join(&file.path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &file.path).unwrap();
+        }
+
+        let redist = Redist {
+            var: "LIBUSBK_DIR",
+            dir: dir.clone(),
+            files: files.into_iter().filter(|file| file.var == "LIBUSBK_DIR").collect(),
+        };
+        assert_eq!(redist.check_layout(), []);
+
+        let manifest_path = dir.join("manifest.txt");
+        fs::write(&manifest_path, format!(
+            "# Pinned\n{}  LIBUSBK_DIR/sys/amd64/libusbK.sys\n{}  LIBUSBK_DIR/dll/x86/libusbK.dll\n{}  LIBUSBK_DIR/dll/arm64/libusbK.dll\n",
+            sha256_hex(b"sys/amd64/libusbK.sys"),
+            "0".repeat(64),
+            "0".repeat(64),
+        )).unwrap();
+        let manifest = HashManifest::read(&manifest_path).unwrap();
+        assert_eq!(redist.check_hashes(&manifest), [Problem::HashMismatch {
+            var: "LIBUSBK_DIR",
+            path: dir.join("dll/x86/libusbK.dll"),
+            expected: "0".repeat(64),
+            actual: sha256_hex(b"dll/x86/libusbK.dll"),
+        }]);
+
+        fs::remove_file(dir.join("dll/x86/libusbK.dll")).unwrap();
+        assert_eq!(redist.check_layout(), [
+            Problem::Missing { var: "LIBUSBK_DIR", arch: String::from("x86"), path: dir.join("dll/x86/libusbK.dll") },
+            Problem::Missing { var: "LIBUSBK_DIR", arch: String::from("amd64"), path: dir.join("dll/x86/libusbK.dll") },
+        ]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
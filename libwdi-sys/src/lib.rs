@@ -6,10 +6,62 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
-#[cfg(not(feature = "dynamic-bindgen"))]
-mod bindings;
-#[cfg(not(feature = "dynamic-bindgen"))]
+// The committed bindings, generated per target architecture since struct layouts differ between
+// 32 and 64 bit Windows. build.rs picks the ones for the target, and fails for architectures that
+// have none; see `LIBWDI_CHECK_BINDINGS` there for generating them. Stub builds use them even with
+// dynamic-bindgen.
+#[cfg(not(wdi_dynamic_bindings))]
+mod bindings
+{
+    include!(env!("WDI_COMMITTED_BINDINGS"));
+}
+#[cfg(not(wdi_dynamic_bindings))]
 pub use bindings::*;
 
-#[cfg(feature = "dynamic-bindgen")]
+#[cfg(wdi_dynamic_bindings)]
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+#[cfg(wdi_stub)]
+mod stub;
+
+#[cfg(feature = "user-driver")]
+include!(concat!(env!("OUT_DIR"), "/user_driver.rs"));
+
+/// What this build of libwdi embeds, as determined by the build script.
+///
+/// The build script's cfg flags only apply to this crate. Build scripts of crates that depend on
+/// libwdi-sys directly also see what's embedded as `links` metadata: `DEP_WDI_HAS_LIBUSB0`,
+/// `DEP_WDI_HAS_LIBUSBK`, `DEP_WDI_HAS_USER_DRIVER`, `DEP_WDI_HAS_X64_INSTALLER`,
+/// `DEP_WDI_HAS_ARM64_INSTALLER`, `DEP_WDI_PREBUILT` and `DEP_WDI_STUB` are set to `1` when
+/// they apply.
+pub mod build_info
+{
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+    /// Whether libwdi was linked from `LIBWDI_LIB_DIR` rather than built from the submodule.
+    pub const PREBUILT: bool = cfg!(wdi_prebuilt);
+
+    /// Whether this is a stub build, where libwdi isn't built at all and every `wdi_*` function
+    /// fails with `WDI_ERROR_NOT_SUPPORTED`.
+    pub const STUB: bool = cfg!(wdi_stub);
+
+    /// The driver types embedded, by their libwdi names.
+    pub const DRIVERS: &[&str] = &[
+        #[cfg(not(wdi_stub))]
+        "WinUSB",
+        #[cfg(wdi_has_libusb0)]
+        "libusb0",
+        #[cfg(wdi_has_libusbk)]
+        "libusbK",
+        #[cfg(wdi_has_user_driver)]
+        "user",
+    ];
+
+    /// The architectures an installer was embedded for.
+    pub const INSTALLERS: &[&str] = &[
+        #[cfg(wdi_has_x64_installer)]
+        "x64",
+        #[cfg(wdi_has_arm64_installer)]
+        "arm64",
+    ];
+}
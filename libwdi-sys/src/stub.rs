@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Stand-ins for every libwdi function, for stub builds (the `stub` feature, or any build on
+//! docs.rs) where libwdi itself isn't built. They satisfy the linker so dependent crates can be
+//! checked, tested and documented on any OS, and fail with [WDI_ERROR_NOT_SUPPORTED] if called.
+
+use std::os::raw::{c_char, c_int, c_ushort};
+use std::ptr;
+
+use crate::*;
+
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_strerror(_errcode: c_int) -> *const c_char
+{
+    c"libwdi is not available in this build (libwdi-sys stub)".as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_is_driver_supported(_driver_type: c_int, _driver_info: *mut VS_FIXEDFILEINFO) -> BOOL
+{
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_is_file_embedded(_path: *const c_char, _name: *const c_char) -> BOOL
+{
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_get_vendor_name(_vid: c_ushort) -> *const c_char
+{
+    ptr::null()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_create_list(list: *mut *mut wdi_device_info, _options: *mut wdi_options_create_list) -> c_int
+{
+    if !list.is_null() {
+        *list = ptr::null_mut();
+    }
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_destroy_list(_list: *mut wdi_device_info) -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_prepare_driver(
+    _device_info: *mut wdi_device_info,
+    _path: *const c_char,
+    _inf_name: *const c_char,
+    _options: *mut wdi_options_prepare_driver,
+) -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_install_driver(
+    _device_info: *mut wdi_device_info,
+    _path: *const c_char,
+    _inf_name: *const c_char,
+    _options: *mut wdi_options_install_driver,
+) -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_install_trusted_certificate(_cert_name: *const c_char, _options: *mut wdi_options_install_cert) -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_set_log_level(_level: c_int) -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_register_logger(_hwnd: HWND, _message: UINT, _buffsize: DWORD) -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_unregister_logger(_hwnd: HWND) -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_read_logger(_buffer: *mut c_char, _buffer_size: DWORD, _message_size: *mut DWORD) -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wdi_get_wdf_version() -> c_int
+{
+    WDI_ERROR_NOT_SUPPORTED
+}
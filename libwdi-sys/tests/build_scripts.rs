@@ -0,0 +1,10 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Runs the unit tests of the build script's modules, which cargo doesn't do for build scripts.
+
+#![allow(dead_code)]
+
+#[path = "../build/patch.rs"]
+mod patch;
+#[path = "../build/redist.rs"]
+mod redist;
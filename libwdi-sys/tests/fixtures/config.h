@@ -0,0 +1,17 @@
+/* A cut down, patched libwdi msvc/config.h, for the build script's tests. */
+
+/* embed WinUSB driver files from the following DDK location */
+#ifndef WDK_DIR
+#define WDK_DIR "C:/Program Files (x86)/Windows Kits/8.0"
+#endif
+
+/* DDK WDF coinstaller version (string) */
+#define WDF_VER "01011"
+
+/* 64bit subdirectory for WinUSB redist files ("x64" or "amd64") */
+#define X64_DIR "x64"
+
+/* Build the 32 and 64 bit installers */
+//#define OPT_M32
+#define OPT_M64
+#define OPT_ARM
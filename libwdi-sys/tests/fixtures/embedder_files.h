@@ -0,0 +1,67 @@
+/*
+ * A cut down libwdi/embedder_files.h, for the build script's tests.
+ */
+#pragma once
+
+struct emb {
+	int reuse_last;
+	char* file_name;
+	char* extraction_subdir;
+	char* extraction_name;
+};
+
+struct emb embeddable_fixed[] = {
+
+// WinUSB
+#if defined(WDK_DIR)
+#if defined(OPT_M32)
+	{ 0, WDK_DIR "\\redist\\wdf\\x86\\WdfCoInstaller" WDF_VER ".dll", "x86" },
+	{ 0, WDK_DIR "\\redist\\winusb\\x86\\winusbcoinstaller2.dll", "x86" },
+#endif
+#if defined(OPT_M64)
+	{ 0, WDK_DIR "\\redist\\wdf\\" X64_DIR "\\WdfCoInstaller" WDF_VER ".dll", "amd64" },
+	{ 0, WDK_DIR "\\redist\\winusb\\" X64_DIR "\\winusbcoinstaller2.dll", "amd64" },
+#endif
+#endif
+
+// libusb0
+#if defined(LIBUSB0_DIR)
+#if defined(OPT_M32)
+	{ 0, LIBUSB0_DIR "\\bin\\x86\\libusb0_x86.dll", "x86" },
+	{ 0, LIBUSB0_DIR "\\bin\\x86\\libusb0.sys", "x86" },
+#endif
+#if defined(OPT_M64)
+	{ 0, LIBUSB0_DIR "\\bin\\amd64\\libusb0.dll", "amd64" },
+	{ 0, LIBUSB0_DIR "\\bin\\amd64\\libusb0.sys", "amd64" },
+#endif
+#endif
+
+// libusbK
+#if defined(LIBUSBK_DIR)
+#if defined(OPT_M32)
+	{ 0, LIBUSBK_DIR "\\sys\\x86\\libusbK.sys", "x86" },
+	{ 0, LIBUSBK_DIR "\\dll\\x86\\libusbK.dll", "x86" },
+#endif
+#if defined(OPT_M64)
+	{ 0, LIBUSBK_DIR "\\sys\\amd64\\libusbK.sys", "amd64" },
+	{ 0, LIBUSBK_DIR "\\dll\\amd64\\libusbK.dll", "amd64" },
+	{ 0, LIBUSBK_DIR "\\dll\\x86\\libusbK.dll", "amd64", "libusbK_x86.dll" },
+#endif
+#endif
+
+// User driver
+#if defined(USER_DIR)
+	{ 1, USER_DIR, "" },
+#endif
+
+// Installers
+#if defined(OPT_M32)
+	{ 0, INSTALLER_PATH_32 "\\installer_x86.exe", "." },
+#endif
+#if defined(OPT_M64)
+	{ 0, INSTALLER_PATH_64 "\\installer_x64.exe", "." },
+#endif
+#if defined(OPT_ARM)
+	{ 0, INSTALLER_PATH_ARM "\\installer_arm64.exe", "." },
+#endif
+};
@@ -0,0 +1,865 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Exporting prepared driver packages to `.zip` and Microsoft `.cab` archives, and importing
+//! them again.
+//!
+//! Every exported archive carries a [Manifest] (as `wdi-manifest.txt` at the archive root)
+//! recording the package's INF, driver type, target hardware IDs, and the size and SHA-256 of
+//! every file, which is checked on import.
+//!
+//! CAB archives are written uncompressed, which every CAB consumer (including `expand.exe` and
+//! the Windows Hardware Dev Center) accepts. Uncompressed and MSZIP compressed CAB archives (the
+//! default for `makecab`) can be imported; LZX and Quantum compression are not supported.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::catalog::hex;
+use crate::package::{DriverPackage, PackageError};
+use crate::{DeviceInfo, DriverType, InstallDriverOptions};
+
+/// The name of the manifest file at the root of every exported archive.
+pub const MANIFEST_NAME: &str = "wdi-manifest.txt";
+
+/// The most an archive may extract to, so that a small crafted archive can't exhaust memory.
+/// Driver packages are a few megabytes.
+pub const MAX_EXTRACTED_SIZE: u64 = 256 << 20;
+
+
+/// The error type for archive export and import.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ArchiveError
+{
+    Io(io::Error),
+
+    Zip(zip::result::ZipError),
+
+    /// The package being exported, or the package found in an archive, is unusable.
+    Package(PackageError),
+
+    /// The archive is not in a format we understand, or is corrupt.
+    Malformed(&'static str),
+
+    /// The archive uses a feature we do not support, such as CAB compression.
+    Unsupported(&'static str),
+
+    /// The archive contains a path that would be extracted outside the destination directory.
+    UnsafePath(String),
+
+    /// The archive extracts to more than [MAX_EXTRACTED_SIZE] bytes.
+    TooLarge,
+
+    /// The archive has no manifest, or its contents do not match the manifest.
+    /// Contains a description of each problem.
+    ManifestMismatch(Vec<String>),
+}
+
+impl Display for ArchiveError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        use ArchiveError::*;
+
+        match self {
+            Io(e) => write!(f, "I/O error: {}", e),
+            Zip(e) => write!(f, "Zip error: {}", e),
+            Package(e) => write!(f, "{}", e),
+            Malformed(reason) => write!(f, "Malformed archive: {}", reason),
+            Unsupported(what) => write!(f, "Unsupported archive feature: {}", what),
+            UnsafePath(path) => write!(f, "Archive member has an unsafe path: {}", path),
+            TooLarge => write!(f, "Archive extracts to more than {} bytes", MAX_EXTRACTED_SIZE),
+            ManifestMismatch(problems) => write!(f, "Archive does not match its manifest: {}", problems.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self {
+            ArchiveError::Io(e) => Some(e),
+            ArchiveError::Zip(e) => Some(e),
+            ArchiveError::Package(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ArchiveError
+{
+    fn from(other: io::Error) -> Self
+    {
+        Self::Io(other)
+    }
+}
+
+impl From<zip::result::ZipError> for ArchiveError
+{
+    fn from(other: zip::result::ZipError) -> Self
+    {
+        Self::Zip(other)
+    }
+}
+
+impl From<PackageError> for ArchiveError
+{
+    fn from(other: PackageError) -> Self
+    {
+        Self::Package(other)
+    }
+}
+
+
+/// Archive formats a driver package can be exported to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArchiveFormat
+{
+    Zip,
+
+    /// Microsoft Cabinet, as used for WHQL and Windows Update submissions.
+    Cab,
+}
+
+impl ArchiveFormat
+{
+    /// Guesses the archive format from the extension of `path`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self>
+    {
+        let ext = path.as_ref().extension()?.to_string_lossy().to_ascii_lowercase();
+        match ext.as_str() {
+            "zip" => Some(Self::Zip),
+            "cab" => Some(Self::Cab),
+            _ => None,
+        }
+    }
+}
+
+
+/// One file recorded in a [Manifest].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry
+{
+    /// Path relative to the package root, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    /// SHA-256 of the file contents, as uppercase hex.
+    pub sha256: String,
+}
+
+/// A description of the contents of an exported driver package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest
+{
+    pub inf_name: String,
+    /// See [DriverPackage::driver_type]. Recorded as `unknown` when None.
+    pub driver_type: Option<DriverType>,
+    pub hardware_ids: Vec<String>,
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest
+{
+    /// Builds the manifest for `package`, hashing every file in its directory.
+    pub fn for_package(package: &DriverPackage) -> Result<Self, ArchiveError>
+    {
+        let mut paths = Vec::new();
+        collect_files(package.dir(), package.dir(), &mut paths)?;
+        paths.sort();
+
+        let files = paths
+            .into_iter()
+            .map(|relative| {
+                let data = fs::read(package.dir().join(&relative))?;
+                Ok(ManifestEntry {
+                    path: to_archive_path(&relative),
+                    size: data.len() as u64,
+                    sha256: hex(&Sha256::digest(&data)),
+                })
+            })
+            .collect::<Result<_, ArchiveError>>()?;
+
+        Ok(Self {
+            inf_name: package.inf_name().to_string(),
+            driver_type: package.driver_type(),
+            hardware_ids: package.hardware_ids().to_vec(),
+            files,
+        })
+    }
+
+    /// Serializes this manifest to its line based text format.
+    pub fn to_text(&self) -> String
+    {
+        let mut text = String::from("# wdi driver package manifest\n");
+        text.push_str(&format!("inf = {}\n", self.inf_name));
+        match self.driver_type {
+            Some(driver_type) => text.push_str(&format!("driver = {}\n", driver_type)),
+            None => text.push_str("driver = unknown\n"),
+        }
+        for id in &self.hardware_ids {
+            text.push_str(&format!("hardware_id = {}\n", id));
+        }
+        for file in &self.files {
+            text.push_str(&format!("file = {} {} {}\n", file.sha256, file.size, file.path));
+        }
+
+        text
+    }
+
+    /// Parses the text format produced by [Manifest::to_text].
+    pub fn from_text(text: &str) -> Result<Self, ArchiveError>
+    {
+        let mut inf_name = None;
+        // Some(None) once the manifest records the driver type as unknown.
+        let mut driver_type = None;
+        let mut hardware_ids = Vec::new();
+        let mut files = Vec::new();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(ArchiveError::Malformed("invalid manifest line"))?;
+            let value = value.trim();
+            match key.trim() {
+                "inf" => inf_name = Some(value.to_string()),
+                "driver" if value == "unknown" => driver_type = Some(None),
+                "driver" => {
+                    driver_type = Some(Some(value.parse().map_err(|_| ArchiveError::Malformed("invalid manifest driver type"))?));
+                },
+                "hardware_id" => hardware_ids.push(value.to_string()),
+                "file" => {
+                    let mut parts = value.splitn(3, ' ');
+                    let (Some(sha256), Some(size), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+                        return Err(ArchiveError::Malformed("invalid manifest file entry"));
+                    };
+                    files.push(ManifestEntry {
+                        path: path.to_string(),
+                        size: size.parse().map_err(|_| ArchiveError::Malformed("invalid manifest file size"))?,
+                        sha256: sha256.to_ascii_uppercase(),
+                    });
+                },
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            inf_name: inf_name.ok_or(ArchiveError::Malformed("manifest does not name an INF"))?,
+            driver_type: driver_type.ok_or(ArchiveError::Malformed("manifest does not name a driver type"))?,
+            hardware_ids,
+            files,
+        })
+    }
+
+    /// Checks the files under `dir` against this manifest, returning a description of each
+    /// file that is missing, has the wrong contents, or is not listed.
+    pub fn check(&self, dir: &Path) -> Result<Vec<String>, ArchiveError>
+    {
+        let mut problems = Vec::new();
+
+        for entry in &self.files {
+            match fs::read(dir.join(&entry.path)) {
+                Ok(data) => {
+                    if data.len() as u64 != entry.size || hex(&Sha256::digest(&data)) != entry.sha256 {
+                        problems.push(format!("{} does not match its recorded hash", entry.path));
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => problems.push(format!("{} is missing", entry.path)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut present = Vec::new();
+        collect_files(dir, dir, &mut present)?;
+        for path in present {
+            let path = to_archive_path(&path);
+            if path != MANIFEST_NAME && !self.files.iter().any(|entry| entry.path == path) {
+                problems.push(format!("{} is not listed in the manifest", path));
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+fn collect_files(base: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()>
+{
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(base, &path, files)?;
+        } else {
+            files.push(path.strip_prefix(base).unwrap_or(&path).to_path_buf());
+        }
+    }
+
+    Ok(())
+}
+
+fn to_archive_path(path: &Path) -> String
+{
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Converts a path stored in an archive to a relative path, rejecting anything that could
+/// escape the extraction directory.
+fn sanitize_member_path(name: &str) -> Result<PathBuf, ArchiveError>
+{
+    let normalized = name.replace('\\', "/");
+    let path = Path::new(&normalized);
+    let safe = path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !safe || normalized.is_empty() {
+        return Err(ArchiveError::UnsafePath(name.to_string()));
+    }
+
+    Ok(path.to_path_buf())
+}
+
+
+/// Writes `package` to the archive `path` in the given format and returns the manifest written
+/// along with it. The package is validated first, so incomplete packages are never exported.
+pub fn export_package<P: AsRef<Path>>(package: &DriverPackage, path: P, format: ArchiveFormat)
+    -> Result<Manifest, ArchiveError>
+{
+    package.validate()?;
+
+    let manifest = Manifest::for_package(package)?;
+    let mut members: Vec<(String, Vec<u8>)> = Vec::with_capacity(manifest.files.len() + 1);
+    members.push((MANIFEST_NAME.to_string(), manifest.to_text().into_bytes()));
+    for entry in &manifest.files {
+        members.push((entry.path.clone(), fs::read(package.dir().join(&entry.path))?));
+    }
+
+    let file = File::create(path.as_ref())?;
+    match format {
+        ArchiveFormat::Zip => write_zip(file, &members)?,
+        ArchiveFormat::Cab => write_cab(file, &members)?,
+    }
+
+    Ok(manifest)
+}
+
+impl DriverPackage
+{
+    /// Exports this package to an archive. See [export_package].
+    pub fn export<P: AsRef<Path>>(&self, path: P, format: ArchiveFormat) -> Result<Manifest, ArchiveError>
+    {
+        export_package(self, path, format)
+    }
+}
+
+/// Extracts the archive at `path` (whose format is guessed from its extension, see
+/// [ArchiveFormat::from_path]) into `dest`, checks it against its manifest, and loads the
+/// package it contains. The manifest is returned rather than left in `dest`.
+pub fn import_package<P: AsRef<Path>, D: AsRef<Path>>(path: P, dest: D) -> Result<(DriverPackage, Manifest), ArchiveError>
+{
+    let path = path.as_ref();
+    let dest = dest.as_ref();
+    let format = ArchiveFormat::from_path(path).ok_or(ArchiveError::Unsupported("unknown archive extension"))?;
+
+    let file = File::open(path)?;
+    let members = match format {
+        ArchiveFormat::Zip => read_zip(file, MAX_EXTRACTED_SIZE)?,
+        ArchiveFormat::Cab => read_cab(file, MAX_EXTRACTED_SIZE)?,
+    };
+
+    for (name, data) in members {
+        let target = dest.join(sanitize_member_path(&name)?);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(target, data)?;
+    }
+
+    let manifest_text = fs::read_to_string(dest.join(MANIFEST_NAME))
+        .map_err(|_| ArchiveError::ManifestMismatch(vec![format!("{} is missing", MANIFEST_NAME)]))?;
+    let manifest = Manifest::from_text(&manifest_text)?;
+    // The manifest isn't part of the package, and its catalog doesn't cover it.
+    fs::remove_file(dest.join(MANIFEST_NAME))?;
+    let problems = manifest.check(dest)?;
+    if !problems.is_empty() {
+        return Err(ArchiveError::ManifestMismatch(problems));
+    }
+
+    let package = DriverPackage::load(dest, &manifest.inf_name)?;
+
+    Ok((package, manifest))
+}
+
+
+/// A driver package extracted from an archive into a temporary directory, which is removed
+/// again when this is dropped. Dereferences to the [DriverPackage].
+#[derive(Debug)]
+pub struct ExtractedPackage
+{
+    package: DriverPackage,
+    manifest: Manifest,
+    temp_dir: PathBuf,
+}
+
+impl ExtractedPackage
+{
+    /// Extracts the archive at `path` into a fresh temporary directory. See [import_package].
+    ///
+    /// The directory's name has a random part, and it's an error if it already exists, so that
+    /// nothing else can have prepared its contents.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ArchiveError>
+    {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        // std's randomly keyed hasher is the only randomness at hand.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u32(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0));
+        let temp_dir = std::env::temp_dir().join(format!("wdi-package-{}-{:016x}", std::process::id(), hasher.finish()));
+        fs::create_dir(&temp_dir)?;
+
+        match import_package(path, &temp_dir) {
+            Ok((package, manifest)) => Ok(Self { package, manifest, temp_dir }),
+            Err(e) => {
+                let _ = fs::remove_dir_all(&temp_dir);
+                Err(e)
+            },
+        }
+    }
+
+    /// The manifest the archive was checked against.
+    pub fn manifest(&self) -> &Manifest
+    {
+        &self.manifest
+    }
+}
+
+impl Deref for ExtractedPackage
+{
+    type Target = DriverPackage;
+
+    fn deref(&self) -> &DriverPackage
+    {
+        &self.package
+    }
+}
+
+impl Drop for ExtractedPackage
+{
+    fn drop(&mut self)
+    {
+        let _ = fs::remove_dir_all(&self.temp_dir);
+    }
+}
+
+/// Extracts the package archive at `path` to a temporary directory and installs it for `device`.
+/// The temporary directory is removed afterwards.
+pub fn install_from_archive<P: AsRef<Path>>(device: &mut DeviceInfo, path: P, options: &mut InstallDriverOptions)
+    -> Result<(), ArchiveError>
+{
+    let extracted = ExtractedPackage::open(path)?;
+    extracted.install(device, options)?;
+
+    Ok(())
+}
+
+
+fn write_zip<W: Write + Seek>(writer: W, members: &[(String, Vec<u8>)]) -> Result<(), ArchiveError>
+{
+    use zip::write::SimpleFileOptions;
+
+    // A fixed timestamp keeps exported archives reproducible.
+    let options = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .last_modified_time(zip::DateTime::default());
+
+    let mut zip = zip::ZipWriter::new(writer);
+    for (name, data) in members {
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(data)?;
+    }
+    zip.finish()?;
+
+    Ok(())
+}
+
+/// Reads every file of a zip archive, failing with [ArchiveError::TooLarge] if they come to more
+/// than `limit` bytes.
+fn read_zip<R: Read + Seek>(reader: R, limit: u64) -> Result<Vec<(String, Vec<u8>)>, ArchiveError>
+{
+    let mut zip = zip::ZipArchive::new(reader)?;
+    let mut members = Vec::with_capacity(zip.len());
+
+    let mut remaining = limit;
+    for index in 0..zip.len() {
+        let file = zip.by_index(index)?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().to_string();
+
+        // The declared size is only a hint, and may be a lie.
+        let mut data = Vec::with_capacity(file.size().min(1 << 20) as usize);
+        file.take(remaining + 1).read_to_end(&mut data)?;
+        remaining = remaining.checked_sub(data.len() as u64).ok_or(ArchiveError::TooLarge)?;
+        members.push((name, data));
+    }
+
+    Ok(members)
+}
+
+
+// Microsoft Cabinet format, as documented in [MS-CAB].
+const CAB_HEADER_LEN: usize = 36;
+const CAB_FOLDER_LEN: usize = 8;
+const CAB_FILE_LEN: usize = 16;
+const CAB_DATA_HEADER_LEN: usize = 8;
+const CAB_MAX_BLOCK: usize = 0x8000;
+const CAB_COMPRESS_NONE: u16 = 0;
+const CAB_COMPRESS_MSZIP: u16 = 1;
+const CAB_ATTRIB_ARCHIVE: u16 = 0x20;
+const CAB_ATTRIB_NAME_IS_UTF: u16 = 0x80;
+/// 1980-01-01 in MS-DOS date format, for reproducible archives.
+const CAB_DOS_DATE: u16 = (1 << 5) | 1;
+
+/// Writes a single-folder, uncompressed cabinet.
+fn write_cab<W: Write>(mut writer: W, members: &[(String, Vec<u8>)]) -> Result<(), ArchiveError>
+{
+    if members.len() > u16::MAX as usize {
+        return Err(ArchiveError::Unsupported("more than 65535 files in a cabinet"));
+    }
+
+    let mut file_table = Vec::new();
+    let mut folder_data: Vec<u8> = Vec::new();
+    for (name, data) in members {
+        let offset = u32::try_from(folder_data.len()).map_err(|_| ArchiveError::Unsupported("cabinet larger than 4 GiB"))?;
+        let name = name.replace('/', "\\");
+        let attribs = if name.is_ascii() { CAB_ATTRIB_ARCHIVE } else { CAB_ATTRIB_ARCHIVE | CAB_ATTRIB_NAME_IS_UTF };
+
+        file_table.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file_table.extend_from_slice(&offset.to_le_bytes());
+        file_table.extend_from_slice(&0u16.to_le_bytes()); // Folder index.
+        file_table.extend_from_slice(&CAB_DOS_DATE.to_le_bytes());
+        file_table.extend_from_slice(&0u16.to_le_bytes()); // Time.
+        file_table.extend_from_slice(&attribs.to_le_bytes());
+        file_table.extend_from_slice(name.as_bytes());
+        file_table.push(0);
+
+        folder_data.extend_from_slice(data);
+    }
+
+    let blocks: Vec<&[u8]> = folder_data.chunks(CAB_MAX_BLOCK).collect();
+    if blocks.len() > u16::MAX as usize {
+        return Err(ArchiveError::Unsupported("cabinet folder too large"));
+    }
+
+    let files_offset = CAB_HEADER_LEN + CAB_FOLDER_LEN;
+    let data_offset = files_offset + file_table.len();
+    let total_len = data_offset + blocks.len() * CAB_DATA_HEADER_LEN + folder_data.len();
+    let total_len = u32::try_from(total_len).map_err(|_| ArchiveError::Unsupported("cabinet larger than 4 GiB"))?;
+
+    let mut header = Vec::with_capacity(files_offset);
+    header.extend_from_slice(b"MSCF");
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&total_len.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(files_offset as u32).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&[3, 1]); // Format version 1.3.
+    header.extend_from_slice(&1u16.to_le_bytes()); // Folder count.
+    header.extend_from_slice(&(members.len() as u16).to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes()); // Flags.
+    header.extend_from_slice(&0u16.to_le_bytes()); // Set ID.
+    header.extend_from_slice(&0u16.to_le_bytes()); // Cabinet index in set.
+
+    header.extend_from_slice(&(data_offset as u32).to_le_bytes());
+    header.extend_from_slice(&(blocks.len() as u16).to_le_bytes());
+    header.extend_from_slice(&CAB_COMPRESS_NONE.to_le_bytes());
+
+    writer.write_all(&header)?;
+    writer.write_all(&file_table)?;
+    for block in blocks {
+        writer.write_all(&0u32.to_le_bytes())?; // Checksum; zero means not computed.
+        writer.write_all(&(block.len() as u16).to_le_bytes())?;
+        writer.write_all(&(block.len() as u16).to_le_bytes())?;
+        writer.write_all(block)?;
+    }
+
+    Ok(())
+}
+
+/// Decompresses one MSZIP data block onto the end of `folder`. Each block is a complete deflate
+/// stream after a `CK` signature, but may refer back into the previous 32 KiB of the folder.
+fn inflate_mszip_block(folder: &mut Vec<u8>, block: &[u8], uncompressed_len: usize) -> Result<(), ArchiveError>
+{
+    use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+    use miniz_oxide::inflate::TINFLStatus;
+
+    let deflate = block.strip_prefix(b"CK").ok_or(ArchiveError::Malformed("MSZIP block without its signature"))?;
+    let start = folder.len();
+    folder.resize(start + uncompressed_len, 0);
+
+    let mut decompressor = DecompressorOxide::new();
+    let (status, _, written) = decompress(
+        &mut decompressor,
+        deflate,
+        folder,
+        start,
+        inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+    );
+    if status != TINFLStatus::Done || written != uncompressed_len {
+        return Err(ArchiveError::Malformed("corrupt MSZIP block"));
+    }
+
+    Ok(())
+}
+
+/// Reads every file of a cabinet whose folders are uncompressed or MSZIP compressed, failing with
+/// [ArchiveError::TooLarge] if the folders decode to more than `limit` bytes.
+fn read_cab<R: Read>(mut reader: R, limit: u64) -> Result<Vec<(String, Vec<u8>)>, ArchiveError>
+{
+    let mut cab = Vec::new();
+    reader.read_to_end(&mut cab)?;
+
+    let u16_at = |offset: usize| -> Result<u16, ArchiveError> {
+        cab.get(offset..offset + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .ok_or(ArchiveError::Malformed("truncated cabinet"))
+    };
+    let u32_at = |offset: usize| -> Result<u32, ArchiveError> {
+        cab.get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or(ArchiveError::Malformed("truncated cabinet"))
+    };
+
+    if cab.get(0..4) != Some(b"MSCF") {
+        return Err(ArchiveError::Malformed("not a cabinet"));
+    }
+    let files_offset = u32_at(16)? as usize;
+    let folder_count = u16_at(26)? as usize;
+    let file_count = u16_at(28)? as usize;
+    let flags = u16_at(30)?;
+    if flags & 0x3 != 0 {
+        return Err(ArchiveError::Unsupported("multi-cabinet sets"));
+    }
+
+    // Optional per-cabinet, per-folder and per-data-block reserved areas.
+    let (mut folders_offset, folder_reserve, data_reserve) = if flags & 0x4 != 0 {
+        let header_reserve = u16_at(CAB_HEADER_LEN)? as usize;
+        let folder_reserve = *cab.get(CAB_HEADER_LEN + 2).ok_or(ArchiveError::Malformed("truncated cabinet"))? as usize;
+        let data_reserve = *cab.get(CAB_HEADER_LEN + 3).ok_or(ArchiveError::Malformed("truncated cabinet"))? as usize;
+        (CAB_HEADER_LEN + 4 + header_reserve, folder_reserve, data_reserve)
+    } else {
+        (CAB_HEADER_LEN, 0, 0)
+    };
+
+    // Decode each folder's data in full.
+    let mut remaining = usize::try_from(limit).unwrap_or(usize::MAX);
+    let mut folders: Vec<Vec<u8>> = Vec::with_capacity(folder_count);
+    for _ in 0..folder_count {
+        let mut block_offset = u32_at(folders_offset)? as usize;
+        let block_count = u16_at(folders_offset + 4)?;
+        let compression = u16_at(folders_offset + 6)? & 0xf;
+        if compression != CAB_COMPRESS_NONE && compression != CAB_COMPRESS_MSZIP {
+            return Err(ArchiveError::Unsupported("LZX or Quantum compressed cabinet folders"));
+        }
+
+        let mut data = Vec::new();
+        for _ in 0..block_count {
+            let len = u16_at(block_offset + 4)? as usize;
+            let uncompressed_len = u16_at(block_offset + 6)? as usize;
+            let start = block_offset + CAB_DATA_HEADER_LEN + data_reserve;
+            let block = start
+                .checked_add(len)
+                .and_then(|end| cab.get(start..end))
+                .ok_or(ArchiveError::Malformed("truncated cabinet"))?;
+            let decoded_len = if compression == CAB_COMPRESS_MSZIP { uncompressed_len } else { len };
+            remaining = remaining.checked_sub(decoded_len).ok_or(ArchiveError::TooLarge)?;
+            if compression == CAB_COMPRESS_MSZIP {
+                inflate_mszip_block(&mut data, block, uncompressed_len)?;
+            } else {
+                data.extend_from_slice(block);
+            }
+            block_offset = start + len;
+        }
+        folders.push(data);
+        folders_offset += CAB_FOLDER_LEN + folder_reserve;
+    }
+
+    let mut members = Vec::with_capacity(file_count);
+    let mut offset = files_offset;
+    for _ in 0..file_count {
+        let size = u32_at(offset)? as usize;
+        let start = u32_at(offset + 4)? as usize;
+        let folder = u16_at(offset + 8)? as usize;
+        let name_start = offset + CAB_FILE_LEN;
+        let name_len = cab
+            .get(name_start..)
+            .and_then(|rest| rest.iter().position(|b| *b == 0))
+            .ok_or(ArchiveError::Malformed("truncated cabinet"))?;
+        let name = String::from_utf8_lossy(&cab[name_start..name_start + name_len]).into_owned();
+        offset = name_start + name_len + 1;
+
+        let data = folders
+            .get(folder)
+            .and_then(|folder| folder.get(start..start.checked_add(size)?))
+            .ok_or(ArchiveError::Malformed("cabinet file lies outside its folder"))?;
+        members.push((name, data.to_vec()));
+    }
+
+    Ok(members)
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::{fixture, scratch_dir};
+
+    fn fixture_package() -> DriverPackage
+    {
+        DriverPackage::load(fixture("package"), "usb_device.inf").unwrap()
+    }
+
+    #[test]
+    fn manifest_round_trip()
+    {
+        let manifest = Manifest::for_package(&fixture_package()).unwrap();
+        assert_eq!(manifest.inf_name, "usb_device.inf");
+        assert_eq!(manifest.driver_type, Some(DriverType::WinUsb));
+        assert_eq!(manifest.hardware_ids, [r"USB\VID_1D50&PID_6018"]);
+        let paths: Vec<&str> = manifest.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["amd64/driver.sys", "usb_device.cat", "usb_device.inf"]);
+        assert_eq!(Manifest::from_text(&manifest.to_text()).unwrap(), manifest);
+        assert!(manifest.check(&fixture("package")).unwrap().is_empty());
+
+        let unknown = Manifest { driver_type: None, ..manifest.clone() };
+        assert!(unknown.to_text().contains("driver = unknown\n"));
+        assert_eq!(Manifest::from_text(&unknown.to_text()).unwrap(), unknown);
+
+        let no_driver = manifest.to_text().replace("driver = WinUSB\n", "");
+        assert!(matches!(Manifest::from_text(&no_driver), Err(ArchiveError::Malformed(_))));
+        let no_inf = manifest.to_text().replace("inf = usb_device.inf\n", "");
+        assert!(matches!(Manifest::from_text(&no_inf), Err(ArchiveError::Malformed(_))));
+    }
+
+    #[test]
+    fn cab_round_trip()
+    {
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let members = vec![
+            (String::from("usb_device.inf"), b"[Version]\r\n".to_vec()),
+            (String::from("amd64/big.bin"), big),
+            (String::from("empty.txt"), Vec::new()),
+            (String::from("r\u{e9}sum\u{e9}.txt"), b"caf\xc3\xa9".to_vec()),
+        ];
+
+        let mut cab = Vec::new();
+        write_cab(&mut cab, &members).unwrap();
+        let expected: Vec<(String, Vec<u8>)> = members
+            .iter()
+            .map(|(name, data)| (name.replace('/', "\\"), data.clone()))
+            .collect();
+        assert_eq!(read_cab(&cab[..], MAX_EXTRACTED_SIZE).unwrap(), expected);
+
+        assert!(matches!(read_cab(&cab[..cab.len() - 1], MAX_EXTRACTED_SIZE), Err(ArchiveError::Malformed(_))));
+        assert!(matches!(read_cab(&b"PK\x03\x04"[..], MAX_EXTRACTED_SIZE), Err(ArchiveError::Malformed(_))));
+
+        let total: usize = members.iter().map(|(_, data)| data.len()).sum();
+        assert!(read_cab(&cab[..], total as u64).is_ok());
+        assert!(matches!(read_cab(&cab[..], total as u64 - 1), Err(ArchiveError::TooLarge)));
+    }
+
+    #[test]
+    fn read_mszip_cab()
+    {
+        let members = read_cab(File::open(fixture("mszip.cab")).unwrap(), MAX_EXTRACTED_SIZE).unwrap();
+        let big: String = (0..5000).map(|i| format!("line {} of the big file\n", i % 700)).collect();
+
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0], (String::from("small.txt"), b"wdi MSZIP cabinet test\r\n".to_vec()));
+        assert_eq!(members[1].0, "dir\\big.txt");
+        assert!(members[1].1 == big.as_bytes(), "MSZIP data decoded incorrectly");
+
+        let total = (members[0].1.len() + members[1].1.len()) as u64;
+        let cab = File::open(fixture("mszip.cab")).unwrap();
+        assert!(matches!(read_cab(cab, total - 1), Err(ArchiveError::TooLarge)));
+    }
+
+    #[test]
+    fn zip_size_limit()
+    {
+        let members = vec![
+            (String::from("usb_device.inf"), b"[Version]\r\n".to_vec()),
+            (String::from("amd64/zeros.bin"), vec![0; 100_000]),
+        ];
+        let mut zip = io::Cursor::new(Vec::new());
+        write_zip(&mut zip, &members).unwrap();
+        // The zeros compress to far less than their size.
+        assert!(zip.get_ref().len() < 10_000);
+
+        let total = (members[0].1.len() + members[1].1.len()) as u64;
+        zip.set_position(0);
+        assert_eq!(read_zip(&mut zip, total).unwrap(), members);
+        zip.set_position(0);
+        assert!(matches!(read_zip(&mut zip, total - 1), Err(ArchiveError::TooLarge)));
+    }
+
+    #[test]
+    fn export_and_import()
+    {
+        let package = fixture_package();
+        let dir = scratch_dir("archive");
+
+        for (name, format) in [("package.zip", ArchiveFormat::Zip), ("package.cab", ArchiveFormat::Cab)] {
+            let archive = dir.join(name);
+            let manifest = package.export(&archive, format).unwrap();
+            assert_eq!(ArchiveFormat::from_path(&archive), Some(format));
+
+            let dest = dir.join(format!("{}-extracted", name));
+            let (imported, imported_manifest) = import_package(&archive, &dest).unwrap();
+            assert_eq!(imported_manifest, manifest);
+            assert_eq!(imported.hardware_ids(), package.hardware_ids());
+            assert!(imported.verify().unwrap().is_intact());
+
+            // Tampering with an extracted file is caught by the manifest.
+            fs::write(dest.join("usb_device.inf"), b"").unwrap();
+            fs::write(dest.join("extra.txt"), b"").unwrap();
+            assert_eq!(
+                manifest.check(&dest).unwrap(),
+                ["usb_device.inf does not match its recorded hash", "extra.txt is not listed in the manifest"],
+            );
+
+            let extracted = ExtractedPackage::open(&archive).unwrap();
+            let temp_dir = extracted.dir().to_path_buf();
+            assert!(temp_dir.join("usb_device.cat").is_file());
+            drop(extracted);
+            assert!(!temp_dir.exists());
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reject_unsafe_paths()
+    {
+        assert_eq!(sanitize_member_path(r"amd64\driver.sys").unwrap(), Path::new("amd64/driver.sys"));
+        for name in ["../evil.dll", r"..\evil.dll", "/etc/passwd", ""] {
+            assert!(matches!(sanitize_member_path(name), Err(ArchiveError::UnsafePath(_))), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,743 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Recording a device's driver before replacing it, and rolling back to it.
+//!
+//! [DriverBackup::install_driver] records the device's current driver in a backup directory,
+//! along with its upper filters and a copy of its driver store package, before installing.
+//! [DriverBackup::rollback] restores the device's upper filters and reinstalls the recorded package (from the copy, if it
+//! has since left the driver store), or, if the device had no driver, uninstalls the new one and
+//! removes its package. The system operations go through a [DriverBackend], so the flow can be
+//! tested against a [FakeBackend].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::catalog::hex;
+use crate::snapshot::DeviceKey;
+use crate::uninstall::{
+    current_inf, driver_store_location, is_oem_inf_name, reinstall_inf, remove_driver_package, set_upper_filters,
+    uninstall_driver, upper_filters, windows_dir, RemovePackageOptions,
+};
+use crate::{c_text, DeviceInfo, Error, InstallDriverOptions};
+
+
+/// The error type for [DriverBackup] operations.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BackupError
+{
+    /// A driver operation failed.
+    Wdi(Error),
+
+    /// Reading or writing the backup directory failed.
+    Io(io::Error),
+
+    /// A backup record couldn't be parsed.
+    Malformed(&'static str),
+
+    /// There's no backup record for the device.
+    NoRecord,
+
+    /// The device had a driver, but its INF couldn't be determined when it was recorded, so it
+    /// can't be reinstalled.
+    NoInf,
+}
+
+impl Display for BackupError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        use BackupError::*;
+
+        match self {
+            Wdi(e) => write!(f, "{}", e),
+            Io(e) => write!(f, "I/O error: {}", e),
+            Malformed(reason) => write!(f, "Malformed backup record: {}", reason),
+            NoRecord => write!(f, "No driver backup recorded for this device"),
+            NoInf => write!(f, "The recorded driver's INF is unknown, so it cannot be reinstalled"),
+        }
+    }
+}
+
+impl std::error::Error for BackupError
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self {
+            BackupError::Wdi(e) => Some(e),
+            BackupError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for BackupError
+{
+    fn from(other: Error) -> Self
+    {
+        Self::Wdi(other)
+    }
+}
+
+impl From<io::Error> for BackupError
+{
+    fn from(other: io::Error) -> Self
+    {
+        Self::Io(other)
+    }
+}
+
+
+/// A device's driver, as it was before an install.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverRecord
+{
+    /// The device's [DeviceKey].
+    pub device: String,
+
+    /// The driver's service name, or None if the device had no driver.
+    pub driver: Option<String>,
+
+    /// libwdi's packed driver version.
+    pub driver_version: u64,
+
+    /// The INF in the driver store the driver was installed from, e.g. `oem12.inf`.
+    pub inf: Option<String>,
+
+    /// The copy of the driver's package in the backup directory: the path of its INF, relative
+    /// to the directory. None if the driver store package couldn't be found.
+    pub package: Option<String>,
+
+    /// Every entry of the device's `UpperFilters`, in order.
+    pub upper_filters: Vec<String>,
+}
+
+fn text(bytes: &Option<Vec<u8>>) -> Option<String>
+{
+    bytes
+        .as_deref()
+        .map(|bytes| c_text(bytes).into_owned())
+        .filter(|text| !text.is_empty())
+}
+
+impl DriverRecord
+{
+    /// Records `device`'s driver, which was installed from the driver store INF `inf`. The
+    /// package copy is left unset, and only the upper filter libwdi reports is recorded;
+    /// [DriverBackup::record] fills in both.
+    pub fn for_device(device: &DeviceInfo, inf: Option<String>) -> Self
+    {
+        Self {
+            device: DeviceKey::for_device(device).to_string(),
+            driver: text(&device.driver),
+            driver_version: device.driver_version,
+            inf,
+            package: None,
+            upper_filters: text(&device.upper_filter).into_iter().collect(),
+        }
+    }
+
+    pub fn to_text(&self) -> String
+    {
+        let mut text = String::from("# wdi driver backup\n");
+        text.push_str(&format!("device = {}\n", self.device));
+        if let Some(driver) = &self.driver {
+            text.push_str(&format!("driver = {}\n", driver));
+        }
+        text.push_str(&format!("driver_version = {}\n", self.driver_version));
+        if let Some(inf) = &self.inf {
+            text.push_str(&format!("inf = {}\n", inf));
+        }
+        if let Some(package) = &self.package {
+            text.push_str(&format!("package = {}\n", package));
+        }
+        for upper_filter in &self.upper_filters {
+            text.push_str(&format!("upper_filter = {}\n", upper_filter));
+        }
+
+        text
+    }
+
+    /// Parses the text format produced by [DriverRecord::to_text].
+    pub fn from_text(text: &str) -> Result<Self, BackupError>
+    {
+        let mut device = None;
+        let mut record = Self {
+            device: String::new(),
+            driver: None,
+            driver_version: 0,
+            inf: None,
+            package: None,
+            upper_filters: Vec::new(),
+        };
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(BackupError::Malformed("invalid record line"))?;
+            let value = value.trim().to_string();
+            match key.trim() {
+                "device" => device = Some(value),
+                "driver" => record.driver = Some(value),
+                "driver_version" => {
+                    record.driver_version = value.parse().map_err(|_| BackupError::Malformed("invalid driver version"))?;
+                },
+                "inf" => record.inf = Some(value),
+                "package" => record.package = Some(value),
+                "upper_filter" => record.upper_filters.push(value),
+                _ => (),
+            }
+        }
+
+        record.device = device.ok_or(BackupError::Malformed("record does not name a device"))?;
+        Ok(record)
+    }
+}
+
+
+/// The system operations [DriverBackup] needs.
+pub trait DriverBackend
+{
+    /// The driver store INF `device`'s current driver was installed from, if any.
+    fn current_inf(&mut self, device: &DeviceInfo) -> Result<Option<String>, Error>;
+
+    /// The path of the driver store INF `inf`'s package copy in the driver store, if it can be
+    /// found.
+    fn store_location(&mut self, inf: &str) -> Option<PathBuf>;
+
+    fn install(&mut self, device: &mut DeviceInfo, path: &str, inf_name: &str, options: &mut InstallDriverOptions)
+        -> Result<(), Error>;
+
+    /// Reinstalls the driver store INF `inf` for `device`. Fails with [Error::NotFound] if the
+    /// package is no longer in the driver store.
+    fn reinstall(&mut self, device: &DeviceInfo, inf: &str) -> Result<(), Error>;
+
+    /// Installs the package whose INF is at `inf_path` for `device`, adding it to the driver
+    /// store.
+    fn install_inf(&mut self, device: &DeviceInfo, inf_path: &Path) -> Result<(), Error>;
+
+    /// Every entry of `device`'s `UpperFilters`.
+    fn upper_filters(&mut self, device: &DeviceInfo) -> Result<Vec<String>, Error>;
+
+    /// Sets `device`'s `UpperFilters`, or removes them if `filters` is empty.
+    fn set_upper_filters(&mut self, device: &DeviceInfo, filters: &[String]) -> Result<(), Error>;
+
+    /// Removes `device`'s driver binding, returning the `oem<N>.inf` package it was bound to, if
+    /// any.
+    fn uninstall(&mut self, device: &DeviceInfo) -> Result<Option<String>, Error>;
+
+    /// Removes the libwdi package `oem_inf` from the driver store. Fails with [Error::Busy] if
+    /// devices still use it.
+    fn remove_package(&mut self, oem_inf: &str) -> Result<(), Error>;
+}
+
+/// The real system, via libwdi and SetupAPI. Only works on Windows.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemBackend;
+
+impl DriverBackend for SystemBackend
+{
+    fn current_inf(&mut self, device: &DeviceInfo) -> Result<Option<String>, Error>
+    {
+        match current_inf(device) {
+            // The device is gone, so there's nothing to back up.
+            Err(Error::NotFound) => Ok(None),
+            result => result,
+        }
+    }
+
+    fn store_location(&mut self, inf: &str) -> Option<PathBuf>
+    {
+        driver_store_location(&windows_dir().ok()?.join("INF").join(inf))
+    }
+
+    fn install(&mut self, device: &mut DeviceInfo, path: &str, inf_name: &str, options: &mut InstallDriverOptions)
+        -> Result<(), Error>
+    {
+        crate::install_driver(device, path, inf_name, options)
+    }
+
+    fn reinstall(&mut self, device: &DeviceInfo, inf: &str) -> Result<(), Error>
+    {
+        let inf_path = windows_dir()?.join("INF").join(inf);
+        if !inf_path.is_file() {
+            return Err(Error::NotFound);
+        }
+        self.install_inf(device, &inf_path)
+    }
+
+    fn install_inf(&mut self, device: &DeviceInfo, inf_path: &Path) -> Result<(), Error>
+    {
+        let hardware_id = text(&device.hardware_id).ok_or(Error::InvalidParam)?;
+        reinstall_inf(&hardware_id, inf_path).map(|_reboot| ())
+    }
+
+    fn upper_filters(&mut self, device: &DeviceInfo) -> Result<Vec<String>, Error>
+    {
+        match upper_filters(device) {
+            // The device is gone, so there's nothing to back up.
+            Err(Error::NotFound) => Ok(Vec::new()),
+            result => result,
+        }
+    }
+
+    fn set_upper_filters(&mut self, device: &DeviceInfo, filters: &[String]) -> Result<(), Error>
+    {
+        set_upper_filters(device, filters)
+    }
+
+    fn uninstall(&mut self, device: &DeviceInfo) -> Result<Option<String>, Error>
+    {
+        uninstall_driver(device).map(|uninstalled| uninstalled.oem_inf)
+    }
+
+    fn remove_package(&mut self, oem_inf: &str) -> Result<(), Error>
+    {
+        remove_driver_package(oem_inf, RemovePackageOptions::default())
+    }
+}
+
+/// A [DriverBackend] that only pretends, for testing code that uses [DriverBackup].
+#[derive(Debug, Clone, Default)]
+pub struct FakeBackend
+{
+    /// The driver store INF each device is bound to, by [DeviceKey]. Installs add an `oem<N>.inf`.
+    pub infs: BTreeMap<DeviceKey, String>,
+
+    /// Each device's upper filters, by [DeviceKey].
+    pub upper_filters: BTreeMap<DeviceKey, Vec<String>>,
+
+    /// Where each driver store INF's package is, for [DriverBackend::store_location].
+    pub store: BTreeMap<String, PathBuf>,
+
+    /// The driver store INFs that have been removed, and so can't be reinstalled.
+    pub removed: BTreeSet<String>,
+
+    /// Every operation performed, e.g. `install oem0.inf USB\VID_1D50&PID_6018\...`.
+    pub log: Vec<String>,
+
+    installs: usize,
+}
+
+impl FakeBackend
+{
+    fn next_inf(&mut self) -> String
+    {
+        let inf = format!("oem{}.inf", self.installs);
+        self.installs += 1;
+        inf
+    }
+}
+
+impl DriverBackend for FakeBackend
+{
+    fn current_inf(&mut self, device: &DeviceInfo) -> Result<Option<String>, Error>
+    {
+        Ok(self.infs.get(&DeviceKey::for_device(device)).cloned())
+    }
+
+    fn store_location(&mut self, inf: &str) -> Option<PathBuf>
+    {
+        self.store.get(inf).cloned()
+    }
+
+    fn install(&mut self, device: &mut DeviceInfo, _path: &str, _inf_name: &str, _options: &mut InstallDriverOptions)
+        -> Result<(), Error>
+    {
+        let key = DeviceKey::for_device(device);
+        let inf = self.next_inf();
+        self.log.push(format!("install {} {}", inf, key));
+        self.infs.insert(key, inf);
+        Ok(())
+    }
+
+    fn reinstall(&mut self, device: &DeviceInfo, inf: &str) -> Result<(), Error>
+    {
+        if self.removed.contains(inf) {
+            return Err(Error::NotFound);
+        }
+
+        let key = DeviceKey::for_device(device);
+        self.log.push(format!("reinstall {} {}", inf, key));
+        self.infs.insert(key, inf.to_string());
+        Ok(())
+    }
+
+    fn install_inf(&mut self, device: &DeviceInfo, inf_path: &Path) -> Result<(), Error>
+    {
+        if !inf_path.is_file() {
+            return Err(Error::NotFound);
+        }
+
+        let key = DeviceKey::for_device(device);
+        let inf = self.next_inf();
+        let name = inf_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        self.log.push(format!("install {} from {} {}", inf, name, key));
+        self.infs.insert(key, inf);
+        Ok(())
+    }
+
+    fn upper_filters(&mut self, device: &DeviceInfo) -> Result<Vec<String>, Error>
+    {
+        Ok(self.upper_filters.get(&DeviceKey::for_device(device)).cloned().unwrap_or_default())
+    }
+
+    fn set_upper_filters(&mut self, device: &DeviceInfo, filters: &[String]) -> Result<(), Error>
+    {
+        let key = DeviceKey::for_device(device);
+        if filters.is_empty() {
+            self.log.push(format!("filter - {}", key));
+            self.upper_filters.remove(&key);
+        } else {
+            self.log.push(format!("filter {} {}", filters.join(","), key));
+            self.upper_filters.insert(key, filters.to_vec());
+        }
+        Ok(())
+    }
+
+    fn uninstall(&mut self, device: &DeviceInfo) -> Result<Option<String>, Error>
+    {
+        let key = DeviceKey::for_device(device);
+        self.log.push(format!("uninstall {}", key));
+        self.upper_filters.remove(&key);
+        Ok(self.infs.remove(&key).filter(|inf| is_oem_inf_name(inf)))
+    }
+
+    fn remove_package(&mut self, oem_inf: &str) -> Result<(), Error>
+    {
+        if self.infs.values().any(|inf| inf == oem_inf) {
+            return Err(Error::Busy);
+        }
+
+        self.log.push(format!("remove {}", oem_inf));
+        self.removed.insert(oem_inf.to_string());
+        Ok(())
+    }
+}
+
+
+/// Driver installs with a record of each device's previous driver, kept as one file per device
+/// in a backup directory.
+///
+/// Only the first driver recorded for a device is kept, so installing repeatedly still rolls
+/// back to the driver the device had originally. Rolling back (or [DriverBackup::forget])
+/// clears the record.
+#[derive(Debug)]
+pub struct DriverBackup<B: DriverBackend = SystemBackend>
+{
+    dir: PathBuf,
+    backend: B,
+}
+
+impl DriverBackup<SystemBackend>
+{
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self
+    {
+        Self::with_backend(dir, SystemBackend)
+    }
+}
+
+impl<B: DriverBackend> DriverBackup<B>
+{
+    pub fn with_backend<P: AsRef<Path>>(dir: P, backend: B) -> Self
+    {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            backend,
+        }
+    }
+
+    pub fn dir(&self) -> &Path
+    {
+        &self.dir
+    }
+
+    pub fn backend(&self) -> &B
+    {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B
+    {
+        &mut self.backend
+    }
+
+    /// The name of `device`'s record, and of the directory its package copy is kept in.
+    fn record_name(device: &DeviceInfo) -> String
+    {
+        let key = DeviceKey::for_device(device);
+        let digest = Sha256::digest(key.as_str().as_bytes());
+        hex(&digest[..16])
+    }
+
+    fn record_path(&self, device: &DeviceInfo) -> PathBuf
+    {
+        self.dir.join(format!("{}.txt", Self::record_name(device)))
+    }
+
+    /// The record for `device`, if there is one.
+    pub fn get(&self, device: &DeviceInfo) -> Result<Option<DriverRecord>, BackupError>
+    {
+        match fs::read_to_string(self.record_path(device)) {
+            Ok(text) => Ok(Some(DriverRecord::from_text(&text)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Every record in the backup directory.
+    pub fn records(&self) -> Result<Vec<DriverRecord>, BackupError>
+    {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "txt").unwrap_or(false) {
+                records.push(DriverRecord::from_text(&fs::read_to_string(path)?)?);
+            }
+        }
+        records.sort_by(|a, b| a.device.cmp(&b.device));
+
+        Ok(records)
+    }
+
+    /// Records `device`'s current driver, unless there's already a record for it, and returns
+    /// the record. The driver's package is copied from the driver store, if it can be found.
+    pub fn record(&mut self, device: &DeviceInfo) -> Result<DriverRecord, BackupError>
+    {
+        if let Some(record) = self.get(device)? {
+            return Ok(record);
+        }
+
+        let mut record = DriverRecord::for_device(device, None);
+        if device.driver.is_some() {
+            record.inf = self.backend.current_inf(device)?;
+            record.upper_filters = self.backend.upper_filters(device)?;
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        let store_inf = record.inf.as_deref().and_then(|inf| self.backend.store_location(inf));
+        if let Some((package_dir, inf_name)) = store_inf
+            .as_deref()
+            .and_then(|store_inf| Some((store_inf.parent()?, store_inf.file_name()?.to_str()?)))
+        {
+            let name = Self::record_name(device);
+            copy_dir(package_dir, &self.dir.join(&name))?;
+            record.package = Some(format!("{}/{}", name, inf_name));
+        }
+        fs::write(self.record_path(device), record.to_text())?;
+
+        Ok(record)
+    }
+
+    /// Deletes the record for `device` and its package copy, if there are any.
+    pub fn forget(&mut self, device: &DeviceInfo) -> Result<(), BackupError>
+    {
+        match fs::remove_dir_all(self.dir.join(Self::record_name(device))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        match fs::remove_file(self.record_path(device)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records `device`'s driver, then installs the prepared package in `path` like
+    /// [install_driver](crate::install_driver).
+    pub fn install_driver(
+        &mut self,
+        device: &mut DeviceInfo,
+        path: &str,
+        inf_name: &str,
+        options: &mut InstallDriverOptions,
+    ) -> Result<(), BackupError>
+    {
+        self.record(device)?;
+        Ok(self.backend.install(device, path, inf_name, options)?)
+    }
+
+    /// Restores `device`'s recorded driver and upper filter and clears the record, returning it.
+    ///
+    /// If the device had no driver, it's uninstalled and the package installed for it is
+    /// removed from the driver store, unless other devices still use it.
+    pub fn rollback(&mut self, device: &DeviceInfo) -> Result<DriverRecord, BackupError>
+    {
+        let record = self.get(device)?.ok_or(BackupError::NoRecord)?;
+        match (&record.driver, &record.inf) {
+            (None, _) => {
+                if let Some(oem_inf) = self.backend.uninstall(device)? {
+                    match self.backend.remove_package(&oem_inf) {
+                        Err(Error::Busy) => (),
+                        result => result?,
+                    }
+                }
+            },
+            (Some(_), Some(inf)) => {
+                self.backend.set_upper_filters(device, &record.upper_filters)?;
+                match (self.backend.reinstall(device, inf), &record.package) {
+                    // The package has left the driver store since, so install the copy.
+                    (Err(Error::NotFound), Some(package)) => self.backend.install_inf(device, &self.dir.join(package))?,
+                    (result, _) => result?,
+                }
+            },
+            (Some(_), None) => return Err(BackupError::NoInf),
+        }
+        self.forget(device)?;
+
+        Ok(record)
+    }
+}
+
+/// Recursively copies the directory `from` to `to`.
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()>
+{
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        if path.is_dir() {
+            copy_dir(&path, &to.join(name))?;
+        } else {
+            fs::copy(&path, to.join(name))?;
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::{fixture, probe, scratch_dir};
+
+    fn device(serial: &str, driver: Option<&str>) -> DeviceInfo
+    {
+        probe()
+            .serial(serial)
+            .driver(driver)
+            .hardware_id(r"USB\VID_1D50&PID_6018&REV_0100")
+            .upper_filter("vendorfilter")
+            .driver_version(0x000A_0000_4A61_0001)
+            .build()
+    }
+
+    #[test]
+    fn install_and_roll_back()
+    {
+        let dir = scratch_dir("backup");
+        let mut vendor = device("A", Some("usbser"));
+        let filters = vec![String::from("vendorfilter"), String::from("othervendor")];
+        let mut fake = FakeBackend::default();
+        fake.infs.insert(DeviceKey::for_device(&vendor), String::from("usbser.inf"));
+        // libwdi only reports the first of these.
+        fake.upper_filters.insert(DeviceKey::for_device(&vendor), filters.clone());
+        let mut backup = DriverBackup::with_backend(&dir, fake);
+
+        let mut options = InstallDriverOptions::default();
+        backup.install_driver(&mut vendor, "pkg", "usb_device.inf", &mut options).unwrap();
+        // A second install keeps the original record.
+        backup.install_driver(&mut vendor, "pkg", "usb_device.inf", &mut options).unwrap();
+
+        let record = backup.get(&vendor).unwrap().unwrap();
+        assert_eq!(record.driver.as_deref(), Some("usbser"));
+        assert_eq!(record.inf.as_deref(), Some("usbser.inf"));
+        assert_eq!(record.upper_filters, filters);
+        assert_eq!(DriverRecord::from_text(&record.to_text()).unwrap(), record);
+
+        let mut bare = device("B", None);
+        backup.install_driver(&mut bare, "pkg", "usb_device.inf", &mut options).unwrap();
+        assert_eq!(backup.records().unwrap().len(), 2);
+
+        assert_eq!(backup.rollback(&vendor).unwrap(), record);
+        assert_eq!(backup.rollback(&bare).unwrap().driver, None);
+        assert!(matches!(backup.rollback(&vendor), Err(BackupError::NoRecord)));
+        assert!(backup.records().unwrap().is_empty());
+
+        let log = &backup.backend().log;
+        assert_eq!(log[2], r"install oem2.inf USB\VID_1D50&PID_6018\B");
+        assert_eq!(log[3], r"filter vendorfilter,othervendor USB\VID_1D50&PID_6018\A");
+        assert_eq!(log[4], r"reinstall usbser.inf USB\VID_1D50&PID_6018\A");
+        assert_eq!(log[5], r"uninstall USB\VID_1D50&PID_6018\B");
+        assert_eq!(log[6], "remove oem2.inf");
+        assert_eq!(log.len(), 7);
+        assert_eq!(backup.backend().infs.get(&DeviceKey::for_device(&vendor)).map(String::as_str), Some("usbser.inf"));
+        assert!(backup.backend().removed.contains("oem2.inf"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn roll_back_from_package_copy()
+    {
+        let dir = scratch_dir("backup-copy");
+        let mut vendor = device("A", Some("vendorusb"));
+        let key = DeviceKey::for_device(&vendor);
+        let mut fake = FakeBackend::default();
+        fake.infs.insert(key.clone(), String::from("oem7.inf"));
+        fake.store.insert(String::from("oem7.inf"), fixture("package/usb_device.inf"));
+        let mut backup = DriverBackup::with_backend(&dir, fake);
+
+        let mut options = InstallDriverOptions::default();
+        backup.install_driver(&mut vendor, "pkg", "usb_device.inf", &mut options).unwrap();
+        let record = backup.get(&vendor).unwrap().unwrap();
+        let package = record.package.as_deref().unwrap();
+        assert!(package.ends_with("/usb_device.inf"));
+        assert!(dir.join(package).is_file());
+        assert!(dir.join(package).with_file_name("usb_device.cat").is_file());
+
+        // The device had no upper filters, but has since been given libusb0 as a filter, and the
+        // vendor package was removed from the driver store.
+        assert!(record.upper_filters.is_empty());
+        backup.backend_mut().upper_filters.insert(key.clone(), vec![String::from("libusb0")]);
+        backup.backend_mut().removed.insert(String::from("oem7.inf"));
+
+        assert_eq!(backup.rollback(&vendor).unwrap(), record);
+        let fake = backup.backend();
+        assert_eq!(fake.log[1], r"filter - USB\VID_1D50&PID_6018\A");
+        assert_eq!(fake.log[2], r"install oem1.inf from usb_device.inf USB\VID_1D50&PID_6018\A");
+        assert_eq!(fake.upper_filters.get(&key), None);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keep_packages_in_use()
+    {
+        let dir = scratch_dir("backup-busy");
+        let mut bare = device("B", None);
+        let other = device("C", Some("WinUSB"));
+        let mut backup = DriverBackup::with_backend(&dir, FakeBackend::default());
+
+        let mut options = InstallDriverOptions::default();
+        backup.install_driver(&mut bare, "pkg", "usb_device.inf", &mut options).unwrap();
+        backup
+            .backend_mut()
+            .infs
+            .insert(DeviceKey::for_device(&other), String::from("oem0.inf"));
+
+        assert_eq!(backup.rollback(&bare).unwrap().package, None);
+        assert_eq!(backup.backend().log.last().map(String::as_str), Some(r"uninstall USB\VID_1D50&PID_6018\B"));
+        assert!(backup.backend().removed.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
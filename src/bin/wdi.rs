@@ -0,0 +1,600 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! `wdi`, a command line driver installer in the spirit of Zadig. Built with the `cli` feature.
+//!
+//! Run `wdi help` for usage. Exit codes are 0 on success, 2 for usage errors, 3 when no device
+//! matches, and 10 plus the magnitude of the libwdi error code for libwdi errors (e.g. 13 for
+//! [Error::Access]), with 109 for [Error::Other].
+
+use std::borrow::Cow;
+use std::ffi::CString;
+use std::fmt::Write as _;
+use std::process::ExitCode;
+
+use wdi::{
+    CreateListOptions, DeviceInfo, DriverType, Error, Guid, InstallCertOptions, InstallDriverOptions,
+    PrepareDriverOptions,
+};
+
+
+const USAGE: &str = "\
+Usage: wdi <command> [options]
+
+Commands:
+    list [--all] [--hubs] [--trim] [--json]
+        List USB devices; by default only those without a driver.
+    info <device> [--json]
+        Show everything known about a device.
+    prepare <device> --dir <dir> [prepare options]
+        Extract the driver files and generate an INF for a connected device.
+    install <device> --dir <dir> [prepare options] [install options] [--no-prepare]
+        Prepare, then install the driver for a connected device.
+    extract --vid <vid> --pid <pid> [--mi <mi>] [--desc <text>] --dir <dir> [prepare options]
+        Extract the driver files for a device that needn't be connected.
+    cert install <name> [--no-warning]
+        Install an embedded certificate into the Trusted Publisher store.
+    version
+        Describe the libwdi build this tool embeds.
+
+Devices are selected with --vid <vid> --pid <pid> [--mi <mi>], or --device-id <id>. IDs are hex.
+
+Prepare options:
+    --inf <name>            INF file name (default usb_device.inf)
+    --driver <type>         winusb (default), libusb0, libusbk or user
+    --vendor-name <name>    Manufacturer shown in the device manager
+    --device-guid <guid>    DeviceInterfaceGUID to use instead of a generated one
+    --derive-guid           Use a DeviceInterfaceGUID derived from the device's IDs
+    --no-cat                Don't generate a catalog
+    --no-signing            Don't self-sign the catalog
+    --cert-subject <name>   Subject of the self-signed certificate
+    --wcid                  Use the generic WCID driver
+    --external-inf          Use the existing INF named by --inf
+
+Install options:
+    --filter                Install as a filter driver (libusb0 only)
+    --timeout <ms>          Wait this long for pending installations (default 0, no timeout)
+";
+
+/// Why the command failed, which decides the exit code.
+#[derive(Debug)]
+enum Failure
+{
+    Usage(String),
+    NoMatch(String),
+    Wdi(Error),
+}
+
+impl From<Error> for Failure
+{
+    fn from(e: Error) -> Self
+    {
+        Failure::Wdi(e)
+    }
+}
+
+impl Failure
+{
+    fn exit_code(&self) -> u8
+    {
+        match self {
+            Failure::Usage(_) => 2,
+            Failure::NoMatch(_) => 3,
+            Failure::Wdi(e) => (10 + e.to_error_code().unsigned_abs()).min(125) as u8,
+        }
+    }
+}
+
+fn usage<T>(message: impl Into<String>) -> Result<T, Failure>
+{
+    Err(Failure::Usage(message.into()))
+}
+
+/// The arguments after the command, consumed option by option. Whatever is left at the end is
+/// either positional, or an unknown option.
+struct Args
+{
+    args: Vec<String>,
+}
+
+impl Args
+{
+    /// Removes `--name`, returning whether it was present.
+    fn flag(&mut self, name: &str) -> bool
+    {
+        let before = self.args.len();
+        self.args.retain(|arg| arg != name);
+        self.args.len() != before
+    }
+
+    /// Removes `--name <value>` or `--name=<value>`, returning the value.
+    fn value(&mut self, name: &str) -> Result<Option<String>, Failure>
+    {
+        let prefix = format!("{}=", name);
+        if let Some(index) = self.args.iter().position(|arg| arg.starts_with(&prefix)) {
+            let arg = self.args.remove(index);
+            return Ok(Some(arg[prefix.len()..].to_string()));
+        }
+
+        let Some(index) = self.args.iter().position(|arg| arg == name) else {
+            return Ok(None);
+        };
+        if index + 1 >= self.args.len() {
+            return usage(format!("{} needs a value", name));
+        }
+        self.args.remove(index);
+        Ok(Some(self.args.remove(index)))
+    }
+
+    fn parsed<T>(&mut self, name: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, Failure>
+    {
+        match self.value(name)? {
+            Some(value) => match parse(&value) {
+                Some(parsed) => Ok(Some(parsed)),
+                None => usage(format!("invalid value {:?} for {}", value, name)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn required<T>(&mut self, name: &str, parse: impl Fn(&str) -> Option<T>) -> Result<T, Failure>
+    {
+        match self.parsed(name, parse)? {
+            Some(value) => Ok(value),
+            None => usage(format!("{} is required", name)),
+        }
+    }
+
+    /// Checks that only the expected number of positional arguments are left, and returns them.
+    fn finish(self, positional: usize) -> Result<Vec<String>, Failure>
+    {
+        if let Some(unknown) = self.args.iter().find(|arg| arg.starts_with("--")) {
+            return usage(format!("unknown option {}", unknown));
+        }
+        if self.args.len() != positional {
+            return usage(format!("expected {} argument(s), got {:?}", positional, self.args));
+        }
+
+        Ok(self.args)
+    }
+}
+
+fn parse_hex_u16(s: &str) -> Option<u16>
+{
+    u16::from_str_radix(s.trim_start_matches("0x").trim_start_matches("0X"), 16).ok()
+}
+
+fn parse_hex_u8(s: &str) -> Option<u8>
+{
+    u8::from_str_radix(s.trim_start_matches("0x").trim_start_matches("0X"), 16).ok()
+}
+
+/// Which connected device to operate on.
+enum Selector
+{
+    Ids
+    {
+        vid: u16,
+        pid: u16,
+        mi: Option<u8>,
+    },
+    DeviceId(String),
+}
+
+impl Selector
+{
+    fn from_args(args: &mut Args) -> Result<Self, Failure>
+    {
+        if let Some(device_id) = args.value("--device-id")? {
+            return Ok(Selector::DeviceId(device_id));
+        }
+
+        let vid = args.required("--vid", parse_hex_u16)?;
+        let pid = args.required("--pid", parse_hex_u16)?;
+        let mi = args.parsed("--mi", parse_hex_u8)?;
+        Ok(Selector::Ids { vid, pid, mi })
+    }
+
+    fn matches(&self, device: &DeviceInfo) -> bool
+    {
+        match self {
+            Selector::Ids { vid, pid, mi } => {
+                let mi_matches = match mi {
+                    Some(mi) => device.is_composite && device.mi == *mi,
+                    None => true,
+                };
+                device.vid == *vid && device.pid == *pid && mi_matches
+            },
+            Selector::DeviceId(id) => device.device_id_str().unwrap_or_default().eq_ignore_ascii_case(id),
+        }
+    }
+
+    /// Finds the one connected device this selects.
+    fn find(&self) -> Result<DeviceInfo, Failure>
+    {
+        let options = CreateListOptions {
+            list_all: true,
+            ..Default::default()
+        };
+        let devices = match wdi::create_list(options) {
+            // No devices at all just means none matches.
+            Err(Error::NoDevice) => Vec::new(),
+            result => result?,
+        };
+        let mut matching: Vec<DeviceInfo> = devices.into_iter().filter(|device| self.matches(device)).collect();
+
+        match matching.len() {
+            0 => Err(Failure::NoMatch(String::from("no connected device matches"))),
+            1 => Ok(matching.remove(0)),
+            n => usage(format!("{} devices match; select one with --mi or --device-id", n)),
+        }
+    }
+}
+
+fn json_string(s: &str) -> String
+{
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_optional(value: Option<Cow<str>>) -> String
+{
+    match value {
+        Some(text) => json_string(&text),
+        None => String::from("null"),
+    }
+}
+
+fn device_json(device: &DeviceInfo) -> String
+{
+    let mi = if device.is_composite { device.mi.to_string() } else { String::from("null") };
+    format!(
+        "{{\"vid\":{},\"pid\":{},\"mi\":{},\"desc\":{},\"driver\":{},\"driver_version\":{},\"device_id\":{},\
+        \"hardware_id\":{},\"compatible_id\":{},\"upper_filter\":{},\"derived_guid\":{}}}",
+        device.vid,
+        device.pid,
+        mi,
+        json_string(&device.desc_str()),
+        json_optional(device.driver_str()),
+        device.driver_version,
+        json_optional(device.device_id_str()),
+        json_optional(device.hardware_id_str()),
+        json_optional(device.compatible_id_str()),
+        json_optional(device.upper_filter_str()),
+        json_string(&Guid::for_device_info(device).to_string()),
+    )
+}
+
+/// Formats libwdi's packed driver version, e.g. `6.1.7600.16385`.
+fn driver_version(version: u64) -> String
+{
+    if version == 0 {
+        return String::new();
+    }
+    format!("{}.{}.{}.{}", version >> 48, (version >> 32) & 0xffff, (version >> 16) & 0xffff, version & 0xffff)
+}
+
+fn list(mut args: Args) -> Result<(), Failure>
+{
+    let options = CreateListOptions {
+        list_all: args.flag("--all"),
+        list_hubs: args.flag("--hubs"),
+        trim_whitespaces: args.flag("--trim"),
+    };
+    let json = args.flag("--json");
+    args.finish(0)?;
+
+    let devices = match wdi::create_list(options) {
+        // libwdi reports an empty list as not found, which isn't an error for listing.
+        Err(Error::NoDevice) => Vec::new(),
+        result => result?,
+    };
+
+    if json {
+        let devices: Vec<String> = devices.iter().map(device_json).collect();
+        println!("[{}]", devices.join(","));
+        return Ok(());
+    }
+
+    println!("{:<4} {:<4} {:<3} {:<12} {:<16} DESCRIPTION", "VID", "PID", "MI", "DRIVER", "VERSION");
+    for device in &devices {
+        let mi = if device.is_composite { format!("{:02X}", device.mi) } else { String::from("-") };
+        let driver = device.driver_str().unwrap_or(Cow::Borrowed("(none)"));
+        println!(
+            "{:04X} {:04X} {:<3} {:<12} {:<16} {}",
+            device.vid,
+            device.pid,
+            mi,
+            driver,
+            driver_version(device.driver_version),
+            device.desc_str(),
+        );
+    }
+
+    Ok(())
+}
+
+fn info(mut args: Args) -> Result<(), Failure>
+{
+    let selector = Selector::from_args(&mut args)?;
+    let json = args.flag("--json");
+    args.finish(0)?;
+
+    let device = selector.find()?;
+    if json {
+        println!("{}", device_json(&device));
+        return Ok(());
+    }
+
+    println!("Description:      {}", device.desc_str());
+    println!("VID:PID:          {:04X}:{:04X}", device.vid, device.pid);
+    if device.is_composite {
+        println!("Interface:        {:02X}", device.mi);
+    }
+    println!("Driver:           {}", device.driver_str().unwrap_or_default());
+    println!("Driver version:   {}", driver_version(device.driver_version));
+    println!("Device ID:        {}", device.device_id_str().unwrap_or_default());
+    println!("Hardware ID:      {}", device.hardware_id_str().unwrap_or_default());
+    println!("Compatible ID:    {}", device.compatible_id_str().unwrap_or_default());
+    println!("Upper filter:     {}", device.upper_filter_str().unwrap_or_default());
+    println!("Derived GUID:     {}", Guid::for_device_info(&device));
+
+    Ok(())
+}
+
+/// The prepare options and output location shared by prepare, install and extract.
+struct Prepare
+{
+    dir: String,
+    inf_name: String,
+    options: PrepareDriverOptions,
+    derive_guid: bool,
+}
+
+impl Prepare
+{
+    fn from_args(args: &mut Args) -> Result<Self, Failure>
+    {
+        let dir = args.required("--dir", |s| Some(s.to_string()))?;
+        let driver_type = args
+            .parsed("--driver", |s| s.parse::<DriverType>().ok())?
+            .unwrap_or_default();
+        let inf_name = args.value("--inf")?.unwrap_or_else(|| default_inf_name(driver_type));
+        let vendor_name = args.parsed("--vendor-name", |s| CString::new(s).ok())?;
+        let device_guid = args.parsed("--device-guid", |s| s.parse::<Guid>().ok())?;
+        let derive_guid = args.flag("--derive-guid");
+        if derive_guid && device_guid.is_some() {
+            return usage("--device-guid and --derive-guid are mutually exclusive");
+        }
+        let cert_subject = args.parsed("--cert-subject", |s| CString::new(s).ok())?;
+
+        let options = PrepareDriverOptions::default()
+            .driver_type(driver_type)
+            .vendor_name(vendor_name)
+            .device_guid(device_guid)
+            .disable_cat(args.flag("--no-cat"))
+            .disable_signing(args.flag("--no-signing"))
+            .cert_subject(cert_subject)
+            .use_wcid_driver(args.flag("--wcid"))
+            .external_inf(args.flag("--external-inf"));
+
+        Ok(Self { dir, inf_name, options, derive_guid })
+    }
+
+    fn run(&mut self, device: &mut DeviceInfo) -> Result<(), Failure>
+    {
+        if self.derive_guid {
+            self.options = self.options.clone().device_guid(Some(Guid::for_device_info(device)));
+        }
+        wdi::prepare_driver(device, &self.dir, &self.inf_name, &mut self.options)?;
+        eprintln!("Prepared {} in {}", self.inf_name, self.dir);
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "user-driver")]
+fn default_inf_name(driver_type: DriverType) -> String
+{
+    match driver_type {
+        DriverType::User => String::from(wdi::USER_DRIVER_INF),
+        _ => String::from("usb_device.inf"),
+    }
+}
+
+#[cfg(not(feature = "user-driver"))]
+fn default_inf_name(_driver_type: DriverType) -> String
+{
+    String::from("usb_device.inf")
+}
+
+fn prepare(mut args: Args) -> Result<(), Failure>
+{
+    let selector = Selector::from_args(&mut args)?;
+    let mut prepare = Prepare::from_args(&mut args)?;
+    args.finish(0)?;
+
+    let mut device = selector.find()?;
+    prepare.run(&mut device)
+}
+
+fn install(mut args: Args) -> Result<(), Failure>
+{
+    let selector = Selector::from_args(&mut args)?;
+    let mut prepare = Prepare::from_args(&mut args)?;
+    let no_prepare = args.flag("--no-prepare");
+    let timeout = args.parsed("--timeout", |s| s.parse::<u32>().ok())?.unwrap_or(0);
+    let mut options = InstallDriverOptions::default()
+        .install_filter_driver(args.flag("--filter"))
+        .pending_install_timeout(timeout);
+    args.finish(0)?;
+
+    let mut device = selector.find()?;
+    if !no_prepare {
+        prepare.run(&mut device)?;
+    }
+    wdi::install_driver(&mut device, &prepare.dir, &prepare.inf_name, &mut options)?;
+    eprintln!("Installed {} for {}", prepare.inf_name, device.desc_str());
+
+    Ok(())
+}
+
+fn extract(mut args: Args) -> Result<(), Failure>
+{
+    let vid = args.required("--vid", parse_hex_u16)?;
+    let pid = args.required("--pid", parse_hex_u16)?;
+    let mi = args.parsed("--mi", parse_hex_u8)?;
+    let desc = args.value("--desc")?.unwrap_or_else(|| String::from("USB Device"));
+    let mut prepare = Prepare::from_args(&mut args)?;
+    args.finish(0)?;
+
+    let desc = match CString::new(desc) {
+        Ok(desc) => desc,
+        Err(_) => return usage("--desc can't contain NUL"),
+    };
+    let mut device = DeviceInfo {
+        vid,
+        pid,
+        is_composite: mi.is_some(),
+        mi: mi.unwrap_or(0),
+        desc: desc.into_bytes_with_nul(),
+        driver: None,
+        device_id: None,
+        hardware_id: None,
+        compatible_id: None,
+        upper_filter: None,
+        driver_version: 0,
+    };
+    prepare.run(&mut device)
+}
+
+fn cert(mut args: Args) -> Result<(), Failure>
+{
+    let mut options = InstallCertOptions::default().disable_warning(args.flag("--no-warning"));
+    let positional = args.finish(2)?;
+    let [action, name] = positional.as_slice() else {
+        unreachable!();
+    };
+    if action != "install" {
+        return usage(format!("unknown cert command {:?}", action));
+    }
+
+    wdi::install_trusted_certificate(name, &mut options)?;
+    eprintln!("Installed certificate {}", name);
+
+    Ok(())
+}
+
+fn run(mut args: Vec<String>) -> Result<(), Failure>
+{
+    if args.is_empty() {
+        return usage("no command given");
+    }
+    let command = args.remove(0);
+    let args = Args { args };
+
+    match command.as_str() {
+        "list" => list(args),
+        "info" => info(args),
+        "prepare" => prepare(args),
+        "install" => install(args),
+        "extract" => extract(args),
+        "cert" => cert(args),
+        "version" | "--version" | "-V" => {
+            args.finish(0)?;
+            print!("{}", wdi::build_info());
+            Ok(())
+        },
+        "help" | "--help" | "-h" => {
+            print!("{}", USAGE);
+            Ok(())
+        },
+        other => usage(format!("unknown command {:?}", other)),
+    }
+}
+
+fn main() -> ExitCode
+{
+    match run(std::env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            match &failure {
+                Failure::Usage(message) => eprintln!("wdi: {}\n\n{}", message, USAGE),
+                Failure::NoMatch(message) => eprintln!("wdi: {}", message),
+                Failure::Wdi(e) => eprintln!("wdi: {}", e),
+            }
+            ExitCode::from(failure.exit_code())
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn args(args: &[&str]) -> Args
+    {
+        Args { args: args.iter().map(|arg| arg.to_string()).collect() }
+    }
+
+    #[test]
+    fn flags()
+    {
+        let mut parsed = args(&["--all", "device", "--all"]);
+        assert!(parsed.flag("--all"));
+        assert!(!parsed.flag("--all"));
+        assert!(!parsed.flag("--json"));
+        assert_eq!(parsed.finish(1).unwrap(), ["device"]);
+    }
+
+    #[test]
+    fn values()
+    {
+        let mut parsed = args(&["--vid", "1d50", "--pid=6018", "--mi", "--desc"]);
+        assert_eq!(parsed.value("--pid").unwrap().as_deref(), Some("6018"));
+        assert_eq!(parsed.parsed("--vid", parse_hex_u16).unwrap(), Some(0x1d50));
+        assert_eq!(parsed.parsed("--vid", parse_hex_u16).unwrap(), None);
+
+        // The option after --mi is taken as its value, but --desc has none.
+        assert_eq!(parsed.value("--mi").unwrap().as_deref(), Some("--desc"));
+        let mut parsed = args(&["--desc"]);
+        assert!(matches!(parsed.value("--desc"), Err(Failure::Usage(_))));
+
+        let mut parsed = args(&["--vid", "zz", "--pid=0x6018"]);
+        assert!(matches!(parsed.parsed("--vid", parse_hex_u16), Err(Failure::Usage(_))));
+        assert_eq!(parsed.required("--pid", parse_hex_u16).unwrap(), 0x6018);
+        assert!(matches!(parsed.required("--pid", parse_hex_u16), Err(Failure::Usage(_))));
+    }
+
+    #[test]
+    fn finish()
+    {
+        assert_eq!(args(&["install", "name"]).finish(2).unwrap(), ["install", "name"]);
+        assert!(matches!(args(&["install"]).finish(2), Err(Failure::Usage(_))));
+        assert!(matches!(args(&["install", "name", "--force"]).finish(2), Err(Failure::Usage(_))));
+        assert!(matches!(args(&["--verbose"]).finish(0), Err(Failure::Usage(_))));
+    }
+
+    #[test]
+    fn hex()
+    {
+        assert_eq!(parse_hex_u16("0x1D50"), Some(0x1d50));
+        assert_eq!(parse_hex_u16("6018"), Some(0x6018));
+        assert_eq!(parse_hex_u16("10000"), None);
+        assert_eq!(parse_hex_u8("0X04"), Some(4));
+        assert_eq!(parse_hex_u8("g"), None);
+    }
+}
@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! What this build of libwdi embeds. See [build_info].
+
+use std::fmt;
+use std::fmt::Display;
+
+use libwdi_sys::build_info as sys;
+
+use crate::DriverType;
+
+
+/// A description of the libwdi build linked into this binary, from [build_info]. Its [Display]
+/// implementation gives a multi-line summary suitable for `--version` output or support bundles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo
+{
+    /// The libwdi version, from its `configure.ac`. None for a prebuilt library, or if the
+    /// submodule wasn't a full checkout.
+    pub libwdi_version: Option<&'static str>,
+
+    /// The commit of the libwdi submodule, if it was a git checkout.
+    pub libwdi_revision: Option<&'static str>,
+
+    /// Whether libwdi was linked from `LIBWDI_LIB_DIR` rather than built from source.
+    pub prebuilt: bool,
+
+    /// Whether this is a stub build (the `stub` feature, or docs.rs), where libwdi isn't built
+    /// at all and every call into it fails with [Error::NotSupported](crate::Error::NotSupported).
+    pub stub: bool,
+
+    /// The Rust target triple libwdi was built for.
+    pub target: &'static str,
+
+    /// The driver types whose files are embedded.
+    pub drivers: Vec<DriverType>,
+
+    /// The architectures an installer is embedded for, e.g. `x64` and `arm64`.
+    pub installers: &'static [&'static str],
+
+    /// The file version of the embedded WDK redistributables, e.g. `1.11.9200.16384` for the
+    /// WDK 8.0 WDF co-installer.
+    pub wdk_version: Option<&'static str>,
+
+    /// The version of the embedded WDF co-installer, e.g. 1011 for WDF 1.11, as reported by
+    /// libwdi at runtime.
+    pub wdf_version: Option<u32>,
+
+    /// The file version of the embedded `libusb0.sys`, if libusb0 is embedded.
+    pub libusb0_version: Option<&'static str>,
+
+    /// The file version of the embedded `libusbK.sys`, if libusbK is embedded.
+    pub libusbk_version: Option<&'static str>,
+
+    /// The `DriverVer` version of the user driver's INF, if a user driver is embedded.
+    pub user_driver_version: Option<&'static str>,
+}
+
+/// Describes the libwdi build linked into this binary: which drivers and installers it embeds,
+/// and which versions of libwdi, the WDK and the drivers it was built from.
+pub fn build_info() -> BuildInfo
+{
+    let drivers = sys::DRIVERS
+        .iter()
+        .filter_map(|name| name.parse().ok())
+        .collect();
+
+    let wdf_version = unsafe { libwdi_sys::wdi_get_wdf_version() };
+
+    BuildInfo {
+        libwdi_version: sys::LIBWDI_VERSION,
+        libwdi_revision: sys::LIBWDI_REVISION,
+        prebuilt: sys::PREBUILT,
+        stub: sys::STUB,
+        target: sys::TARGET,
+        drivers,
+        installers: sys::INSTALLERS,
+        wdk_version: sys::WDK_VERSION,
+        wdf_version: u32::try_from(wdf_version).ok().filter(|v| *v != 0),
+        libusb0_version: sys::LIBUSB0_VERSION,
+        libusbk_version: sys::LIBUSBK_VERSION,
+        user_driver_version: sys::USER_DRIVER_VERSION,
+    }
+}
+
+impl Display for BuildInfo
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let unknown = "unknown";
+
+        write!(f, "libwdi {}", self.libwdi_version.unwrap_or(unknown))?;
+        if let Some(revision) = self.libwdi_revision {
+            write!(f, " ({})", revision)?;
+        }
+        if self.prebuilt {
+            write!(f, " (prebuilt)")?;
+        }
+        if self.stub {
+            write!(f, " (stub)")?;
+        }
+        writeln!(f, " for {}", self.target)?;
+
+        let drivers: Vec<String> = self.drivers.iter().map(ToString::to_string).collect();
+        writeln!(f, "drivers: {}", drivers.join(", "))?;
+        writeln!(f, "installers: {}", self.installers.join(", "))?;
+        match self.wdf_version {
+            Some(version) => writeln!(f, "WDF co-installer: {}", version)?,
+            None => writeln!(f, "WDF co-installer: {}", unknown)?,
+        }
+
+        let versions = [
+            ("WDK", self.wdk_version),
+            ("libusb0", self.libusb0_version),
+            ("libusbK", self.libusbk_version),
+            ("user driver", self.user_driver_version),
+        ];
+        for (name, version) in versions {
+            if let Some(version) = version {
+                writeln!(f, "{}: {}", name, version)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,901 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Parsing of Windows security catalogs (`.cat` files) and verification of prepared driver
+//! packages against them.
+//!
+//! A catalog is a PKCS#7 `SignedData` structure wrapping a Certificate Trust List, with one
+//! entry per file covered by the catalog. Each entry carries the hash of the file it covers
+//! (for PE files, the Authenticode hash) and usually the name of that file. None of this needs
+//! Windows, so [verify_package] works on any host.
+
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::der::{self, DerError, Element, Reader};
+
+const OID_SIGNED_DATA: &str = "1.2.840.113549.1.7.2";
+const OID_CTL: &str = "1.3.6.1.4.1.311.10.1";
+const OID_CAT_NAME_VALUE: &str = "1.3.6.1.4.1.311.12.2.1";
+const OID_SPC_INDIRECT_DATA: &str = "1.3.6.1.4.1.311.2.1.4";
+const OID_SHA1: &str = "1.3.14.3.2.26";
+const OID_SHA256: &str = "2.16.840.1.101.3.4.2.1";
+
+
+/// The error type for catalog parsing and package verification.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CatalogError
+{
+    /// Reading the catalog or one of the package files failed.
+    Io(io::Error),
+
+    /// The package directory does not contain a catalog.
+    NoCatalog(PathBuf),
+
+    /// The package directory contains more than one catalog, and it is unclear which to use.
+    AmbiguousCatalog(Vec<PathBuf>),
+
+    /// The catalog is not a well-formed PKCS#7 Certificate Trust List.
+    Malformed(&'static str),
+}
+
+impl Display for CatalogError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        use CatalogError::*;
+
+        match self {
+            Io(e) => write!(f, "I/O error: {}", e),
+            NoCatalog(dir) => write!(f, "No catalog found in {}", dir.display()),
+            AmbiguousCatalog(cats) => write!(f, "Multiple catalogs found: {:?}", cats),
+            Malformed(reason) => write!(f, "Malformed catalog: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CatalogError
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self {
+            CatalogError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CatalogError
+{
+    fn from(other: io::Error) -> Self
+    {
+        Self::Io(other)
+    }
+}
+
+impl From<DerError> for CatalogError
+{
+    fn from(other: DerError) -> Self
+    {
+        Self::Malformed(other.0)
+    }
+}
+
+
+/// Hash algorithms that can appear in a catalog member.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm
+{
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm
+{
+    fn from_oid(oid: &str) -> Option<Self>
+    {
+        match oid {
+            OID_SHA1 => Some(Self::Sha1),
+            OID_SHA256 => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    fn from_digest_len(len: usize) -> Option<Self>
+    {
+        match len {
+            20 => Some(Self::Sha1),
+            32 => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    fn hash_ranges(self, data: &[u8], ranges: &[(usize, usize)]) -> Vec<u8>
+    {
+        match self {
+            Self::Sha1 => {
+                let mut hasher = Sha1::new();
+                for &(start, end) in ranges {
+                    hasher.update(&data[start..end]);
+                }
+                hasher.finalize().to_vec()
+            },
+            Self::Sha256 => {
+                let mut hasher = Sha256::new();
+                for &(start, end) in ranges {
+                    hasher.update(&data[start..end]);
+                }
+                hasher.finalize().to_vec()
+            },
+        }
+    }
+
+    /// Computes the hash Windows would record in a catalog for a file with contents `data`:
+    /// the Authenticode image hash for PE files, and a flat hash for everything else.
+    pub fn file_digest(self, data: &[u8]) -> Vec<u8>
+    {
+        match pe_hash_ranges(data) {
+            Some(ranges) => self.hash_ranges(data, &ranges),
+            None => self.hash_ranges(data, &[(0, data.len())]),
+        }
+    }
+}
+
+/// Returns the byte ranges of a PE image that are covered by its Authenticode hash: everything
+/// except the checksum, the certificate table directory entry and the certificate table itself.
+/// Returns None if `data` does not look like a PE image.
+fn pe_hash_ranges(data: &[u8]) -> Option<Vec<(usize, usize)>>
+{
+    let read_u16 = |offset: usize| -> Option<u16> {
+        data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    if data.get(0..2)? != b"MZ" {
+        return None;
+    }
+    let pe_offset = read_u32(0x3c)? as usize;
+    if data.get(pe_offset..pe_offset + 4)? != b"PE\0\0" {
+        return None;
+    }
+
+    let optional_header = pe_offset + 24;
+    let (rva_count_offset, data_dirs) = match read_u16(optional_header)? {
+        0x10b => (optional_header + 92, optional_header + 96),
+        0x20b => (optional_header + 108, optional_header + 112),
+        _ => return None,
+    };
+    let checksum = optional_header + 64;
+
+    if read_u32(rva_count_offset)? <= 4 {
+        // No certificate table directory entry at all.
+        return Some(vec![(0, checksum), (checksum + 4, data.len())]);
+    }
+
+    let cert_dir = data_dirs + 4 * 8;
+    let cert_offset = read_u32(cert_dir)? as usize;
+    let cert_size = read_u32(cert_dir + 4)? as usize;
+
+    let mut ranges = vec![(0, checksum), (checksum + 4, cert_dir)];
+    match cert_offset.checked_add(cert_size) {
+        Some(cert_end) if cert_offset != 0 && cert_size != 0 && cert_offset >= cert_dir + 8 && cert_end <= data.len() => {
+            ranges.push((cert_dir + 8, cert_offset));
+            ranges.push((cert_end, data.len()));
+        },
+        _ => ranges.push((cert_dir + 8, data.len())),
+    }
+
+    Some(ranges)
+}
+
+
+/// A point in time as encoded in an X.509 certificate, always in UTC.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CertificateTime
+{
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl CertificateTime
+{
+    fn parse(element: &Element) -> Result<Self, CatalogError>
+    {
+        // Slicing by byte below is only safe on ASCII.
+        let text = std::str::from_utf8(element.contents)
+            .ok()
+            .filter(|text| text.is_ascii())
+            .ok_or(CatalogError::Malformed("certificate time is not ASCII"))?;
+        let digits = text.trim_end_matches('Z');
+
+        let (year, rest) = match element.tag {
+            der::TAG_UTC_TIME if digits.len() >= 10 => {
+                let yy: u16 = digits[0..2].parse().map_err(|_| CatalogError::Malformed("invalid UTCTime"))?;
+                (if yy < 50 { 2000 + yy } else { 1900 + yy }, &digits[2..])
+            },
+            der::TAG_GENERALIZED_TIME if digits.len() >= 12 => {
+                let yyyy = digits[0..4].parse().map_err(|_| CatalogError::Malformed("invalid GeneralizedTime"))?;
+                (yyyy, &digits[4..])
+            },
+            _ => return Err(CatalogError::Malformed("invalid certificate time")),
+        };
+
+        let field = |range: std::ops::Range<usize>| -> Result<u8, CatalogError> {
+            rest.get(range)
+                .unwrap_or("0")
+                .parse()
+                .map_err(|_| CatalogError::Malformed("invalid certificate time"))
+        };
+
+        Ok(Self {
+            year,
+            month: field(0..2)?,
+            day: field(2..4)?,
+            hour: field(4..6)?,
+            minute: field(6..8)?,
+            second: if rest.len() >= 10 { field(8..10)? } else { 0 },
+        })
+    }
+
+    /// Converts this time to a [SystemTime].
+    pub fn to_system_time(&self) -> SystemTime
+    {
+        // Days since the Unix epoch, from Howard Hinnant's `days_from_civil` algorithm.
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        let seconds = days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        if seconds >= 0 {
+            UNIX_EPOCH + Duration::from_secs(seconds as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+        }
+    }
+}
+
+impl Display for CertificateTime
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )
+    }
+}
+
+
+/// The certificate that signed a catalog.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignerCertificate
+{
+    /// Subject distinguished name, e.g. `CN=USB\VID_1D50&PID_6018 (libwdi autogenerated)`.
+    pub subject: String,
+
+    /// Issuer distinguished name. Equal to `subject` for libwdi's self-signed certificates.
+    pub issuer: String,
+
+    /// Serial number, as uppercase hex.
+    pub serial: String,
+
+    pub not_before: CertificateTime,
+    pub not_after: CertificateTime,
+}
+
+impl SignerCertificate
+{
+    /// Whether this certificate has expired at `now`.
+    pub fn is_expired_at(&self, now: SystemTime) -> bool
+    {
+        now > self.not_after.to_system_time()
+    }
+
+    /// Whether this certificate has expired.
+    pub fn is_expired(&self) -> bool
+    {
+        self.is_expired_at(SystemTime::now())
+    }
+
+    fn parse(cert: &Element) -> Result<(Self, Vec<u8>, Vec<u8>), CatalogError>
+    {
+        let mut tbs = cert.children().expect(der::TAG_SEQUENCE)?.children();
+        tbs.optional(der::context(0))?;
+        let serial = tbs.expect(der::TAG_INTEGER)?;
+        tbs.expect(der::TAG_SEQUENCE)?; // Signature algorithm.
+        let issuer = tbs.expect(der::TAG_SEQUENCE)?;
+        let mut validity = tbs.expect(der::TAG_SEQUENCE)?.children();
+        let not_before = CertificateTime::parse(&validity.next_element()?)?;
+        let not_after = CertificateTime::parse(&validity.next_element()?)?;
+        let subject = tbs.expect(der::TAG_SEQUENCE)?;
+
+        let info = Self {
+            subject: format_name(&subject)?,
+            issuer: format_name(&issuer)?,
+            serial: hex(serial.contents),
+            not_before,
+            not_after,
+        };
+
+        Ok((info, issuer.raw.to_vec(), serial.contents.to_vec()))
+    }
+}
+
+/// Formats an X.509 Name as a comma separated list of `KEY=value` pairs.
+fn format_name(name: &Element) -> Result<String, CatalogError>
+{
+    let mut parts: Vec<String> = Vec::new();
+    for rdn in name.children() {
+        for attr in rdn?.children() {
+            let mut attr = attr?.children();
+            let oid = attr.expect(der::TAG_OID)?.oid()?;
+            let value = attr.next_element()?.string().unwrap_or_default();
+            let key = match oid.as_str() {
+                "2.5.4.3" => "CN",
+                "2.5.4.6" => "C",
+                "2.5.4.7" => "L",
+                "2.5.4.8" => "ST",
+                "2.5.4.10" => "O",
+                "2.5.4.11" => "OU",
+                "1.2.840.113549.1.9.1" => "E",
+                other => other,
+            };
+            parts.push(format!("{}={}", key, value));
+        }
+    }
+
+    Ok(parts.join(", "))
+}
+
+/// Formats bytes as uppercase hex, the way catalog member tags are written.
+pub(crate) fn hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>>
+{
+    if text.len() & 1 != 0 || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+
+/// One file covered by a catalog.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogMember
+{
+    /// The member's tag. For catalogs generated by libwdi (and MakeCat) this is the hex
+    /// encoded hash of the file.
+    pub tag: String,
+
+    /// The file name this member was created for, if the catalog records it.
+    pub file: Option<String>,
+
+    /// The hash algorithm of `digest`.
+    pub algorithm: HashAlgorithm,
+
+    /// The file hash recorded in the catalog.
+    pub digest: Vec<u8>,
+}
+
+/// A parsed security catalog.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catalog
+{
+    pub members: Vec<CatalogMember>,
+
+    /// The certificate that signed this catalog, or None if the catalog is unsigned
+    /// (e.g. prepared with [PrepareDriverOptions::disable_signing](crate::PrepareDriverOptions::disable_signing)).
+    pub signer: Option<SignerCertificate>,
+}
+
+impl Catalog
+{
+    /// Reads and parses the catalog at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CatalogError>
+    {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Parses a DER encoded catalog.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CatalogError>
+    {
+        let mut content_info = Reader::new(data).expect(der::TAG_SEQUENCE)?.children();
+        if content_info.expect(der::TAG_OID)?.oid()? != OID_SIGNED_DATA {
+            return Err(CatalogError::Malformed("not a PKCS#7 SignedData structure"));
+        }
+        let mut signed_data = content_info
+            .expect(der::context(0))?
+            .children()
+            .expect(der::TAG_SEQUENCE)?
+            .children();
+
+        signed_data.expect(der::TAG_INTEGER)?; // Version.
+        signed_data.expect(der::TAG_SET)?; // Digest algorithms.
+
+        let mut encap = signed_data.expect(der::TAG_SEQUENCE)?.children();
+        if encap.expect(der::TAG_OID)?.oid()? != OID_CTL {
+            return Err(CatalogError::Malformed("SignedData does not contain a Certificate Trust List"));
+        }
+        let mut ctl_wrapper = encap.expect(der::context(0))?.children();
+        let mut ctl_element = ctl_wrapper.next_element()?;
+        // The CTL is normally embedded directly, but may also be wrapped in an OCTET STRING.
+        if ctl_element.tag == der::TAG_OCTET_STRING {
+            ctl_element = Reader::new(ctl_element.contents).expect(der::TAG_SEQUENCE)?;
+        }
+        let members = Self::parse_ctl(&ctl_element.expect(der::TAG_SEQUENCE)?)?;
+
+        let certificates: Vec<Element> = match signed_data.optional(der::context(0))? {
+            Some(certs) => certs.children().collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        signed_data.optional(der::context(1))?; // CRLs.
+
+        let signer = match signed_data.optional(der::TAG_SET)? {
+            Some(signer_infos) => Self::find_signer(&signer_infos, &certificates)?,
+            None => None,
+        };
+
+        Ok(Self { members, signer })
+    }
+
+    fn parse_ctl(ctl: &Element) -> Result<Vec<CatalogMember>, CatalogError>
+    {
+        let mut ctl = ctl.children();
+        ctl.expect(der::TAG_SEQUENCE)?; // Subject usage.
+
+        // Skip the optional list identifier, sequence number and update times, up to the
+        // subject algorithm.
+        loop {
+            match ctl.next_element()? {
+                element if element.tag == der::TAG_SEQUENCE => break,
+                _ => continue,
+            }
+        }
+
+        let subjects = match ctl.optional(der::TAG_SEQUENCE)? {
+            Some(subjects) => subjects,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut members = Vec::new();
+        for subject in subjects.children() {
+            let mut subject = subject?.children();
+            let identifier = subject.expect(der::TAG_OCTET_STRING)?;
+
+            // The identifier is normally the UTF-16 encoded hex hash of the file, but fall back
+            // to treating it as a raw hash.
+            let decoded = der::utf16_le(identifier.contents);
+            let tag = if unhex(&decoded).is_some() { decoded } else { hex(identifier.contents) };
+
+            let mut file = None;
+            let mut digest = None;
+            if let Some(attributes) = subject.optional(der::TAG_SET)? {
+                for attribute in attributes.children() {
+                    let mut attribute = attribute?.children();
+                    let oid = attribute.expect(der::TAG_OID)?.oid()?;
+                    let value = match attribute.expect(der::TAG_SET)?.children().next() {
+                        Some(value) => value?,
+                        None => continue,
+                    };
+                    match oid.as_str() {
+                        OID_CAT_NAME_VALUE => {
+                            let mut name_value = value.children();
+                            let name = name_value.expect(der::TAG_BMP_STRING)?.string()?;
+                            name_value.expect(der::TAG_INTEGER)?; // Flags.
+                            let value = name_value.expect(der::TAG_OCTET_STRING)?;
+                            if name.eq_ignore_ascii_case("File") {
+                                file = Some(der::utf16_le(value.contents));
+                            }
+                        },
+                        OID_SPC_INDIRECT_DATA => {
+                            let mut indirect = value.children();
+                            indirect.expect(der::TAG_SEQUENCE)?; // Data type and value.
+                            let mut digest_info = indirect.expect(der::TAG_SEQUENCE)?.children();
+                            let mut algorithm = digest_info.expect(der::TAG_SEQUENCE)?.children();
+                            let algorithm = HashAlgorithm::from_oid(&algorithm.expect(der::TAG_OID)?.oid()?)
+                                .ok_or(CatalogError::Malformed("unsupported member hash algorithm"))?;
+                            let value = digest_info.expect(der::TAG_OCTET_STRING)?;
+                            digest = Some((algorithm, value.contents.to_vec()));
+                        },
+                        _ => (),
+                    }
+                }
+            }
+
+            let (algorithm, digest) = match digest {
+                Some(digest) => digest,
+                None => {
+                    let raw = unhex(&tag).ok_or(CatalogError::Malformed("member has no hash"))?;
+                    let algorithm = HashAlgorithm::from_digest_len(raw.len())
+                        .ok_or(CatalogError::Malformed("member has no hash"))?;
+                    (algorithm, raw)
+                },
+            };
+
+            members.push(CatalogMember { tag, file, algorithm, digest });
+        }
+
+        Ok(members)
+    }
+
+    fn find_signer(signer_infos: &Element, certificates: &[Element]) -> Result<Option<SignerCertificate>, CatalogError>
+    {
+        let signer_info = match signer_infos.children().next() {
+            Some(info) => info?,
+            None => return Ok(None),
+        };
+
+        let mut parsed: Vec<(SignerCertificate, Vec<u8>, Vec<u8>)> = certificates
+            .iter()
+            .map(SignerCertificate::parse)
+            .collect::<Result<_, _>>()?;
+
+        let mut signer_info = signer_info.children();
+        signer_info.expect(der::TAG_INTEGER)?; // Version.
+        let sid = signer_info.next_element()?;
+
+        // Match on issuer and serial number where the signer identifies itself that way,
+        // otherwise assume the first certificate is the signer's.
+        if sid.tag == der::TAG_SEQUENCE {
+            let mut sid = sid.children();
+            let issuer = sid.expect(der::TAG_SEQUENCE)?;
+            let serial = sid.expect(der::TAG_INTEGER)?;
+            if let Some(index) = parsed.iter().position(|(_, i, s)| i == issuer.raw && s == serial.contents) {
+                return Ok(Some(parsed.swap_remove(index).0));
+            }
+        }
+
+        Ok(parsed.into_iter().next().map(|(cert, _, _)| cert))
+    }
+}
+
+
+/// A file whose hash does not match the one its catalog records for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashMismatch
+{
+    /// The file, relative to the package directory.
+    pub file: PathBuf,
+
+    /// The hash recorded in the catalog, as uppercase hex.
+    pub expected: String,
+
+    /// The hash of the file on disk, as uppercase hex.
+    pub actual: String,
+}
+
+/// The result of [verify_package].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageVerification
+{
+    /// The catalog the package was verified against.
+    pub catalog: PathBuf,
+
+    /// Files covered by the catalog whose hashes match, relative to the package directory.
+    pub verified: Vec<PathBuf>,
+
+    /// Catalog members with no corresponding file in the package, by file name where the
+    /// catalog records it, or by tag otherwise.
+    pub missing: Vec<String>,
+
+    /// Files in the package that the catalog does not cover.
+    pub extra: Vec<PathBuf>,
+
+    /// Files whose contents no longer match the catalog.
+    pub mismatched: Vec<HashMismatch>,
+
+    /// The certificate that signed the catalog, or None if it is unsigned.
+    pub signer: Option<SignerCertificate>,
+}
+
+impl PackageVerification
+{
+    /// Whether the catalog still covers exactly the files in the package.
+    pub fn is_intact(&self) -> bool
+    {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Recursively lists the files under `dir`, relative to `base`, skipping catalogs.
+fn package_files(base: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()>
+{
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            package_files(base, &path, files)?;
+        } else if !is_catalog(&path) {
+            files.push(path.strip_prefix(base).unwrap_or(&path).to_path_buf());
+        }
+    }
+
+    Ok(())
+}
+
+fn is_catalog(path: &Path) -> bool
+{
+    path.extension().map(|ext| ext.eq_ignore_ascii_case("cat")).unwrap_or(false)
+}
+
+/// Finds the catalog of the package in `dir`, which must be the only `.cat` file at its top level.
+fn find_catalog(dir: &Path) -> Result<PathBuf, CatalogError>
+{
+    let mut catalogs: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| path.is_file() && is_catalog(path))
+        .collect();
+
+    match catalogs.len() {
+        0 => Err(CatalogError::NoCatalog(dir.to_path_buf())),
+        1 => Ok(catalogs.remove(0)),
+        _ => {
+            catalogs.sort();
+            Err(CatalogError::AmbiguousCatalog(catalogs))
+        },
+    }
+}
+
+/// Verifies a driver package, such as the output directory of [prepare_driver](crate::prepare_driver),
+/// against its catalog.
+///
+/// `path` may either be the package directory, in which case it must contain exactly one `.cat`
+/// file, or the catalog itself, in which case the package is the directory containing it.
+///
+/// Every file in the package (including those in subdirectories) is hashed the way Windows
+/// would hash it for the catalog, and the result reports which catalog members are missing,
+/// which files are not covered by the catalog, and which files no longer match their recorded
+/// hash. Unlike installing the package, this works on any host OS.
+pub fn verify_package<P: AsRef<Path>>(path: P) -> Result<PackageVerification, CatalogError>
+{
+    let path = path.as_ref();
+    let (dir, catalog_path) = if path.is_dir() {
+        (path.to_path_buf(), find_catalog(path)?)
+    } else {
+        // A bare file name has an empty parent, which read_dir() doesn't accept.
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        (dir, path.to_path_buf())
+    };
+
+    let catalog = Catalog::open(&catalog_path)?;
+
+    let mut files = Vec::new();
+    package_files(&dir, &dir, &mut files)?;
+    files.sort();
+    let contents: Vec<Vec<u8>> = files
+        .iter()
+        .map(|file| fs::read(dir.join(file)))
+        .collect::<io::Result<_>>()?;
+
+    // Hash every file once per algorithm actually used by the catalog.
+    let mut digests: Vec<Vec<(HashAlgorithm, Vec<u8>)>> = vec![Vec::new(); files.len()];
+    for algorithm in [HashAlgorithm::Sha1, HashAlgorithm::Sha256] {
+        if catalog.members.iter().any(|m| m.algorithm == algorithm) {
+            for (index, data) in contents.iter().enumerate() {
+                digests[index].push((algorithm, algorithm.file_digest(data)));
+            }
+        }
+    }
+    let digest_of = |index: usize, algorithm: HashAlgorithm| -> &[u8] {
+        digests[index]
+            .iter()
+            .find(|(alg, _)| *alg == algorithm)
+            .map(|(_, digest)| digest.as_slice())
+            .unwrap_or(&[])
+    };
+    let name_matches = |index: usize, name: &str| -> bool {
+        files[index]
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().eq_ignore_ascii_case(name))
+            .unwrap_or(false)
+    };
+
+    let mut covered = vec![false; files.len()];
+    let mut unmatched_members = Vec::new();
+    for member in &catalog.members {
+        let found = (0..files.len()).find(|&index| {
+            digest_of(index, member.algorithm) == member.digest.as_slice()
+                && member.file.as_deref().map(|name| name_matches(index, name)).unwrap_or(true)
+        });
+        match found {
+            Some(index) => covered[index] = true,
+            None => unmatched_members.push(member),
+        }
+    }
+
+    // A member whose file is present but not covered is a mismatch; otherwise it is missing.
+    let mut missing = Vec::new();
+    let mut mismatched = Vec::new();
+    for member in unmatched_members {
+        let candidate = member.file.as_deref().and_then(|name| {
+            (0..files.len()).find(|&index| !covered[index] && name_matches(index, name))
+        });
+        match candidate {
+            Some(index) => {
+                covered[index] = true;
+                mismatched.push(HashMismatch {
+                    file: files[index].clone(),
+                    expected: hex(&member.digest),
+                    actual: hex(digest_of(index, member.algorithm)),
+                });
+            },
+            None => missing.push(member.file.clone().unwrap_or_else(|| member.tag.clone())),
+        }
+    }
+
+    let mut verified = Vec::new();
+    let mut extra = Vec::new();
+    for (index, file) in files.into_iter().enumerate() {
+        if mismatched.iter().any(|m| m.file == file) {
+            continue;
+        }
+        if covered[index] {
+            verified.push(file);
+        } else {
+            extra.push(file);
+        }
+    }
+
+    Ok(PackageVerification {
+        catalog: catalog_path,
+        verified,
+        missing,
+        extra,
+        mismatched,
+        signer: catalog.signer,
+    })
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::{copy_dir, fixture, scratch_dir};
+
+    const INF_SHA1: &str = "5688638BFF34DF0118A55FCE64C0079E24A5F6A9";
+    const SYS_SHA256: &str = "2CC9C57CD4026A0448537562E42F760502548AA129AFD4F44C54DA0F778CB6F9";
+
+    #[test]
+    fn parse_fixture_catalog()
+    {
+        let catalog = Catalog::open(fixture("package/usb_device.cat")).unwrap();
+
+        assert_eq!(catalog.members.len(), 2);
+        assert_eq!(catalog.members[0].tag, INF_SHA1);
+        assert_eq!(catalog.members[0].file.as_deref(), Some("usb_device.inf"));
+        assert_eq!(catalog.members[0].algorithm, HashAlgorithm::Sha1);
+        assert_eq!(hex(&catalog.members[0].digest), INF_SHA1);
+        assert_eq!(catalog.members[1].file.as_deref(), Some("driver.sys"));
+        assert_eq!(catalog.members[1].algorithm, HashAlgorithm::Sha256);
+        assert_eq!(hex(&catalog.members[1].digest), SYS_SHA256);
+
+        let signer = catalog.signer.unwrap();
+        assert_eq!(signer.subject, r"CN=USB\VID_1D50&PID_6018 (libwdi autogenerated)");
+        assert_eq!(signer.issuer, signer.subject);
+        assert_eq!(signer.serial, "1D506018");
+        assert_eq!(signer.not_before.to_string(), "2023-01-01 00:00:00 UTC");
+        assert_eq!(signer.not_after.to_string(), "2033-01-01 12:30:15 UTC");
+
+        // 2033-01-01 12:30:15 UTC is 1988195415 seconds after the epoch.
+        let expiry = UNIX_EPOCH + Duration::from_secs(1_988_195_415);
+        assert_eq!(signer.not_after.to_system_time(), expiry);
+        assert!(!signer.is_expired_at(expiry));
+        assert!(signer.is_expired_at(expiry + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn verify_intact_package()
+    {
+        let verification = verify_package(fixture("package")).unwrap();
+        assert!(verification.is_intact());
+        assert_eq!(verification.catalog, fixture("package/usb_device.cat"));
+        assert_eq!(verification.verified, [Path::new("amd64/driver.sys"), Path::new("usb_device.inf")]);
+        assert!(verification.signer.is_some());
+
+        // The checksum and certificate table of a PE image aren't covered by its hash.
+        let dir = scratch_dir("catalog");
+        copy_dir(&fixture("package"), &dir);
+        let sys = dir.join("amd64/driver.sys");
+        let mut image = fs::read(&sys).unwrap();
+        image[0x58 + 64] ^= 0xff;
+        let cert_offset = image.len() - 16;
+        image[cert_offset + 8] ^= 0xff;
+        fs::write(&sys, image).unwrap();
+
+        let verification = verify_package(dir.join("usb_device.cat")).unwrap();
+        assert!(verification.is_intact(), "{:?}", verification);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verify_changed_package()
+    {
+        let dir = scratch_dir("catalog");
+        copy_dir(&fixture("package"), &dir);
+        let mut inf = fs::read(dir.join("usb_device.inf")).unwrap();
+        inf.extend_from_slice(b"; edited\r\n");
+        fs::write(dir.join("usb_device.inf"), inf).unwrap();
+        fs::remove_file(dir.join("amd64/driver.sys")).unwrap();
+        fs::write(dir.join("amd64/extra.dll"), b"MZ").unwrap();
+
+        let verification = verify_package(&dir).unwrap();
+        assert!(!verification.is_intact());
+        assert!(verification.verified.is_empty());
+        assert_eq!(verification.missing, ["driver.sys"]);
+        assert_eq!(verification.extra, [Path::new("amd64/extra.dll")]);
+        assert_eq!(verification.mismatched.len(), 1);
+        assert_eq!(verification.mismatched[0].file, Path::new("usb_device.inf"));
+        assert_eq!(verification.mismatched[0].expected, INF_SHA1);
+        assert_ne!(verification.mismatched[0].actual, INF_SHA1);
+
+        fs::write(dir.join("other.cat"), b"").unwrap();
+        assert!(matches!(verify_package(&dir), Err(CatalogError::AmbiguousCatalog(cats)) if cats.len() == 2));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reject_malformed_catalogs()
+    {
+        let catalog = fs::read(fixture("package/usb_device.cat")).unwrap();
+        assert!(matches!(Catalog::from_bytes(&catalog[..catalog.len() / 2]), Err(CatalogError::Malformed(_))));
+        assert!(matches!(Catalog::from_bytes(b"\x30\x03\x02\x01\x01"), Err(CatalogError::Malformed(_))));
+    }
+
+    #[test]
+    fn non_ascii_certificate_times()
+    {
+        let time = |tag: u8, contents: &'static [u8]| {
+            CertificateTime::parse(&Element { tag, contents, raw: contents })
+        };
+
+        let utc = time(der::TAG_UTC_TIME, b"230101120000Z").unwrap();
+        assert_eq!((utc.year, utc.month, utc.second), (2023, 1, 0));
+        assert!(matches!(time(der::TAG_UTC_TIME, "2\u{e9}0101120000Z".as_bytes()), Err(CatalogError::Malformed(_))));
+        assert!(matches!(
+            time(der::TAG_GENERALIZED_TIME, "202\u{e9}0101120000Z".as_bytes()),
+            Err(CatalogError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn out_of_range_certificate_table()
+    {
+        let mut image = fs::read(fixture("package/amd64/driver.sys")).unwrap();
+        let cert_dir = 0x58 + 112 + 32;
+        image[cert_dir..cert_dir + 8].copy_from_slice(&[0xf0, 0xff, 0xff, 0xff, 0x20, 0, 0, 0]);
+
+        let ranges = pe_hash_ranges(&image).unwrap();
+        assert_eq!(ranges.last(), Some(&(cert_dir + 8, image.len())));
+    }
+}
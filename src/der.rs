@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! A minimal DER reader, just capable enough to walk the PKCS#7 structures Windows uses for
+//! security catalogs and the X.509 certificates embedded in them.
+
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_UTF8_STRING: u8 = 0x0c;
+pub const TAG_PRINTABLE_STRING: u8 = 0x13;
+pub const TAG_IA5_STRING: u8 = 0x16;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_BMP_STRING: u8 = 0x1e;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+/// Tag of a constructed, context-specific element, e.g. `[0]`.
+pub const fn context(n: u8) -> u8
+{
+    0xa0 | n
+}
+
+/// A single DER element: its tag and the bytes of its contents.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Element<'a>
+{
+    pub tag: u8,
+    pub contents: &'a [u8],
+    /// The complete encoding of this element, including its header.
+    pub raw: &'a [u8],
+}
+
+impl<'a> Element<'a>
+{
+    /// Returns a reader over the children of this (constructed) element.
+    pub fn children(&self) -> Reader<'a>
+    {
+        Reader::new(self.contents)
+    }
+
+    /// Returns this element if it has the tag `tag`, or an error otherwise.
+    pub fn expect(self, tag: u8) -> Result<Self, DerError>
+    {
+        if self.tag == tag {
+            Ok(self)
+        } else {
+            Err(DerError("unexpected tag"))
+        }
+    }
+
+    /// Formats the contents of an OBJECT IDENTIFIER in the usual dotted notation.
+    pub fn oid(&self) -> Result<String, DerError>
+    {
+        if self.tag != TAG_OID || self.contents.is_empty() {
+            return Err(DerError("expected an OBJECT IDENTIFIER"));
+        }
+
+        let first = self.contents[0];
+        let mut oid = format!("{}.{}", first / 40, first % 40);
+        let mut arc: u64 = 0;
+        for byte in &self.contents[1..] {
+            arc = (arc << 7) | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                oid.push_str(&format!(".{}", arc));
+                arc = 0;
+            }
+        }
+
+        Ok(oid)
+    }
+
+    /// Decodes any of the string types found in certificates and catalogs.
+    pub fn string(&self) -> Result<String, DerError>
+    {
+        match self.tag {
+            TAG_UTF8_STRING | TAG_PRINTABLE_STRING | TAG_IA5_STRING => {
+                Ok(String::from_utf8_lossy(self.contents).into_owned())
+            },
+            TAG_BMP_STRING => Ok(utf16_be(self.contents)),
+            _ => Err(DerError("expected a string")),
+        }
+    }
+}
+
+/// Decodes big endian UTF-16, as used by BMPString.
+pub fn utf16_be(bytes: &[u8]) -> String
+{
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Decodes little endian UTF-16, as used for most strings inside Windows catalogs.
+pub fn utf16_le(bytes: &[u8]) -> String
+{
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// The error returned for truncated or otherwise malformed DER.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DerError(pub &'static str);
+
+/// Sequentially reads DER elements from a byte slice.
+#[derive(Debug, Copy, Clone)]
+pub struct Reader<'a>
+{
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a>
+{
+    pub fn new(data: &'a [u8]) -> Self
+    {
+        Self { data }
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.data.is_empty()
+    }
+
+    /// Looks at the tag of the next element without consuming it.
+    pub fn peek_tag(&self) -> Option<u8>
+    {
+        self.data.first().copied()
+    }
+
+    /// Reads the next element.
+    pub fn next_element(&mut self) -> Result<Element<'a>, DerError>
+    {
+        let data = self.data;
+        if data.len() < 2 {
+            return Err(DerError("truncated element header"));
+        }
+
+        let tag = data[0];
+        if tag & 0x1f == 0x1f {
+            return Err(DerError("multi-byte tags are not supported"));
+        }
+
+        let (len, header_len) = match data[1] {
+            short @ 0..=0x7f => (short as usize, 2),
+            0x80 => return Err(DerError("indefinite lengths are not allowed in DER")),
+            long => {
+                let count = (long & 0x7f) as usize;
+                if count > std::mem::size_of::<usize>() || data.len() < 2 + count {
+                    return Err(DerError("invalid element length"));
+                }
+                let len = data[2..2 + count]
+                    .iter()
+                    .fold(0usize, |acc, byte| (acc << 8) | *byte as usize);
+                (len, 2 + count)
+            },
+        };
+
+        let end = header_len.checked_add(len).ok_or(DerError("invalid element length"))?;
+        if data.len() < end {
+            return Err(DerError("truncated element"));
+        }
+
+        self.data = &data[end..];
+
+        Ok(Element {
+            tag,
+            contents: &data[header_len..end],
+            raw: &data[..end],
+        })
+    }
+
+    /// Reads the next element, requiring it to have the tag `tag`.
+    pub fn expect(&mut self, tag: u8) -> Result<Element<'a>, DerError>
+    {
+        self.next_element()?.expect(tag)
+    }
+
+    /// Reads the next element only if it has the tag `tag`.
+    pub fn optional(&mut self, tag: u8) -> Result<Option<Element<'a>>, DerError>
+    {
+        if self.peek_tag() == Some(tag) {
+            self.next_element().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<'a> Iterator for Reader<'a>
+{
+    type Item = Result<Element<'a>, DerError>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.next_element())
+        }
+    }
+}
@@ -14,13 +14,28 @@ pub enum Error
     NotFound,
     NoDevice,
     Busy,
+    Io,
+    Timeout,
+    Overflow,
+    PendingInstallation,
+    Interrupted,
+    NotSupported,
+    Exists,
+    UserCancel,
+    NeedsAdmin,
+    Wow64,
+    InfSyntax,
+    CatMissing,
+    Unsigned,
+    Other,
 }
 
 impl Error
 {
     /// Create an [Error] from a libwdi error code (e.g. [libwdi_sys::WDI_ERROR_NO_DEVICE].
     ///
-    /// If there is not a corresponding error for the passed value, this function returns None.
+    /// Returns None for [libwdi_sys::WDI_SUCCESS] and other non-negative values. Negative codes
+    /// libwdi doesn't define become [Error::Other].
     pub fn from_error_code(code: i32) -> Option<Self>
     {
         use libwdi_sys::*;
@@ -33,11 +48,59 @@ impl Error
             WDI_ERROR_NO_DEVICE => Some(NoDevice),
             WDI_ERROR_NOT_FOUND => Some(NotFound),
             WDI_ERROR_BUSY => Some(Busy),
+            WDI_ERROR_IO => Some(Io),
+            WDI_ERROR_TIMEOUT => Some(Timeout),
+            WDI_ERROR_OVERFLOW => Some(Overflow),
+            WDI_ERROR_PENDING_INSTALLATION => Some(PendingInstallation),
+            WDI_ERROR_INTERRUPTED => Some(Interrupted),
+            WDI_ERROR_NOT_SUPPORTED => Some(NotSupported),
+            WDI_ERROR_EXISTS => Some(Exists),
+            WDI_ERROR_USER_CANCEL => Some(UserCancel),
+            WDI_ERROR_NEEDS_ADMIN => Some(NeedsAdmin),
+            WDI_ERROR_WOW64 => Some(Wow64),
+            WDI_ERROR_INF_SYNTAX => Some(InfSyntax),
+            WDI_ERROR_CAT_MISSING => Some(CatMissing),
+            WDI_ERROR_UNSIGNED => Some(Unsigned),
+            code if code < 0 => Some(Other),
             _ => None,
         }
     }
 }
 
+impl Error
+{
+    /// The libwdi error code for this error, e.g. [libwdi_sys::WDI_ERROR_NO_DEVICE]. Since any
+    /// code libwdi doesn't define becomes [Error::Other], that gives [libwdi_sys::WDI_ERROR_OTHER].
+    pub fn to_error_code(self) -> i32
+    {
+        use libwdi_sys::*;
+        use Error::*;
+
+        match self {
+            InvalidParam => WDI_ERROR_INVALID_PARAM,
+            Access => WDI_ERROR_ACCESS,
+            Resource => WDI_ERROR_RESOURCE,
+            NotFound => WDI_ERROR_NOT_FOUND,
+            NoDevice => WDI_ERROR_NO_DEVICE,
+            Busy => WDI_ERROR_BUSY,
+            Io => WDI_ERROR_IO,
+            Timeout => WDI_ERROR_TIMEOUT,
+            Overflow => WDI_ERROR_OVERFLOW,
+            PendingInstallation => WDI_ERROR_PENDING_INSTALLATION,
+            Interrupted => WDI_ERROR_INTERRUPTED,
+            NotSupported => WDI_ERROR_NOT_SUPPORTED,
+            Exists => WDI_ERROR_EXISTS,
+            UserCancel => WDI_ERROR_USER_CANCEL,
+            NeedsAdmin => WDI_ERROR_NEEDS_ADMIN,
+            Wow64 => WDI_ERROR_WOW64,
+            InfSyntax => WDI_ERROR_INF_SYNTAX,
+            CatMissing => WDI_ERROR_CAT_MISSING,
+            Unsigned => WDI_ERROR_UNSIGNED,
+            Other => WDI_ERROR_OTHER,
+        }
+    }
+}
+
 impl Display for Error
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
@@ -51,6 +114,20 @@ impl Display for Error
             NotFound => write!(f, "Entity not found")?,
             Busy => write!(f, "Resource busy, or API call already running")?,
             Resource => write!(f, "Could not acquire resource (insufficient memory, etc)")?,
+            Io => write!(f, "Input/output error")?,
+            Timeout => write!(f, "Operation timed out")?,
+            Overflow => write!(f, "Overflow")?,
+            PendingInstallation => write!(f, "Another installation is pending")?,
+            Interrupted => write!(f, "System call interrupted (perhaps due to signal)")?,
+            NotSupported => write!(f, "Operation not supported or unimplemented on this platform")?,
+            Exists => write!(f, "Entity already exists")?,
+            UserCancel => write!(f, "Cancelled by user")?,
+            NeedsAdmin => write!(f, "Couldn't run installer with required privileges")?,
+            Wow64 => write!(f, "Attempted to run the 32 bit installer on 64 bit")?,
+            InfSyntax => write!(f, "Bad inf syntax")?,
+            CatMissing => write!(f, "Missing cat file")?,
+            Unsigned => write!(f, "System policy prevents the installation of unsigned drivers")?,
+            Other => write!(f, "Other error")?,
         };
 
         Ok(())
@@ -58,3 +135,24 @@ impl Display for Error
 }
 
 impl std::error::Error for Error { }
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn every_error_code_maps()
+    {
+        assert_eq!(Error::from_error_code(libwdi_sys::WDI_SUCCESS), None);
+        for code in -19..0 {
+            let error = Error::from_error_code(code).unwrap();
+            assert_ne!(error, Error::Other, "{}", code);
+            assert_eq!(error.to_error_code(), code);
+        }
+        assert_eq!(Error::from_error_code(libwdi_sys::WDI_ERROR_NOT_SUPPORTED), Some(Error::NotSupported));
+        assert_eq!(Error::from_error_code(libwdi_sys::WDI_ERROR_OTHER), Some(Error::Other));
+        assert_eq!(Error::from_error_code(-42), Some(Error::Other));
+    }
+}
@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! A typed GUID, for device interface GUIDs and the like.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use sha1::{Digest, Sha1};
+
+use crate::DeviceInfo;
+
+
+/// A GUID, stored in RFC 4122 (big endian) byte order.
+///
+/// Formats as the braced, upper case form INFs and the registry use, e.g.
+/// `{88BAE032-5A81-49F0-BC3D-A4FF138216D6}`, and parses both braced and unbraced forms in
+/// either case.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Guid([u8; 16]);
+
+/// The namespace [Guid::for_device] derives GUIDs in. This is the name-based GUID of
+/// `https://github.com/blackmagic-debug/wdi-rs` in the RFC 4122 URL namespace, and must never
+/// change, or every derived GUID would change with it.
+pub const WDI_NAMESPACE: Guid = Guid([
+    0xe8, 0x60, 0x5c, 0xd7, 0x72, 0x4c, 0x53, 0x91, 0xa1, 0x36, 0x12, 0x09, 0x99, 0x03, 0x85, 0xc2,
+]);
+
+/// The error returned when parsing a [Guid] fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseGuidError(String);
+
+impl Display for ParseGuidError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:?} is not a GUID; expected the form {{XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX}}", self.0)
+    }
+}
+
+impl std::error::Error for ParseGuidError { }
+
+impl Guid
+{
+    /// The all-zero GUID.
+    pub const NIL: Guid = Guid([0; 16]);
+
+    /// Constructs a GUID from bytes in RFC 4122 (big endian) order.
+    pub const fn from_bytes(bytes: [u8; 16]) -> Self
+    {
+        Self(bytes)
+    }
+
+    /// The GUID's bytes in RFC 4122 (big endian) order.
+    pub const fn as_bytes(&self) -> &[u8; 16]
+    {
+        &self.0
+    }
+
+    /// Constructs a GUID from the mixed endian layout of the Windows `GUID` struct, as found in
+    /// descriptors and other binary structures.
+    pub fn from_bytes_le(bytes: [u8; 16]) -> Self
+    {
+        let mut out = bytes;
+        out[0..4].reverse();
+        out[4..6].reverse();
+        out[6..8].reverse();
+        Self(out)
+    }
+
+    /// The GUID in the mixed endian layout of the Windows `GUID` struct.
+    pub fn to_bytes_le(&self) -> [u8; 16]
+    {
+        Self::from_bytes_le(self.0).0
+    }
+
+    /// Generates a random (version 4) GUID.
+    ///
+    /// The randomness comes from the standard library's per-process random hash keys mixed with
+    /// the current time, which is plenty for GUIDs but must not be used as key material.
+    pub fn new_v4() -> Self
+    {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        let mut bytes = [0u8; 16];
+        for half in bytes.chunks_exact_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(nanos);
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+
+        Self(bytes).with_version(4)
+    }
+
+    /// Derives a name-based (version 5, SHA-1) GUID from `name` in `namespace`. The same inputs
+    /// always give the same GUID.
+    pub fn new_v5(namespace: &Guid, name: &[u8]) -> Self
+    {
+        let mut hasher = Sha1::new();
+        hasher.update(namespace.0);
+        hasher.update(name);
+        let digest = hasher.finalize();
+
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        Self(bytes).with_version(5)
+    }
+
+    /// Derives a stable device interface GUID for the USB device (or, if `mi` is given, the
+    /// interface of a composite device) with the given IDs.
+    ///
+    /// This is [Guid::new_v5] in [WDI_NAMESPACE] of the device's hardware ID, e.g.
+    /// `USB\VID_1D50&PID_6018&MI_04`, so every machine derives the same GUID for the same device
+    /// without it being hardcoded anywhere.
+    pub fn for_device(vid: u16, pid: u16, mi: Option<u8>) -> Self
+    {
+        let mut name = format!("USB\\VID_{:04X}&PID_{:04X}", vid, pid);
+        if let Some(mi) = mi {
+            name.push_str(&format!("&MI_{:02X}", mi));
+        }
+
+        Self::new_v5(&WDI_NAMESPACE, name.as_bytes())
+    }
+
+    /// [Guid::for_device] for a [DeviceInfo] from [create_list](crate::create_list).
+    pub fn for_device_info(device: &DeviceInfo) -> Self
+    {
+        let mi = if device.is_composite { Some(device.mi) } else { None };
+        Self::for_device(device.vid, device.pid, mi)
+    }
+
+    /// The GUID's version, e.g. 4 for random and 5 for name-based GUIDs.
+    pub fn version(&self) -> u8
+    {
+        self.0[6] >> 4
+    }
+
+    /// The GUID without braces, e.g. `88BAE032-5A81-49F0-BC3D-A4FF138216D6`.
+    pub fn to_unbraced_string(&self) -> String
+    {
+        let b = &self.0;
+        format!(
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15],
+        )
+    }
+
+    /// Sets the version and RFC 4122 variant bits.
+    fn with_version(mut self, version: u8) -> Self
+    {
+        self.0[6] = (self.0[6] & 0x0f) | (version << 4);
+        self.0[8] = (self.0[8] & 0x3f) | 0x80;
+        self
+    }
+}
+
+impl Display for Guid
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{{{}}}", self.to_unbraced_string())
+    }
+}
+
+impl FromStr for Guid
+{
+    type Err = ParseGuidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let err = || ParseGuidError(s.to_string());
+
+        let trimmed = s.trim();
+        let inner = match trimmed.strip_prefix('{') {
+            Some(rest) => rest.strip_suffix('}').ok_or_else(err)?,
+            None => trimmed,
+        };
+
+        let groups: Vec<&str> = inner.split('-').collect();
+        let lengths: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        if lengths != [8, 4, 4, 4, 12] {
+            return Err(err());
+        }
+
+        let hex: String = groups.concat();
+        let mut bytes = [0u8; 16];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            let pair = hex.get(index * 2..index * 2 + 2).ok_or_else(err)?;
+            if !pair.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(err());
+            }
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| err())?;
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn parse_and_format()
+    {
+        let guid: Guid = "{88bae032-5a81-49f0-bc3d-a4ff138216d6}".parse().unwrap();
+        assert_eq!(guid.to_string(), "{88BAE032-5A81-49F0-BC3D-A4FF138216D6}");
+        assert_eq!("88BAE032-5A81-49F0-BC3D-A4FF138216D6".parse(), Ok(guid));
+        assert_eq!(Guid::from_bytes_le(guid.to_bytes_le()), guid);
+
+        for bad in ["{88BAE032-5A81-49F0-BC3D-A4FF138216D6", "88BAE0325A8149F0BC3DA4FF138216D6", "{88BAE032-5A81-49F0-BC3D-A4FF138216DG}"] {
+            assert!(bad.parse::<Guid>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn name_based_guids_are_stable()
+    {
+        // Cross-checked against Python's uuid.uuid5.
+        assert_eq!(Guid::for_device(0x1d50, 0x6018, Some(4)).to_string(), "{79912E3F-D52D-5BCF-8939-6BD30C1D2900}");
+        assert_ne!(Guid::for_device(0x1d50, 0x6018, None), Guid::for_device(0x1d50, 0x6018, Some(0)));
+
+        let random = Guid::new_v4();
+        assert_eq!(random.version(), 4);
+        assert_ne!(random, Guid::new_v4());
+    }
+}
@@ -0,0 +1,303 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! A lenient parser for Windows INF files, covering what is needed to make sense of the INFs
+//! libwdi generates (and of most hand written driver INFs).
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+
+/// A single line in an INF section, e.g. `AddService = WinUSB, 0x00000002, WinUSB_ServiceInstall`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfLine
+{
+    /// The part before the `=`, if there is one.
+    pub key: Option<String>,
+
+    /// The comma separated values, with quotes removed. `%strkey%` tokens are *not* expanded;
+    /// see [Inf::expand].
+    pub values: Vec<String>,
+}
+
+impl InfLine
+{
+    /// The first value on this line, or an empty string.
+    pub fn value(&self) -> &str
+    {
+        self.values.first().map(String::as_str).unwrap_or("")
+    }
+}
+
+/// A parsed INF file.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Inf
+{
+    sections: Vec<(String, Vec<InfLine>)>,
+}
+
+impl Inf
+{
+    /// Reads and parses the INF at `path`. INFs may be either UTF-16 (with a BOM) or 8-bit text.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self>
+    {
+        Ok(Self::parse(&decode(&fs::read(path)?)))
+    }
+
+    /// Parses the text of an INF file.
+    pub fn parse(text: &str) -> Self
+    {
+        let mut sections: Vec<(String, Vec<InfLine>)> = Vec::new();
+        let mut pending = String::new();
+
+        for raw_line in text.lines() {
+            let line = strip_comment(raw_line);
+            let line = line.trim();
+
+            // A trailing backslash continues the line.
+            if let Some(continued) = line.strip_suffix('\\') {
+                pending.push_str(continued);
+                continue;
+            }
+            pending.push_str(line);
+            let line = std::mem::take(&mut pending);
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if let Some(end) = line.find(']') {
+                    sections.push((line[1..end].trim().to_string(), Vec::new()));
+                }
+                continue;
+            }
+
+            // Lines before the first section are ignored, as Windows does.
+            let Some((_, lines)) = sections.last_mut() else {
+                continue;
+            };
+
+            let (key, rest) = match split_unquoted(line, '=').as_slice() {
+                [key, rest] => (Some(unquote(key)), rest.to_string()),
+                _ => (None, line.to_string()),
+            };
+            let values = split_unquoted(&rest, ',')
+                .into_iter()
+                .map(|v| unquote(&v))
+                .collect();
+
+            lines.push(InfLine { key, values });
+        }
+
+        Self { sections }
+    }
+
+    /// The names of all sections, in file order.
+    pub fn section_names(&self) -> impl Iterator<Item = &str>
+    {
+        self.sections.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// The lines of the section `name` (case insensitive). Sections that appear multiple times
+    /// are merged.
+    pub fn section(&self, name: &str) -> Vec<&InfLine>
+    {
+        self.sections
+            .iter()
+            .filter(|(section, _)| section.eq_ignore_ascii_case(name))
+            .flat_map(|(_, lines)| lines.iter())
+            .collect()
+    }
+
+    /// Whether the section `name` exists.
+    pub fn has_section(&self, name: &str) -> bool
+    {
+        self.sections.iter().any(|(section, _)| section.eq_ignore_ascii_case(name))
+    }
+
+    /// All lines in the section `section` with the key `key` (both case insensitive).
+    pub fn entries<'a>(&'a self, section: &str, key: &'a str) -> impl Iterator<Item = &'a InfLine>
+    {
+        self.section(section)
+            .into_iter()
+            .filter(move |line| line.key.as_deref().map(|k| k.eq_ignore_ascii_case(key)).unwrap_or(false))
+    }
+
+    /// The first value of the key `key` in section `section`, with `%strkey%` tokens expanded.
+    pub fn value(&self, section: &str, key: &str) -> Option<String>
+    {
+        self.entries(section, key).next().map(|line| self.expand(line.value()))
+    }
+
+    /// Expands `%strkey%` tokens using the `[Strings]` section. `%%` becomes a literal `%`,
+    /// and unknown tokens are left untouched.
+    pub fn expand(&self, text: &str) -> String
+    {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find('%') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let Some(end) = after.find('%') else {
+                out.push_str(&rest[start..]);
+                return out;
+            };
+
+            let token = &after[..end];
+            if token.is_empty() {
+                out.push('%');
+            } else {
+                match self.value_unexpanded("Strings", token) {
+                    Some(value) => out.push_str(&value),
+                    None => {
+                        out.push('%');
+                        out.push_str(token);
+                        out.push('%');
+                    },
+                }
+            }
+            rest = &after[end + 1..];
+        }
+        out.push_str(rest);
+
+        out
+    }
+
+    fn value_unexpanded(&self, section: &str, key: &str) -> Option<String>
+    {
+        self.entries(section, key).next().map(|line| line.value().to_string())
+    }
+}
+
+/// Decodes INF file contents, which may be UTF-16LE with a BOM, UTF-8, or (most commonly)
+/// an 8-bit code page, which we approximate as Latin-1.
+fn decode(data: &[u8]) -> String
+{
+    if let Some(utf16) = data.strip_prefix(&[0xff, 0xfe]) {
+        let units: Vec<u16> = utf16
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        return String::from_utf16_lossy(&units);
+    }
+
+    let data = data.strip_prefix(&[0xef, 0xbb, 0xbf]).unwrap_or(data);
+    match std::str::from_utf8(data) {
+        Ok(text) => text.to_string(),
+        Err(_) => data.iter().map(|b| *b as char).collect(),
+    }
+}
+
+/// Removes a `;` comment from a line, ignoring semicolons inside quotes.
+fn strip_comment(line: &str) -> &str
+{
+    let mut in_quotes = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => return &line[..index],
+            _ => (),
+        }
+    }
+
+    line
+}
+
+/// Splits `text` on `separator`, ignoring separators inside quotes. For `=`, only the first
+/// unquoted occurrence splits.
+fn split_unquoted(text: &str, separator: char) -> Vec<String>
+{
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in text.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        if c == separator && !in_quotes && (separator != '=' || parts.is_empty()) {
+            parts.push(std::mem::take(&mut current));
+        } else {
+            current.push(c);
+        }
+    }
+    parts.push(current);
+
+    parts
+}
+
+/// Trims whitespace and surrounding quotes. `""` inside quotes is an escaped quote.
+fn unquote(text: &str) -> String
+{
+    let text = text.trim();
+    match text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        Some(inner) => inner.replace("\"\"", "\""),
+        None => text.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const INF: &str = r#"; Leading comment
+ignored = before any section
+[Strings]
+Name = "Probe; ""quoted"""
+Dir  = amd64
+
+[Version]
+Provider = %Name%
+CatalogFile = probe.cat ; trailing comment
+
+[Files]
+a.sys, \
+    %Dir%\a.sys, 100%%
+"#;
+
+    #[test]
+    fn parse_lines()
+    {
+        let inf = Inf::parse(INF);
+
+        assert_eq!(inf.section_names().collect::<Vec<_>>(), ["Strings", "Version", "Files"]);
+        assert_eq!(inf.value("version", "PROVIDER").as_deref(), Some(r#"Probe; "quoted""#));
+        assert_eq!(inf.value("Version", "CatalogFile").as_deref(), Some("probe.cat"));
+        assert!(!inf.has_section("Install"));
+
+        // A continued line without a key.
+        let files = inf.section("Files");
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].key, None);
+        assert_eq!(files[0].values, ["a.sys", r"%Dir%\a.sys", "100%%"]);
+        assert_eq!(inf.expand(&files[0].values[1]), r"amd64\a.sys");
+        assert_eq!(inf.expand(&files[0].values[2]), "100%");
+        assert_eq!(inf.expand("%Unknown% %Dir"), "%Unknown% %Dir");
+    }
+
+    #[test]
+    fn merge_repeated_sections()
+    {
+        let inf = Inf::parse("[A]\nx = 1\n[B]\n[a]\nx = 2\n");
+        let values: Vec<&str> = inf.entries("A", "x").map(InfLine::value).collect();
+        assert_eq!(values, ["1", "2"]);
+    }
+
+    #[test]
+    fn decode_encodings()
+    {
+        let utf16: Vec<u8> = [0xff, 0xfe]
+            .into_iter()
+            .chain("[Version]\r\nProvider = libwdi\r\n".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        assert_eq!(Inf::parse(&decode(&utf16)).value("Version", "Provider").as_deref(), Some("libwdi"));
+
+        assert_eq!(decode(b"\xef\xbb\xbfProvider"), "Provider");
+        assert_eq!(decode(b"Caf\xe9"), "Caf\u{e9}");
+    }
+}
@@ -4,17 +4,53 @@
 //! High-ish level API to [libwdi](https://github.com/pbatard/libwdi).
 //!
 //! This crate is still extremely work in progress, but the major high level functions of interest
-//! are [create_list] and [prepare_driver].
+//! are [create_list] and [prepare_driver]. [DriverPackage] ties the output of [prepare_driver] to
+//! [install_driver].
 
 use std::ptr;
+use std::borrow::Cow;
 use std::ffi::{CString, CStr};
 use std::fmt;
 use std::fmt::Display;
+use std::str::FromStr;
 
 use bstr::ByteSlice;
 
 pub mod error;
 pub use error::Error;
+mod der;
+pub mod build_info;
+pub use build_info::{build_info, BuildInfo};
+pub mod catalog;
+pub use catalog::verify_package;
+pub mod guid;
+pub use guid::Guid;
+pub mod inf;
+pub mod msos;
+pub mod package;
+pub use package::DriverPackage;
+pub mod snapshot;
+pub use snapshot::DeviceSnapshot;
+pub mod watcher;
+pub use watcher::{DeviceEvent, DeviceWatcher};
+pub mod query;
+pub use query::{wait_for_device, wait_for_driver, DeviceQuery};
+pub mod plan;
+pub use plan::{plan, InstallPlan};
+pub mod uninstall;
+pub use uninstall::{remove_driver_package, uninstall_driver, RemovePackageOptions};
+pub mod backup;
+pub use backup::DriverBackup;
+pub mod store;
+pub use store::DriverStore;
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "serde")]
+mod serialization;
+#[cfg(feature = "policy")]
+pub mod policy;
+#[cfg(test)]
+mod testing;
 
 use libwdi_sys::wdi_device_info;
 
@@ -134,6 +170,47 @@ impl DeviceInfo
     }
 }
 
+/// Text accessors. libwdi's strings are NUL terminated bytes; these strip the terminator and
+/// replace any invalid UTF-8.
+impl DeviceInfo
+{
+    pub fn desc_str(&self) -> Cow<'_, str>
+    {
+        c_text(&self.desc)
+    }
+
+    pub fn driver_str(&self) -> Option<Cow<'_, str>>
+    {
+        self.driver.as_deref().map(c_text)
+    }
+
+    pub fn device_id_str(&self) -> Option<Cow<'_, str>>
+    {
+        self.device_id.as_deref().map(c_text)
+    }
+
+    pub fn hardware_id_str(&self) -> Option<Cow<'_, str>>
+    {
+        self.hardware_id.as_deref().map(c_text)
+    }
+
+    pub fn compatible_id_str(&self) -> Option<Cow<'_, str>>
+    {
+        self.compatible_id.as_deref().map(c_text)
+    }
+
+    pub fn upper_filter_str(&self) -> Option<Cow<'_, str>>
+    {
+        self.upper_filter.as_deref().map(c_text)
+    }
+}
+
+/// A NUL terminated libwdi string as text, without its terminator.
+pub(crate) fn c_text(bytes: &[u8]) -> Cow<'_, str>
+{
+    bytes.trim_end_with(|c| c == '\0').to_str_lossy()
+}
+
 impl fmt::Debug for DeviceInfo
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
@@ -244,7 +321,7 @@ pub fn create_list(options: CreateListOptions) -> Result<Vec<DeviceInfo>, Error>
 }
 
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[repr(i32)]
 pub enum DriverType
 {
@@ -257,10 +334,16 @@ pub enum DriverType
     /// libusbK.sys.
     LibusbK = libwdi_sys::WDI_LIBUSBK,
 
-    /// A custom user driver.
+    /// A custom user driver, embedded from `USER_DIR` with the `user-driver` feature. Its INF is
+    /// [USER_DRIVER_INF].
     User    = libwdi_sys::WDI_USER,
 }
 
+/// The name of the INF of the user driver embedded with the `user-driver` feature, to pass as
+/// `inf_name` to [prepare_driver] and [install_driver] with [DriverType::User].
+#[cfg(feature = "user-driver")]
+pub const USER_DRIVER_INF: &str = libwdi_sys::WDI_USER_INF;
+
 /// The error that occurs if [DriverType::from_raw] fails, including [DriverType]'s [TryFrom]
 /// implementation.
 #[derive(Debug, Copy, Clone, PartialEq, Default)]
@@ -290,6 +373,14 @@ impl DriverType
             _ => Err(DriverTypeConversionError)
         }
     }
+
+    /// Whether this libwdi build embeds the files for this driver, i.e. whether
+    /// [prepare_driver] can extract it. Depends on the `libusb0`, `libusbk` and `user-driver`
+    /// features.
+    pub fn is_supported(self) -> bool
+    {
+        unsafe { libwdi_sys::wdi_is_driver_supported(self as i32, ptr::null_mut()) != 0 }
+    }
 }
 
 impl TryFrom<i32> for DriverType
@@ -312,6 +403,41 @@ impl Default for DriverType
     }
 }
 
+/// Formats the driver's conventional name, e.g. `WinUSB` or `libusbK`.
+impl Display for DriverType
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        use DriverType::*;
+
+        match self {
+            WinUsb => write!(f, "WinUSB"),
+            Libusb0 => write!(f, "libusb0"),
+            LibusbK => write!(f, "libusbK"),
+            User => write!(f, "user"),
+        }
+    }
+}
+
+/// Parses the names produced by [DriverType]'s [Display] implementation, case insensitively.
+impl FromStr for DriverType
+{
+    type Err = DriverTypeConversionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        use DriverType::*;
+
+        match s.to_ascii_lowercase().as_str() {
+            "winusb" => Ok(WinUsb),
+            "libusb0" | "libusb-win32" => Ok(Libusb0),
+            "libusbk" => Ok(LibusbK),
+            "user" => Ok(User),
+            _ => Err(DriverTypeConversionError),
+        }
+    }
+}
+
 
 /// Options for [prepare_driver].
 #[derive(Debug, Clone, PartialEq, Default)]
@@ -369,13 +495,14 @@ impl PrepareDriverOptions
         }
     }
 
-    /// A GUID string (including braces) that is meant to override the DeviceGUID automatically
-    /// generated by libwdi when creating a generic INF for WinUSB, libusb0 or libusbK.
+    /// A GUID that is meant to override the DeviceGUID automatically generated by libwdi when
+    /// creating a generic INF for WinUSB, libusb0 or libusbK. [Guid::for_device] derives one
+    /// that is the same on every machine.
     /// Be mindful that, if you need to force a DeviceGUID, this means that your device is no longer a generic one, in which case you should embed your driver file, including the static inf, as user files.
-    pub fn device_guid(self, device_guid: Option<CString>) -> Self
+    pub fn device_guid(self, device_guid: Option<Guid>) -> Self
     {
         Self {
-            device_guid: device_guid.map(|s| s.into_bytes_with_nul()),
+            device_guid: device_guid.map(|guid| format!("{}\0", guid).into_bytes()),
             ..self
         }
     }
@@ -446,14 +573,15 @@ impl PrepareDriverOptions
             })
     }
 
-    pub fn get_device_guid(&self) -> Option<&CStr>
+    /// Returns [None] if no GUID is set, or if options from [PrepareDriverOptions::clone_from_raw]
+    /// held a string that is not a valid GUID.
+    pub fn get_device_guid(&self) -> Option<Guid>
     {
         self.device_guid
             .as_ref()
-            .map(|s| {
-                CStr::from_bytes_with_nul(s.as_slice())
-                    .expect("Unreachable: device_guid cannot be an invalid C string")
-            })
+            .and_then(|s| CStr::from_bytes_with_nul(s.as_slice()).ok())
+            .and_then(|s| s.to_str().ok())
+            .and_then(|s| s.parse().ok())
     }
 
     pub fn get_disable_cat(&self) -> bool
@@ -616,6 +744,58 @@ impl Default for InstallDriverOptions
     }
 }
 
+/// Builder API.
+impl InstallDriverOptions
+{
+    /// Handle to a Window application that should receive a modal progress dialog. When this
+    /// parameter is provided, a modal progress dialog will be displayed for the duration of the
+    /// driver installation process.
+    pub fn hwnd(self, hwnd: libwdi_sys::HWND) -> Self
+    {
+        Self {
+            hwnd,
+            ..self
+        }
+    }
+
+    /// Install a filter driver instead of the regular driver (libusb-win32 only).
+    pub fn install_filter_driver(self, install_filter_driver: bool) -> Self
+    {
+        Self {
+            install_filter_driver,
+            ..self
+        }
+    }
+
+    /// Number of milliseconds to wait for any pending installations. 0, means no timeout.
+    pub fn pending_install_timeout(self, pending_install_timeout: u32) -> Self
+    {
+        Self {
+            pending_install_timeout,
+            ..self
+        }
+    }
+}
+
+/// Getters, with non-standard names due to the builder API.
+impl InstallDriverOptions
+{
+    pub fn get_hwnd(&self) -> libwdi_sys::HWND
+    {
+        self.hwnd
+    }
+
+    pub fn get_install_filter_driver(&self) -> bool
+    {
+        self.install_filter_driver
+    }
+
+    pub fn get_pending_install_timeout(&self) -> u32
+    {
+        self.pending_install_timeout
+    }
+}
+
 /// Functions for converting betwen this and [libwdi_sys::wdi_options_install_driver].
 impl InstallDriverOptions
 {
@@ -664,3 +844,105 @@ pub fn install_driver(device: &mut DeviceInfo, path: &str, inf_name: &str, optio
 
     Ok(())
 }
+
+
+/// Options for [install_trusted_certificate].
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstallCertOptions
+{
+    /// Handle to a Window application that can receive a modal progress dialog.
+    hwnd: libwdi_sys::HWND,
+
+    /// Disable the warning about installing a certificate into the Trusted Publisher store.
+    disable_warning: bool,
+}
+
+impl Default for InstallCertOptions
+{
+    fn default() -> Self
+    {
+        Self {
+            hwnd: ptr::null_mut(),
+            disable_warning: false,
+        }
+    }
+}
+
+/// Builder API.
+impl InstallCertOptions
+{
+    /// Handle to a Window application that can receive a modal progress dialog.
+    pub fn hwnd(self, hwnd: libwdi_sys::HWND) -> Self
+    {
+        Self {
+            hwnd,
+            ..self
+        }
+    }
+
+    /// Disable the warning about installing a certificate into the Trusted Publisher store.
+    pub fn disable_warning(self, disable_warning: bool) -> Self
+    {
+        Self {
+            disable_warning,
+            ..self
+        }
+    }
+}
+
+/// Getters, with non-standard names due to the builder API.
+impl InstallCertOptions
+{
+    pub fn get_hwnd(&self) -> libwdi_sys::HWND
+    {
+        self.hwnd
+    }
+
+    pub fn get_disable_warning(&self) -> bool
+    {
+        self.disable_warning
+    }
+}
+
+/// Functions for converting between this and [libwdi_sys::wdi_options_install_cert].
+impl InstallCertOptions
+{
+    pub fn as_raw(&mut self) -> libwdi_sys::wdi_options_install_cert
+    {
+        libwdi_sys::wdi_options_install_cert {
+            hWnd: self.hwnd,
+            disable_warning: self.disable_warning as i32,
+        }
+    }
+
+    pub fn from_raw(other: &libwdi_sys::wdi_options_install_cert) -> Self
+    {
+        Self {
+            hwnd: other.hWnd,
+            disable_warning: other.disable_warning != 0,
+        }
+    }
+}
+
+
+/// A Rust interface to [libwdi_sys::wdi_install_trusted_certificate] ([original documentation]).
+///
+/// Installs the certificate embedded in libwdi as `cert_name` (e.g. from `USER_DIR`) into the
+/// Trusted Publisher store, so drivers signed with it install without a prompt. Returns
+/// [Error::InvalidParam] if `cert_name` contains a NUL.
+///
+/// [original documentation]:
+/// https://github.com/pbatard/libwdi/wiki/Usage#int_wdi_install_trusted_certificateconst_char_cert_name_struct_wdi_options_install_cert_options
+pub fn install_trusted_certificate(cert_name: &str, options: &mut InstallCertOptions) -> Result<(), Error>
+{
+    let cstr_cert_name = CString::new(cert_name).map_err(|_| Error::InvalidParam)?;
+    let mut opt = options.as_raw();
+
+    let ret = unsafe { libwdi_sys::wdi_install_trusted_certificate(cstr_cert_name.as_ptr(), &mut opt) };
+
+    if let Some(e) = Error::from_error_code(ret) {
+        return Err(e);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1108 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Parsing, generation and validation of Microsoft OS descriptors.
+//!
+//! Windows only binds WinUSB to a device automatically (see
+//! [PrepareDriverOptions::use_wcid_driver](crate::PrepareDriverOptions::use_wcid_driver)) if the
+//! device's firmware serves correct Microsoft OS descriptors, in either of two generations:
+//!
+//! * MS OS 1.0 ("WCID"): the `0xEE` string descriptor ([OsStringDescriptor]), the Extended
+//!   Compat ID descriptor ([CompatIdDescriptor]) and the Extended Properties descriptor
+//!   ([ExtendedPropertiesDescriptor]).
+//! * MS OS 2.0: a BOS platform capability ([PlatformCapability]) pointing at a descriptor set
+//!   ([DescriptorSet]).
+//!
+//! Every descriptor type here can be parsed from and serialized to the exact bytes the device
+//! sends, and [MsOs1Descriptors::validate_winusb] and [DescriptorSet::validate_winusb] check
+//! them against what Windows needs to bind WinUSB. None of this needs Windows.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+
+use crate::Guid;
+
+
+/// The error returned when a descriptor blob cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorError
+{
+    /// Byte offset into the blob at which the problem was found.
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+impl Display for DescriptorError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "Invalid descriptor at offset {}: {}", self.offset, self.reason)
+    }
+}
+
+impl std::error::Error for DescriptorError { }
+
+fn error<T>(offset: usize, reason: &'static str) -> Result<T, DescriptorError>
+{
+    Err(DescriptorError { offset, reason })
+}
+
+/// Little endian field access with bounds checking.
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a>
+{
+    fn slice(&self, offset: usize, len: usize) -> Result<&'a [u8], DescriptorError>
+    {
+        match self.0.get(offset..offset + len) {
+            Some(bytes) => Ok(bytes),
+            None => error(offset, "descriptor is truncated"),
+        }
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8, DescriptorError>
+    {
+        Ok(self.slice(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, DescriptorError>
+    {
+        let b = self.slice(offset, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, DescriptorError>
+    {
+        let b = self.slice(offset, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+fn utf16_to_string(bytes: &[u8]) -> String
+{
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Encodes `s` as NUL-terminated UTF-16LE.
+fn string_to_utf16z(s: &str) -> Vec<u8>
+{
+    s.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect()
+}
+
+/// Decodes an 8-byte, NUL-padded ASCII ID such as `WINUSB\0\0`.
+fn decode_id(bytes: &[u8]) -> String
+{
+    bytes.iter().take_while(|b| **b != 0).map(|b| *b as char).collect()
+}
+
+fn encode_id(id: &str) -> [u8; 8]
+{
+    let mut out = [0u8; 8];
+    for (dst, src) in out.iter_mut().zip(id.bytes()) {
+        *dst = src;
+    }
+    out
+}
+
+/// Whether `s` is a braced GUID, like `{88BAE032-5A81-49F0-BC3D-A4FF138216D6}`.
+fn is_braced_guid(s: &str) -> bool
+{
+    s.starts_with('{') && s.parse::<Guid>().is_ok()
+}
+
+
+/// Registry value types usable in extended property and registry property descriptors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropertyValue
+{
+    /// `REG_SZ`.
+    String(String),
+    /// `REG_EXPAND_SZ`.
+    ExpandString(String),
+    /// `REG_BINARY`.
+    Binary(Vec<u8>),
+    /// `REG_DWORD_LITTLE_ENDIAN`.
+    DwordLittleEndian(u32),
+    /// `REG_DWORD_BIG_ENDIAN`.
+    DwordBigEndian(u32),
+    /// `REG_LINK`.
+    Link(String),
+    /// `REG_MULTI_SZ`.
+    MultiString(Vec<String>),
+}
+
+impl PropertyValue
+{
+    /// The registry type code of this value.
+    pub fn data_type(&self) -> u32
+    {
+        match self {
+            PropertyValue::String(_) => 1,
+            PropertyValue::ExpandString(_) => 2,
+            PropertyValue::Binary(_) => 3,
+            PropertyValue::DwordLittleEndian(_) => 4,
+            PropertyValue::DwordBigEndian(_) => 5,
+            PropertyValue::Link(_) => 6,
+            PropertyValue::MultiString(_) => 7,
+        }
+    }
+
+    fn decode(data_type: u32, data: &[u8], offset: usize) -> Result<Self, DescriptorError>
+    {
+        let string = || utf16_to_string(data).trim_end_matches('\0').to_string();
+        let dword = |big_endian: bool| -> Result<u32, DescriptorError> {
+            match <[u8; 4]>::try_from(data) {
+                Ok(b) if big_endian => Ok(u32::from_be_bytes(b)),
+                Ok(b) => Ok(u32::from_le_bytes(b)),
+                Err(_) => error(offset, "DWORD property data is not 4 bytes long"),
+            }
+        };
+
+        Ok(match data_type {
+            1 => PropertyValue::String(string()),
+            2 => PropertyValue::ExpandString(string()),
+            3 => PropertyValue::Binary(data.to_vec()),
+            4 => PropertyValue::DwordLittleEndian(dword(false)?),
+            5 => PropertyValue::DwordBigEndian(dword(true)?),
+            6 => PropertyValue::Link(string()),
+            7 => PropertyValue::MultiString(
+                utf16_to_string(data)
+                    .split('\0')
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect()
+            ),
+            _ => return error(offset, "unknown property data type"),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8>
+    {
+        match self {
+            PropertyValue::String(s) | PropertyValue::ExpandString(s) | PropertyValue::Link(s) => string_to_utf16z(s),
+            PropertyValue::Binary(data) => data.clone(),
+            PropertyValue::DwordLittleEndian(v) => v.to_le_bytes().to_vec(),
+            PropertyValue::DwordBigEndian(v) => v.to_be_bytes().to_vec(),
+            PropertyValue::MultiString(strings) => {
+                let mut data: Vec<u8> = strings.iter().flat_map(|s| string_to_utf16z(s)).collect();
+                data.extend_from_slice(&[0, 0]);
+                data
+            },
+        }
+    }
+}
+
+/// A named registry value that the device asks Windows to set for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryProperty
+{
+    pub name: String,
+    pub value: PropertyValue,
+}
+
+impl RegistryProperty
+{
+    /// The `DeviceInterfaceGUIDs` property WinUSB reads to register device interfaces.
+    pub fn device_interface_guids<I, S>(guids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            name: String::from("DeviceInterfaceGUIDs"),
+            value: PropertyValue::MultiString(guids.into_iter().map(Into::into).collect()),
+        }
+    }
+}
+
+
+/// The MS OS 1.0 string descriptor, served at string index `0xEE`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OsStringDescriptor
+{
+    /// The `bRequest` value Windows uses to fetch the other MS OS 1.0 descriptors.
+    pub vendor_code: u8,
+}
+
+impl OsStringDescriptor
+{
+    /// The string index this descriptor must be served at.
+    pub const INDEX: u8 = 0xee;
+    const SIGNATURE: &'static str = "MSFT100";
+    const LEN: usize = 18;
+
+    pub fn parse(data: &[u8]) -> Result<Self, DescriptorError>
+    {
+        let bytes = Bytes(data);
+        if bytes.u8(0)? as usize != Self::LEN || data.len() < Self::LEN {
+            return error(0, "bLength must be 0x12");
+        }
+        if bytes.u8(1)? != 0x03 {
+            return error(1, "bDescriptorType must be STRING (0x03)");
+        }
+        if utf16_to_string(bytes.slice(2, 14)?) != Self::SIGNATURE {
+            return error(2, "qwSignature must be \"MSFT100\"");
+        }
+
+        Ok(Self { vendor_code: bytes.u8(16)? })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut out = vec![Self::LEN as u8, 0x03];
+        out.extend(Self::SIGNATURE.encode_utf16().flat_map(u16::to_le_bytes));
+        out.push(self.vendor_code);
+        out.push(0);
+        out
+    }
+}
+
+
+/// One function (interface group) in an Extended Compat ID descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatIdFunction
+{
+    pub first_interface: u8,
+    /// e.g. `WINUSB`.
+    pub compatible_id: String,
+    pub sub_compatible_id: String,
+}
+
+/// The MS OS 1.0 Extended Compat ID descriptor (`wIndex` 4).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CompatIdDescriptor
+{
+    pub functions: Vec<CompatIdFunction>,
+}
+
+impl CompatIdDescriptor
+{
+    /// The `wIndex` Windows requests this descriptor with.
+    pub const INDEX: u16 = 4;
+    const HEADER_LEN: usize = 16;
+    const FUNCTION_LEN: usize = 24;
+
+    /// A descriptor binding WinUSB to the whole device, or to the function starting at interface 0.
+    pub fn winusb() -> Self
+    {
+        Self {
+            functions: vec![CompatIdFunction {
+                first_interface: 0,
+                compatible_id: String::from("WINUSB"),
+                sub_compatible_id: String::new(),
+            }],
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, DescriptorError>
+    {
+        let bytes = Bytes(data);
+        let length = bytes.u32(0)? as usize;
+        if length != data.len() {
+            return error(0, "dwLength does not match the descriptor length");
+        }
+        if bytes.u16(4)? != 0x0100 {
+            return error(4, "bcdVersion must be 0x0100");
+        }
+        if bytes.u16(6)? != Self::INDEX {
+            return error(6, "wIndex must be 4 for an Extended Compat ID descriptor");
+        }
+        let count = bytes.u8(8)? as usize;
+        if length != Self::HEADER_LEN + count * Self::FUNCTION_LEN {
+            return error(8, "bCount does not match dwLength");
+        }
+
+        let functions = (0..count)
+            .map(|index| {
+                let offset = Self::HEADER_LEN + index * Self::FUNCTION_LEN;
+                Ok(CompatIdFunction {
+                    first_interface: bytes.u8(offset)?,
+                    compatible_id: decode_id(bytes.slice(offset + 2, 8)?),
+                    sub_compatible_id: decode_id(bytes.slice(offset + 10, 8)?),
+                })
+            })
+            .collect::<Result<_, DescriptorError>>()?;
+
+        Ok(Self { functions })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let length = Self::HEADER_LEN + self.functions.len() * Self::FUNCTION_LEN;
+        let mut out = Vec::with_capacity(length);
+        out.extend((length as u32).to_le_bytes());
+        out.extend(0x0100u16.to_le_bytes());
+        out.extend(Self::INDEX.to_le_bytes());
+        out.push(self.functions.len() as u8);
+        out.extend([0u8; 7]);
+        for function in &self.functions {
+            out.push(function.first_interface);
+            out.push(0x01);
+            out.extend(encode_id(&function.compatible_id));
+            out.extend(encode_id(&function.sub_compatible_id));
+            out.extend([0u8; 6]);
+        }
+        out
+    }
+}
+
+
+/// The MS OS 1.0 Extended Properties descriptor (`wIndex` 5).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExtendedPropertiesDescriptor
+{
+    pub properties: Vec<RegistryProperty>,
+}
+
+impl ExtendedPropertiesDescriptor
+{
+    /// The `wIndex` Windows requests this descriptor with.
+    pub const INDEX: u16 = 5;
+    const HEADER_LEN: usize = 10;
+
+    pub fn parse(data: &[u8]) -> Result<Self, DescriptorError>
+    {
+        let bytes = Bytes(data);
+        if bytes.u32(0)? as usize != data.len() {
+            return error(0, "dwLength does not match the descriptor length");
+        }
+        if bytes.u16(4)? != 0x0100 {
+            return error(4, "bcdVersion must be 0x0100");
+        }
+        if bytes.u16(6)? != Self::INDEX {
+            return error(6, "wIndex must be 5 for an Extended Properties descriptor");
+        }
+        let count = bytes.u16(8)?;
+
+        let mut properties = Vec::with_capacity(count as usize);
+        let mut offset = Self::HEADER_LEN;
+        for _ in 0..count {
+            let size = bytes.u32(offset)? as usize;
+            let data_type = bytes.u32(offset + 4)?;
+            let name_len = bytes.u16(offset + 8)? as usize;
+            let name = utf16_to_string(bytes.slice(offset + 10, name_len)?);
+            let data_len_offset = offset + 10 + name_len;
+            let data_len = bytes.u32(data_len_offset)? as usize;
+            let value = PropertyValue::decode(data_type, bytes.slice(data_len_offset + 4, data_len)?, data_len_offset + 4)?;
+            if size != 14 + name_len + data_len {
+                return error(offset, "dwSize does not match the property's name and data lengths");
+            }
+
+            properties.push(RegistryProperty {
+                name: name.trim_end_matches('\0').to_string(),
+                value,
+            });
+            offset += size;
+        }
+        if offset != data.len() {
+            return error(offset, "trailing data after the last property");
+        }
+
+        Ok(Self { properties })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut body = Vec::new();
+        for property in &self.properties {
+            let name = string_to_utf16z(&property.name);
+            let data = property.value.encode();
+            body.extend(((14 + name.len() + data.len()) as u32).to_le_bytes());
+            body.extend(property.value.data_type().to_le_bytes());
+            body.extend((name.len() as u16).to_le_bytes());
+            body.extend(&name);
+            body.extend((data.len() as u32).to_le_bytes());
+            body.extend(&data);
+        }
+
+        let mut out = Vec::with_capacity(Self::HEADER_LEN + body.len());
+        out.extend(((Self::HEADER_LEN + body.len()) as u32).to_le_bytes());
+        out.extend(0x0100u16.to_le_bytes());
+        out.extend(Self::INDEX.to_le_bytes());
+        out.extend((self.properties.len() as u16).to_le_bytes());
+        out.extend(body);
+        out
+    }
+}
+
+
+/// How serious a validation finding is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity
+{
+    /// Windows will still bind WinUSB, but something is unusual.
+    Warning,
+    /// Windows will not bind WinUSB, or will bind it without a usable device interface.
+    Error,
+}
+
+/// A problem found by one of the `validate_winusb` functions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding
+{
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Display for Finding
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+fn finding(severity: Severity, message: impl Into<String>) -> Finding
+{
+    Finding { severity, message: message.into() }
+}
+
+/// Checks that `properties` contains a device interface GUID WinUSB can use.
+fn check_interface_guids(properties: &[RegistryProperty], context: &str, findings: &mut Vec<Finding>)
+{
+    let mut found = false;
+    for property in properties {
+        match (property.name.as_str(), &property.value) {
+            ("DeviceInterfaceGUIDs", PropertyValue::MultiString(guids)) => {
+                found = true;
+                if guids.is_empty() {
+                    findings.push(finding(Severity::Error, format!("{}: DeviceInterfaceGUIDs is empty", context)));
+                }
+                for guid in guids.iter().filter(|guid| !is_braced_guid(guid)) {
+                    findings.push(finding(Severity::Error, format!("{}: {:?} is not a braced GUID", context, guid)));
+                }
+            },
+            ("DeviceInterfaceGUID", PropertyValue::String(guid)) => {
+                found = true;
+                if !is_braced_guid(guid) {
+                    findings.push(finding(Severity::Error, format!("{}: {:?} is not a braced GUID", context, guid)));
+                }
+            },
+            ("DeviceInterfaceGUIDs", _) => {
+                found = true;
+                findings.push(finding(Severity::Error, format!("{}: DeviceInterfaceGUIDs must be REG_MULTI_SZ", context)));
+            },
+            ("DeviceInterfaceGUID", _) => {
+                found = true;
+                findings.push(finding(Severity::Error, format!("{}: DeviceInterfaceGUID must be REG_SZ", context)));
+            },
+            (name, _) if name.eq_ignore_ascii_case("DeviceInterfaceGUIDs") || name.eq_ignore_ascii_case("DeviceInterfaceGUID") => {
+                findings.push(finding(Severity::Warning, format!("{}: property name {:?} has unexpected capitalization", context, name)));
+            },
+            _ => (),
+        }
+    }
+
+    if !found {
+        findings.push(finding(
+            Severity::Error,
+            format!("{}: no DeviceInterfaceGUIDs property; WinUSB will bind but applications cannot find the device", context),
+        ));
+    }
+}
+
+
+/// The full set of MS OS 1.0 descriptors served by a device.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MsOs1Descriptors
+{
+    pub string: Option<OsStringDescriptor>,
+    pub compat_id: Option<CompatIdDescriptor>,
+
+    /// Extended Properties descriptors by interface number. Non-composite devices only use
+    /// interface 0.
+    pub properties: BTreeMap<u8, ExtendedPropertiesDescriptor>,
+}
+
+impl MsOs1Descriptors
+{
+    /// Checks these descriptors against what Windows needs to bind WinUSB automatically.
+    pub fn validate_winusb(&self) -> Vec<Finding>
+    {
+        let mut findings = Vec::new();
+
+        if self.string.is_none() {
+            findings.push(finding(Severity::Error, "no MS OS string descriptor at index 0xEE"));
+        }
+
+        let Some(compat_id) = &self.compat_id else {
+            findings.push(finding(Severity::Error, "no Extended Compat ID descriptor"));
+            return findings;
+        };
+
+        let winusb_functions: Vec<&CompatIdFunction> = compat_id
+            .functions
+            .iter()
+            .filter(|f| f.compatible_id == "WINUSB")
+            .collect();
+        if winusb_functions.is_empty() {
+            findings.push(finding(Severity::Error, "no function has the compatible ID \"WINUSB\""));
+        }
+        for function in &compat_id.functions {
+            if function.compatible_id.eq_ignore_ascii_case("WINUSB") && function.compatible_id != "WINUSB" {
+                findings.push(finding(Severity::Error, format!("compatible ID {:?} must be upper case", function.compatible_id)));
+            }
+        }
+
+        for function in winusb_functions {
+            if !function.sub_compatible_id.is_empty() {
+                findings.push(finding(
+                    Severity::Warning,
+                    format!("interface {}: WinUSB does not use a sub-compatible ID", function.first_interface),
+                ));
+            }
+            let context = format!("interface {}", function.first_interface);
+            match self.properties.get(&function.first_interface) {
+                Some(properties) => check_interface_guids(&properties.properties, &context, &mut findings),
+                None => findings.push(finding(Severity::Error, format!("{}: no Extended Properties descriptor", context))),
+            }
+        }
+
+        findings
+    }
+}
+
+
+/// The UUID identifying the MS OS 2.0 platform capability, `{D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}`,
+/// in the byte order it appears on the wire.
+pub const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+/// The lowest Windows version that reads MS OS 2.0 descriptors, Windows 8.1.
+pub const WINDOWS_8_1: u32 = 0x0603_0000;
+
+/// One descriptor set advertised by a [PlatformCapability].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DescriptorSetInfo
+{
+    /// Minimum Windows version this set applies to, e.g. [WINDOWS_8_1].
+    pub windows_version: u32,
+    /// Total length of the descriptor set, which must match [DescriptorSet::to_bytes].
+    pub total_length: u16,
+    /// The `bRequest` value Windows uses to fetch the descriptor set.
+    pub vendor_code: u8,
+    /// Non-zero if the device supports an alternate enumeration.
+    pub alt_enum_code: u8,
+}
+
+/// The MS OS 2.0 platform capability descriptor, found in the device's BOS descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlatformCapability
+{
+    pub sets: Vec<DescriptorSetInfo>,
+}
+
+impl PlatformCapability
+{
+    const HEADER_LEN: usize = 20;
+    const SET_INFO_LEN: usize = 8;
+
+    /// A capability advertising a single descriptor set.
+    pub fn for_set(set: &DescriptorSet, vendor_code: u8) -> Self
+    {
+        Self {
+            sets: vec![DescriptorSetInfo {
+                windows_version: set.windows_version,
+                total_length: set.to_bytes().len() as u16,
+                vendor_code,
+                alt_enum_code: 0,
+            }],
+        }
+    }
+
+    /// Parses the capability from the device capability descriptor bytes.
+    // usize::is_multiple_of needs Rust 1.87.
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn parse(data: &[u8]) -> Result<Self, DescriptorError>
+    {
+        let bytes = Bytes(data);
+        let length = bytes.u8(0)? as usize;
+        if length != data.len() || length < Self::HEADER_LEN || (length - Self::HEADER_LEN) % Self::SET_INFO_LEN != 0 {
+            return error(0, "bLength does not match the descriptor length");
+        }
+        if bytes.u8(1)? != 0x10 {
+            return error(1, "bDescriptorType must be DEVICE CAPABILITY (0x10)");
+        }
+        if bytes.u8(2)? != 0x05 {
+            return error(2, "bDevCapabilityType must be PLATFORM (0x05)");
+        }
+        if bytes.slice(4, 16)? != MS_OS_20_PLATFORM_UUID {
+            return error(4, "PlatformCapabilityUUID is not the MS OS 2.0 UUID");
+        }
+
+        let sets = (Self::HEADER_LEN..length)
+            .step_by(Self::SET_INFO_LEN)
+            .map(|offset| {
+                Ok(DescriptorSetInfo {
+                    windows_version: bytes.u32(offset)?,
+                    total_length: bytes.u16(offset + 4)?,
+                    vendor_code: bytes.u8(offset + 6)?,
+                    alt_enum_code: bytes.u8(offset + 7)?,
+                })
+            })
+            .collect::<Result<_, DescriptorError>>()?;
+
+        Ok(Self { sets })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let length = Self::HEADER_LEN + self.sets.len() * Self::SET_INFO_LEN;
+        let mut out = vec![length as u8, 0x10, 0x05, 0x00];
+        out.extend(MS_OS_20_PLATFORM_UUID);
+        for set in &self.sets {
+            out.extend(set.windows_version.to_le_bytes());
+            out.extend(set.total_length.to_le_bytes());
+            out.push(set.vendor_code);
+            out.push(set.alt_enum_code);
+        }
+        out
+    }
+}
+
+
+/// A feature descriptor inside an MS OS 2.0 descriptor set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Feature
+{
+    CompatibleId
+    {
+        compatible_id: String,
+        sub_compatible_id: String,
+    },
+    RegistryProperty(RegistryProperty),
+    MinResumeTime
+    {
+        resume_recovery_time: u8,
+        resume_signaling_time: u8,
+    },
+    ModelId([u8; 16]),
+    CcgpDevice,
+    VendorRevision(u16),
+}
+
+const SET_HEADER: u16 = 0x00;
+const SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const FEATURE_REG_PROPERTY: u16 = 0x04;
+const FEATURE_MIN_RESUME_TIME: u16 = 0x05;
+const FEATURE_MODEL_ID: u16 = 0x06;
+const FEATURE_CCGP_DEVICE: u16 = 0x07;
+const FEATURE_VENDOR_REVISION: u16 = 0x08;
+
+impl Feature
+{
+    /// The `WINUSB` compatible ID feature.
+    pub fn winusb() -> Self
+    {
+        Feature::CompatibleId {
+            compatible_id: String::from("WINUSB"),
+            sub_compatible_id: String::new(),
+        }
+    }
+
+    fn parse(bytes: &Bytes, offset: usize, length: usize, kind: u16) -> Result<Self, DescriptorError>
+    {
+        let expect_len = |expected: usize| -> Result<(), DescriptorError> {
+            if length == expected { Ok(()) } else { error(offset, "feature descriptor has the wrong wLength") }
+        };
+
+        Ok(match kind {
+            FEATURE_COMPATIBLE_ID => {
+                expect_len(20)?;
+                Feature::CompatibleId {
+                    compatible_id: decode_id(bytes.slice(offset + 4, 8)?),
+                    sub_compatible_id: decode_id(bytes.slice(offset + 12, 8)?),
+                }
+            },
+            FEATURE_REG_PROPERTY => {
+                let data_type = bytes.u16(offset + 4)? as u32;
+                let name_len = bytes.u16(offset + 6)? as usize;
+                let name = utf16_to_string(bytes.slice(offset + 8, name_len)?);
+                let data_len_offset = offset + 8 + name_len;
+                let data_len = bytes.u16(data_len_offset)? as usize;
+                let value = PropertyValue::decode(data_type, bytes.slice(data_len_offset + 2, data_len)?, data_len_offset + 2)?;
+                expect_len(10 + name_len + data_len)?;
+                Feature::RegistryProperty(RegistryProperty {
+                    name: name.trim_end_matches('\0').to_string(),
+                    value,
+                })
+            },
+            FEATURE_MIN_RESUME_TIME => {
+                expect_len(6)?;
+                Feature::MinResumeTime {
+                    resume_recovery_time: bytes.u8(offset + 4)?,
+                    resume_signaling_time: bytes.u8(offset + 5)?,
+                }
+            },
+            FEATURE_MODEL_ID => {
+                expect_len(20)?;
+                let mut id = [0u8; 16];
+                id.copy_from_slice(bytes.slice(offset + 4, 16)?);
+                Feature::ModelId(id)
+            },
+            FEATURE_CCGP_DEVICE => {
+                expect_len(4)?;
+                Feature::CcgpDevice
+            },
+            FEATURE_VENDOR_REVISION => {
+                expect_len(6)?;
+                Feature::VendorRevision(bytes.u16(offset + 4)?)
+            },
+            _ => return error(offset + 2, "unknown MS OS 2.0 descriptor type"),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8>
+    {
+        let (kind, body): (u16, Vec<u8>) = match self {
+            Feature::CompatibleId { compatible_id, sub_compatible_id } => {
+                let mut body = encode_id(compatible_id).to_vec();
+                body.extend(encode_id(sub_compatible_id));
+                (FEATURE_COMPATIBLE_ID, body)
+            },
+            Feature::RegistryProperty(property) => {
+                let name = string_to_utf16z(&property.name);
+                let data = property.value.encode();
+                let mut body = (property.value.data_type() as u16).to_le_bytes().to_vec();
+                body.extend((name.len() as u16).to_le_bytes());
+                body.extend(&name);
+                body.extend((data.len() as u16).to_le_bytes());
+                body.extend(&data);
+                (FEATURE_REG_PROPERTY, body)
+            },
+            Feature::MinResumeTime { resume_recovery_time, resume_signaling_time } => {
+                (FEATURE_MIN_RESUME_TIME, vec![*resume_recovery_time, *resume_signaling_time])
+            },
+            Feature::ModelId(id) => (FEATURE_MODEL_ID, id.to_vec()),
+            Feature::CcgpDevice => (FEATURE_CCGP_DEVICE, Vec::new()),
+            Feature::VendorRevision(revision) => (FEATURE_VENDOR_REVISION, revision.to_le_bytes().to_vec()),
+        };
+
+        let mut out = ((4 + body.len()) as u16).to_le_bytes().to_vec();
+        out.extend(kind.to_le_bytes());
+        out.extend(body);
+        out
+    }
+}
+
+/// Features applying to one function (group of interfaces) of a composite device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionSubset
+{
+    pub first_interface: u8,
+    pub features: Vec<Feature>,
+}
+
+/// Features applying to one configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationSubset
+{
+    /// Despite its name in the specification (`bConfigurationValue`), this is the zero based
+    /// *index* of the configuration.
+    pub configuration_index: u8,
+    pub features: Vec<Feature>,
+    pub functions: Vec<FunctionSubset>,
+}
+
+/// An MS OS 2.0 descriptor set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorSet
+{
+    pub windows_version: u32,
+    /// Features applying to the whole device.
+    pub features: Vec<Feature>,
+    pub configurations: Vec<ConfigurationSubset>,
+}
+
+impl DescriptorSet
+{
+    /// A descriptor set binding WinUSB to a non-composite device, registering the given
+    /// device interface GUIDs.
+    pub fn winusb<I, S>(interface_guids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            windows_version: WINDOWS_8_1,
+            features: vec![
+                Feature::winusb(),
+                Feature::RegistryProperty(RegistryProperty::device_interface_guids(interface_guids)),
+            ],
+            configurations: Vec::new(),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, DescriptorError>
+    {
+        let bytes = Bytes(data);
+        if bytes.u16(0)? != 10 || bytes.u16(2)? != SET_HEADER {
+            return error(0, "descriptor set must start with a 10 byte set header");
+        }
+        let windows_version = bytes.u32(4)?;
+        if bytes.u16(8)? as usize != data.len() {
+            return error(8, "wTotalLength does not match the descriptor set length");
+        }
+
+        let mut set = Self { windows_version, features: Vec::new(), configurations: Vec::new() };
+        // Where the current configuration and function subsets end, for nesting.
+        let mut configuration_end = 0;
+        let mut function_end = 0;
+
+        let mut offset = 10;
+        while offset < data.len() {
+            let length = bytes.u16(offset)? as usize;
+            let kind = bytes.u16(offset + 2)?;
+            if length < 4 || offset + length > data.len() {
+                return error(offset, "descriptor wLength is out of bounds");
+            }
+
+            match kind {
+                SUBSET_HEADER_CONFIGURATION => {
+                    if length != 8 {
+                        return error(offset, "configuration subset header must be 8 bytes");
+                    }
+                    configuration_end = offset + bytes.u16(offset + 6)? as usize;
+                    if configuration_end > data.len() || offset > configuration_end {
+                        return error(offset + 6, "configuration subset wTotalLength is out of bounds");
+                    }
+                    set.configurations.push(ConfigurationSubset {
+                        configuration_index: bytes.u8(offset + 4)?,
+                        features: Vec::new(),
+                        functions: Vec::new(),
+                    });
+                },
+                SUBSET_HEADER_FUNCTION => {
+                    if length != 8 {
+                        return error(offset, "function subset header must be 8 bytes");
+                    }
+                    let Some(configuration) = set.configurations.last_mut().filter(|_| offset < configuration_end) else {
+                        return error(offset, "function subset outside a configuration subset");
+                    };
+                    function_end = offset + bytes.u16(offset + 6)? as usize;
+                    if function_end > configuration_end || offset > function_end {
+                        return error(offset + 6, "function subset wSubsetLength is out of bounds");
+                    }
+                    configuration.functions.push(FunctionSubset {
+                        first_interface: bytes.u8(offset + 4)?,
+                        features: Vec::new(),
+                    });
+                },
+                SET_HEADER => return error(offset, "nested descriptor set header"),
+                _ => {
+                    let feature = Feature::parse(&bytes, offset, length, kind)?;
+                    let configuration = set.configurations.last_mut().filter(|_| offset < configuration_end);
+                    match configuration {
+                        Some(configuration) => match configuration.functions.last_mut().filter(|_| offset < function_end) {
+                            Some(function) => function.features.push(feature),
+                            None => configuration.features.push(feature),
+                        },
+                        None => set.features.push(feature),
+                    }
+                },
+            }
+
+            offset += length;
+        }
+
+        Ok(set)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let features = |features: &[Feature]| -> Vec<u8> {
+            features.iter().flat_map(Feature::to_bytes).collect()
+        };
+
+        let mut body = features(&self.features);
+        for configuration in &self.configurations {
+            let mut config_body = features(&configuration.features);
+            for function in &configuration.functions {
+                let function_features = features(&function.features);
+                config_body.extend(8u16.to_le_bytes());
+                config_body.extend(SUBSET_HEADER_FUNCTION.to_le_bytes());
+                config_body.push(function.first_interface);
+                config_body.push(0);
+                config_body.extend(((8 + function_features.len()) as u16).to_le_bytes());
+                config_body.extend(function_features);
+            }
+            body.extend(8u16.to_le_bytes());
+            body.extend(SUBSET_HEADER_CONFIGURATION.to_le_bytes());
+            body.push(configuration.configuration_index);
+            body.push(0);
+            body.extend(((8 + config_body.len()) as u16).to_le_bytes());
+            body.extend(config_body);
+        }
+
+        let mut out = 10u16.to_le_bytes().to_vec();
+        out.extend(SET_HEADER.to_le_bytes());
+        out.extend(self.windows_version.to_le_bytes());
+        out.extend(((10 + body.len()) as u16).to_le_bytes());
+        out.extend(body);
+        out
+    }
+
+    /// Checks this descriptor set, and optionally the platform capability advertising it,
+    /// against what Windows needs to bind WinUSB automatically.
+    pub fn validate_winusb(&self, capability: Option<&PlatformCapability>) -> Vec<Finding>
+    {
+        let mut findings = Vec::new();
+
+        if self.windows_version < WINDOWS_8_1 {
+            findings.push(finding(
+                Severity::Error,
+                format!("dwWindowsVersion 0x{:08x} is below Windows 8.1; Windows will ignore the set", self.windows_version),
+            ));
+        }
+
+        if let Some(capability) = capability {
+            let length = self.to_bytes().len();
+            match capability.sets.iter().find(|info| info.windows_version == self.windows_version) {
+                Some(info) => {
+                    if info.total_length as usize != length {
+                        findings.push(finding(
+                            Severity::Error,
+                            format!("platform capability says the set is {} bytes, but it is {}", info.total_length, length),
+                        ));
+                    }
+                    if info.vendor_code == 0 {
+                        findings.push(finding(Severity::Warning, "platform capability uses vendor code 0"));
+                    }
+                },
+                None => findings.push(finding(
+                    Severity::Error,
+                    "platform capability does not advertise a set for this dwWindowsVersion",
+                )),
+            }
+        }
+
+        let is_winusb = |features: &[Feature]| -> bool {
+            features.iter().any(|f| matches!(f, Feature::CompatibleId { compatible_id, .. } if compatible_id == "WINUSB"))
+        };
+        let properties = |features: &[Feature]| -> Vec<RegistryProperty> {
+            features
+                .iter()
+                .filter_map(|f| match f {
+                    Feature::RegistryProperty(p) => Some(p.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let mut bound = false;
+        if is_winusb(&self.features) {
+            bound = true;
+            check_interface_guids(&properties(&self.features), "device", &mut findings);
+        }
+        for configuration in &self.configurations {
+            if configuration.configuration_index != 0 {
+                findings.push(finding(
+                    Severity::Warning,
+                    format!(
+                        "configuration subset uses bConfigurationValue {}; this is an index, and Windows only reads 0",
+                        configuration.configuration_index,
+                    ),
+                ));
+            }
+            if is_winusb(&configuration.features) {
+                bound = true;
+                check_interface_guids(&properties(&configuration.features), "configuration", &mut findings);
+            }
+            for function in &configuration.functions {
+                if is_winusb(&function.features) {
+                    bound = true;
+                    let context = format!("function at interface {}", function.first_interface);
+                    check_interface_guids(&properties(&function.features), &context, &mut findings);
+                }
+            }
+        }
+
+        if !bound {
+            findings.push(finding(Severity::Error, "no \"WINUSB\" compatible ID feature in the descriptor set"));
+        }
+
+        findings
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const GUID: &str = "{CDB3B5AD-293B-4663-AA36-1AAE46463776}";
+
+    #[test]
+    fn ms_os_1_round_trip()
+    {
+        let string = OsStringDescriptor { vendor_code: 0x21 };
+        assert_eq!(OsStringDescriptor::parse(&string.to_bytes()), Ok(string));
+
+        let compat = CompatIdDescriptor::winusb();
+        assert_eq!(compat.to_bytes().len(), 40);
+        assert_eq!(CompatIdDescriptor::parse(&compat.to_bytes()), Ok(compat.clone()));
+
+        let properties = ExtendedPropertiesDescriptor {
+            properties: vec![RegistryProperty::device_interface_guids([GUID])],
+        };
+        assert_eq!(ExtendedPropertiesDescriptor::parse(&properties.to_bytes()), Ok(properties.clone()));
+
+        let descriptors = MsOs1Descriptors {
+            string: Some(string),
+            compat_id: Some(compat),
+            properties: BTreeMap::from([(0, properties)]),
+        };
+        assert_eq!(descriptors.validate_winusb(), Vec::new());
+    }
+
+    #[test]
+    fn ms_os_2_round_trip()
+    {
+        let mut set = DescriptorSet::winusb([GUID]);
+        set.configurations.push(ConfigurationSubset {
+            configuration_index: 0,
+            features: Vec::new(),
+            functions: vec![FunctionSubset {
+                first_interface: 4,
+                features: vec![Feature::winusb(), Feature::RegistryProperty(RegistryProperty::device_interface_guids([GUID]))],
+            }],
+        });
+        let capability = PlatformCapability::for_set(&set, 0x21);
+
+        assert_eq!(DescriptorSet::parse(&set.to_bytes()), Ok(set.clone()));
+        assert_eq!(PlatformCapability::parse(&capability.to_bytes()), Ok(capability.clone()));
+        assert_eq!(set.validate_winusb(Some(&capability)), Vec::new());
+    }
+
+    #[test]
+    fn validation_catches_common_mistakes()
+    {
+        let mut set = DescriptorSet::winusb(["CDB3B5AD-293B-4663-AA36-1AAE46463776"]);
+        set.windows_version = 0x0602_0000;
+        let capability = PlatformCapability { sets: vec![DescriptorSetInfo {
+            windows_version: 0x0602_0000,
+            total_length: 10,
+            vendor_code: 0x21,
+            alt_enum_code: 0,
+        }] };
+
+        let findings = set.validate_winusb(Some(&capability));
+        assert_eq!(findings.len(), 3);
+        assert!(findings.iter().all(|f| f.severity == Severity::Error));
+
+        let mut truncated = set.to_bytes();
+        truncated.pop();
+        assert!(DescriptorSet::parse(&truncated).is_err());
+    }
+}
@@ -0,0 +1,568 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! A model of a prepared driver package: a directory holding an INF, its catalog and the
+//! per-architecture driver binaries, as written by [prepare_driver].
+
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::catalog::{self, CatalogError, PackageVerification};
+use crate::inf::Inf;
+use crate::{install_driver, prepare_driver};
+use crate::{DeviceInfo, DriverType, Error, Guid, InstallDriverOptions, PrepareDriverOptions};
+
+
+/// A Windows processor architecture a driver package may carry binaries for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum Architecture
+{
+    X86,
+    Amd64,
+    Arm64,
+}
+
+impl Architecture
+{
+    pub const ALL: [Architecture; 3] = [Architecture::X86, Architecture::Amd64, Architecture::Arm64];
+
+    /// The name used for this architecture in INF section decorations and libwdi's
+    /// extraction subdirectories, e.g. `amd64`.
+    pub fn inf_name(self) -> &'static str
+    {
+        match self {
+            Architecture::X86 => "x86",
+            Architecture::Amd64 => "amd64",
+            Architecture::Arm64 => "arm64",
+        }
+    }
+}
+
+impl Display for Architecture
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}", self.inf_name())
+    }
+}
+
+
+/// A file a driver package needs besides its INF and catalog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PackageFile
+{
+    /// The architecture this file is for, or None if the INF does not restrict it.
+    pub arch: Option<Architecture>,
+
+    /// The path of this file relative to the package directory.
+    pub path: PathBuf,
+}
+
+
+/// The error type for [DriverPackage] operations.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PackageError
+{
+    /// libwdi returned an error.
+    Wdi(Error),
+
+    /// Reading the package failed.
+    Io(io::Error),
+
+    /// Files the package's INF refers to are not present.
+    Incomplete(Vec<PathBuf>),
+
+    /// The package does not list the hardware ID of the device it was asked to install for.
+    WrongDevice
+    {
+        hardware_ids: Vec<String>,
+    },
+
+    /// The package's catalog could not be read.
+    Catalog(CatalogError),
+}
+
+impl Display for PackageError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        use PackageError::*;
+
+        match self {
+            Wdi(e) => write!(f, "{}", e),
+            Io(e) => write!(f, "I/O error: {}", e),
+            Incomplete(missing) => write!(f, "Driver package is missing files: {:?}", missing),
+            WrongDevice { hardware_ids } => {
+                write!(f, "Driver package does not target this device (package targets {:?})", hardware_ids)
+            },
+            Catalog(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PackageError
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self {
+            PackageError::Wdi(e) => Some(e),
+            PackageError::Io(e) => Some(e),
+            PackageError::Catalog(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for PackageError
+{
+    fn from(other: Error) -> Self
+    {
+        Self::Wdi(other)
+    }
+}
+
+impl From<io::Error> for PackageError
+{
+    fn from(other: io::Error) -> Self
+    {
+        Self::Io(other)
+    }
+}
+
+impl From<CatalogError> for PackageError
+{
+    fn from(other: CatalogError) -> Self
+    {
+        Self::Catalog(other)
+    }
+}
+
+
+/// The VID, PID and interface number encoded in a USB hardware or device ID such as
+/// `USB\VID_1D50&PID_6018&REV_0100&MI_00`.
+pub(crate) fn usb_id_parts(id: &str) -> (Option<u16>, Option<u16>, Option<u8>)
+{
+    let mut vid = None;
+    let mut pid = None;
+    let mut mi = None;
+
+    let id = id.split('\\').nth(1).unwrap_or(id);
+    for part in id.split('&') {
+        let upper = part.to_ascii_uppercase();
+        if let Some(v) = upper.strip_prefix("VID_") {
+            vid = u16::from_str_radix(v, 16).ok();
+        } else if let Some(p) = upper.strip_prefix("PID_") {
+            pid = u16::from_str_radix(p, 16).ok();
+        } else if let Some(m) = upper.strip_prefix("MI_") {
+            mi = u8::from_str_radix(m, 16).ok();
+        }
+    }
+
+    (vid, pid, mi)
+}
+
+
+/// Whether any of the hardware IDs in `ids` matches `device`.
+pub(crate) fn ids_target(ids: &[String], device: &DeviceInfo) -> bool
+{
+    ids.iter().any(|id| {
+        let (vid, pid, mi) = usb_id_parts(id);
+        let mi_matches = match mi {
+            Some(mi) => device.is_composite && device.mi == mi,
+            None => true,
+        };
+        vid == Some(device.vid) && pid == Some(device.pid) && mi_matches
+    })
+}
+
+
+/// A prepared driver package on disk.
+///
+/// Obtain one from [DriverPackage::prepare], which runs [prepare_driver] and then loads the
+/// result, or from [DriverPackage::load] for a package prepared earlier. Since the package
+/// remembers its own directory and INF, it can be handed straight to [DriverPackage::install].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriverPackage
+{
+    dir: PathBuf,
+    inf_name: String,
+    catalog: Option<String>,
+    driver_type: Option<DriverType>,
+    hardware_ids: Vec<String>,
+    interface_guids: Vec<Guid>,
+    binaries: Vec<PackageFile>,
+}
+
+impl DriverPackage
+{
+    /// Runs [prepare_driver] for `device`, writing the package to `path`, and loads the result.
+    pub fn prepare<P: AsRef<Path>>(device: &mut DeviceInfo, path: P, inf_name: &str, options: &mut PrepareDriverOptions)
+        -> Result<Self, PackageError>
+    {
+        let path = path.as_ref();
+        let path_str = path.to_str().ok_or(Error::InvalidParam)?;
+        prepare_driver(device, path_str, inf_name, options)?;
+
+        Self::load(path, inf_name)
+    }
+
+    /// Loads the package in `dir` described by the INF `inf_name`.
+    ///
+    /// This only fails if the INF itself cannot be read. Use [DriverPackage::validate] to check
+    /// that the rest of the package is present.
+    pub fn load<P: AsRef<Path>>(dir: P, inf_name: &str) -> Result<Self, PackageError>
+    {
+        let dir = dir.as_ref().to_path_buf();
+        let inf = Inf::open(dir.join(inf_name))?;
+
+        Ok(Self {
+            catalog: Self::catalog_from_inf(&inf),
+            driver_type: Self::driver_type_from_inf(&inf),
+            hardware_ids: Self::hardware_ids_from_inf(&inf),
+            interface_guids: Self::interface_guids_from_inf(&inf),
+            binaries: Self::binaries_from_inf(&inf),
+            dir,
+            inf_name: inf_name.to_string(),
+        })
+    }
+
+    pub(crate) fn catalog_from_inf(inf: &Inf) -> Option<String>
+    {
+        inf.value("Version", "CatalogFile").or_else(|| {
+            inf.section("Version")
+                .into_iter()
+                .find(|line| {
+                    line.key
+                        .as_deref()
+                        .map(|key| key.to_ascii_lowercase().starts_with("catalogfile."))
+                        .unwrap_or(false)
+                })
+                .map(|line| inf.expand(line.value()))
+        })
+    }
+
+    /// The generic driver whose service the INF adds, or None for any other service.
+    pub(crate) fn driver_type_from_inf(inf: &Inf) -> Option<DriverType>
+    {
+        let names: Vec<&str> = inf.section_names().collect();
+        for section in names {
+            for line in inf.entries(section, "AddService") {
+                match inf.expand(line.value()).to_ascii_lowercase().as_str() {
+                    "winusb" => return Some(DriverType::WinUsb),
+                    "libusb0" => return Some(DriverType::Libusb0),
+                    "libusbk" => return Some(DriverType::LibusbK),
+                    _ => (),
+                }
+            }
+        }
+
+        None
+    }
+
+    pub(crate) fn hardware_ids_from_inf(inf: &Inf) -> Vec<String>
+    {
+        let mut ids: Vec<String> = Vec::new();
+
+        for manufacturer in inf.section("Manufacturer") {
+            let Some((models, decorations)) = manufacturer.values.split_first() else {
+                continue;
+            };
+            let models = inf.expand(models);
+            let sections = std::iter::once(models.clone())
+                .chain(decorations.iter().map(|decoration| format!("{}.{}", models, inf.expand(decoration))));
+
+            for section in sections {
+                for line in inf.section(&section) {
+                    for id in line.values.iter().skip(1) {
+                        let id = inf.expand(id);
+                        if !id.is_empty() && !ids.iter().any(|known| known.eq_ignore_ascii_case(&id)) {
+                            ids.push(id);
+                        }
+                    }
+                }
+            }
+        }
+
+        ids
+    }
+
+    /// Collects the GUIDs from `HKR,,DeviceInterfaceGUIDs,...` (or `DeviceInterfaceGUID`) lines
+    /// in any AddReg section.
+    fn interface_guids_from_inf(inf: &Inf) -> Vec<Guid>
+    {
+        let mut guids: Vec<Guid> = Vec::new();
+
+        let names: Vec<&str> = inf.section_names().collect();
+        for section in names {
+            for line in inf.section(section) {
+                if line.key.is_some() || line.values.len() < 5 || !line.values[0].eq_ignore_ascii_case("HKR") {
+                    continue;
+                }
+                let name = inf.expand(&line.values[2]);
+                if !name.eq_ignore_ascii_case("DeviceInterfaceGUIDs") && !name.eq_ignore_ascii_case("DeviceInterfaceGUID") {
+                    continue;
+                }
+                for value in &line.values[4..] {
+                    if let Ok(guid) = inf.expand(value).parse::<Guid>() {
+                        if !guids.contains(&guid) {
+                            guids.push(guid);
+                        }
+                    }
+                }
+            }
+        }
+
+        guids
+    }
+
+    fn binaries_from_inf(inf: &Inf) -> Vec<PackageFile>
+    {
+        let mut files: Vec<PackageFile> = Vec::new();
+
+        let disk_path = |arch: Option<Architecture>, disk: &str| -> Option<String> {
+            let mut sections = vec!["SourceDisksNames".to_string()];
+            if let Some(arch) = arch {
+                sections.insert(0, format!("SourceDisksNames.{}", arch.inf_name()));
+            }
+            let line = sections
+                .iter()
+                .find_map(|section| inf.entries(section, disk).next())?;
+            Some(line.values.get(3).map(|p| inf.expand(p)).unwrap_or_default())
+        };
+
+        let arches: Vec<Option<Architecture>> = Architecture::ALL
+            .into_iter()
+            .filter(|arch| {
+                inf.has_section(&format!("SourceDisksNames.{}", arch.inf_name()))
+                    || inf.has_section(&format!("SourceDisksFiles.{}", arch.inf_name()))
+            })
+            .map(Some)
+            .collect();
+        let arches = if arches.is_empty() { vec![None] } else { arches };
+
+        for arch in arches {
+            let mut sections = vec!["SourceDisksFiles".to_string()];
+            if let Some(arch) = arch {
+                sections.push(format!("SourceDisksFiles.{}", arch.inf_name()));
+            }
+
+            for section in sections {
+                for line in inf.section(&section) {
+                    let Some(name) = line.key.as_deref() else {
+                        continue;
+                    };
+                    let disk = line.value();
+                    let mut path = PathBuf::new();
+                    if let Some(disk_dir) = disk_path(arch, disk) {
+                        path.push(disk_dir.trim_start_matches('\\').replace('\\', "/"));
+                    }
+                    if let Some(subdir) = line.values.get(1) {
+                        path.push(inf.expand(subdir).trim_start_matches('\\').replace('\\', "/"));
+                    }
+                    path.push(inf.expand(name));
+
+                    if !files.iter().any(|f| f.path == path) {
+                        files.push(PackageFile { arch, path });
+                    }
+                }
+            }
+        }
+
+        files
+    }
+
+    /// The directory containing this package.
+    pub fn dir(&self) -> &Path
+    {
+        &self.dir
+    }
+
+    /// The file name of this package's INF.
+    pub fn inf_name(&self) -> &str
+    {
+        &self.inf_name
+    }
+
+    /// The full path to this package's INF.
+    pub fn inf_path(&self) -> PathBuf
+    {
+        self.dir.join(&self.inf_name)
+    }
+
+    /// The full path to the catalog named by this package's INF, if it names one.
+    pub fn catalog_path(&self) -> Option<PathBuf>
+    {
+        self.catalog.as_ref().map(|catalog| self.dir.join(catalog))
+    }
+
+    /// The driver this package installs, as determined from its INF's service entries, or None
+    /// if it installs something other than one of libwdi's generic drivers, such as a vendor
+    /// driver or a [DriverType::User] driver.
+    pub fn driver_type(&self) -> Option<DriverType>
+    {
+        self.driver_type
+    }
+
+    /// The hardware IDs this package's INF matches, e.g. `USB\VID_1D50&PID_6018&MI_00`.
+    pub fn hardware_ids(&self) -> &[String]
+    {
+        &self.hardware_ids
+    }
+
+    /// The device interface GUIDs the INF registers, e.g. the one passed to
+    /// [PrepareDriverOptions::device_guid] or generated
+    /// by libwdi.
+    pub fn device_interface_guids(&self) -> &[Guid]
+    {
+        &self.interface_guids
+    }
+
+    /// The driver binaries and co-installers this package's INF copies.
+    pub fn binaries(&self) -> &[PackageFile]
+    {
+        &self.binaries
+    }
+
+    /// The binaries needed on `arch`.
+    pub fn binaries_for(&self, arch: Architecture) -> impl Iterator<Item = &PackageFile>
+    {
+        self.binaries.iter().filter(move |file| file.arch.is_none() || file.arch == Some(arch))
+    }
+
+    /// Returns the full paths of every file the package needs that is not present on disk.
+    pub fn missing_files(&self) -> Vec<PathBuf>
+    {
+        std::iter::once(self.inf_path())
+            .chain(self.catalog_path())
+            .chain(self.binaries.iter().map(|file| self.dir.join(&file.path)))
+            .filter(|path| !path.is_file())
+            .collect()
+    }
+
+    /// Checks that the INF, the catalog and every binary the INF refers to are present.
+    pub fn validate(&self) -> Result<(), PackageError>
+    {
+        let missing = self.missing_files();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(PackageError::Incomplete(missing))
+        }
+    }
+
+    /// Whether this package's INF lists a hardware ID matching `device`.
+    pub fn targets(&self, device: &DeviceInfo) -> bool
+    {
+        ids_target(&self.hardware_ids, device)
+    }
+
+    /// Verifies the package's files against its catalog. See [catalog::verify_package].
+    pub fn verify(&self) -> Result<PackageVerification, PackageError>
+    {
+        let catalog = self.catalog_path().ok_or_else(|| PackageError::Incomplete(vec![self.dir.join("*.cat")]))?;
+        Ok(catalog::verify_package(catalog)?)
+    }
+
+    /// Installs this package for `device` with [install_driver], after checking that the package
+    /// is complete and targets `device`.
+    pub fn install(&self, device: &mut DeviceInfo, options: &mut InstallDriverOptions) -> Result<(), PackageError>
+    {
+        self.validate()?;
+        if !self.targets(device) {
+            return Err(PackageError::WrongDevice { hardware_ids: self.hardware_ids.clone() });
+        }
+
+        let dir = self.dir.to_str().ok_or(Error::InvalidParam)?;
+        install_driver(device, dir, &self.inf_name, options)?;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs;
+
+    use super::*;
+    use crate::testing::{fixture, probe, scratch_dir};
+
+    #[test]
+    fn load_fixture_package()
+    {
+        let package = DriverPackage::load(fixture("libusbk"), "usb_device.inf").unwrap();
+
+        assert_eq!(package.inf_path(), fixture("libusbk/usb_device.inf"));
+        assert_eq!(package.catalog_path(), Some(fixture("libusbk/usb_device.cat")));
+        assert_eq!(package.driver_type(), Some(DriverType::LibusbK));
+        assert_eq!(package.hardware_ids(), [r"USB\VID_1D50&PID_6018&MI_04"]);
+        assert_eq!(package.device_interface_guids(), ["{B2E6A5C1-47C2-5D38-9A5B-0C8E9B6F0E4D}".parse::<Guid>().unwrap()]);
+
+        let amd64: Vec<&Path> = package.binaries_for(Architecture::Amd64).map(|file| file.path.as_path()).collect();
+        assert_eq!(
+            amd64,
+            [
+                Path::new("amd64/libusbK.sys"),
+                Path::new("amd64/libusbK.dll"),
+                Path::new("amd64/libusbK_x86.dll"),
+                Path::new("amd64/WdfCoInstaller01011.dll"),
+            ],
+        );
+        assert_eq!(package.binaries_for(Architecture::X86).count(), 3);
+        assert_eq!(package.binaries_for(Architecture::Arm64).count(), 0);
+
+        assert!(package.targets(&probe().mi(4).build()));
+        assert!(!package.targets(&probe().mi(0).build()));
+        assert!(!package.targets(&probe().build()));
+    }
+
+    #[test]
+    fn validate_completeness()
+    {
+        let dir = scratch_dir("package");
+        fs::copy(fixture("libusbk/usb_device.inf"), dir.join("usb_device.inf")).unwrap();
+        let package = DriverPackage::load(&dir, "usb_device.inf").unwrap();
+
+        let missing = match package.validate() {
+            Err(PackageError::Incomplete(missing)) => missing,
+            other => panic!("expected an incomplete package, got {:?}", other),
+        };
+        assert_eq!(missing.len(), 8);
+        assert_eq!(missing[0], dir.join("usb_device.cat"));
+        assert!(missing.contains(&dir.join("x86/WdfCoInstaller01011.dll")));
+
+        for path in &missing {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        package.validate().unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn driver_types()
+    {
+        // Services are matched after string expansion, case insensitively.
+        let inf = Inf::parse("[Strings]\nsvc = \"winusb\"\n[Install.Services]\nAddService = %svc%, 2, Svc\n");
+        assert_eq!(DriverPackage::driver_type_from_inf(&inf), Some(DriverType::WinUsb));
+
+        let vendor = Inf::open(fixture("driver-store/oem13.inf")).unwrap();
+        assert_eq!(DriverPackage::driver_type_from_inf(&vendor), None);
+        assert_eq!(DriverPackage::hardware_ids_from_inf(&vendor), [r"USB\VID_0403&PID_6001"]);
+        assert_eq!(DriverPackage::catalog_from_inf(&vendor).as_deref(), Some("vendor_serial.cat"));
+    }
+
+    #[test]
+    fn usb_ids()
+    {
+        assert_eq!(usb_id_parts(r"USB\VID_1D50&PID_6018&REV_0100&MI_04"), (Some(0x1d50), Some(0x6018), Some(4)));
+        assert_eq!(usb_id_parts(r"usb\vid_1d50&pid_6017"), (Some(0x1d50), Some(0x6017), None));
+        assert_eq!(usb_id_parts(r"USB\Class_02"), (None, None, None));
+    }
+}
@@ -0,0 +1,348 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Dry runs of [prepare_driver] followed by [install_driver].
+//!
+//! [plan] works out what a driver install would do without touching the system, so it can be
+//! shown to (and, with the `serde` feature, saved for) whoever has to approve it.
+//! [InstallPlan::execute] then carries it out.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::package::{Architecture, PackageFile};
+use crate::{install_driver, prepare_driver, DeviceInfo, DriverType, Error, InstallDriverOptions, PrepareDriverOptions};
+
+
+/// The files libwdi extracts besides the INF and catalog, from a table of (subdirectory, file
+/// name) like [libwdi_sys::build_info::PACKAGE_FILES]. libwdi extracts every embedded file,
+/// whichever driver is being prepared.
+fn package_files(table: &[(&str, &str)]) -> Vec<PackageFile>
+{
+    table
+        .iter()
+        .map(|(subdir, name)| {
+            let arch = Architecture::ALL.into_iter().find(|arch| arch.inf_name() == *subdir);
+            let path = if arch.is_some() { Path::new(subdir).join(name) } else { PathBuf::from(name) };
+            PackageFile { arch, path }
+        })
+        .collect()
+}
+
+/// Something that would make the install fail.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Problem
+{
+    /// This build of libwdi doesn't embed the driver. See [DriverType::is_supported].
+    UnsupportedDriver(DriverType),
+
+    /// The INF name isn't a plain file name ending in `.inf`.
+    InvalidInfName(String),
+
+    /// The package directory exists but isn't a directory.
+    NotADirectory(PathBuf),
+
+    /// [PrepareDriverOptions::external_inf] is set, but the INF isn't there.
+    MissingExternalInf(PathBuf),
+
+    /// Filter drivers are only supported by libusb0.
+    FilterNeedsLibusb0(DriverType),
+
+    /// The device has no device ID, which libwdi needs to find it when installing.
+    MissingDeviceId,
+
+    /// The device has no hardware ID, which libwdi needs to write the INF and install it.
+    MissingHardwareId,
+}
+
+impl Problem
+{
+    /// The error libwdi would (or, if checks were skipped, should) fail with.
+    pub fn to_error(&self) -> Error
+    {
+        match self {
+            Problem::UnsupportedDriver(_) => Error::NotSupported,
+            Problem::InvalidInfName(_) => Error::InvalidParam,
+            Problem::NotADirectory(_) => Error::Exists,
+            Problem::MissingExternalInf(_) => Error::NotFound,
+            Problem::FilterNeedsLibusb0(_) => Error::InvalidParam,
+            Problem::MissingDeviceId | Problem::MissingHardwareId => Error::InvalidParam,
+        }
+    }
+}
+
+impl Display for Problem
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Problem::UnsupportedDriver(driver) => write!(f, "{} is not supported by this build of libwdi", driver),
+            Problem::InvalidInfName(name) => write!(f, "{:?} is not a valid INF name", name),
+            Problem::NotADirectory(path) => write!(f, "{} is not a directory", path.display()),
+            Problem::MissingExternalInf(path) => write!(f, "External INF {} does not exist", path.display()),
+            Problem::FilterNeedsLibusb0(driver) => write!(f, "{} can't be installed as a filter driver", driver),
+            Problem::MissingDeviceId => write!(f, "The device has no device ID"),
+            Problem::MissingHardwareId => write!(f, "The device has no hardware ID"),
+        }
+    }
+}
+
+/// What [prepare_driver] and [install_driver] would do for a device, from [plan].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InstallPlan
+{
+    pub device: DeviceInfo,
+
+    /// The package directory.
+    pub path: String,
+
+    pub inf_name: String,
+
+    pub prepare: PrepareDriverOptions,
+
+    pub install: InstallDriverOptions,
+
+    /// The service name of the device's current driver, if it has one.
+    pub current_driver: Option<String>,
+
+    /// The driver that would replace it.
+    pub driver: DriverType,
+
+    /// The files [prepare_driver] would write, relative to `path`.
+    pub files: Vec<PackageFile>,
+
+    /// The subject of the self-signed certificate that would be added to the system's trusted
+    /// root and publisher stores to sign the catalog, if any.
+    pub trusted_certificate: Option<String>,
+
+    /// Everything that would make the install fail. Empty if it should succeed.
+    pub problems: Vec<Problem>,
+}
+
+/// Works out what calling [prepare_driver] and then [install_driver] with these arguments would
+/// do, without side effects.
+pub fn plan(
+    device: &DeviceInfo,
+    path: &str,
+    inf_name: &str,
+    prepare: &PrepareDriverOptions,
+    install: &InstallDriverOptions,
+) -> InstallPlan
+{
+    let driver = prepare.get_driver_type();
+    let dir = Path::new(path);
+    let mut problems = Vec::new();
+
+    if !driver.is_supported() {
+        problems.push(Problem::UnsupportedDriver(driver));
+    }
+    let valid_inf_name = inf_name.len() > ".inf".len()
+        && inf_name.to_ascii_lowercase().ends_with(".inf")
+        && !inf_name.contains(['/', '\\', ':']);
+    if !valid_inf_name {
+        problems.push(Problem::InvalidInfName(inf_name.to_string()));
+    }
+    if dir.exists() && !dir.is_dir() {
+        problems.push(Problem::NotADirectory(dir.to_path_buf()));
+    }
+    if prepare.get_external_inf() && !dir.join(inf_name).is_file() {
+        problems.push(Problem::MissingExternalInf(dir.join(inf_name)));
+    }
+    if install.get_install_filter_driver() && driver != DriverType::Libusb0 {
+        problems.push(Problem::FilterNeedsLibusb0(driver));
+    }
+    if device.device_id_str().is_none() {
+        problems.push(Problem::MissingDeviceId);
+    }
+    if device.hardware_id_str().is_none() {
+        problems.push(Problem::MissingHardwareId);
+    }
+
+    let mut files = Vec::new();
+    if !prepare.get_external_inf() {
+        files.push(PackageFile { arch: None, path: PathBuf::from(inf_name) });
+    }
+    let signs = !prepare.get_disable_cat() && !prepare.get_disable_signing();
+    if !prepare.get_disable_cat() {
+        files.push(PackageFile {
+            arch: None,
+            path: Path::new(inf_name).with_extension("cat"),
+        });
+    }
+    files.extend(package_files(libwdi_sys::build_info::PACKAGE_FILES));
+
+    let trusted_certificate = signs.then(|| match prepare.get_cert_subject() {
+        Some(subject) => subject.to_string_lossy().into_owned(),
+        None => {
+            let mut id = format!("USB\\VID_{:04X}&PID_{:04X}", device.vid, device.pid);
+            if device.is_composite {
+                id.push_str(&format!("&MI_{:02X}", device.mi));
+            }
+            format!("CN={} (libwdi autogenerated)", id)
+        },
+    });
+
+    InstallPlan {
+        device: device.clone(),
+        path: path.to_string(),
+        inf_name: inf_name.to_string(),
+        prepare: prepare.clone(),
+        install: install.clone(),
+        current_driver: device.driver_str().map(Cow::into_owned),
+        driver,
+        files,
+        trusted_certificate,
+        problems,
+    }
+}
+
+impl InstallPlan
+{
+    /// Whether no problems were found.
+    pub fn is_ready(&self) -> bool
+    {
+        self.problems.is_empty()
+    }
+
+    /// The files that would be written, as full paths.
+    pub fn file_paths(&self) -> impl Iterator<Item = PathBuf> + '_
+    {
+        self.files.iter().map(|file| Path::new(&self.path).join(&file.path))
+    }
+
+    /// Carries out the plan with [prepare_driver] and [install_driver]. Fails with the first
+    /// problem's error without doing anything if there are any problems.
+    pub fn execute(&self) -> Result<(), Error>
+    {
+        if let Some(problem) = self.problems.first() {
+            return Err(problem.to_error());
+        }
+
+        let mut device = self.device.clone();
+        prepare_driver(&mut device, &self.path, &self.inf_name, &mut self.prepare.clone())?;
+        install_driver(&mut device, &self.path, &self.inf_name, &mut self.install.clone())
+    }
+}
+
+impl Display for InstallPlan
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(
+            f,
+            "Device:       {} ({:04X}:{:04X})",
+            self.device.desc_str(),
+            self.device.vid,
+            self.device.pid,
+        )?;
+        writeln!(f, "Driver:       {} -> {}", self.current_driver.as_deref().unwrap_or("(none)"), self.driver)?;
+        writeln!(f, "Package:      {}", Path::new(&self.path).join(&self.inf_name).display())?;
+        for path in self.file_paths() {
+            writeln!(f, "  writes      {}", path.display())?;
+        }
+        if let Some(subject) = &self.trusted_certificate {
+            writeln!(f, "Trusts:       {}", subject)?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "Problem:      {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::probe;
+
+    /// The problems other than the build not supporting the driver, which depends on features.
+    fn problems(plan: &InstallPlan) -> Vec<Problem>
+    {
+        plan.problems
+            .iter()
+            .filter(|problem| !matches!(problem, Problem::UnsupportedDriver(_)))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn plan_libusbk()
+    {
+        let prepare = PrepareDriverOptions::default().driver_type(DriverType::LibusbK);
+        let device = probe()
+            .mi(4)
+            .driver("usbser")
+            .serial("6&1234&0&0004")
+            .hardware_id(r"USB\VID_1D50&PID_6018&REV_0100&MI_04")
+            .build();
+        let plan = plan(&device, "wdi-plan-test", "bmp.inf", &prepare, &Default::default());
+
+        assert_eq!(plan.current_driver.as_deref(), Some("usbser"));
+        assert_eq!(plan.driver, DriverType::LibusbK);
+        assert_eq!(plan.files[0].path, Path::new("bmp.inf"));
+        assert_eq!(plan.files[1].path, Path::new("bmp.cat"));
+        assert_eq!(plan.files.len(), 2 + libwdi_sys::build_info::PACKAGE_FILES.len());
+        assert_eq!(
+            plan.trusted_certificate.as_deref(),
+            Some(r"CN=USB\VID_1D50&PID_6018&MI_04 (libwdi autogenerated)"),
+        );
+        assert!(problems(&plan).is_empty());
+    }
+
+    #[test]
+    fn plan_problems()
+    {
+        let prepare = PrepareDriverOptions::default()
+            .driver_type(DriverType::WinUsb)
+            .disable_cat(true)
+            .external_inf(true);
+        let install = InstallDriverOptions::default().install_filter_driver(true);
+        let plan = plan(&probe().mi(4).driver("usbser").build(), "wdi-plan-test", "../bmp.txt", &prepare, &install);
+
+        assert_eq!(plan.trusted_certificate, None);
+        assert_eq!(plan.files.len(), libwdi_sys::build_info::PACKAGE_FILES.len());
+        assert_eq!(
+            problems(&plan),
+            [
+                Problem::InvalidInfName(String::from("../bmp.txt")),
+                Problem::MissingExternalInf(Path::new("wdi-plan-test").join("../bmp.txt")),
+                Problem::FilterNeedsLibusb0(DriverType::WinUsb),
+                Problem::MissingDeviceId,
+                Problem::MissingHardwareId,
+            ],
+        );
+        assert_eq!(plan.execute(), Err(plan.problems[0].to_error()));
+    }
+
+    #[test]
+    fn package_file_table()
+    {
+        let files = package_files(&[
+            ("x86", "WdfCoInstaller01011.dll"),
+            ("amd64", "libusbK_x86.dll"),
+            (".", "installer_x64.exe"),
+        ]);
+        assert_eq!(files, [
+            PackageFile { arch: Some(Architecture::X86), path: Path::new("x86").join("WdfCoInstaller01011.dll") },
+            PackageFile { arch: Some(Architecture::Amd64), path: Path::new("amd64").join("libusbK_x86.dll") },
+            PackageFile { arch: None, path: PathBuf::from("installer_x64.exe") },
+        ]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn plan_round_trip()
+    {
+        let prepare = PrepareDriverOptions::default().driver_type(DriverType::Libusb0);
+        let plan = plan(&probe().mi(4).driver("usbser").build(), "wdi-plan-test", "bmp.inf", &prepare, &Default::default());
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(serde_json::from_str::<InstallPlan>(&json).unwrap(), plan);
+    }
+}
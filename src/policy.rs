@@ -0,0 +1,429 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Declarative driver assignments, with the `policy` feature.
+//!
+//! A [Policy] is a list of [Rule]s, each assigning a driver to the devices its matcher selects,
+//! and is usually read from a TOML file:
+//!
+//! ```toml
+//! # Where driver packages are extracted, one subdirectory per rule. Defaults to a directory
+//! # under the system temporary directory.
+//! driver_dir = 'C:\wdi'
+//!
+//! [[rule]]
+//! name = "Black Magic Probe GDB server"
+//! match = { vid = "1D50", pid = "6018", mi = "00" }
+//! driver = "WinUSB"
+//!
+//! [[rule]]
+//! name = "Black Magic Probe DFU"
+//! match = { vid = "1D50", pid = "6017" }
+//! driver = "libusbK"
+//! inf_name = "bmp_dfu.inf"
+//! prepare = { vendor_name = "1BitSquared", disable_signing = true }
+//! install = { pending_install_timeout = 120000 }
+//! ```
+//!
+//! Matchers, `prepare` and `install` use the same representation as the `serde` feature (see
+//! the [DeviceQuery] and [PrepareDriverOptions] docs). Every matcher needs a `vid` or a
+//! `device_id`, and unknown keys are errors, so a typo can't make a rule match every device.
+//! [Policy::apply] installs the rule's driver on every connected device that matches it and
+//! isn't already bound to that driver.
+
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+
+use crate::query::bound_to;
+use crate::{
+    create_list, install_driver, prepare_driver, CreateListOptions, DeviceInfo, DeviceQuery, DriverType, Error,
+    InstallDriverOptions, PrepareDriverOptions,
+};
+
+
+/// The error type for reading and writing policy files.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PolicyError
+{
+    Io(io::Error),
+
+    /// The policy isn't valid TOML, or doesn't describe a policy.
+    Parse(toml::de::Error),
+
+    Serialize(toml::ser::Error),
+}
+
+impl Display for PolicyError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        use PolicyError::*;
+
+        match self {
+            Io(e) => write!(f, "I/O error: {}", e),
+            Parse(e) => write!(f, "Invalid policy: {}", e),
+            Serialize(e) => write!(f, "Error serializing policy: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self {
+            PolicyError::Io(e) => Some(e),
+            PolicyError::Parse(e) => Some(e),
+            PolicyError::Serialize(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for PolicyError
+{
+    fn from(other: io::Error) -> Self
+    {
+        Self::Io(other)
+    }
+}
+
+impl From<toml::de::Error> for PolicyError
+{
+    fn from(other: toml::de::Error) -> Self
+    {
+        Self::Parse(other)
+    }
+}
+
+impl From<toml::ser::Error> for PolicyError
+{
+    fn from(other: toml::ser::Error) -> Self
+    {
+        Self::Serialize(other)
+    }
+}
+
+
+/// A driver assignment: every device matching `query` should be bound to `driver`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule
+{
+    /// Only used in reports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// The `match` table. Must have a `vid` or `device_id`.
+    #[serde(rename = "match", deserialize_with = "specific_query")]
+    pub query: DeviceQuery,
+
+    /// Overrides `prepare`'s driver type.
+    pub driver: DriverType,
+
+    /// The INF to generate. Defaults to `usb_device.inf`, or [USER_DRIVER_INF](crate::USER_DRIVER_INF)
+    /// for [DriverType::User].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inf_name: Option<String>,
+
+    #[serde(default)]
+    pub prepare: PrepareDriverOptions,
+
+    #[serde(default)]
+    pub install: InstallDriverOptions,
+}
+
+/// Deserializes a [DeviceQuery] that can't match every device.
+fn specific_query<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DeviceQuery, D::Error>
+{
+    let query = DeviceQuery::deserialize(deserializer)?;
+    if query.get_vid().is_none() && query.get_device_id().is_none() {
+        return Err(de::Error::custom("a rule's match needs a vid or a device_id"));
+    }
+
+    Ok(query)
+}
+
+impl Rule
+{
+    /// Whether `device` is already bound to this rule's driver.
+    pub fn is_satisfied_by(&self, device: &DeviceInfo) -> bool
+    {
+        bound_to(device, self.driver)
+    }
+
+    pub fn inf_name(&self) -> &str
+    {
+        match (&self.inf_name, self.driver) {
+            (Some(inf_name), _) => inf_name,
+            #[cfg(feature = "user-driver")]
+            (None, DriverType::User) => crate::USER_DRIVER_INF,
+            (None, _) => "usb_device.inf",
+        }
+    }
+}
+
+/// A set of driver assignments. When several rules match a device, the first one applies.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy
+{
+    /// Where driver packages are extracted, in a `rule-<n>` subdirectory for rule n. Defaults
+    /// to `wdi-policy` in the system temporary directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub driver_dir: Option<PathBuf>,
+
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl Policy
+{
+    pub fn from_toml(text: &str) -> Result<Self, PolicyError>
+    {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, PolicyError>
+    {
+        Ok(toml::to_string(self)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PolicyError>
+    {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PolicyError>
+    {
+        Ok(fs::write(path, self.to_toml()?)?)
+    }
+
+    /// The index of the first rule matching `device`.
+    pub fn rule_for(&self, device: &DeviceInfo) -> Option<usize>
+    {
+        self.rules.iter().position(|rule| rule.query.matches(device))
+    }
+
+    /// The directory rule `index`'s driver package is extracted to.
+    pub fn package_dir(&self, index: usize) -> PathBuf
+    {
+        self.driver_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("wdi-policy"))
+            .join(format!("rule-{}", index))
+    }
+
+    /// Enumerates the connected devices, and prepares and installs the matching rule's driver
+    /// for every device not already bound to it. A failed install is recorded in the report and
+    /// doesn't stop the others; only a failed enumeration is an error.
+    pub fn apply(&self) -> Result<PolicyReport, Error>
+    {
+        let options = CreateListOptions {
+            list_all: true,
+            ..Default::default()
+        };
+        let devices = match create_list(options) {
+            Err(Error::NoDevice) => Vec::new(),
+            result => result?,
+        };
+
+        let mut report = PolicyReport::default();
+        for (rule, mut device) in self.matching(devices) {
+            let outcome = if self.rules[rule].is_satisfied_by(&device) {
+                Outcome::AlreadyBound
+            } else {
+                match self.install(rule, &mut device) {
+                    Ok(()) => Outcome::Installed,
+                    Err(e) => Outcome::Failed(e),
+                }
+            };
+            report.devices.push(DeviceReport { device, rule, outcome });
+        }
+
+        Ok(report)
+    }
+
+    /// The devices that match a rule, with that rule's index.
+    fn matching(&self, devices: Vec<DeviceInfo>) -> Vec<(usize, DeviceInfo)>
+    {
+        devices
+            .into_iter()
+            .filter_map(|device| Some((self.rule_for(&device)?, device)))
+            .collect()
+    }
+
+    fn install(&self, index: usize, device: &mut DeviceInfo) -> Result<(), Error>
+    {
+        let rule = &self.rules[index];
+        let dir = self.package_dir(index);
+        let dir = dir.to_str().ok_or(Error::InvalidParam)?;
+
+        let mut prepare = rule.prepare.clone().driver_type(rule.driver);
+        prepare_driver(device, dir, rule.inf_name(), &mut prepare)?;
+        install_driver(device, dir, rule.inf_name(), &mut rule.install.clone())
+    }
+}
+
+/// What [Policy::apply] did for a device.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Outcome
+{
+    /// The device was already bound to the rule's driver, so nothing was done.
+    AlreadyBound,
+
+    Installed,
+
+    /// Preparing or installing the driver failed.
+    Failed(Error),
+}
+
+/// What [Policy::apply] did for one device matching a rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceReport
+{
+    /// As enumerated, before any install.
+    pub device: DeviceInfo,
+
+    /// The index of the rule that matched.
+    pub rule: usize,
+
+    pub outcome: Outcome,
+}
+
+/// The result of [Policy::apply], with an entry for every connected device that matched a rule.
+/// Devices no rule matched aren't included.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PolicyReport
+{
+    pub devices: Vec<DeviceReport>,
+}
+
+impl PolicyReport
+{
+    /// Whether every matching device now has its rule's driver.
+    pub fn is_success(&self) -> bool
+    {
+        self.failures().next().is_none()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &DeviceReport>
+    {
+        self.devices.iter().filter(|report| matches!(report.outcome, Outcome::Failed(_)))
+    }
+}
+
+/// One line per device, e.g. `rule 0: Black Magic Probe (1D50:6018): installed`.
+impl Display for PolicyReport
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        for report in &self.devices {
+            let device = &report.device;
+            write!(
+                f,
+                "rule {}: {} ({:04X}:{:04X}): ",
+                report.rule,
+                device.desc_str(),
+                device.vid,
+                device.pid,
+            )?;
+            match report.outcome {
+                Outcome::AlreadyBound => writeln!(f, "already bound")?,
+                Outcome::Installed => writeln!(f, "installed")?,
+                Outcome::Failed(e) => writeln!(f, "failed: {}", e)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::probe;
+
+    const POLICY: &str = r#"
+        driver_dir = "C:/wdi"
+
+        [[rule]]
+        name = "GDB server"
+        match = { vid = "1D50", pid = "6018", mi = "00" }
+        driver = "WinUSB"
+
+        [[rule]]
+        match = { vid = "0x1d50" }
+        driver = "libusbK"
+        inf_name = "bmp.inf"
+        prepare = { vendor_name = "1BitSquared", disable_signing = true }
+        install = { pending_install_timeout = 120000 }
+    "#;
+
+    #[test]
+    fn parse_and_round_trip()
+    {
+        let policy = Policy::from_toml(POLICY).unwrap();
+        assert_eq!(policy.rules.len(), 2);
+        assert_eq!(policy.rules[0].query, DeviceQuery::new().vid(0x1d50).pid(0x6018).mi(0));
+        assert_eq!(policy.rules[0].inf_name(), "usb_device.inf");
+        assert_eq!(policy.rules[1].driver, DriverType::LibusbK);
+        assert_eq!(policy.rules[1].inf_name(), "bmp.inf");
+        assert!(policy.rules[1].prepare.get_disable_signing());
+        assert_eq!(policy.rules[1].install.get_pending_install_timeout(), 120000);
+        assert_eq!(policy.package_dir(1), Path::new("C:/wdi").join("rule-1"));
+
+        assert_eq!(Policy::from_toml(&policy.to_toml().unwrap()).unwrap(), policy);
+
+        assert!(matches!(Policy::from_toml("[[rule]]\nmatch = { vid = \"1D50\" }\n"), Err(PolicyError::Parse(_))));
+    }
+
+    #[test]
+    fn reject_rules_matching_everything()
+    {
+        let invalid = [
+            // No matcher at all.
+            "[[rule]]\ndriver = \"WinUSB\"\n",
+            "[[rule]]\nmatch = {}\ndriver = \"WinUSB\"\n",
+            "[[rule]]\nmatch = { pid = \"6018\", mi = \"00\" }\ndriver = \"WinUSB\"\n",
+            // Unknown keys, like a misspelt vid or the old top level matcher.
+            "[[rule]]\nmatch = { vendor = \"1D50\" }\ndriver = \"WinUSB\"\n",
+            "[[rule]]\nvid = \"1D50\"\nmatch = { device_id = \"USB\\\\VID_1D50&PID_6018\\\\1\" }\ndriver = \"WinUSB\"\n",
+            "driver-dir = \"C:/wdi\"\n",
+        ];
+        for text in invalid {
+            assert!(matches!(Policy::from_toml(text), Err(PolicyError::Parse(_))), "{}", text);
+        }
+
+        let policy = Policy::from_toml("[[rule]]\nmatch = { device_id = 'USB\\VID_1D50&PID_6018\\1' }\ndriver = \"WinUSB\"\n").unwrap();
+        assert_eq!(policy.rules[0].query, DeviceQuery::new().device_id(r"USB\VID_1D50&PID_6018\1"));
+    }
+
+    #[test]
+    fn first_matching_rule_applies()
+    {
+        let policy = Policy::from_toml(POLICY).unwrap();
+        let devices = vec![
+            probe().mi(0).driver("WinUSB").build(),
+            probe().mi(2).driver("usbser").build(),
+            probe().pid(0x6017).build(),
+        ];
+
+        let matching = policy.matching(devices);
+        let rules: Vec<usize> = matching.iter().map(|(rule, _)| *rule).collect();
+        assert_eq!(rules, [0, 1, 1]);
+
+        let satisfied: Vec<bool> = matching
+            .iter()
+            .map(|(rule, device)| policy.rules[*rule].is_satisfied_by(device))
+            .collect();
+        assert_eq!(satisfied, [true, false, false]);
+    }
+}
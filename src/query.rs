@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Selecting devices, and waiting for them to show up.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{create_list, CreateListOptions, DeviceInfo, DriverType, Error};
+
+
+/// How often [wait_for_device] and [wait_for_driver] enumerate devices.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Which devices to match. Every criterion that is set must match; an empty query matches any
+/// device.
+///
+/// ```
+/// use wdi::DeviceQuery;
+///
+/// // Interface 4 of a Black Magic Probe.
+/// let query = DeviceQuery::new().vid(0x1d50).pid(0x6018).mi(4);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeviceQuery
+{
+    vid: Option<u16>,
+    pid: Option<u16>,
+    mi: Option<u8>,
+    device_id: Option<String>,
+}
+
+impl DeviceQuery
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Matches devices with this USB vendor ID.
+    pub fn vid(self, vid: u16) -> Self
+    {
+        Self { vid: Some(vid), ..self }
+    }
+
+    /// Matches devices with this USB product ID.
+    pub fn pid(self, pid: u16) -> Self
+    {
+        Self { pid: Some(pid), ..self }
+    }
+
+    /// Matches this interface of composite devices. Non-composite devices never match.
+    pub fn mi(self, mi: u8) -> Self
+    {
+        Self { mi: Some(mi), ..self }
+    }
+
+    /// Matches the device with this device instance ID, case insensitively.
+    pub fn device_id(self, device_id: &str) -> Self
+    {
+        Self {
+            device_id: Some(device_id.to_string()),
+            ..self
+        }
+    }
+
+    // Option::is_none_or needs Rust 1.82.
+    #[allow(clippy::unnecessary_map_or)]
+    pub fn matches(&self, device: &DeviceInfo) -> bool
+    {
+        let device_id_matches = |id: &String| {
+            device
+                .device_id_str()
+                .map(|d| d.eq_ignore_ascii_case(id))
+                .unwrap_or(false)
+        };
+
+        self.vid.map_or(true, |vid| device.vid == vid)
+            && self.pid.map_or(true, |pid| device.pid == pid)
+            && self.mi.map_or(true, |mi| device.is_composite && device.mi == mi)
+            && self.device_id.as_ref().map_or(true, device_id_matches)
+    }
+}
+
+/// Getters, with non-standard names due to the builder API.
+impl DeviceQuery
+{
+    pub fn get_vid(&self) -> Option<u16>
+    {
+        self.vid
+    }
+
+    pub fn get_pid(&self) -> Option<u16>
+    {
+        self.pid
+    }
+
+    pub fn get_mi(&self) -> Option<u8>
+    {
+        self.mi
+    }
+
+    pub fn get_device_id(&self) -> Option<&str>
+    {
+        self.device_id.as_deref()
+    }
+}
+
+/// Whether `device` is bound to `driver`. Since libwdi reports the driver's service name, a
+/// [DriverType::User] driver is taken to be any bound driver other than WinUSB, libusb0 and
+/// libusbK.
+pub(crate) fn bound_to(device: &DeviceInfo, driver: DriverType) -> bool
+{
+    let Some(name) = device.driver_str() else {
+        return false;
+    };
+
+    match name.parse::<DriverType>() {
+        Ok(bound) => bound == driver,
+        Err(_) => driver == DriverType::User && !name.is_empty(),
+    }
+}
+
+/// Blocks until a device matching `query` is connected, with or without a driver, returning the
+/// first one found. Fails with [Error::Timeout] if none shows up within `timeout`.
+///
+/// Any other error from [create_list], except that no devices at all are connected, is returned
+/// immediately.
+pub fn wait_for_device(query: &DeviceQuery, timeout: Duration) -> Result<DeviceInfo, Error>
+{
+    wait_for(timeout, |device| query.matches(device))
+}
+
+/// Like [wait_for_device], but the device must also be bound to `driver`, e.g. after
+/// [install_driver](crate::install_driver) or when it re-enumerates with a driver that was
+/// already installed.
+pub fn wait_for_driver(query: &DeviceQuery, driver: DriverType, timeout: Duration) -> Result<DeviceInfo, Error>
+{
+    wait_for(timeout, |device| query.matches(device) && bound_to(device, driver))
+}
+
+fn wait_for<P: FnMut(&DeviceInfo) -> bool>(timeout: Duration, mut predicate: P) -> Result<DeviceInfo, Error>
+{
+    // Devices that already have a driver are only listed with list_all.
+    let options = CreateListOptions {
+        list_all: true,
+        ..Default::default()
+    };
+    // Timeouts too long to represent, like Duration::MAX, never expire.
+    let deadline = Instant::now().checked_add(timeout);
+
+    loop {
+        let devices = match create_list(options) {
+            Err(Error::NoDevice) => Vec::new(),
+            result => result?,
+        };
+        if let Some(device) = devices.into_iter().find(|device| predicate(device)) {
+            return Ok(device);
+        }
+
+        let now = Instant::now();
+        let remaining = match deadline {
+            Some(deadline) if now >= deadline => return Err(Error::Timeout),
+            Some(deadline) => deadline - now,
+            None => POLL_INTERVAL,
+        };
+        thread::sleep(POLL_INTERVAL.min(remaining));
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::probe;
+
+    #[test]
+    fn query_matching()
+    {
+        let bmp = probe().mi(4).serial("6&1234&0&0004").build();
+
+        assert!(DeviceQuery::new().matches(&bmp));
+        assert!(DeviceQuery::new().vid(0x1d50).pid(0x6018).mi(4).matches(&bmp));
+        assert!(!DeviceQuery::new().vid(0x1d50).pid(0x6017).matches(&bmp));
+        assert!(!DeviceQuery::new().mi(0).matches(&probe().build()));
+        assert!(DeviceQuery::new()
+            .device_id(r"usb\vid_1d50&pid_6018&mi_04\6&1234&0&0004")
+            .matches(&bmp));
+    }
+
+    #[test]
+    fn driver_binding()
+    {
+        assert!(bound_to(&probe().driver("WinUSB").build(), DriverType::WinUsb));
+        assert!(bound_to(&probe().driver("libusbK").build(), DriverType::LibusbK));
+        assert!(!bound_to(&probe().driver("libusbK").build(), DriverType::WinUsb));
+        assert!(!bound_to(&probe().build(), DriverType::WinUsb));
+        assert!(bound_to(&probe().driver("bmp_dfu").build(), DriverType::User));
+        assert!(!bound_to(&probe().driver("WinUSB").build(), DriverType::User));
+    }
+
+    #[cfg(feature = "stub")]
+    #[test]
+    fn wait_returns_other_errors()
+    {
+        let result = wait_for_device(&DeviceQuery::new(), Duration::from_secs(10));
+        assert_eq!(result, Err(Error::NotSupported));
+
+        // Doesn't overflow computing the deadline.
+        let result = wait_for_driver(&DeviceQuery::new(), DriverType::WinUsb, Duration::MAX);
+        assert_eq!(result, Err(Error::NotSupported));
+    }
+}
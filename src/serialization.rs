@@ -0,0 +1,530 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! serde support, with the `serde` feature.
+//!
+//! The representations are meant to be read and written by people as much as by programs:
+//! libwdi's NUL-terminated strings are plain strings, VIDs, PIDs and interface numbers are hex
+//! strings as in hardware IDs (e.g. `"1D50"`), driver versions are dotted
+//! (e.g. `"6.1.7600.16385"`), and driver types, GUIDs and errors are their names. Fields that
+//! are unset or false may be left out when deserializing, so option presets only need to
+//! mention what they change.
+//!
+//! The window handles in [InstallDriverOptions] can't be meaningfully serialized, so they're
+//! skipped, and are always null after deserializing.
+
+use std::ffi::CString;
+use std::fmt;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::{c_text, CreateListOptions, DeviceInfo, DeviceQuery, DriverType, Error, Guid, InstallDriverOptions, PrepareDriverOptions};
+
+
+/// Serializes as a hex string with a fixed number of digits, and deserializes from a hex string
+/// with or without a `0x` prefix.
+macro_rules! hex_module {
+    ($name:ident, $ty:ty, $width:literal) => {
+        mod $name
+        {
+            use super::*;
+
+            pub fn serialize<S: Serializer>(value: &$ty, serializer: S) -> Result<S::Ok, S::Error>
+            {
+                serializer.serialize_str(&format!(concat!("{:0", $width, "X}"), value))
+            }
+
+            pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<$ty, D::Error>
+            {
+                let s = String::deserialize(deserializer)?;
+                let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(&s);
+                <$ty>::from_str_radix(digits, 16)
+                    .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&s), &"a hex number"))
+            }
+        }
+    };
+}
+
+hex_module!(hex_u16, u16, 4);
+hex_module!(hex_u8, u8, 2);
+
+#[derive(Serialize, Deserialize)]
+struct HexU16(#[serde(with = "hex_u16")] u16);
+
+#[derive(Serialize, Deserialize)]
+struct HexU8(#[serde(with = "hex_u8")] u8);
+
+/// A NUL-terminated byte string, without its terminator.
+fn to_text(bytes: &[u8]) -> String
+{
+    c_text(bytes).into_owned()
+}
+
+/// The NUL-terminated byte string for `text`.
+fn from_text<E: de::Error>(text: String) -> Result<Vec<u8>, E>
+{
+    CString::new(text)
+        .map(CString::into_bytes_with_nul)
+        .map_err(|_| E::custom("strings can't contain NUL"))
+}
+
+fn from_optional_text<E: de::Error>(text: Option<String>) -> Result<Option<Vec<u8>>, E>
+{
+    text.map(from_text).transpose()
+}
+
+fn to_cstring<E: de::Error>(text: Option<String>) -> Result<Option<CString>, E>
+{
+    text.map(CString::new)
+        .transpose()
+        .map_err(|_| E::custom("strings can't contain NUL"))
+}
+
+fn is_false(value: &bool) -> bool
+{
+    !value
+}
+
+fn is_zero(value: &u32) -> bool
+{
+    *value == 0
+}
+
+
+/// Serializes as its name, e.g. `"WinUSB"`, and deserializes from any name [DriverType]'s
+/// [FromStr](std::str::FromStr) implementation accepts.
+impl Serialize for DriverType
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DriverType
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|_| {
+            de::Error::invalid_value(de::Unexpected::Str(&s), &"WinUSB, libusb0, libusbK or user")
+        })
+    }
+}
+
+
+/// Serializes in the braced form, e.g. `"{88BAE032-5A81-49F0-BC3D-A4FF138216D6}"`, and
+/// deserializes from braced or unbraced forms.
+impl Serialize for Guid
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Guid
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        struct GuidVisitor;
+
+        impl Visitor<'_> for GuidVisitor
+        {
+            type Value = Guid;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result
+            {
+                write!(f, "a GUID string")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Guid, E>
+            {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(GuidVisitor)
+    }
+}
+
+
+/// Serializes as the variant's name, e.g. `"NoDevice"`.
+impl Serialize for Error
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        serializer.serialize_str(&format!("{:?}", self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Error
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let s = String::deserialize(deserializer)?;
+        (-99..0)
+            .filter_map(Error::from_error_code)
+            .find(|e| format!("{:?}", e) == s)
+            .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(&s), &"a wdi::Error variant name"))
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct DeviceInfoRepr
+{
+    #[serde(with = "hex_u16")]
+    vid: u16,
+    #[serde(with = "hex_u16")]
+    pid: u16,
+    #[serde(default, skip_serializing_if = "is_false")]
+    is_composite: bool,
+    #[serde(with = "hex_u8", default)]
+    mi: u8,
+    desc: String,
+    #[serde(default)]
+    driver: Option<String>,
+    #[serde(default)]
+    device_id: Option<String>,
+    #[serde(default)]
+    hardware_id: Option<String>,
+    #[serde(default)]
+    compatible_id: Option<String>,
+    #[serde(default)]
+    upper_filter: Option<String>,
+    #[serde(default)]
+    driver_version: DriverVersion,
+}
+
+/// libwdi's packed driver version, as `major.minor.build.revision`.
+#[derive(Default)]
+struct DriverVersion(u64);
+
+impl Serialize for DriverVersion
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        let v = self.0;
+        serializer.collect_str(&format_args!("{}.{}.{}.{}", v >> 48, (v >> 32) & 0xffff, (v >> 16) & 0xffff, v & 0xffff))
+    }
+}
+
+impl<'de> Deserialize<'de> for DriverVersion
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let s = String::deserialize(deserializer)?;
+        let parts: Result<Vec<u16>, _> = s.split('.').map(str::parse).collect();
+        match parts.as_deref() {
+            Ok(&[major, minor, build, revision]) => Ok(DriverVersion(
+                (major as u64) << 48 | (minor as u64) << 32 | (build as u64) << 16 | revision as u64,
+            )),
+            _ => Err(de::Error::invalid_value(de::Unexpected::Str(&s), &"a version like 6.1.7600.16385")),
+        }
+    }
+}
+
+impl Serialize for DeviceInfo
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        DeviceInfoRepr {
+            vid: self.vid,
+            pid: self.pid,
+            is_composite: self.is_composite,
+            mi: self.mi,
+            desc: to_text(&self.desc),
+            driver: self.driver.as_deref().map(to_text),
+            device_id: self.device_id.as_deref().map(to_text),
+            hardware_id: self.hardware_id.as_deref().map(to_text),
+            compatible_id: self.compatible_id.as_deref().map(to_text),
+            upper_filter: self.upper_filter.as_deref().map(to_text),
+            driver_version: DriverVersion(self.driver_version),
+        }
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceInfo
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let repr = DeviceInfoRepr::deserialize(deserializer)?;
+        Ok(Self {
+            vid: repr.vid,
+            pid: repr.pid,
+            is_composite: repr.is_composite,
+            mi: repr.mi,
+            desc: from_text(repr.desc)?,
+            driver: from_optional_text(repr.driver)?,
+            device_id: from_optional_text(repr.device_id)?,
+            hardware_id: from_optional_text(repr.hardware_id)?,
+            compatible_id: from_optional_text(repr.compatible_id)?,
+            upper_filter: from_optional_text(repr.upper_filter)?,
+            driver_version: repr.driver_version.0,
+        })
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct CreateListOptionsRepr
+{
+    #[serde(default, skip_serializing_if = "is_false")]
+    list_all: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    list_hubs: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    trim_whitespaces: bool,
+}
+
+impl Serialize for CreateListOptions
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        CreateListOptionsRepr {
+            list_all: self.list_all,
+            list_hubs: self.list_hubs,
+            trim_whitespaces: self.trim_whitespaces,
+        }
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CreateListOptions
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let repr = CreateListOptionsRepr::deserialize(deserializer)?;
+        Ok(Self {
+            list_all: repr.list_all,
+            list_hubs: repr.list_hubs,
+            trim_whitespaces: repr.trim_whitespaces,
+        })
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct PrepareDriverOptionsRepr
+{
+    #[serde(default)]
+    driver_type: DriverType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vendor_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_guid: Option<Guid>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    disable_cat: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    disable_signing: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cert_subject: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    use_wcid_driver: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    external_inf: bool,
+}
+
+impl Serialize for PrepareDriverOptions
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        PrepareDriverOptionsRepr {
+            driver_type: self.driver_type,
+            vendor_name: self.vendor_name.as_deref().map(to_text),
+            device_guid: self.get_device_guid(),
+            disable_cat: self.disable_cat,
+            disable_signing: self.disable_signing,
+            cert_subject: self.cert_subject.as_deref().map(to_text),
+            use_wcid_driver: self.use_wcid_driver,
+            external_inf: self.external_inf,
+        }
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PrepareDriverOptions
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let repr = PrepareDriverOptionsRepr::deserialize(deserializer)?;
+        Ok(Self::default()
+            .driver_type(repr.driver_type)
+            .device_guid(repr.device_guid)
+            .disable_cat(repr.disable_cat)
+            .disable_signing(repr.disable_signing)
+            .use_wcid_driver(repr.use_wcid_driver)
+            .external_inf(repr.external_inf)
+            .vendor_name(to_cstring(repr.vendor_name)?)
+            .cert_subject(to_cstring(repr.cert_subject)?))
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct InstallDriverOptionsRepr
+{
+    #[serde(default, skip_serializing_if = "is_false")]
+    install_filter_driver: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pending_install_timeout: u32,
+}
+
+impl Serialize for InstallDriverOptions
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        InstallDriverOptionsRepr {
+            install_filter_driver: self.install_filter_driver,
+            pending_install_timeout: self.pending_install_timeout,
+        }
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for InstallDriverOptions
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let repr = InstallDriverOptionsRepr::deserialize(deserializer)?;
+        Ok(Self::default()
+            .install_filter_driver(repr.install_filter_driver)
+            .pending_install_timeout(repr.pending_install_timeout))
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceQueryRepr
+{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vid: Option<HexU16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pid: Option<HexU16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mi: Option<HexU8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+}
+
+impl Serialize for DeviceQuery
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        DeviceQueryRepr {
+            vid: self.get_vid().map(HexU16),
+            pid: self.get_pid().map(HexU16),
+            mi: self.get_mi().map(HexU8),
+            device_id: self.get_device_id().map(String::from),
+        }
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceQuery
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let repr = DeviceQueryRepr::deserialize(deserializer)?;
+        let mut query = Self::new();
+        if let Some(HexU16(vid)) = repr.vid {
+            query = query.vid(vid);
+        }
+        if let Some(HexU16(pid)) = repr.pid {
+            query = query.pid(pid);
+        }
+        if let Some(HexU8(mi)) = repr.mi {
+            query = query.mi(mi);
+        }
+        if let Some(device_id) = repr.device_id {
+            query = query.device_id(&device_id);
+        }
+        Ok(query)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn round_trip<T>(value: &T) -> String
+    where
+        T: Serialize + for<'de> Deserialize<'de> + PartialEq + fmt::Debug,
+    {
+        let json = serde_json::to_string(value).unwrap();
+        let back: T = serde_json::from_str(&json).unwrap();
+        assert_eq!(&back, value, "{}", json);
+        json
+    }
+
+    #[test]
+    fn device_info()
+    {
+        let device = DeviceInfo {
+            vid: 0x1d50,
+            pid: 0x6018,
+            is_composite: true,
+            mi: 4,
+            desc: b"Black Magic Probe (DFU)\0".to_vec(),
+            driver: Some(b"WinUSB\0".to_vec()),
+            device_id: Some(b"USB\\VID_1D50&PID_6018&MI_04\\6&1234&0&0004\0".to_vec()),
+            hardware_id: Some(b"USB\\VID_1D50&PID_6018&REV_0100&MI_04\0".to_vec()),
+            compatible_id: None,
+            upper_filter: None,
+            driver_version: (6 << 48) | (1 << 32) | (7600 << 16) | 16385,
+        };
+
+        let json = round_trip(&device);
+        assert!(json.contains(r#""vid":"1D50","pid":"6018""#), "{}", json);
+        assert!(json.contains(r#""mi":"04","desc":"Black Magic Probe (DFU)""#), "{}", json);
+        assert!(json.contains(r#""driver_version":"6.1.7600.16385""#), "{}", json);
+
+        let minimal: DeviceInfo = serde_json::from_str(r#"{"vid":"0x1d50","pid":"6018","desc":"Probe"}"#).unwrap();
+        assert_eq!(minimal.desc, b"Probe\0");
+        assert_eq!(minimal.driver_version, 0);
+    }
+
+    #[test]
+    fn options()
+    {
+        round_trip(&CreateListOptions { list_all: true, list_hubs: false, trim_whitespaces: true });
+        assert_eq!(round_trip(&CreateListOptions::default()), "{}");
+
+        let prepare = PrepareDriverOptions::default()
+            .driver_type(DriverType::LibusbK)
+            .vendor_name(Some(CString::new("1BitSquared").unwrap()))
+            .device_guid(Some(Guid::for_device(0x1d50, 0x6018, Some(4))))
+            .disable_signing(true)
+            .cert_subject(Some(CString::new("CN=Test").unwrap()));
+        let json = round_trip(&prepare);
+        assert!(json.contains(r#""driver_type":"libusbK""#), "{}", json);
+        assert!(json.contains(r#""device_guid":"{79912E3F-D52D-5BCF-8939-6BD30C1D2900}""#), "{}", json);
+
+        round_trip(&InstallDriverOptions::default().install_filter_driver(true).pending_install_timeout(5000));
+
+        let json = round_trip(&DeviceQuery::new().vid(0x1d50).pid(0x6018).mi(4));
+        assert_eq!(json, r#"{"vid":"1D50","pid":"6018","mi":"04"}"#);
+    }
+
+    #[test]
+    fn names()
+    {
+        for driver_type in [DriverType::WinUsb, DriverType::Libusb0, DriverType::LibusbK, DriverType::User] {
+            round_trip(&driver_type);
+        }
+        assert_eq!(serde_json::from_str::<DriverType>(r#""winusb""#).unwrap(), DriverType::WinUsb);
+
+        for code in -19..0 {
+            round_trip(&Error::from_error_code(code).unwrap());
+        }
+        assert_eq!(round_trip(&Error::NoDevice), r#""NoDevice""#);
+        assert_eq!(round_trip(&Error::Other), r#""Other""#);
+        assert!(serde_json::from_str::<Error>(r#""Bogus""#).is_err());
+    }
+}
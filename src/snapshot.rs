@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Snapshots of the device list, and what changed between two of them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+use std::time::SystemTime;
+
+use crate::{create_list, CreateListOptions, DeviceInfo, Error};
+
+
+/// The stable identity of a device across [create_list] calls: its device instance ID (e.g.
+/// `USB\VID_1D50&PID_6018&MI_04\6&1234&0&0004`), upper cased since Windows compares them case
+/// insensitively.
+///
+/// Devices libwdi reports without a device ID fall back to `USB\VID_####&PID_####[&MI_##]`,
+/// with `#2`, `#3` and so on appended to tell identical devices apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceKey(String);
+
+impl DeviceKey
+{
+    /// The key for `device`, without any disambiguating suffix.
+    pub fn for_device(device: &DeviceInfo) -> Self
+    {
+        let id = match device.device_id_str() {
+            Some(id) => id.to_uppercase(),
+            None => {
+                let mut id = format!("USB\\VID_{:04X}&PID_{:04X}", device.vid, device.pid);
+                if device.is_composite {
+                    id.push_str(&format!("&MI_{:02X}", device.mi));
+                }
+                id
+            },
+        };
+
+        Self(id)
+    }
+
+    pub fn as_str(&self) -> &str
+    {
+        &self.0
+    }
+}
+
+impl Display for DeviceKey
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The devices present at one point in time, by [DeviceKey].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceSnapshot
+{
+    devices: BTreeMap<DeviceKey, DeviceInfo>,
+    taken_at: SystemTime,
+}
+
+impl DeviceSnapshot
+{
+    /// Takes a snapshot with [create_list]. No devices at all is an empty snapshot rather than
+    /// [Error::NoDevice].
+    pub fn take(options: CreateListOptions) -> Result<Self, Error>
+    {
+        let devices = match create_list(options) {
+            Err(Error::NoDevice) => Vec::new(),
+            result => result?,
+        };
+
+        Ok(Self::from_devices(devices))
+    }
+
+    /// Builds a snapshot from an existing device list, e.g. one from [create_list].
+    pub fn from_devices<I: IntoIterator<Item = DeviceInfo>>(devices: I) -> Self
+    {
+        let mut map = BTreeMap::new();
+        for device in devices {
+            let key = DeviceKey::for_device(&device);
+            let mut unique = key.clone();
+            let mut n = 1;
+            while map.contains_key(&unique) {
+                n += 1;
+                unique = DeviceKey(format!("{}#{}", key, n));
+            }
+            map.insert(unique, device);
+        }
+
+        Self {
+            devices: map,
+            taken_at: SystemTime::now(),
+        }
+    }
+
+    /// When the snapshot was taken.
+    pub fn taken_at(&self) -> SystemTime
+    {
+        self.taken_at
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.devices.is_empty()
+    }
+
+    pub fn get(&self, key: &DeviceKey) -> Option<&DeviceInfo>
+    {
+        self.devices.get(key)
+    }
+
+    /// The devices, ordered by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&DeviceKey, &DeviceInfo)>
+    {
+        self.devices.iter()
+    }
+
+    /// What changed between this snapshot and a `newer` one.
+    pub fn diff(&self, newer: &DeviceSnapshot) -> SnapshotDiff
+    {
+        let added = newer
+            .devices
+            .iter()
+            .filter(|(key, _)| !self.devices.contains_key(key))
+            .map(|(key, device)| (key.clone(), device.clone()))
+            .collect();
+
+        let removed = self
+            .devices
+            .iter()
+            .filter(|(key, _)| !newer.devices.contains_key(key))
+            .map(|(key, device)| (key.clone(), device.clone()))
+            .collect();
+
+        let changed = self
+            .devices
+            .iter()
+            .filter_map(|(key, before)| {
+                let after = newer.devices.get(key)?;
+                let fields = ChangedField::between(before, after);
+                if fields.is_empty() {
+                    return None;
+                }
+                Some(DeviceChange {
+                    key: key.clone(),
+                    before: before.clone(),
+                    after: after.clone(),
+                    fields,
+                })
+            })
+            .collect();
+
+        SnapshotDiff { added, removed, changed }
+    }
+}
+
+/// A driver related field of [DeviceInfo] that [DeviceSnapshot::diff] watches.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ChangedField
+{
+    Driver,
+    DriverVersion,
+    UpperFilter,
+}
+
+impl ChangedField
+{
+    fn between(before: &DeviceInfo, after: &DeviceInfo) -> Vec<Self>
+    {
+        let mut fields = Vec::new();
+        if before.driver != after.driver {
+            fields.push(ChangedField::Driver);
+        }
+        if before.driver_version != after.driver_version {
+            fields.push(ChangedField::DriverVersion);
+        }
+        if before.upper_filter != after.upper_filter {
+            fields.push(ChangedField::UpperFilter);
+        }
+        fields
+    }
+}
+
+impl Display for ChangedField
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            ChangedField::Driver => write!(f, "driver"),
+            ChangedField::DriverVersion => write!(f, "driver version"),
+            ChangedField::UpperFilter => write!(f, "upper filter"),
+        }
+    }
+}
+
+/// A device present in both snapshots whose driver changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceChange
+{
+    pub key: DeviceKey,
+    pub before: DeviceInfo,
+    pub after: DeviceInfo,
+
+    /// Which fields changed, in declaration order.
+    pub fields: Vec<ChangedField>,
+}
+
+/// The result of [DeviceSnapshot::diff]. Each list is ordered by key.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SnapshotDiff
+{
+    pub added: Vec<(DeviceKey, DeviceInfo)>,
+    pub removed: Vec<(DeviceKey, DeviceInfo)>,
+    pub changed: Vec<DeviceChange>,
+}
+
+impl SnapshotDiff
+{
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool
+    {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// One line per difference, e.g. `+ USB\VID_1D50&PID_6018\... (Black Magic Probe)`.
+impl Display for SnapshotDiff
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let desc = DeviceInfo::desc_str;
+
+        for (key, device) in &self.added {
+            writeln!(f, "+ {} ({})", key, desc(device))?;
+        }
+        for (key, device) in &self.removed {
+            writeln!(f, "- {} ({})", key, desc(device))?;
+        }
+        for change in &self.changed {
+            let fields: Vec<String> = change.fields.iter().map(ToString::to_string).collect();
+            writeln!(f, "~ {} ({}): {} changed", change.key, desc(&change.after), fields.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::probe;
+
+    #[test]
+    fn diff_by_identity()
+    {
+        let probe_a = r"USB\VID_1D50&PID_6018&MI_04\6&1234&0&0004";
+        let probe_b = r"USB\VID_1D50&PID_6018&MI_04\6&5678&0&0004";
+
+        let before = DeviceSnapshot::from_devices([probe().mi(4).device_id(probe_a).build(), probe().mi(4).device_id(probe_b).build()]);
+
+        // Same devices in a different order, with different case, is no change at all.
+        let reordered = DeviceSnapshot::from_devices([
+            probe().mi(4).device_id(&probe_b.to_lowercase()).build(),
+            probe().mi(4).device_id(probe_a).build(),
+        ]);
+        assert!(before.diff(&reordered).is_empty());
+
+        let installed = probe().mi(4).device_id(probe_a).driver("WinUSB").driver_version(1).build();
+        let after = DeviceSnapshot::from_devices([installed, probe().mi(4).build()]);
+        let diff = before.diff(&after);
+
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].0.as_str(), r"USB\VID_1D50&PID_6018&MI_04");
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].0.as_str(), probe_b);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].fields, [ChangedField::Driver, ChangedField::DriverVersion]);
+
+        // Identical devices without IDs are told apart rather than collapsed.
+        let twins = DeviceSnapshot::from_devices([probe().mi(4).build(), probe().mi(4).build()]);
+        assert_eq!(twins.len(), 2);
+    }
+}
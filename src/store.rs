@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! The third party driver packages in the Windows driver store, and which of them libwdi made.
+//!
+//! Windows publishes each third party package as `%WINDIR%\INF\oem<N>.inf`, keeping the
+//! original INF, catalog and binaries in the driver store's `FileRepository`. [DriverStore::open]
+//! reads them with SetupAPI; [DriverStore::from_dir] reads any directory of `oem<N>.inf` files,
+//! such as the `INF` directory of an offline Windows image.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::catalog::{Catalog, SignerCertificate};
+use crate::inf::Inf;
+use crate::package::{ids_target, DriverPackage, PackageError};
+use crate::uninstall::{current_inf, driver_store_location, is_oem_inf_name, windows_dir};
+use crate::{DeviceInfo, DriverType, Guid};
+
+
+/// A package in the driver store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorePackage
+{
+    /// The name Windows published the package under, e.g. `oem12.inf`, as passed to
+    /// [remove_driver_package](crate::remove_driver_package).
+    pub published_name: String,
+
+    /// The package's INF in the driver store, if known.
+    pub store_inf: Option<PathBuf>,
+
+    /// The `Provider` from the INF, e.g. `libwdi`.
+    pub provider: Option<String>,
+
+    /// The device setup class, e.g. `Universal Serial Bus devices`.
+    pub class: Option<String>,
+
+    pub class_guid: Option<Guid>,
+
+    /// The date from `DriverVer`, as written in the INF (`mm/dd/yyyy`).
+    pub driver_date: Option<String>,
+
+    /// The version from `DriverVer`, e.g. `6.1.7600.16385`.
+    pub driver_version: Option<String>,
+
+    /// The generic driver the package installs, or None for any other driver.
+    pub driver_type: Option<DriverType>,
+
+    pub hardware_ids: Vec<String>,
+
+    /// The `CatalogFile` from the INF.
+    pub catalog: Option<String>,
+
+    /// The certificate that signed the catalog, if the catalog could be found and read, and is
+    /// signed.
+    pub signer: Option<SignerCertificate>,
+}
+
+impl StorePackage
+{
+    /// Describes the package published as `published_name` from its INF. The signer is left
+    /// unset.
+    pub fn from_inf(published_name: &str, inf: &Inf) -> Self
+    {
+        let driver_ver = inf
+            .entries("Version", "DriverVer")
+            .next()
+            .map(|line| line.values.iter().map(|value| inf.expand(value).trim().to_string()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let non_empty = |value: Option<&String>| value.filter(|value| !value.is_empty()).cloned();
+
+        Self {
+            published_name: published_name.to_string(),
+            store_inf: None,
+            provider: inf.value("Version", "Provider"),
+            class: inf.value("Version", "Class"),
+            class_guid: inf.value("Version", "ClassGuid").and_then(|guid| guid.parse().ok()),
+            driver_date: non_empty(driver_ver.first()),
+            driver_version: non_empty(driver_ver.get(1)),
+            driver_type: DriverPackage::driver_type_from_inf(inf),
+            hardware_ids: DriverPackage::hardware_ids_from_inf(inf),
+            catalog: DriverPackage::catalog_from_inf(inf),
+            signer: None,
+        }
+    }
+
+    /// Reads the package published as `published_name` from the INF at `inf_path`, and its
+    /// signer from the catalog next to it, if there is one. Only fails if the INF can't be read.
+    pub fn load<P: AsRef<Path>>(published_name: &str, inf_path: P) -> Result<Self, PackageError>
+    {
+        let inf_path = inf_path.as_ref();
+        let mut package = Self::from_inf(published_name, &Inf::open(inf_path)?);
+
+        let catalog_path = package
+            .catalog
+            .as_ref()
+            .and_then(|catalog| Some(inf_path.parent()?.join(catalog)))
+            .filter(|path| path.is_file());
+        // An unreadable catalog only means the signer is unknown.
+        package.signer = catalog_path
+            .and_then(|path| Catalog::open(path).ok())
+            .and_then(|catalog| catalog.signer);
+
+        Ok(package)
+    }
+
+    /// Whether libwdi generated this package's INF, which names libwdi as its provider.
+    pub fn is_libwdi(&self) -> bool
+    {
+        self.provider
+            .as_deref()
+            .map(|provider| provider.trim().eq_ignore_ascii_case("libwdi"))
+            .unwrap_or(false)
+    }
+
+    /// Whether this package's INF lists a hardware ID matching `device`.
+    pub fn targets(&self, device: &DeviceInfo) -> bool
+    {
+        ids_target(&self.hardware_ids, device)
+    }
+}
+
+/// A store package and the connected devices it targets, from [DriverStore::cross_reference].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageUsage<'a>
+{
+    pub package: &'a StorePackage,
+
+    /// The devices the package lists a hardware ID for.
+    pub devices: Vec<&'a DeviceInfo>,
+
+    /// Of those, the ones whose current driver was installed from this package.
+    pub bound: Vec<&'a DeviceInfo>,
+}
+
+impl PackageUsage<'_>
+{
+    /// Whether none of the given devices uses this package. For packages from libwdi, which
+    /// each target a single device, this usually means the package is left over from a device
+    /// that has since been given another driver or is no longer connected.
+    pub fn is_stale(&self) -> bool
+    {
+        self.bound.is_empty()
+    }
+}
+
+/// The third party packages in a driver store.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DriverStore
+{
+    packages: Vec<StorePackage>,
+    unreadable: Vec<String>,
+}
+
+impl DriverStore
+{
+    /// Reads this system's driver store. Only works on Windows.
+    pub fn open() -> Result<Self, PackageError>
+    {
+        let inf_dir = windows_dir()?.join("INF");
+        Self::read(&inf_dir, driver_store_location)
+    }
+
+    /// Reads the `oem<N>.inf` packages in `dir`, e.g. `C:\Windows\INF`, looking for their
+    /// catalogs next to them.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Self, PackageError>
+    {
+        Self::read(dir.as_ref(), |_| None)
+    }
+
+    /// Reads the packages in `dir`. Packages whose INF can't be read are listed in
+    /// [DriverStore::unreadable] rather than failing the whole read.
+    fn read<L: Fn(&Path) -> Option<PathBuf>>(dir: &Path, locate: L) -> Result<Self, PackageError>
+    {
+        let mut packages = Vec::new();
+        let mut unreadable = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()).filter(|name| is_oem_inf_name(name))
+            else {
+                continue;
+            };
+
+            // The driver store copy sits next to the catalog; fall back to the published INF.
+            let store_inf = locate(&path).filter(|store_inf| store_inf.is_file());
+            match StorePackage::load(name, store_inf.as_deref().unwrap_or(&path)) {
+                Ok(mut package) => {
+                    package.store_inf = store_inf;
+                    packages.push(package);
+                },
+                Err(_) => unreadable.push(name.to_string()),
+            }
+        }
+
+        packages.sort_by_key(|package| published_order(&package.published_name));
+        unreadable.sort_by_key(|name| published_order(name));
+
+        Ok(Self { packages, unreadable })
+    }
+
+    pub fn packages(&self) -> &[StorePackage]
+    {
+        &self.packages
+    }
+
+    /// The published names of the packages whose INF couldn't be read.
+    pub fn unreadable(&self) -> &[String]
+    {
+        &self.unreadable
+    }
+
+    /// The packages libwdi generated.
+    pub fn libwdi_packages(&self) -> impl Iterator<Item = &StorePackage>
+    {
+        self.packages.iter().filter(|package| package.is_libwdi())
+    }
+
+    /// The package published as `published_name`, case insensitively.
+    pub fn get(&self, published_name: &str) -> Option<&StorePackage>
+    {
+        self.packages
+            .iter()
+            .find(|package| package.published_name.eq_ignore_ascii_case(published_name))
+    }
+
+    /// Matches every libwdi package against `devices` (e.g. from [create_list](crate::create_list)
+    /// with [list_all](crate::CreateListOptions::list_all)), using the INF each device's driver
+    /// was installed from to tell which package it's bound to. Only works on Windows.
+    pub fn cross_reference<'a>(&'a self, devices: &'a [DeviceInfo]) -> Vec<PackageUsage<'a>>
+    {
+        self.cross_reference_with(devices, |device| current_inf(device).ok().flatten())
+    }
+
+    /// [DriverStore::cross_reference], with `inf_of` giving the driver store INF each device's
+    /// driver was installed from.
+    fn cross_reference_with<'a, F>(&'a self, devices: &'a [DeviceInfo], inf_of: F) -> Vec<PackageUsage<'a>>
+    where
+        F: Fn(&DeviceInfo) -> Option<String>,
+    {
+        let devices: Vec<(&DeviceInfo, Option<String>)> = devices.iter().map(|device| (device, inf_of(device))).collect();
+
+        self.libwdi_packages()
+            .map(|package| {
+                let targeted: Vec<&(&DeviceInfo, Option<String>)> =
+                    devices.iter().filter(|(device, _)| package.targets(device)).collect();
+                let bound = targeted
+                    .iter()
+                    .filter(|(_, inf)| inf.as_deref().is_some_and(|inf| inf.eq_ignore_ascii_case(&package.published_name)))
+                    .map(|(device, _)| *device)
+                    .collect();
+                let devices = targeted.iter().map(|(device, _)| *device).collect();
+                PackageUsage { package, devices, bound }
+            })
+            .collect()
+    }
+}
+
+/// Sorts `oem<N>.inf` names by number, so oem2.inf comes before oem10.inf.
+fn published_order(published_name: &str) -> (u32, String)
+{
+    let number = published_name[3..published_name.len() - 4].parse::<u32>().unwrap_or(0);
+    (number, published_name.to_ascii_lowercase())
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::{copy_dir, fixture, probe, scratch_dir};
+
+    #[test]
+    fn read_fixture_store()
+    {
+        let store = DriverStore::from_dir(fixture("driver-store")).unwrap();
+        let names: Vec<&str> = store.packages().iter().map(|package| package.published_name.as_str()).collect();
+        assert_eq!(names, ["oem12.inf", "oem13.inf", "oem14.inf"]);
+
+        let winusb = store.get("OEM12.INF").unwrap();
+        assert!(winusb.is_libwdi());
+        assert_eq!(winusb.class.as_deref(), Some("Universal Serial Bus devices"));
+        assert_eq!(winusb.class_guid, "{88BAE032-5A81-49F0-BC3D-A4FF138216D6}".parse().ok());
+        assert_eq!(winusb.driver_date.as_deref(), Some("01/01/2023"));
+        assert_eq!(winusb.driver_version.as_deref(), Some("6.1.7600.16385"));
+        assert_eq!(winusb.driver_type, Some(DriverType::WinUsb));
+        assert_eq!(winusb.hardware_ids, [r"USB\VID_1D50&PID_6017"]);
+        assert_eq!(winusb.catalog.as_deref(), Some("usb_device.cat"));
+        assert_eq!(winusb.signer, None);
+
+        let vendor = store.get("oem13.inf").unwrap();
+        assert!(!vendor.is_libwdi());
+        assert_eq!(vendor.provider.as_deref(), Some("Example Semiconductor"));
+        assert_eq!(vendor.driver_type, None);
+
+        let libwdi: Vec<&str> = store.libwdi_packages().map(|package| package.published_name.as_str()).collect();
+        assert_eq!(libwdi, ["oem12.inf", "oem14.inf"]);
+        assert_eq!(store.get("oem14.inf").unwrap().driver_type, Some(DriverType::LibusbK));
+    }
+
+    #[test]
+    fn cross_reference_devices()
+    {
+        let store = DriverStore::from_dir(fixture("driver-store")).unwrap();
+        let devices = [
+            probe().pid(0x6017).serial("A").driver("WinUSB").build(),
+            probe().pid(0x6017).serial("B").driver("WinUSB").build(),
+            probe().mi(4).driver("usbser").build(),
+            probe().mi(0).build(),
+        ];
+        // The second WinUSB device got its driver from another package.
+        let inf_of = |device: &DeviceInfo| match device.device_id_str().as_deref() {
+            Some(r"USB\VID_1D50&PID_6017\A") => Some(String::from("OEM12.INF")),
+            Some(r"USB\VID_1D50&PID_6017\B") => Some(String::from("oem40.inf")),
+            _ => device.driver.as_ref().map(|_| String::from("usbser.inf")),
+        };
+
+        let usage = store.cross_reference_with(&devices, inf_of);
+        assert_eq!(usage.len(), 2);
+
+        assert_eq!(usage[0].package.published_name, "oem12.inf");
+        assert_eq!(usage[0].devices, [&devices[0], &devices[1]]);
+        assert_eq!(usage[0].bound, [&devices[0]]);
+        assert!(!usage[0].is_stale());
+
+        // The GDB interface is connected, but has been given another driver.
+        assert_eq!(usage[1].package.published_name, "oem14.inf");
+        assert_eq!(usage[1].devices, [&devices[2]]);
+        assert!(usage[1].is_stale());
+    }
+
+    #[test]
+    fn skip_unreadable_packages()
+    {
+        let dir = scratch_dir("store");
+        copy_dir(&fixture("driver-store"), &dir);
+        // Reading a directory as an INF fails.
+        fs::create_dir(dir.join("oem20.inf")).unwrap();
+
+        let store = DriverStore::from_dir(&dir).unwrap();
+        assert_eq!(store.packages().len(), 3);
+        assert_eq!(store.unreadable(), ["oem20.inf"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
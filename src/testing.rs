@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Helpers shared by the unit tests.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::DeviceInfo;
+
+
+/// The path of `path` under `tests/fixtures`.
+pub fn fixture(path: &str) -> PathBuf
+{
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(path)
+}
+
+/// Creates a new, empty directory in the system's temporary directory. Each call returns a
+/// different directory, so tests running in parallel don't interfere.
+pub fn scratch_dir(name: &str) -> PathBuf
+{
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "wdi-{}-test-{}-{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// Recursively copies the directory `from` to `to`.
+pub fn copy_dir(from: &Path, to: &Path)
+{
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target);
+        } else {
+            fs::copy(&path, &target).unwrap();
+        }
+    }
+}
+
+
+/// Starts building a [DeviceInfo] for a Black Magic Probe (`1d50:6018`), not composite, with no
+/// driver and no IDs.
+pub fn probe() -> DeviceBuilder
+{
+    DeviceBuilder {
+        info: DeviceInfo {
+            vid: 0x1d50,
+            pid: 0x6018,
+            is_composite: false,
+            mi: 0,
+            desc: b"Black Magic Probe\0".to_vec(),
+            driver: None,
+            device_id: None,
+            hardware_id: None,
+            compatible_id: None,
+            upper_filter: None,
+            driver_version: 0,
+        },
+        serial: None,
+    }
+}
+
+/// Builds test [DeviceInfo]s; see [probe].
+#[derive(Debug, Clone)]
+pub struct DeviceBuilder
+{
+    info: DeviceInfo,
+    serial: Option<String>,
+}
+
+fn c_string(text: &str) -> Vec<u8>
+{
+    format!("{}\0", text).into_bytes()
+}
+
+impl DeviceBuilder
+{
+    pub fn pid(self, pid: u16) -> Self
+    {
+        Self { info: DeviceInfo { pid, ..self.info }, ..self }
+    }
+
+    /// Makes this an interface of a composite device, or not, for None.
+    pub fn mi<M: Into<Option<u8>>>(self, mi: M) -> Self
+    {
+        let mi = mi.into();
+        Self {
+            info: DeviceInfo { is_composite: mi.is_some(), mi: mi.unwrap_or(0), ..self.info },
+            ..self
+        }
+    }
+
+    pub fn driver<'a, D: Into<Option<&'a str>>>(self, driver: D) -> Self
+    {
+        Self { info: DeviceInfo { driver: driver.into().map(c_string), ..self.info }, ..self }
+    }
+
+    pub fn device_id(self, device_id: &str) -> Self
+    {
+        Self { info: DeviceInfo { device_id: Some(c_string(device_id)), ..self.info }, serial: None }
+    }
+
+    /// Sets the device ID to the one Windows gives a device with this VID, PID and interface and
+    /// the serial number `serial`.
+    pub fn serial(self, serial: &str) -> Self
+    {
+        Self { serial: Some(serial.to_string()), ..self }
+    }
+
+    pub fn hardware_id(self, hardware_id: &str) -> Self
+    {
+        Self { info: DeviceInfo { hardware_id: Some(c_string(hardware_id)), ..self.info }, ..self }
+    }
+
+    pub fn upper_filter(self, upper_filter: &str) -> Self
+    {
+        Self { info: DeviceInfo { upper_filter: Some(c_string(upper_filter)), ..self.info }, ..self }
+    }
+
+    pub fn driver_version(self, driver_version: u64) -> Self
+    {
+        Self { info: DeviceInfo { driver_version, ..self.info }, ..self }
+    }
+
+    pub fn build(self) -> DeviceInfo
+    {
+        let mut info = self.info;
+        if let Some(serial) = self.serial {
+            let mi = if info.is_composite { format!("&MI_{:02X}", info.mi) } else { String::new() };
+            info.device_id = Some(c_string(&format!(r"USB\VID_{:04X}&PID_{:04X}{}\{}", info.vid, info.pid, mi, serial)));
+        }
+
+        info
+    }
+}
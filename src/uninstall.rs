@@ -0,0 +1,502 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Undoing [install_driver](crate::install_driver), with SetupAPI: unbinding a device's driver,
+//! and deleting the `oemNN.inf` driver package it installed from the driver store.
+//!
+//! Windows rebinds a device to the best driver in the driver store when it next enumerates, so
+//! to revert a device to its previous driver, uninstall its driver and then remove the package
+//! [uninstall_driver] reports.
+
+use std::borrow::Cow;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::inf::Inf;
+use crate::store::StorePackage;
+use crate::{DeviceInfo, Error};
+
+
+/// The result of [uninstall_driver].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct UninstalledDriver
+{
+    /// The driver store name of the INF the device was bound to (e.g. `oem12.inf`), to pass to
+    /// [remove_driver_package], if it was a third party driver.
+    pub oem_inf: Option<String>,
+
+    /// Windows must restart to finish removing the driver.
+    pub needs_reboot: bool,
+}
+
+/// Options for [remove_driver_package].
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct RemovePackageOptions
+{
+    /// Remove the package even if devices are still bound to it, or it wasn't made by libwdi.
+    force: bool,
+}
+
+impl RemovePackageOptions
+{
+    pub fn force(self, force: bool) -> Self
+    {
+        Self { force }
+    }
+}
+
+/// Getters, with non-standard names due to the builder API.
+impl RemovePackageOptions
+{
+    pub fn get_force(&self) -> bool
+    {
+        self.force
+    }
+}
+
+/// Whether `name` is a driver store INF name, `oem<N>.inf`.
+pub(crate) fn is_oem_inf_name(name: &str) -> bool
+{
+    let name = name.to_ascii_lowercase();
+    name.strip_prefix("oem")
+        .and_then(|rest| rest.strip_suffix(".inf"))
+        .map(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+        .unwrap_or(false)
+}
+
+/// Removes `device` from the system along with its driver binding, as "Uninstall device" in
+/// Device Manager does, leaving the driver package in the driver store. Requires administrator
+/// privileges.
+///
+/// Fails with [Error::InvalidParam] if `device` has no device ID, [Error::NotFound] if no such
+/// device exists, and [Error::NotSupported] on platforms other than Windows.
+pub fn uninstall_driver(device: &DeviceInfo) -> Result<UninstalledDriver, Error>
+{
+    setupapi::uninstall_device(&device_id(device)?)
+}
+
+/// The INF `device`'s driver was installed from, as named in the driver store (e.g.
+/// `oem12.inf`, or `usbser.inf` for an inbox driver), or None if it has no driver.
+pub(crate) fn current_inf(device: &DeviceInfo) -> Result<Option<String>, Error>
+{
+    setupapi::device_inf(&device_id(device)?)
+}
+
+/// Installs the INF at `inf_path` (e.g. `C:\Windows\INF\oem12.inf`) for the devices with hardware
+/// ID `hardware_id`, even if Windows ranks their current driver higher, adding its package to the
+/// driver store if it isn't there yet. Returns whether Windows must restart to finish.
+pub(crate) fn reinstall_inf(hardware_id: &str, inf_path: &Path) -> Result<bool, Error>
+{
+    setupapi::reinstall_inf(hardware_id, inf_path)
+}
+
+/// Every entry of `device`'s `UpperFilters`, which libwdi only reports the first of.
+pub(crate) fn upper_filters(device: &DeviceInfo) -> Result<Vec<String>, Error>
+{
+    setupapi::upper_filters(&device_id(device)?)
+}
+
+/// Sets `device`'s `UpperFilters` to `filters`, or removes them if there are none.
+pub(crate) fn set_upper_filters(device: &DeviceInfo, filters: &[String]) -> Result<(), Error>
+{
+    setupapi::set_upper_filters(&device_id(device)?, filters)
+}
+
+/// The Windows directory, e.g. `C:\Windows`.
+pub(crate) fn windows_dir() -> Result<PathBuf, Error>
+{
+    setupapi::windows_dir()
+}
+
+/// Where the driver store keeps the package published as `inf_path` (e.g.
+/// `C:\Windows\INF\oem12.inf`): the path of its INF in the `FileRepository`.
+pub(crate) fn driver_store_location(inf_path: &Path) -> Option<PathBuf>
+{
+    setupapi::driver_store_location(inf_path)
+}
+
+fn device_id(device: &DeviceInfo) -> Result<String, Error>
+{
+    device
+        .device_id_str()
+        .map(Cow::into_owned)
+        .filter(|id| !id.is_empty())
+        .ok_or(Error::InvalidParam)
+}
+
+/// Deletes the driver package `oem_inf` (e.g. `oem12.inf`, as reported by [uninstall_driver])
+/// from the driver store. Requires administrator privileges.
+///
+/// Unless [RemovePackageOptions::force] is set, only packages libwdi generated (whose INF names
+/// libwdi as its provider, see [StorePackage::is_libwdi]) are removed, so a vendor's package
+/// isn't deleted by mistake.
+///
+/// Fails with [Error::InvalidParam] if `oem_inf` isn't an `oem<N>.inf` name or, without
+/// [RemovePackageOptions::force], isn't a libwdi package, [Error::NotFound] if there's no such
+/// package, [Error::Busy] if devices still use it and [RemovePackageOptions::force] isn't set,
+/// and [Error::NotSupported] on platforms other than Windows.
+pub fn remove_driver_package(oem_inf: &str, options: RemovePackageOptions) -> Result<(), Error>
+{
+    if !is_oem_inf_name(oem_inf) {
+        return Err(Error::InvalidParam);
+    }
+    if !options.force {
+        check_libwdi_package(oem_inf, &windows_dir()?.join("INF").join(oem_inf))?;
+    }
+
+    setupapi::uninstall_oem_inf(oem_inf, options.force)
+}
+
+/// Fails with [Error::InvalidParam] unless the INF at `inf_path`, published as `oem_inf`, is
+/// one libwdi generated, or [Error::NotFound] if it doesn't exist.
+fn check_libwdi_package(oem_inf: &str, inf_path: &Path) -> Result<(), Error>
+{
+    let inf = Inf::open(inf_path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::NotFound,
+        _ => Error::Io,
+    })?;
+
+    if StorePackage::from_inf(oem_inf, &inf).is_libwdi() {
+        Ok(())
+    } else {
+        Err(Error::InvalidParam)
+    }
+}
+
+
+#[cfg(windows)]
+mod setupapi
+{
+    use std::mem;
+    use std::path::{Path, PathBuf};
+    use std::ptr;
+
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, UINT};
+    use winapi::shared::windef::HWND;
+    use winapi::shared::winerror::{ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, ERROR_INVALID_DATA, ERROR_SUCCESS};
+    use winapi::um::errhandlingapi::GetLastError;
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::setupapi::{
+        SetupDiCallClassInstaller, SetupDiCreateDeviceInfoList, SetupDiDestroyDeviceInfoList,
+        SetupDiGetDeviceInstallParamsW, SetupDiGetDeviceRegistryPropertyW, SetupDiOpenDevRegKey, SetupDiOpenDeviceInfoW, SetupDiSetDeviceRegistryPropertyW,
+        SetupGetInfDriverStoreLocationW, SetupUninstallOEMInfW,
+        DICS_FLAG_GLOBAL, DIF_REMOVE, DIREG_DRV, DI_NEEDREBOOT, DI_NEEDRESTART, HDEVINFO, SPDRP_UPPERFILTERS,
+        SP_DEVINFO_DATA, SP_DEVINSTALL_PARAMS_W, SUOI_FORCEDELETE,
+    };
+    use winapi::um::sysinfoapi::GetWindowsDirectoryW;
+    use winapi::um::winnt::{KEY_READ, LPCWSTR, REG_MULTI_SZ, REG_SZ};
+    use winapi::um::winreg::{RegCloseKey, RegQueryValueExW};
+
+    use super::UninstalledDriver;
+    use crate::Error;
+
+    // SetupAPI's own error codes, which winapi doesn't define.
+    const ERROR_NO_SUCH_DEVINST: DWORD = 0xE000020B;
+    const ERROR_INVALID_DEVINST_NAME: DWORD = 0xE0000205;
+    const ERROR_INF_IN_USE_BY_DEVICES: DWORD = 0xE000023D;
+
+    const INSTALLFLAG_FORCE: DWORD = 0x00000001;
+
+    // From newdev.h, which winapi doesn't cover.
+    #[link(name = "newdev")]
+    extern "system" {
+        fn UpdateDriverForPlugAndPlayDevicesW(
+            hwndParent: HWND,
+            HardwareId: LPCWSTR,
+            FullInfPath: LPCWSTR,
+            InstallFlags: DWORD,
+            bRebootRequired: *mut BOOL,
+        ) -> BOOL;
+    }
+
+    fn wide(s: &str) -> Vec<u16>
+    {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+
+    fn last_error() -> Error
+    {
+        match unsafe { GetLastError() } {
+            ERROR_ACCESS_DENIED => Error::NeedsAdmin,
+            ERROR_FILE_NOT_FOUND | ERROR_NO_SUCH_DEVINST | ERROR_INVALID_DEVINST_NAME => Error::NotFound,
+            ERROR_INF_IN_USE_BY_DEVICES => Error::Busy,
+            _ => Error::Other,
+        }
+    }
+
+    /// A device information set, destroyed on drop.
+    struct DeviceInfoSet(HDEVINFO);
+
+    impl Drop for DeviceInfoSet
+    {
+        fn drop(&mut self)
+        {
+            unsafe { SetupDiDestroyDeviceInfoList(self.0) };
+        }
+    }
+
+    /// Opens the device with instance ID `device_id`.
+    fn open_device(device_id: &str) -> Result<(DeviceInfoSet, SP_DEVINFO_DATA), Error>
+    {
+        let set = unsafe { SetupDiCreateDeviceInfoList(ptr::null(), ptr::null_mut()) };
+        if set == INVALID_HANDLE_VALUE as HDEVINFO {
+            return Err(last_error());
+        }
+        let set = DeviceInfoSet(set);
+
+        let mut data: SP_DEVINFO_DATA = unsafe { mem::zeroed() };
+        data.cbSize = mem::size_of::<SP_DEVINFO_DATA>() as DWORD;
+        let id = wide(device_id);
+        if unsafe { SetupDiOpenDeviceInfoW(set.0, id.as_ptr(), ptr::null_mut(), 0, &mut data) } == FALSE {
+            return Err(last_error());
+        }
+
+        Ok((set, data))
+    }
+
+    pub fn device_inf(device_id: &str) -> Result<Option<String>, Error>
+    {
+        let (set, mut data) = open_device(device_id)?;
+        Ok(inf_path(&set, &mut data))
+    }
+
+    pub fn uninstall_device(device_id: &str) -> Result<UninstalledDriver, Error>
+    {
+        let (set, mut data) = open_device(device_id)?;
+
+        // The driver key goes away with the device, so read it first.
+        let oem_inf = inf_path(&set, &mut data).filter(|inf| super::is_oem_inf_name(inf));
+
+        if unsafe { SetupDiCallClassInstaller(DIF_REMOVE, set.0, &mut data) } == FALSE {
+            return Err(last_error());
+        }
+
+        let mut params: SP_DEVINSTALL_PARAMS_W = unsafe { mem::zeroed() };
+        params.cbSize = mem::size_of::<SP_DEVINSTALL_PARAMS_W>() as DWORD;
+        let needs_reboot = unsafe { SetupDiGetDeviceInstallParamsW(set.0, &mut data, &mut params) } != FALSE
+            && params.Flags & (DI_NEEDREBOOT | DI_NEEDRESTART) != 0;
+
+        Ok(UninstalledDriver { oem_inf, needs_reboot })
+    }
+
+    /// The `InfPath` value of the device's driver key, e.g. `oem12.inf`.
+    fn inf_path(set: &DeviceInfoSet, data: &mut SP_DEVINFO_DATA) -> Option<String>
+    {
+        let key = unsafe { SetupDiOpenDevRegKey(set.0, data, DICS_FLAG_GLOBAL, 0, DIREG_DRV, KEY_READ) };
+        if key as usize == INVALID_HANDLE_VALUE as usize {
+            return None;
+        }
+
+        let name = wide("InfPath");
+        let mut value = [0u16; 260];
+        let mut size = mem::size_of_val(&value) as DWORD;
+        let mut kind: DWORD = 0;
+        let status = unsafe {
+            RegQueryValueExW(key, name.as_ptr(), ptr::null_mut(), &mut kind, value.as_mut_ptr() as *mut u8, &mut size)
+        };
+        unsafe { RegCloseKey(key) };
+
+        if status as DWORD != ERROR_SUCCESS || kind != REG_SZ {
+            return None;
+        }
+        let len = value.iter().position(|&c| c == 0).unwrap_or(value.len());
+        Some(String::from_utf16_lossy(&value[..len]))
+    }
+
+    pub fn windows_dir() -> Result<PathBuf, Error>
+    {
+        let mut windows = [0u16; 260];
+        let len = unsafe { GetWindowsDirectoryW(windows.as_mut_ptr(), windows.len() as UINT) } as usize;
+        if len == 0 || len > windows.len() {
+            return Err(last_error());
+        }
+
+        Ok(PathBuf::from(String::from_utf16_lossy(&windows[..len])))
+    }
+
+    pub fn driver_store_location(inf_path: &Path) -> Option<PathBuf>
+    {
+        let name = wide(inf_path.to_str()?);
+        let mut location = [0u16; 260];
+        let mut required: DWORD = 0;
+        let ok = unsafe {
+            SetupGetInfDriverStoreLocationW(
+                name.as_ptr(),
+                ptr::null_mut(),
+                ptr::null(),
+                location.as_mut_ptr(),
+                location.len() as DWORD,
+                &mut required,
+            )
+        };
+        if ok == FALSE {
+            return None;
+        }
+
+        let len = location.iter().position(|&c| c == 0).unwrap_or(location.len());
+        Some(PathBuf::from(String::from_utf16_lossy(&location[..len])))
+    }
+
+    pub fn upper_filters(device_id: &str) -> Result<Vec<String>, Error>
+    {
+        let (set, mut data) = open_device(device_id)?;
+
+        let mut value = [0u16; 1024];
+        let mut kind: DWORD = 0;
+        let mut required: DWORD = 0;
+        let ok = unsafe {
+            SetupDiGetDeviceRegistryPropertyW(
+                set.0,
+                &mut data,
+                SPDRP_UPPERFILTERS,
+                &mut kind,
+                value.as_mut_ptr() as *mut u8,
+                mem::size_of_val(&value) as DWORD,
+                &mut required,
+            )
+        };
+        if ok == FALSE {
+            // The device has no upper filters.
+            if unsafe { GetLastError() } == ERROR_INVALID_DATA {
+                return Ok(Vec::new());
+            }
+            return Err(last_error());
+        }
+        if kind != REG_MULTI_SZ {
+            return Ok(Vec::new());
+        }
+
+        // A REG_MULTI_SZ: NUL terminated strings, ending with an empty one.
+        let len = (required as usize / mem::size_of::<u16>()).min(value.len());
+        Ok(value[..len]
+            .split(|&c| c == 0)
+            .filter(|filter| !filter.is_empty())
+            .map(String::from_utf16_lossy)
+            .collect())
+    }
+
+    pub fn set_upper_filters(device_id: &str, filters: &[String]) -> Result<(), Error>
+    {
+        let (set, mut data) = open_device(device_id)?;
+
+        // A REG_MULTI_SZ: each NUL terminated filter, then an empty string. No buffer deletes
+        // the property.
+        let value: Vec<u16> = filters.iter().flat_map(|filter| wide(filter)).chain(Some(0)).collect();
+        let (buffer, size) = if filters.is_empty() {
+            (ptr::null(), 0)
+        } else {
+            (value.as_ptr() as *const u8, mem::size_of_val(&value[..]) as DWORD)
+        };
+        if unsafe { SetupDiSetDeviceRegistryPropertyW(set.0, &mut data, SPDRP_UPPERFILTERS, buffer, size) } == FALSE {
+            return Err(last_error());
+        }
+
+        Ok(())
+    }
+
+    pub fn reinstall_inf(hardware_id: &str, inf_path: &Path) -> Result<bool, Error>
+    {
+        let inf_path = inf_path.to_str().ok_or(Error::InvalidParam)?;
+
+        let id = wide(hardware_id);
+        let path = wide(inf_path);
+        let mut reboot: BOOL = FALSE;
+        let ok = unsafe {
+            UpdateDriverForPlugAndPlayDevicesW(ptr::null_mut(), id.as_ptr(), path.as_ptr(), INSTALLFLAG_FORCE, &mut reboot)
+        };
+        if ok == FALSE {
+            return Err(last_error());
+        }
+
+        Ok(reboot != FALSE)
+    }
+
+    pub fn uninstall_oem_inf(oem_inf: &str, force: bool) -> Result<(), Error>
+    {
+        let name = wide(oem_inf);
+        let flags = if force { SUOI_FORCEDELETE } else { 0 };
+        if unsafe { SetupUninstallOEMInfW(name.as_ptr(), flags, ptr::null_mut()) } == FALSE {
+            return Err(last_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(windows))]
+mod setupapi
+{
+    use std::path::{Path, PathBuf};
+
+    use super::UninstalledDriver;
+    use crate::Error;
+
+    pub fn uninstall_device(_device_id: &str) -> Result<UninstalledDriver, Error>
+    {
+        Err(Error::NotSupported)
+    }
+
+    pub fn device_inf(_device_id: &str) -> Result<Option<String>, Error>
+    {
+        Err(Error::NotSupported)
+    }
+
+    pub fn windows_dir() -> Result<PathBuf, Error>
+    {
+        Err(Error::NotSupported)
+    }
+
+    pub fn driver_store_location(_inf_path: &Path) -> Option<PathBuf>
+    {
+        None
+    }
+
+    pub fn upper_filters(_device_id: &str) -> Result<Vec<String>, Error>
+    {
+        Err(Error::NotSupported)
+    }
+
+    pub fn set_upper_filters(_device_id: &str, _filters: &[String]) -> Result<(), Error>
+    {
+        Err(Error::NotSupported)
+    }
+
+    pub fn reinstall_inf(_hardware_id: &str, _inf_path: &Path) -> Result<bool, Error>
+    {
+        Err(Error::NotSupported)
+    }
+
+    pub fn uninstall_oem_inf(_oem_inf: &str, _force: bool) -> Result<(), Error>
+    {
+        Err(Error::NotSupported)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::fixture;
+
+    #[test]
+    fn oem_inf_names()
+    {
+        assert!(is_oem_inf_name("oem12.inf"));
+        assert!(is_oem_inf_name("OEM0.INF"));
+        assert!(!is_oem_inf_name("oem.inf"));
+        assert!(!is_oem_inf_name("usb_device.inf"));
+        assert!(!is_oem_inf_name(r"C:\Windows\INF\oem12.inf"));
+
+        let options = RemovePackageOptions::default().force(true);
+        assert_eq!(remove_driver_package("usb_device.inf", options), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn only_libwdi_packages()
+    {
+        let store = fixture("driver-store");
+        assert_eq!(check_libwdi_package("oem12.inf", &store.join("oem12.inf")), Ok(()));
+        assert_eq!(check_libwdi_package("oem13.inf", &store.join("oem13.inf")), Err(Error::InvalidParam));
+        assert_eq!(check_libwdi_package("oem99.inf", &store.join("oem99.inf")), Err(Error::NotFound));
+    }
+}
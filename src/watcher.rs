@@ -0,0 +1,569 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Watching for devices arriving, leaving, and changing drivers.
+//!
+//! A [DeviceWatcher] enumerates devices from a [DeviceSource] and turns the differences
+//! between consecutive enumerations into [DeviceEvent]s. It enumerates every
+//! [interval](DeviceWatcher::interval), and on Windows also as soon as a device interface
+//! arrives or is removed. Driver changes that don't add or remove an interface, such as
+//! installing a filter driver, are only seen at the next interval.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+use crate::snapshot::{DeviceChange, DeviceKey, DeviceSnapshot};
+use crate::{create_list, CreateListOptions, DeviceInfo, Error};
+
+
+/// Something that can list the devices currently present.
+pub trait DeviceSource
+{
+    fn enumerate(&mut self) -> Result<Vec<DeviceInfo>, Error>;
+}
+
+/// The real device list, from [create_list].
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ListSource
+{
+    pub options: CreateListOptions,
+}
+
+impl DeviceSource for ListSource
+{
+    /// No devices at all is an empty list rather than [Error::NoDevice].
+    fn enumerate(&mut self) -> Result<Vec<DeviceInfo>, Error>
+    {
+        match create_list(self.options) {
+            Err(Error::NoDevice) => Ok(Vec::new()),
+            result => result,
+        }
+    }
+}
+
+/// A [DeviceSource] that plays back a script of enumeration results, for testing code that uses
+/// a [DeviceWatcher] without any hardware. Once the script runs out, the last device list is
+/// repeated forever.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedSource
+{
+    script: VecDeque<Result<Vec<DeviceInfo>, Error>>,
+    last: Vec<DeviceInfo>,
+}
+
+impl ScriptedSource
+{
+    pub fn new<I: IntoIterator<Item = Vec<DeviceInfo>>>(steps: I) -> Self
+    {
+        Self {
+            script: steps.into_iter().map(Ok).collect(),
+            last: Vec::new(),
+        }
+    }
+
+    /// Appends a device list to the script.
+    pub fn push(&mut self, devices: Vec<DeviceInfo>)
+    {
+        self.script.push_back(Ok(devices));
+    }
+
+    /// Appends a failed enumeration to the script.
+    pub fn push_error(&mut self, error: Error)
+    {
+        self.script.push_back(Err(error));
+    }
+}
+
+impl DeviceSource for ScriptedSource
+{
+    fn enumerate(&mut self) -> Result<Vec<DeviceInfo>, Error>
+    {
+        match self.script.pop_front() {
+            Some(Ok(devices)) => {
+                self.last = devices.clone();
+                Ok(devices)
+            },
+            Some(Err(e)) => Err(e),
+            None => Ok(self.last.clone()),
+        }
+    }
+}
+
+/// A change noticed by a [DeviceWatcher].
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceEvent
+{
+    Arrived(DeviceKey, DeviceInfo),
+    Removed(DeviceKey, DeviceInfo),
+
+    /// The device's driver, driver version or upper filter changed.
+    DriverChanged(DeviceChange),
+}
+
+impl DeviceEvent
+{
+    pub fn key(&self) -> &DeviceKey
+    {
+        match self {
+            DeviceEvent::Arrived(key, _) => key,
+            DeviceEvent::Removed(key, _) => key,
+            DeviceEvent::DriverChanged(change) => &change.key,
+        }
+    }
+
+    /// The device as it is now, or as it was last seen for [DeviceEvent::Removed].
+    pub fn device(&self) -> &DeviceInfo
+    {
+        match self {
+            DeviceEvent::Arrived(_, device) => device,
+            DeviceEvent::Removed(_, device) => device,
+            DeviceEvent::DriverChanged(change) => &change.after,
+        }
+    }
+}
+
+type Filter = Box<dyn FnMut(&DeviceInfo) -> bool + Send>;
+
+/// Produces [DeviceEvent]s from periodic enumeration of a [DeviceSource].
+///
+/// The first enumeration only establishes what's already there, unless
+/// [report_existing](DeviceWatcher::report_existing) is set. Use [DeviceWatcher::poll] to
+/// enumerate once, or [DeviceWatcher::next_event] (or the watcher as an [Iterator]) to block
+/// until something happens.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use wdi::watcher::{DeviceEvent, DeviceWatcher};
+///
+/// let watcher = DeviceWatcher::new(Default::default())
+///     .interval(Duration::from_millis(250))
+///     .filter(|dev| dev.vid == 0x1d50 && dev.pid == 0x6017);
+///
+/// for event in watcher {
+///     if let DeviceEvent::Arrived(key, _) = event? {
+///         println!("Probe in DFU mode: {}", key);
+///     }
+/// }
+/// # Ok::<(), wdi::Error>(())
+/// ```
+pub struct DeviceWatcher<S: DeviceSource = ListSource>
+{
+    source: S,
+    interval: Duration,
+    filter: Option<Filter>,
+    report_existing: bool,
+    notifications: bool,
+
+    last: Option<DeviceSnapshot>,
+    pending: VecDeque<DeviceEvent>,
+
+    #[cfg(windows)]
+    notifier: Option<notify::Notifier>,
+}
+
+impl DeviceWatcher<ListSource>
+{
+    /// Watches the real device list, enumerated with `options`.
+    pub fn new(options: CreateListOptions) -> Self
+    {
+        Self::with_source(ListSource { options })
+    }
+}
+
+impl<S: DeviceSource> DeviceWatcher<S>
+{
+    pub fn with_source(source: S) -> Self
+    {
+        Self {
+            source,
+            interval: Duration::from_secs(1),
+            filter: None,
+            report_existing: false,
+            notifications: true,
+            last: None,
+            pending: VecDeque::new(),
+            #[cfg(windows)]
+            notifier: None,
+        }
+    }
+
+    /// How long to wait between enumerations. Defaults to one second.
+    pub fn interval(self, interval: Duration) -> Self
+    {
+        Self { interval, ..self }
+    }
+
+    /// Only watch devices for which `filter` returns true. A device that stops matching is
+    /// reported as removed.
+    pub fn filter<F: FnMut(&DeviceInfo) -> bool + Send + 'static>(self, filter: F) -> Self
+    {
+        Self {
+            filter: Some(Box::new(filter)),
+            ..self
+        }
+    }
+
+    /// Report the devices found by the first enumeration as [DeviceEvent::Arrived].
+    pub fn report_existing(self, report_existing: bool) -> Self
+    {
+        Self { report_existing, ..self }
+    }
+
+    /// Whether to also enumerate when Windows reports a device interface arriving or being
+    /// removed, instead of only every interval. Other changes, such as a filter driver being
+    /// installed, still wait for the interval. Defaults to true; has no effect elsewhere.
+    pub fn notifications(self, notifications: bool) -> Self
+    {
+        Self { notifications, ..self }
+    }
+
+    /// The devices seen by the last enumeration, if there has been one.
+    pub fn snapshot(&self) -> Option<&DeviceSnapshot>
+    {
+        self.last.as_ref()
+    }
+
+    pub fn source(&self) -> &S
+    {
+        &self.source
+    }
+
+    pub fn source_mut(&mut self) -> &mut S
+    {
+        &mut self.source
+    }
+
+    /// Enumerates once, returning what changed since the last enumeration. Events are returned
+    /// in the order removals, arrivals, driver changes, so a device that re-enumerates with a
+    /// different identity (e.g. into a bootloader) is removed before its replacement arrives.
+    ///
+    /// Events still queued for [DeviceWatcher::next_event] are returned first.
+    pub fn poll(&mut self) -> Result<Vec<DeviceEvent>, Error>
+    {
+        self.enumerate()?;
+        Ok(self.pending.drain(..).collect())
+    }
+
+    /// Blocks until there is an event, enumerating every interval (or on a device change
+    /// notification).
+    pub fn next_event(&mut self) -> Result<DeviceEvent, Error>
+    {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            // Establish the baseline immediately, rather than an interval from now.
+            if self.last.is_some() {
+                self.wait();
+            }
+            self.enumerate()?;
+        }
+    }
+
+    fn enumerate(&mut self) -> Result<(), Error>
+    {
+        let mut devices = self.source.enumerate()?;
+        if let Some(filter) = &mut self.filter {
+            devices.retain(|device| filter(device));
+        }
+        let current = DeviceSnapshot::from_devices(devices);
+
+        let diff = match &self.last {
+            Some(last) => last.diff(&current),
+            None if self.report_existing => DeviceSnapshot::from_devices([]).diff(&current),
+            None => Default::default(),
+        };
+
+        self.pending.extend(diff.removed.into_iter().map(|(key, device)| DeviceEvent::Removed(key, device)));
+        self.pending.extend(diff.added.into_iter().map(|(key, device)| DeviceEvent::Arrived(key, device)));
+        self.pending.extend(diff.changed.into_iter().map(DeviceEvent::DriverChanged));
+        self.last = Some(current);
+
+        Ok(())
+    }
+
+    fn wait(&mut self)
+    {
+        #[cfg(windows)]
+        if self.notifications {
+            if self.notifier.is_none() {
+                self.notifier = notify::Notifier::start();
+                // Don't retry setting up notifications every interval if it failed.
+                self.notifications = self.notifier.is_some();
+            }
+            if let Some(notifier) = &self.notifier {
+                notifier.wait(self.interval);
+                return;
+            }
+        }
+
+        thread::sleep(self.interval);
+    }
+}
+
+/// Never ends; enumeration errors are yielded as they happen.
+impl<S: DeviceSource> Iterator for DeviceWatcher<S>
+{
+    type Item = Result<DeviceEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        Some(self.next_event())
+    }
+}
+
+impl<S: DeviceSource + fmt::Debug> fmt::Debug for DeviceWatcher<S>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.debug_struct("DeviceWatcher")
+            .field("source", &self.source)
+            .field("interval", &self.interval)
+            .field("filter", &self.filter.is_some())
+            .field("report_existing", &self.report_existing)
+            .field("notifications", &self.notifications)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+
+/// WM_DEVICECHANGE notifications of device interfaces arriving and being removed, received by a
+/// message-only window on its own thread. Message-only windows don't get broadcasts such as
+/// DBT_DEVNODES_CHANGED, which only go to top-level windows.
+#[cfg(windows)]
+mod notify
+{
+    use std::mem;
+    use std::ptr;
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use winapi::shared::minwindef::{LPARAM, LRESULT, UINT, WPARAM};
+    use winapi::shared::windef::HWND;
+    use winapi::um::dbt::{
+        DBT_DEVICEARRIVAL, DBT_DEVICEREMOVECOMPLETE, DBT_DEVTYP_DEVICEINTERFACE, DEV_BROADCAST_DEVICEINTERFACE_W,
+    };
+    use winapi::um::libloaderapi::GetModuleHandleW;
+    use winapi::um::winuser::{
+        CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, GetMessageW, GetWindowLongPtrW,
+        PostMessageW, PostQuitMessage, RegisterClassExW, RegisterDeviceNotificationW, SetWindowLongPtrW,
+        UnregisterDeviceNotification, DEVICE_NOTIFY_ALL_INTERFACE_CLASSES, DEVICE_NOTIFY_WINDOW_HANDLE,
+        GWLP_USERDATA, HWND_MESSAGE, MSG, WM_CLOSE, WM_DESTROY, WM_DEVICECHANGE, WNDCLASSEXW,
+    };
+
+    pub struct Notifier
+    {
+        changes: Receiver<()>,
+
+        /// The window, as an integer so the notifier is Send.
+        hwnd: isize,
+
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Notifier
+    {
+        /// Creates the window and registers for notifications, or returns None if any of that
+        /// fails.
+        pub fn start() -> Option<Self>
+        {
+            let (changes_tx, changes) = mpsc::channel();
+            let (hwnd_tx, hwnd_rx) = mpsc::channel();
+            let thread = thread::Builder::new()
+                .name("wdi-device-notify".into())
+                .spawn(move || unsafe { run(changes_tx, hwnd_tx) })
+                .ok()?;
+
+            match hwnd_rx.recv() {
+                Ok(Some(hwnd)) => Some(Self { changes, hwnd, thread: Some(thread) }),
+                _ => {
+                    let _ = thread.join();
+                    None
+                },
+            }
+        }
+
+        /// Waits for a device change, or until `timeout` passes.
+        pub fn wait(&self, timeout: Duration)
+        {
+            match self.changes.recv_timeout(timeout) {
+                // Windows sends a burst of notifications for each device; one enumeration
+                // covers all of them.
+                Ok(()) => while self.changes.try_recv().is_ok() {},
+                Err(RecvTimeoutError::Timeout) => (),
+                // The window thread died; fall back to plain polling.
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(timeout),
+            }
+        }
+    }
+
+    impl Drop for Notifier
+    {
+        fn drop(&mut self)
+        {
+            unsafe { PostMessageW(self.hwnd as HWND, WM_CLOSE, 0, 0) };
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn wide(s: &str) -> Vec<u16>
+    {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+
+    unsafe fn run(changes: Sender<()>, hwnd_tx: Sender<Option<isize>>)
+    {
+        let class_name = wide("wdi-rs device notifications");
+        let instance = GetModuleHandleW(ptr::null());
+
+        let mut class: WNDCLASSEXW = mem::zeroed();
+        class.cbSize = mem::size_of::<WNDCLASSEXW>() as UINT;
+        class.lpfnWndProc = Some(window_proc);
+        class.hInstance = instance;
+        class.lpszClassName = class_name.as_ptr();
+        // Fails harmlessly if another watcher already registered the class.
+        RegisterClassExW(&class);
+
+        let hwnd = CreateWindowExW(
+            0,
+            class_name.as_ptr(),
+            ptr::null(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            HWND_MESSAGE,
+            ptr::null_mut(),
+            instance,
+            ptr::null_mut(),
+        );
+        if hwnd.is_null() {
+            let _ = hwnd_tx.send(None);
+            return;
+        }
+
+        let changes = Box::into_raw(Box::new(changes));
+        SetWindowLongPtrW(hwnd, GWLP_USERDATA, changes as isize);
+
+        let mut filter: DEV_BROADCAST_DEVICEINTERFACE_W = mem::zeroed();
+        filter.dbcc_size = mem::size_of::<DEV_BROADCAST_DEVICEINTERFACE_W>() as u32;
+        filter.dbcc_devicetype = DBT_DEVTYP_DEVICEINTERFACE;
+        let registration = RegisterDeviceNotificationW(
+            hwnd as _,
+            &mut filter as *mut _ as _,
+            DEVICE_NOTIFY_WINDOW_HANDLE | DEVICE_NOTIFY_ALL_INTERFACE_CLASSES,
+        );
+        if registration.is_null() {
+            DestroyWindow(hwnd);
+            drop(Box::from_raw(changes));
+            let _ = hwnd_tx.send(None);
+            return;
+        }
+
+        let _ = hwnd_tx.send(Some(hwnd as isize));
+
+        let mut msg: MSG = mem::zeroed();
+        while GetMessageW(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+            DispatchMessageW(&msg);
+        }
+
+        UnregisterDeviceNotification(registration);
+        drop(Box::from_raw(changes));
+    }
+
+    unsafe extern "system" fn window_proc(hwnd: HWND, msg: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT
+    {
+        match msg {
+            WM_DEVICECHANGE => {
+                if matches!(wparam, DBT_DEVICEARRIVAL | DBT_DEVICEREMOVECOMPLETE) {
+                    let changes = GetWindowLongPtrW(hwnd, GWLP_USERDATA) as *const Sender<()>;
+                    if !changes.is_null() {
+                        let _ = (*changes).send(());
+                    }
+                }
+                1
+            },
+            WM_DESTROY => {
+                SetWindowLongPtrW(hwnd, GWLP_USERDATA, 0);
+                PostQuitMessage(0);
+                0
+            },
+            _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::probe;
+
+    fn device(serial: &str, driver: Option<&str>) -> DeviceInfo
+    {
+        probe().serial(serial).driver(driver).build()
+    }
+
+    fn dfu(serial: &str) -> DeviceInfo
+    {
+        probe().pid(0x6017).serial(serial).build()
+    }
+
+    #[test]
+    fn scripted_events()
+    {
+        let source = ScriptedSource::new([
+            vec![device("A", Some("usbser"))],
+            vec![dfu("A")],
+            vec![dfu("A"), device("B", None)],
+            vec![dfu("A"), device("B", Some("WinUSB"))],
+        ]);
+        let mut watcher = DeviceWatcher::with_source(source).interval(Duration::ZERO);
+
+        // The baseline reports nothing.
+        assert!(watcher.poll().unwrap().is_empty());
+
+        let events = watcher.poll().unwrap();
+        assert!(matches!(&events[..], [DeviceEvent::Removed(..), DeviceEvent::Arrived(_, dev)] if dev.pid == 0x6017));
+
+        let events = watcher.poll().unwrap();
+        assert!(matches!(&events[..], [DeviceEvent::Arrived(key, _)] if key.as_str().ends_with("\\B")));
+
+        match watcher.next_event().unwrap() {
+            DeviceEvent::DriverChanged(change) => assert_eq!(change.after.driver.as_deref(), Some(&b"WinUSB\0"[..])),
+            other => panic!("expected a driver change, got {:?}", other),
+        }
+
+        // The script repeats its last step once exhausted.
+        assert!(watcher.poll().unwrap().is_empty());
+    }
+
+    #[test]
+    fn filter_and_errors()
+    {
+        let mut source = ScriptedSource::new([vec![device("A", None), dfu("B")]]);
+        source.push_error(Error::Busy);
+        source.push(vec![dfu("B")]);
+
+        let mut watcher = DeviceWatcher::with_source(source)
+            .filter(|dev| dev.pid == 0x6018)
+            .report_existing(true);
+
+        let events = watcher.poll().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], DeviceEvent::Arrived(_, dev) if dev.pid == 0x6018));
+
+        assert_eq!(watcher.poll(), Err(Error::Busy));
+
+        let events = watcher.poll().unwrap();
+        assert!(matches!(&events[..], [DeviceEvent::Removed(..)]));
+    }
+}